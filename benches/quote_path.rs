@@ -0,0 +1,194 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Benchmarks of the work done for each verifier poll and key delivery
+//
+// The modules benchmarked are those of the keylime library, see lib.rs.
+// Quote generation needs a TPM: it is benchmarked on the TPM TCTI points
+// to, or the TPM device, and skipped without one. It is bound by the TPM,
+// so only numbers taken on the target device, or with the same swtpm,
+// compare.
+//
+// Run with:
+//
+//   $ TCTI=tabrmd:bus_type=session cargo bench
+//
+// and compare against a baseline saved with --save-baseline.
+
+use criterion::{
+    black_box, criterion_group, criterion_main, BenchmarkId, Criterion,
+    Throughput,
+};
+use openssl::rsa::Padding;
+use openssl::symm::{self, Cipher};
+use std::fs::File;
+use std::io::Write;
+use tss_esapi::interface_types::algorithm::{
+    AsymmetricAlgorithm, HashingAlgorithm,
+};
+
+use keylime::{crypto, ima, tpm};
+
+// An ima-ng entry as found in real measurement lists
+const IMA_ENTRY: &str = "10 0adefe762c149c7cec19da62f0da1297fcfbffff ima-ng sha256:f1125b940480d20ad841d26d5ea253edc0704b5ec1548c891edf212cb1a9365e /usr/lib64/libcrypto.so.1.1.1k\n";
+
+fn measurement_list(entries: usize) -> tempfile::NamedTempFile {
+    let mut file = tempfile::NamedTempFile::new().unwrap(); //#[allow_ci]
+    for _ in 0..entries {
+        file.write_all(IMA_ENTRY.as_bytes()).unwrap(); //#[allow_ci]
+    }
+    file
+}
+
+// The nonce of the verifier's quotes
+const NONCE: &[u8] = b"1234567890abcdefghij";
+
+fn quote(c: &mut Criterion) {
+    let (nk_pub, _) = crypto::rsa_generate_pair(2048).unwrap(); //#[allow_ci]
+
+    // Hashed for every quote, to be extended into PCR 16
+    let _ = c.bench_function("nk_digest", |b| {
+        b.iter(|| {
+            tpm::pubkey_to_tpm_digest(
+                black_box(&nk_pub),
+                HashingAlgorithm::Sha256,
+            )
+            .unwrap() //#[allow_ci]
+        })
+    });
+
+    let mut ctx = match tpm::get_tpm2_ctx() {
+        Ok(ctx) => ctx,
+        Err(e) => {
+            eprintln!("Skipping the quote benchmarks, no TPM: {}", e);
+            return;
+        }
+    };
+    let (ek_handle, _, _) =
+        tpm::create_ek(&mut ctx, AsymmetricAlgorithm::Rsa).unwrap(); //#[allow_ci]
+    let (ak_handle, _, _) = tpm::create_ak(&mut ctx, ek_handle).unwrap(); //#[allow_ci]
+
+    // Identity quotes, and integrity quotes of the PCRs of the default
+    // tpm_policy of the verifier
+    let mut group = c.benchmark_group("quote");
+    for (name, mask) in
+        [("identity", None), ("integrity", Some("0x408000"))].iter()
+    {
+        let _ = group.bench_with_input(
+            BenchmarkId::new("tpm_quote", name),
+            mask,
+            |b, mask| {
+                b.iter(|| {
+                    let nk_digest = tpm::pubkey_to_tpm_digest(
+                        &nk_pub,
+                        HashingAlgorithm::Sha256,
+                    )
+                    .unwrap(); //#[allow_ci]
+                    tpm::quote(
+                        &mut ctx,
+                        black_box(NONCE),
+                        *mask,
+                        HashingAlgorithm::Sha256,
+                        nk_digest,
+                        ak_handle,
+                    )
+                    .unwrap() //#[allow_ci]
+                })
+            },
+        );
+    }
+    group.finish();
+
+    let _ = ctx.flush_context(ak_handle.into());
+    let _ = ctx.flush_context(ek_handle.into());
+}
+
+fn ima(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ima_measurement_list");
+    for entries in [10_000, 100_000, 1_000_000].iter() {
+        let file = measurement_list(*entries);
+        let _ = group.throughput(Throughput::Elements(*entries as u64));
+
+        // What every integrity quote used to cost
+        let _ = group.bench_with_input(
+            BenchmarkId::new("full_read", entries),
+            entries,
+            |b, _| {
+                b.iter(|| {
+                    let mut ml = ima::MeasurementList::new(Some(
+                        File::open(file.path()).unwrap(), //#[allow_ci]
+                    ));
+                    black_box(ml.update(0).unwrap().len()) //#[allow_ci]
+                })
+            },
+        );
+
+        // A poll with no new entries since the previous one
+        let mut ml =
+            ima::MeasurementList::new(Some(File::open(file.path()).unwrap())); //#[allow_ci]
+        let _ = ml.update(0).unwrap(); //#[allow_ci]
+        let _ = group.bench_with_input(
+            BenchmarkId::new("cached", entries),
+            entries,
+            |b, _| b.iter(|| black_box(ml.update(0).unwrap().len())), //#[allow_ci]
+        );
+    }
+    group.finish();
+}
+
+fn payload(c: &mut Criterion) {
+    let key = [0x42u8; 32];
+    let iv = [0x24u8; 16];
+    let mut group = c.benchmark_group("payload_decryption");
+    for size in [4 * 1024, 1024 * 1024, 16 * 1024 * 1024].iter() {
+        let plaintext = vec![0x5au8; *size];
+        let mut tag = [0u8; 16];
+        let ciphertext = symm::encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &key,
+            Some(&iv),
+            &[],
+            &plaintext,
+            &mut tag,
+        )
+        .unwrap(); //#[allow_ci]
+        let data = [&iv[..], &ciphertext, &tag].concat();
+
+        let _ = group.throughput(Throughput::Bytes(*size as u64));
+        let _ = group.bench_with_input(
+            BenchmarkId::new("decrypt_aead", size),
+            &data,
+            |b, data| {
+                b.iter(|| {
+                    crypto::decrypt_aead(&key, black_box(data)).unwrap() //#[allow_ci]
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn key_shares(c: &mut Criterion) {
+    let (public, private) = crypto::rsa_generate_pair(2048).unwrap(); //#[allow_ci]
+    let rsa = public.rsa().unwrap(); //#[allow_ci]
+    let mut share = vec![0u8; rsa.size() as usize];
+    let len = rsa
+        .public_encrypt(&[0x11u8; 32], &mut share, Padding::PKCS1_OAEP)
+        .unwrap(); //#[allow_ci]
+    share.truncate(len);
+
+    let _ = c.bench_function("rsa_oaep_decrypt", |b| {
+        b.iter(|| {
+            crypto::rsa_oaep_decrypt(&private, black_box(&share)).unwrap() //#[allow_ci]
+        })
+    });
+    let _ = c.bench_function("hmac", |b| {
+        b.iter(|| {
+            crypto::compute_hmac(black_box(&[0x11u8; 32]), b"agent-uuid")
+                .unwrap() //#[allow_ci]
+        })
+    });
+}
+
+criterion_group!(benches, quote, ima, payload, key_shares);
+criterion_main!(benches);
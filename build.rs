@@ -0,0 +1,101 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Records how the agent was built, for keylime_agent --version and the
+// /info endpoint, see src/build_info.rs
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+// Returns the trimmed standard output of a command, if it succeeded
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let output = String::from_utf8(output.stdout).ok()?;
+    Some(output.trim().to_string()).filter(|output| !output.is_empty())
+}
+
+fn git_commit() -> Option<String> {
+    let commit = output("git", &["rev-parse", "--short=12", "HEAD"])?;
+    let dirty =
+        output("git", &["status", "--porcelain", "--untracked-files=no"])
+            .is_some();
+    Some(if dirty {
+        format!("{}-dirty", commit)
+    } else {
+        commit
+    })
+}
+
+// SOURCE_DATE_EPOCH keeps reproducible builds reproducible
+fn build_date() -> Option<String> {
+    match env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => output(
+            "date",
+            &["-u", "-d", &format!("@{}", epoch), "+%Y-%m-%dT%H:%M:%SZ"],
+        ),
+        Err(_) => output("date", &["-u", "+%Y-%m-%dT%H:%M:%SZ"]),
+    }
+}
+
+// Version of a dependency as resolved in Cargo.lock
+fn locked_version(package: &str) -> Option<String> {
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").ok()?;
+    let lock =
+        fs::read_to_string(Path::new(&manifest_dir).join("Cargo.lock"))
+            .ok()?;
+    let name = format!("name = \"{}\"", package);
+    let mut lines = lock.lines();
+    while let Some(line) = lines.next() {
+        if line == name {
+            return lines
+                .next()?
+                .strip_prefix("version = \"")?
+                .strip_suffix('"')
+                .map(String::from);
+        }
+    }
+    None
+}
+
+fn features() -> String {
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(name, _)| {
+            name.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+    features.join(",")
+}
+
+fn main() {
+    let unknown = || String::from("unknown");
+    println!(
+        "cargo:rustc-env=KEYLIME_GIT_COMMIT={}",
+        git_commit().unwrap_or_else(unknown)
+    );
+    println!(
+        "cargo:rustc-env=KEYLIME_BUILD_DATE={}",
+        build_date().unwrap_or_else(unknown)
+    );
+    println!("cargo:rustc-env=KEYLIME_FEATURES={}", features());
+    println!(
+        "cargo:rustc-env=KEYLIME_TSS_ESAPI_VERSION={}",
+        locked_version("tss-esapi").unwrap_or_else(unknown)
+    );
+    println!(
+        "cargo:rustc-env=KEYLIME_TPM2_TSS_VERSION={}",
+        output("pkg-config", &["--modversion", "tss2-esys"])
+            .unwrap_or_else(unknown)
+    );
+
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
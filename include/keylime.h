@@ -0,0 +1,91 @@
+/* SPDX-License-Identifier: Apache-2.0 */
+
+#ifndef KEYLIME_H
+#define KEYLIME_H
+
+/* Generated with cbindgen from src/ffi.rs, do not edit. */
+
+#include <stddef.h>
+#include <stdint.h>
+
+/*
+ * TPM context with the AK quotes are made with, and the IMA measurement
+ * list read so far
+ */
+typedef struct KeylimeContext KeylimeContext;
+
+/*
+ * Message of the last failure on this thread, NULL if none. Valid until
+ * the next call on this thread.
+ */
+const char *keylime_last_error(void);
+
+/*
+ * Frees a string returned by this library
+ *
+ * # Safety
+ *
+ * s is NULL or a string returned by this library, not freed yet.
+ */
+void keylime_string_free(char *s);
+
+/*
+ * Opens the TPM and creates the EK and an AK, NULL on failure
+ */
+KeylimeContext *keylime_context_new(void);
+
+/*
+ * Flushes the AK and closes the TPM
+ *
+ * # Safety
+ *
+ * context is NULL or returned by keylime_context_new(), not freed yet.
+ */
+void keylime_context_free(KeylimeContext *context);
+
+/*
+ * The public area of the AK, as a marshaled TPM2B_PUBLIC to register, in
+ * buf and len. Valid as long as the context is.
+ *
+ * # Safety
+ *
+ * context is valid, buf and len are valid for writes.
+ */
+int keylime_ak_public(const KeylimeContext *context, const uint8_t **buf, size_t *len);
+
+/*
+ * Identity quote for the nonce, with PCR 16 extended with the digest of
+ * the NK, in PEM. The quote is returned in quote, in the format of the
+ * agent API: 'r' + quote + signature + PCR blob.
+ *
+ * # Safety
+ *
+ * context is valid, nonce and nk_pem are NUL-terminated strings and quote
+ * is valid for writes.
+ */
+int keylime_identity_quote(KeylimeContext *context,
+                           const char *nonce,
+                           const char *nk_pem,
+                           char **quote);
+
+/*
+ * Integrity quote for the nonce and PCR mask, ex. "0x408000", and the IMA
+ * entries from ima_ml_entry on, or the whole list if it has fewer
+ * entries. The quote is returned in quote, the entries in ima_ml and the
+ * index of the first of them in ima_ml_first.
+ *
+ * # Safety
+ *
+ * context is valid, nonce, mask and nk_pem are NUL-terminated strings,
+ * and quote, ima_ml and ima_ml_first are valid for writes.
+ */
+int keylime_integrity_quote(KeylimeContext *context,
+                            const char *nonce,
+                            const char *mask,
+                            const char *nk_pem,
+                            size_t ima_ml_entry,
+                            char **quote,
+                            char **ima_ml,
+                            size_t *ima_ml_first);
+
+#endif /* KEYLIME_H */
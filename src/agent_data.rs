@@ -0,0 +1,393 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Agent state persisted across reboots
+//
+// The secure directory is a tmpfs, so everything delivered by the tenant is
+// lost on reboot. When re-provisioning is enabled, the bootstrap key K is
+// sealed to the TPM under a PCR policy and stored, together with the payload
+// still encrypted with K, in the agent_data file of the work directory. On
+// the next start the key is unsealed, which only succeeds if the PCRs hold
+// the same values, and the payload is provisioned again without waiting for
+// the tenant and verifier to deliver U and V.
+//
+// The AK is created on the first start and stored there as well, protected
+// by the EK, so that the agent keeps the AK it registered with, and the
+// show-ak command prints it.
+//
+// Clearing the TPM makes all of this unusable, so a fingerprint of the TPM
+// owner hierarchy is stored as well. When it changed, the agent data is
+// discarded and the agent starts afresh.
+
+use crate::common::{config_get, config_get_or, work_dir_get, AGENT_DATA};
+use crate::error::{Error, Result};
+#[cfg(feature = "testing")]
+use crate::fault;
+use crate::{persist, tpm};
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+use tss_esapi::{structures::PcrSelectionListBuilder, Context};
+
+/// Default PCRs the bootstrap key is sealed to: the firmware and boot loader
+/// measurements in PCRs 0-7
+pub(crate) static DEFAULT_REPROVISION_PCR_MASK: &str = "0xff";
+
+/// Data sealed to the TPM, bound to the values of a set of PCRs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct SealedData {
+    /// PCRs the data is bound to, as a mask in the format of read_mask
+    pub pcr_mask: String,
+    /// PCR bank, as in tpm_hash_alg
+    pub hash_alg: String,
+    /// Base64 of the marshaled TPM2B_PUBLIC of the sealed object
+    pub public: String,
+    /// Base64 of the TPM2B_PRIVATE of the sealed object
+    pub private: String,
+}
+
+impl SealedData {
+    /// Seals data to the current values of the PCRs in pcr_mask
+    pub(crate) fn seal(
+        ctx: &mut Context,
+        pcr_mask: &str,
+        hash_alg: &str,
+        data: &[u8],
+    ) -> Result<Self> {
+        let pcrlist = PcrSelectionListBuilder::new()
+            .with_selection(
+                tpm::get_hash_alg(hash_alg.to_string())?,
+                &tpm::read_mask(pcr_mask)?,
+            )
+            .build();
+        let (public, private) = tpm::seal_data(ctx, pcrlist, data)?;
+
+        Ok(SealedData {
+            pcr_mask: pcr_mask.to_string(),
+            hash_alg: hash_alg.to_string(),
+            public: base64::encode(public),
+            private: base64::encode(private),
+        })
+    }
+
+    /// Unseals the data, failing if the PCRs changed since it was sealed
+    pub(crate) fn unseal(&self, ctx: &mut Context) -> Result<Vec<u8>> {
+        let pcrlist = PcrSelectionListBuilder::new()
+            .with_selection(
+                tpm::get_hash_alg(self.hash_alg.clone())?,
+                &tpm::read_mask(&self.pcr_mask)?,
+            )
+            .build();
+
+        tpm::unseal_data(
+            ctx,
+            pcrlist,
+            &base64::decode(&self.public)?,
+            &base64::decode(&self.private)?,
+        )
+    }
+}
+
+/// Payload kept for re-provisioning after a reboot
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct SealedPayload {
+    /// The bootstrap key K, sealed to the TPM
+    pub key: SealedData,
+    /// The payload as delivered by the tenant, still encrypted with K
+    pub payload: Option<String>,
+}
+
+/// AK created on the first start, loaded under the EK on the next ones
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct PersistedAk {
+    /// Base64 of the marshaled TPM2B_PUBLIC of the AK
+    pub public: String,
+    /// Base64 of the TPM2B_PRIVATE of the AK
+    pub private: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct AgentData {
+    /// Fingerprint of the TPM owner hierarchy, see tpm::owner_fingerprint
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tpm_fingerprint: Option<String>,
+    /// UUID generated with agent_uuid = generate
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<SealedPayload>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ak: Option<PersistedAk>,
+}
+
+/// Returns the path of the agent_data file in the work directory
+pub(crate) fn agent_data_path() -> PathBuf {
+    Path::new(&work_dir_get()).join(AGENT_DATA)
+}
+
+impl AgentData {
+    /// Loads the agent data, or returns the default if the file does not
+    /// exist yet
+    ///
+    /// A file that cannot be parsed is set aside, and the agent starts
+    /// afresh as if there was none.
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        #[cfg(feature = "testing")]
+        fault::file(path)?;
+        match fs::read(path) {
+            Ok(data) => match serde_json::from_slice(&data) {
+                Ok(agent_data) => Ok(agent_data),
+                Err(e) => {
+                    warn!("Unable to parse {}: {}", path.display(), e);
+                    persist::set_aside(path)?;
+                    Ok(AgentData::default())
+                }
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                Ok(AgentData::default())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Stores the agent data, readable only by the agent
+    pub(crate) fn store(&self, path: &Path) -> Result<()> {
+        persist::write(path, 0o600, &serde_json::to_vec(self)?)
+    }
+}
+
+impl AgentData {
+    // Whether the data was stored for another TPM owner hierarchy
+    pub(crate) fn tpm_changed(&self, fingerprint: &str) -> bool {
+        match &self.tpm_fingerprint {
+            Some(stored) => stored != fingerprint,
+            None => false,
+        }
+    }
+}
+
+/// Whether the TPM was cleared since the agent data was stored
+pub(crate) fn tpm_cleared(ctx: &mut Context) -> Result<bool> {
+    let agent_data = AgentData::load(&agent_data_path())?;
+    Ok(agent_data.tpm_changed(&tpm::owner_fingerprint(ctx)?))
+}
+
+/*
+ * Input: TPM context
+ * Return: Result wrap whether the TPM was cleared
+ *
+ * Records the fingerprint of the TPM owner hierarchy on the first start.
+ * On later starts, if the TPM was cleared in between, the persisted
+ * payload and generated UUID are discarded, as they belong to an identity
+ * the TPM no longer has.
+ */
+pub(crate) fn reset_if_tpm_cleared(ctx: &mut Context) -> Result<bool> {
+    let path = agent_data_path();
+    let mut agent_data = AgentData::load(&path)?;
+    let fingerprint = tpm::owner_fingerprint(ctx)?;
+
+    let cleared = agent_data.tpm_changed(&fingerprint);
+    if cleared {
+        agent_data = AgentData::default();
+    }
+    if agent_data.tpm_fingerprint.is_none() {
+        agent_data.tpm_fingerprint = Some(fingerprint);
+        agent_data.store(&path)?;
+    }
+
+    Ok(cleared)
+}
+
+// Loads the EK and the persisted AK, if any
+fn load_persisted_keys(
+    ctx: &mut Context,
+    ak: &PersistedAk,
+) -> Result<tpm::AgentKeys> {
+    let public = base64::decode(&ak.public)?;
+    let private = base64::decode(&ak.private)?;
+    tpm::load_keys(
+        ctx,
+        tpm::AkSource::Load {
+            public: &public,
+            private: &private,
+        },
+    )
+}
+
+/*
+ * Input: TPM context, whether to create the AK if none is persisted
+ * Return: Result wrap the EK and AK
+ *
+ * Loads the EK and the AK persisted in the agent data. On the first start,
+ * or if the AK can no longer be loaded, a new AK is created and persisted
+ * in its place.
+ */
+pub(crate) fn load_keys(
+    ctx: &mut Context,
+    create: bool,
+) -> Result<tpm::AgentKeys> {
+    let path = agent_data_path();
+    let mut agent_data = AgentData::load(&path)?;
+    if let Some(ak) = &agent_data.ak {
+        match load_persisted_keys(ctx, ak) {
+            Ok(keys) => return Ok(keys),
+            Err(e) if create => {
+                warn!("Unable to load the persisted AK, creating one: {}", e)
+            }
+            Err(e) => return Err(e),
+        }
+    } else if !create {
+        return Err(Error::Other(format!(
+            "No AK in {}, the agent creates it on its first start",
+            path.display()
+        )));
+    }
+
+    let keys = tpm::load_keys(ctx, tpm::AkSource::Create)?;
+    let ak = keys.ak()?;
+    agent_data.ak = Some(PersistedAk {
+        public: base64::encode(&ak.tpm2b_pub),
+        private: base64::encode(&ak.private),
+    });
+    if let Err(e) = agent_data.store(&path) {
+        let _ = keys.flush(ctx);
+        return Err(e);
+    }
+    info!("Created the AK and stored it in {}", path.display());
+    Ok(keys)
+}
+
+/// Seals the bootstrap key and stores it along with the encrypted payload,
+/// replacing any payload persisted earlier
+pub(crate) fn persist_payload(
+    ctx: &mut Context,
+    key: &[u8],
+    payload: Option<&str>,
+) -> Result<()> {
+    let pcr_mask = config_get_or(
+        "cloud_agent",
+        "reprovision_pcr_mask",
+        DEFAULT_REPROVISION_PCR_MASK,
+    )?;
+    let hash_alg = config_get("cloud_agent", "tpm_hash_alg")?;
+
+    let path = agent_data_path();
+    let mut agent_data = AgentData::load(&path)?;
+    agent_data.payload = Some(SealedPayload {
+        key: SealedData::seal(ctx, &pcr_mask, &hash_alg, key)?,
+        payload: payload.map(String::from),
+    });
+    agent_data.store(&path)?;
+
+    info!(
+        "Sealed bootstrap key to PCRs {} and stored payload in {}",
+        pcr_mask,
+        path.display()
+    );
+    Ok(())
+}
+
+/// Seals the persisted bootstrap key again, to the PCRs of the
+/// configuration, when reprovision_pcr_mask or tpm_hash_alg changed since
+/// it was sealed
+///
+/// Returns whether the key was sealed again. Fails if it cannot be
+/// unsealed.
+pub(crate) fn reseal_payload(ctx: &mut Context) -> Result<bool> {
+    let sealed = match AgentData::load(&agent_data_path())?.payload {
+        Some(sealed) => sealed,
+        None => return Ok(false),
+    };
+    let pcr_mask = config_get_or(
+        "cloud_agent",
+        "reprovision_pcr_mask",
+        DEFAULT_REPROVISION_PCR_MASK,
+    )?;
+    let hash_alg = config_get("cloud_agent", "tpm_hash_alg")?;
+    if sealed.key.pcr_mask == pcr_mask && sealed.key.hash_alg == hash_alg {
+        return Ok(false);
+    }
+
+    match restore_payload(ctx)? {
+        Some((key, payload)) => {
+            persist_payload(ctx, &key, payload.as_deref())?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Returns the bootstrap key and encrypted payload stored by
+/// persist_payload, if any
+///
+/// Fails if the key cannot be unsealed, e.g. because the boot measurements
+/// changed.
+pub(crate) fn restore_payload(
+    ctx: &mut Context,
+) -> Result<Option<(Vec<u8>, Option<String>)>> {
+    let agent_data = AgentData::load(&agent_data_path())?;
+    let sealed = match agent_data.payload {
+        Some(sealed) => sealed,
+        None => return Ok(None),
+    };
+
+    let key = sealed.key.unseal(ctx).map_err(|e| {
+        Error::Other(format!(
+            "Unable to unseal bootstrap key from PCRs {}: {}",
+            sealed.key.pcr_mask, e
+        ))
+    })?;
+
+    Ok(Some((key, sealed.payload)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn agent_data_store_load() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join(AGENT_DATA);
+
+        assert_eq!(AgentData::load(&path).unwrap(), AgentData::default()); //#[allow_ci]
+
+        let agent_data = AgentData {
+            tpm_fingerprint: Some(String::from("000b1234")),
+            uuid: Some(String::from("d432fbb3-d2f1-4a97-9ef7-75bd81c00000")),
+            payload: Some(SealedPayload {
+                key: SealedData {
+                    pcr_mask: String::from("0xff"),
+                    hash_alg: String::from("sha256"),
+                    public: base64::encode(b"public"),
+                    private: base64::encode(b"private"),
+                },
+                payload: Some(String::from("cGF5bG9hZA==")),
+            }),
+            ak: Some(PersistedAk {
+                public: base64::encode(b"public"),
+                private: base64::encode(b"private"),
+            }),
+        };
+        agent_data.store(&path).unwrap(); //#[allow_ci]
+        assert_eq!(AgentData::load(&path).unwrap(), agent_data); //#[allow_ci]
+
+        fs::write(&path, b"{\"uuid\": \"d432").unwrap(); //#[allow_ci]
+        assert_eq!(AgentData::load(&path).unwrap(), AgentData::default()); //#[allow_ci]
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn tpm_fingerprint() {
+        let mut agent_data = AgentData::default();
+        // Nothing to compare to on the first start
+        assert!(!agent_data.tpm_changed("000b1234"));
+
+        agent_data.tpm_fingerprint = Some(String::from("000b1234"));
+        assert!(!agent_data.tpm_changed("000b1234"));
+        assert!(agent_data.tpm_changed("000b5678"));
+    }
+}
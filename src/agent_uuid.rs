@@ -0,0 +1,213 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Agent UUID
+//
+// agent_uuid in keylime.conf takes the values of the Python agent: a
+// literal UUID, generate for a random UUID, hash_ek for the SHA-256 of the
+// EK public key, or openstack. In addition, dmidecode uses the system UUID
+// set by the firmware, and file:<path> and env:<variable> read the UUID
+// from a file or an environment variable, so that the same keylime.conf can
+// be deployed to every node. A generated UUID is stored in agent_data, so
+// that the agent keeps its identity across restarts.
+
+use crate::agent_data::{agent_data_path, AgentData};
+use crate::error::{Error, Result};
+use crate::tpm;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// System UUID from the SMBIOS tables, as printed by dmidecode -s
+/// system-uuid
+pub(crate) static DMI_PRODUCT_UUID: &str = "/sys/class/dmi/id/product_uuid";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum UuidSource {
+    Literal(String),
+    Generate,
+    HashEk,
+    Openstack,
+    Dmidecode,
+    File(PathBuf),
+    Env(String),
+}
+
+fn parse_source(config: &str) -> UuidSource {
+    let config = config.trim();
+    match config {
+        "generate" => UuidSource::Generate,
+        "hash_ek" => UuidSource::HashEk,
+        "openstack" => UuidSource::Openstack,
+        "dmidecode" => UuidSource::Dmidecode,
+        _ if config.starts_with("file:") => {
+            UuidSource::File(PathBuf::from(&config["file:".len()..]))
+        }
+        _ if config.starts_with("env:") => {
+            UuidSource::Env(config["env:".len()..].to_string())
+        }
+        _ => match Uuid::parse_str(config) {
+            Ok(uuid) => UuidSource::Literal(uuid.to_string()),
+            Err(_) => {
+                warn!("Misformatted UUID {}, generating one", config);
+                UuidSource::Generate
+            }
+        },
+    }
+}
+
+// Normalizes a UUID read from outside of keylime.conf
+fn parse_uuid(uuid: &str, source: &str) -> Result<String> {
+    Uuid::parse_str(uuid.trim())
+        .map(|uuid| uuid.to_string())
+        .map_err(|e| {
+            Error::config_value(
+                "cloud_agent",
+                "agent_uuid",
+                format!("{:?} from {}: {}", uuid.trim(), source, e),
+            )
+        })
+}
+
+// Returns the UUID generated on a previous start, if any
+fn stored(path: &Path) -> Result<Option<String>> {
+    Ok(AgentData::load(path)?.uuid)
+}
+
+// Returns the UUID generated on a previous start, or generates and stores
+// a new one
+fn generated(path: &Path) -> Result<String> {
+    let mut agent_data = AgentData::load(path)?;
+    if let Some(uuid) = &agent_data.uuid {
+        info!("Using the UUID generated earlier: {}", uuid);
+        return Ok(uuid.clone());
+    }
+
+    let uuid = Uuid::new_v4().to_string();
+    agent_data.uuid = Some(uuid.clone());
+    agent_data.store(path)?;
+    info!("Generated a new UUID: {}", uuid);
+    Ok(uuid)
+}
+
+fn hash_ek(ek_tpm2b_pub: &[u8]) -> Result<String> {
+    let pem = tpm::ek_pub_to_pem(ek_tpm2b_pub)?;
+    Ok(hex::encode(openssl::sha::sha256(&pem)))
+}
+
+/*
+ * Input: agent_uuid from keylime.conf
+ *        marshaled TPM2B_PUBLIC of the EK
+ * Return: Result wrap the agent UUID
+ */
+pub(crate) fn get(config: &str, ek_tpm2b_pub: &[u8]) -> Result<String> {
+    match configured(config, ek_tpm2b_pub)? {
+        Some(uuid) => Ok(uuid),
+        None => generated(&agent_data_path()),
+    }
+}
+
+/*
+ * Input: agent_uuid from keylime.conf
+ *        marshaled TPM2B_PUBLIC of the EK
+ * Return: Result wrap the agent UUID, None if it is to be generated and
+ *         was not yet
+ *
+ * As get(), but never generates and stores a UUID, for the maintenance
+ * commands.
+ */
+pub(crate) fn lookup(
+    config: &str,
+    ek_tpm2b_pub: &[u8],
+) -> Result<Option<String>> {
+    match configured(config, ek_tpm2b_pub)? {
+        Some(uuid) => Ok(Some(uuid)),
+        None => stored(&agent_data_path()),
+    }
+}
+
+// The UUID from the source configured, None if it is to be generated
+fn configured(config: &str, ek_tpm2b_pub: &[u8]) -> Result<Option<String>> {
+    let uuid = match parse_source(config) {
+        UuidSource::Literal(uuid) => uuid,
+        UuidSource::Generate => return Ok(None),
+        UuidSource::HashEk => hash_ek(ek_tpm2b_pub)?,
+        UuidSource::Openstack => {
+            info!("Openstack placeholder...");
+            "openstack".into()
+        }
+        UuidSource::Dmidecode => parse_uuid(
+            &fs::read_to_string(DMI_PRODUCT_UUID)
+                .map_err(|e| Error::file(DMI_PRODUCT_UUID, e))?,
+            DMI_PRODUCT_UUID,
+        )?,
+        UuidSource::File(path) => parse_uuid(
+            &fs::read_to_string(&path).map_err(|e| Error::file(&path, e))?,
+            &path.display().to_string(),
+        )?,
+        UuidSource::Env(var) => parse_uuid(
+            &env::var(&var).map_err(|e| {
+                Error::config_value(
+                    "cloud_agent",
+                    "agent_uuid",
+                    format!("unable to read ${}: {}", var, e),
+                )
+            })?,
+            &format!("${}", var),
+        )?,
+    };
+    Ok(Some(uuid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uuid_source() {
+        assert_eq!(parse_source("openstack"), UuidSource::Openstack);
+        assert_eq!(parse_source("hash_ek"), UuidSource::HashEk);
+        assert_eq!(parse_source("generate"), UuidSource::Generate);
+        assert_eq!(parse_source("dmidecode"), UuidSource::Dmidecode);
+        assert_eq!(
+            parse_source("file:/etc/keylime/uuid"),
+            UuidSource::File(PathBuf::from("/etc/keylime/uuid"))
+        );
+        assert_eq!(
+            parse_source("env:AGENT_UUID"),
+            UuidSource::Env(String::from("AGENT_UUID"))
+        );
+        assert_eq!(
+            parse_source("D432FBB3-D2F1-4A97-9EF7-75BD81C00000"),
+            UuidSource::Literal(String::from(
+                "d432fbb3-d2f1-4a97-9ef7-75bd81c00000"
+            ))
+        );
+        assert_eq!(
+            parse_source("D432FBB3-D2F1-4A97-9EF7-75BD81C0000X"),
+            UuidSource::Generate
+        );
+
+        assert!(parse_uuid("D432FBB3-D2F1-4A97-9EF7-75BD81C00000\n", "test")
+            .is_ok());
+        assert!(parse_uuid("not a uuid", "test").is_err());
+    }
+
+    #[test]
+    fn generated_uuid_persists() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join("agent_data.json");
+
+        // Looking the UUID up does not generate it
+        assert_eq!(stored(&path).unwrap(), None); //#[allow_ci]
+        assert!(!path.exists());
+
+        let uuid = generated(&path).unwrap(); //#[allow_ci]
+        let _ = Uuid::parse_str(&uuid).unwrap(); //#[allow_ci]
+        assert_eq!(generated(&path).unwrap(), uuid); //#[allow_ci]
+        assert_eq!(stored(&path).unwrap(), Some(uuid)); //#[allow_ci]
+    }
+}
@@ -0,0 +1,270 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Failure alerts
+//
+// Devices without central log collection can still report attestation
+// problems: with failure_alert_threshold set in keylime.conf, the failure
+// hooks are fired when that many TPM errors, or registrar errors, happen
+// within failure_alert_window minutes. After an alert, the same kind of
+// errors does not raise another one before the window elapsed.
+//
+// Registration failures stop the agent, so the failures are recorded in
+// the runtime directory, to be counted across the restarts of the agent by
+// its service manager.
+
+use crate::common::{config_get_or, run_dir_get};
+use crate::error::{Error, Result};
+use crate::{hooks, persist};
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tracing::warn;
+
+static FAILURES_FILE: &str = "failures.json";
+
+/// Default window of failure_alert_window, in minutes
+pub(crate) static DEFAULT_FAILURE_ALERT_WINDOW: &str = "60";
+
+/// Kinds of errors counted separately
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Source {
+    Tpm,
+    Registrar,
+}
+
+impl Source {
+    /// Kind of an error, if it is counted
+    pub(crate) fn of(error: &Error) -> Option<Source> {
+        match error {
+            Error::Tpm { .. } | Error::TpmInUse => Some(Source::Tpm),
+            Error::Registrar { .. } | Error::Reqwest(_) => {
+                Some(Source::Registrar)
+            }
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Source::Tpm => "tpm",
+            Source::Registrar => "registrar",
+        }
+    }
+
+    fn event(&self) -> hooks::Event {
+        match self {
+            Source::Tpm => hooks::Event::TpmErrors,
+            Source::Registrar => hooks::Event::RegistrarErrors,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Window {
+    /// Times of the recent failures, in seconds since the epoch
+    failures: Vec<u64>,
+    /// Time of the last alert
+    last_alert: Option<u64>,
+}
+
+impl Window {
+    /*
+     * Input: time of the failure, alert threshold and window in seconds
+     * Return: whether to alert
+     */
+    fn record(&mut self, now: u64, threshold: usize, window: u64) -> bool {
+        self.failures
+            .retain(|time| now.saturating_sub(*time) < window);
+        self.failures.push(now);
+
+        let debounced = match self.last_alert {
+            Some(last) => now.saturating_sub(last) < window,
+            None => false,
+        };
+        if self.failures.len() < threshold || debounced {
+            return false;
+        }
+        self.last_alert = Some(now);
+        self.failures.clear();
+        true
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Config {
+    threshold: usize,
+    window: u64,
+}
+
+lazy_static! {
+    // Hook firing task, see run()
+    static ref ALERTS: Mutex<Option<UnboundedSender<Source>>> =
+        Mutex::new(None);
+}
+
+fn config_get() -> Result<Option<Config>> {
+    let threshold =
+        config_get_or("cloud_agent", "failure_alert_threshold", "0")?
+            .trim()
+            .parse::<usize>()
+            .map_err(|e| {
+                Error::config_value(
+                    "cloud_agent",
+                    "failure_alert_threshold",
+                    e,
+                )
+            })?;
+    if threshold == 0 {
+        return Ok(None);
+    }
+    let window = config_get_or(
+        "cloud_agent",
+        "failure_alert_window",
+        DEFAULT_FAILURE_ALERT_WINDOW,
+    )?
+    .trim()
+    .parse::<u64>()
+    .map_err(|e| {
+        Error::config_value("cloud_agent", "failure_alert_window", e)
+    })?;
+
+    Ok(Some(Config {
+        threshold,
+        window: window * 60,
+    }))
+}
+
+fn failures_path() -> PathBuf {
+    Path::new(&run_dir_get()).join(FAILURES_FILE)
+}
+
+// Records the failure, and returns whether to alert
+fn record(source: Source, config: Config, path: &Path) -> Result<bool> {
+    let mut windows: BTreeMap<String, Window> = match fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data).unwrap_or_default(),
+        Err(_) => BTreeMap::new(),
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0);
+
+    let alert = windows
+        .entry(source.name().to_string())
+        .or_default()
+        .record(now, config.threshold, config.window);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    persist::write(path, 0o600, &serde_json::to_vec(&windows)?)?;
+    Ok(alert)
+}
+
+fn record_checked(source: Source) -> bool {
+    let config = match config_get() {
+        Ok(Some(config)) => config,
+        Ok(None) => return false,
+        Err(e) => {
+            warn!("Unable to read the failure alert settings: {}", e);
+            return false;
+        }
+    };
+    match record(source, config, &failures_path()) {
+        Ok(alert) => alert,
+        Err(e) => {
+            warn!("Unable to record {} failure: {}", source.name(), e);
+            false
+        }
+    }
+}
+
+async fn alert(source: Source, agent_uuid: &str) {
+    warn!(
+        "Repeated {} errors, firing the failure hooks",
+        source.name()
+    );
+    hooks::notify(source.event(), agent_uuid).await;
+}
+
+/// Records a failure from synchronous code, the hooks being fired by the
+/// task started with run()
+pub(crate) fn failure(error: &Error) {
+    let source = match Source::of(error) {
+        Some(source) => source,
+        None => return,
+    };
+    if !record_checked(source) {
+        return;
+    }
+    if let Ok(alerts) = ALERTS.lock() {
+        if let Some(sender) = alerts.as_ref() {
+            let _ = sender.send(source);
+        }
+    }
+}
+
+/// Records a failure and fires the hooks right away, e.g. before the agent
+/// stops
+pub(crate) async fn failure_now(error: &Error, agent_uuid: &str) {
+    if let Some(source) = Source::of(error) {
+        if record_checked(source) {
+            alert(source, agent_uuid).await;
+        }
+    }
+}
+
+/// Returns the task firing the hooks for the failures recorded with
+/// failure()
+pub(crate) fn run(
+    agent_uuid: String,
+) -> impl std::future::Future<Output = ()> {
+    let (sender, mut receiver) = mpsc::unbounded_channel::<Source>();
+    if let Ok(mut alerts) = ALERTS.lock() {
+        *alerts = Some(sender);
+    }
+
+    async move {
+        while let Some(source) = receiver.recv().await {
+            alert(source, &agent_uuid).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failure_window() {
+        let mut window = Window::default();
+        assert!(!window.record(100, 2, 60));
+        // The first failure is out of the window
+        assert!(!window.record(170, 2, 60));
+        assert!(window.record(175, 2, 60));
+
+        // Debounced until the window elapsed
+        assert!(!window.record(180, 1, 60));
+        assert!(window.record(235, 1, 60));
+    }
+
+    #[test]
+    fn failures_persist() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join(FAILURES_FILE);
+        let config = Config {
+            threshold: 2,
+            window: 600,
+        };
+
+        assert!(!record(Source::Registrar, config, &path).unwrap()); //#[allow_ci]
+        assert!(!record(Source::Tpm, config, &path).unwrap()); //#[allow_ci]
+        assert!(record(Source::Registrar, config, &path).unwrap()); //#[allow_ci]
+    }
+}
@@ -0,0 +1,155 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Keylime API versions
+//
+// Verifiers and tenants up to Keylime 6.0 use the 1.0 API, with paths
+// either without a version or under /v1.0. Current verifiers first ask the
+// agent for its version at /version, and then use the paths under /v2.0.
+// The same handlers serve every version, and answer in the shape of the
+// version of the path the request came through:
+//
+// - 2.0 integrity quotes take the index of the first IMA entry the
+//   verifier does not have yet, ima_ml_entry, and only return the entries
+//   from there on, along with that index in ima_measurement_list_entry.
+// - 1.0 integrity quotes always return the whole measurement list.
+
+use crate::{keys_handler, quotes_handler};
+
+use actix_web::{web, HttpResponse, Responder};
+use serde_json::json;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum ApiVersion {
+    V1_0,
+    V2_0,
+}
+
+pub(crate) const SUPPORTED_VERSIONS: &[ApiVersion] =
+    &[ApiVersion::V1_0, ApiVersion::V2_0];
+
+impl ApiVersion {
+    /// The version announced at /version
+    pub(crate) const CURRENT: ApiVersion = ApiVersion::V2_0;
+
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            ApiVersion::V1_0 => "1.0",
+            ApiVersion::V2_0 => "2.0",
+        }
+    }
+}
+
+impl fmt::Display for ApiVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+// The endpoints of every version. Runtime policies are much larger than
+// the other requests, so they get their own limit, 0 for none.
+fn routes(cfg: &mut web::ServiceConfig, max_policy_size: usize) {
+    let max_policy_size = match max_policy_size {
+        0 => usize::MAX,
+        size => size,
+    };
+    let _ = cfg
+        .service(
+            web::resource("/keys/verify")
+                .route(web::get().to(keys_handler::verify)),
+        )
+        .service(
+            web::resource("/keys/ukey")
+                .route(web::post().to(keys_handler::u_key)),
+        )
+        .service(
+            web::resource("/keys/vkey")
+                .route(web::post().to(keys_handler::v_key)),
+        )
+        .service(
+            web::resource("/policies/runtime")
+                .app_data(web::JsonConfig::default().limit(max_policy_size))
+                .route(web::post().to(keys_handler::runtime_policy)),
+        )
+        .service(
+            web::resource("/quotes/identity")
+                .route(web::get().to(quotes_handler::identity)),
+        )
+        .service(
+            web::resource("/quotes/integrity")
+                .route(web::get().to(quotes_handler::integrity)),
+        );
+}
+
+/// Registers the endpoints of all supported versions. The version of the
+/// path a request came through is available to the handlers as
+/// web::Data<ApiVersion>.
+pub(crate) fn configure(
+    cfg: &mut web::ServiceConfig,
+    max_policy_size: usize,
+) {
+    let _ =
+        cfg.service(web::resource("/version").route(web::get().to(version)));
+    for version in SUPPORTED_VERSIONS {
+        let _ = cfg.service(
+            web::scope(&format!("/v{}", version))
+                .data(*version)
+                .configure(|cfg| routes(cfg, max_policy_size)),
+        );
+    }
+    let _ =
+        cfg.service(web::resource("/v{version}/{tail:.*}").to(unsupported));
+
+    // Paths without a version are the 1.0 API
+    let _ = cfg.data(ApiVersion::V1_0);
+    routes(cfg, max_policy_size);
+}
+
+// Returns the version verifiers should use, in the same envelope as the
+// quotes
+pub async fn version() -> impl Responder {
+    HttpResponse::Ok().json(json!({
+        "code": 200,
+        "status": "Success",
+        "results": {"supported_version": ApiVersion::CURRENT.as_str()},
+    }))
+}
+
+pub async fn unsupported(
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (version, _) = path.into_inner();
+    HttpResponse::BadRequest().json(json!({
+        "code": 400,
+        "status": format!("API version not supported: {}", version),
+        "results": {},
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+
+    #[test]
+    fn api_version_negotiation() {
+        actix_web::rt::System::new("test").block_on(async {
+            let mut app = test::init_service(
+                App::new().configure(|cfg| configure(cfg, 0)),
+            )
+            .await;
+
+            let req = test::TestRequest::get().uri("/version").to_request();
+            let resp: serde_json::Value =
+                test::read_response_json(&mut app, req).await;
+            assert_eq!(resp["results"]["supported_version"], "2.0");
+
+            let req = test::TestRequest::get()
+                .uri("/v3.0/keys/verify")
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), 400);
+        });
+    }
+}
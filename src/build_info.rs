@@ -0,0 +1,105 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Build information
+//
+// What binary is running, as recorded by build.rs: the version and git
+// commit of the agent, when it was built, with which cargo features, and
+// the versions of the TSS and OpenSSL it uses. keylime_agent --version
+// prints it, the /info endpoint returns it and the agent logs it when it
+// starts, so that bug reports tell exactly what was running.
+
+use actix_web::{HttpResponse, Responder};
+use serde::Serialize;
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BuildInfo {
+    pub version: &'static str,
+    pub git_commit: &'static str,
+    pub build_date: &'static str,
+    pub features: Vec<&'static str>,
+    pub tss_esapi: &'static str,
+    pub tpm2_tss: &'static str,
+    // OpenSSL is linked dynamically, so this is the library in use rather
+    // than the one built against
+    pub openssl: &'static str,
+}
+
+pub(crate) fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_commit: env!("KEYLIME_GIT_COMMIT"),
+        build_date: env!("KEYLIME_BUILD_DATE"),
+        features: env!("KEYLIME_FEATURES")
+            .split(',')
+            .filter(|feature| !feature.is_empty())
+            .collect(),
+        tss_esapi: env!("KEYLIME_TSS_ESAPI_VERSION"),
+        tpm2_tss: env!("KEYLIME_TPM2_TSS_VERSION"),
+        openssl: openssl::version::version(),
+    }
+}
+
+impl BuildInfo {
+    /// Text printed by --version
+    pub(crate) fn to_text(&self) -> String {
+        format!(
+            "keylime_agent {} ({}, built {})\n\
+             features: {}\n\
+             tss-esapi {}, tpm2-tss {}\n\
+             {}\n",
+            self.version,
+            self.git_commit,
+            self.build_date,
+            if self.features.is_empty() {
+                String::from("none")
+            } else {
+                self.features.join(", ")
+            },
+            self.tss_esapi,
+            self.tpm2_tss,
+            self.openssl
+        )
+    }
+}
+
+#[derive(Serialize)]
+struct JsonInfoWrapper {
+    code: u32,
+    status: String,
+    results: BuildInfo,
+}
+
+// Returns the build information, in the same envelope as the quotes
+pub async fn info() -> impl Responder {
+    HttpResponse::Ok().json(JsonInfoWrapper {
+        code: 200,
+        status: String::from("Success"),
+        results: build_info(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_info_text() {
+        let info = BuildInfo {
+            version: "0.1.0",
+            git_commit: "0123456789ab",
+            build_date: "2021-06-01T00:00:00Z",
+            features: vec!["testing"],
+            tss_esapi: "5.0.1",
+            tpm2_tss: "3.0.3",
+            openssl: "OpenSSL 1.1.1k  FIPS 25 Mar 2021",
+        };
+        assert_eq!(
+            info.to_text(),
+            "keylime_agent 0.1.0 (0123456789ab, built 2021-06-01T00:00:00Z)\n\
+             features: testing\n\
+             tss-esapi 5.0.1, tpm2-tss 3.0.3\n\
+             OpenSSL 1.1.1k  FIPS 25 Mar 2021\n"
+        );
+    }
+}
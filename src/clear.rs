@@ -0,0 +1,179 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Factory reset
+//
+// keylime_agent clear removes what the agent keeps on a node across
+// restarts, for nodes being repurposed: the agent data, with the sealed
+// payload and the generated UUID, see agent_data.rs, and the runtime policy
+// delivered by the tenant. The AK is stored in the agent data too, and the
+// EK is derived from the TPM seed on every start, so the agent itself
+// leaves no objects in the TPM, but keys made persistent for the agent,
+// e.g. by the Python agent or by hand with tpm2_evictcontrol, are evicted
+// with --evict-handle.
+//
+// With --deregister, the agent is first removed from the registrar. The
+// registrar only accepts deletions on its TLS port, from clients with a
+// certificate issued by its CA, so the agent presents registrar_tls_cert.
+// Nothing is cleared if that fails, unless --ignore-deregister-errors is
+// given. Otherwise the UUID the agent registered with is printed for
+// removing it with keylime_tenant.
+//
+// The agent must not be running.
+
+use crate::common::{
+    config_get, config_get_or, registrar_ip_get, tpm_ownerpassword_get,
+    work_dir_get, RUNTIME_POLICY,
+};
+use crate::error::{Error, Result};
+use crate::{
+    agent_data, agent_uuid, http, persist, registrar_agent, secure_mount, tpm,
+};
+
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::PKey;
+use openssl::x509::X509;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+// Removes a file, returning whether it existed
+fn remove(path: &Path) -> Result<bool> {
+    match fs::remove_file(path) {
+        Ok(()) => {
+            println!("Removed {}", path.display());
+            Ok(true)
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Reads a file configured in [cloud_agent], relative to the work
+// directory, None if the option is empty
+fn read_config_file(key: &str) -> Result<Option<Vec<u8>>> {
+    let file = config_get_or("cloud_agent", key, "")?;
+    if file.is_empty() {
+        return Ok(None);
+    }
+    let path = Path::new(&work_dir_get()).join(file);
+    fs::read(&path).map(Some).map_err(|e| Error::file(&path, e))
+}
+
+// The client for the TLS port of the registrar
+fn registrar_client() -> Result<reqwest::Client> {
+    let (cert, key) = match (
+        read_config_file("registrar_tls_cert")?,
+        read_config_file("registrar_tls_key")?,
+    ) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => {
+            return Err(Error::config_value(
+                "cloud_agent",
+                "registrar_tls_cert",
+                "--deregister requires registrar_tls_cert and \
+                 registrar_tls_key",
+            ))
+        }
+    };
+    let cert = X509::from_pem(&cert)?;
+    let key = PKey::private_key_from_pem(&key)?;
+    // With native TLS, reqwest only takes client identities as PKCS#12
+    let pkcs12 = Pkcs12::builder()
+        .name("keylime-agent")
+        .pkey(&key)
+        .cert(&cert)
+        .build2("")?;
+    let identity = reqwest::Identity::from_pkcs12_der(&pkcs12.to_der()?, "")?;
+
+    let ca_cert = match read_config_file("registrar_tls_ca_cert")? {
+        Some(pem) => Some(reqwest::Certificate::from_pem(&pem)?),
+        None => None,
+    };
+    Ok(http::client_with_identity(ca_cert, identity)?)
+}
+
+async fn deregister(agent_uuid: &str) -> Result<()> {
+    let registrar_url = format!(
+        "https://{}:{}",
+        registrar_ip_get()?,
+        config_get("registrar", "registrar_tls_port")?
+    );
+    registrar_agent::do_deregister_agent(
+        &registrar_client()?,
+        &registrar_url,
+        agent_uuid,
+    )
+    .await
+}
+
+/*
+ * Input: whether to remove the agent from the registrar
+ *        whether to clear the node all the same if that fails
+ *        persistent handles to evict
+ * Return: Result wrap with error message
+ */
+pub(crate) async fn run(
+    deregister_agent: bool,
+    ignore_deregister_errors: bool,
+    evict_handles: Vec<u32>,
+) -> Result<()> {
+    let mut ctx = tpm::get_tpm2_ctx()?;
+    // Evicting persistent objects takes the owner hierarchy
+    tpm::set_owner_auth(&mut ctx, &tpm_ownerpassword_get()?)?;
+
+    // Before the agent data, which may hold the UUID. A UUID that was never
+    // generated was never registered either.
+    let keys = tpm::load_keys(&mut ctx, tpm::AkSource::None)?;
+    keys.flush(&mut ctx)?;
+    let agent_uuid = agent_uuid::lookup(
+        &config_get("cloud_agent", "agent_uuid")?,
+        &keys.ek_tpm2b_pub,
+    )?;
+
+    let mut deregistered = false;
+    if deregister_agent {
+        match &agent_uuid {
+            Some(agent_uuid) => match deregister(agent_uuid).await {
+                Ok(()) => {
+                    println!(
+                        "Removed agent {} from the registrar",
+                        agent_uuid
+                    );
+                    deregistered = true;
+                }
+                Err(e) if ignore_deregister_errors => eprintln!(
+                    "Unable to remove agent {} from the registrar, \
+                     clearing all the same: {}",
+                    agent_uuid, e
+                ),
+                Err(e) => return Err(e),
+            },
+            None => println!("No UUID was generated, so none was registered"),
+        }
+    }
+
+    for handle in &evict_handles {
+        tpm::evict_persistent(&mut ctx, *handle)?;
+        println!("Evicted persistent handle {:#x}", handle);
+    }
+
+    let agent_data_path = agent_data::agent_data_path();
+    let _ = persist::recover(&agent_data_path)?;
+    if !remove(&agent_data_path)? {
+        println!("No agent data in {}", agent_data_path.display());
+    }
+    let _ = remove(
+        &Path::new(&secure_mount::secure_dir_get()?).join(RUNTIME_POLICY),
+    )?;
+
+    println!("Cleared the agent state, it starts afresh on the next start");
+    if let (Some(agent_uuid), false) = (agent_uuid, deregistered) {
+        println!(
+            "Remove the agent from the registrar with: \
+             keylime_tenant -c regdelete -u {}",
+            agent_uuid
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,622 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Command line options
+//
+// Everything else is configured in keylime.conf; the command line only
+// covers how the agent process itself is run.
+
+use crate::common::{run_dir_get, IMA_ML};
+use crate::error::{Error, Result};
+
+use std::path::{Path, PathBuf};
+
+pub(crate) static USAGE: &str = "Usage: keylime_agent [OPTIONS] [COMMAND]
+
+Options:
+    --foreground          Stay attached to the terminal (default)
+    --daemon              Detach from the terminal and run in the background
+    --pid-file <PATH>     Write the PID of the agent to PATH, refusing to
+                          start if another agent holds it. Defaults to
+                          keylime_agent.pid in the runtime directory with
+                          --daemon
+    --log-file <PATH>     Append the log to PATH instead of standard error
+    --self-test           Check that the agent can run on this host, print
+                          a report and exit
+    --container           Run in container mode, configured from the
+                          environment (same as KEYLIME_CONTAINER=1)
+    -V, --version         Print how the agent was built and exit
+    -h, --help            Print this help
+
+Commands:
+    clear [--deregister [--ignore-deregister-errors]]
+          [--evict-handle <HANDLE>]...
+                          Reset the node for another use: delete the agent
+                          data, with the sealed payload and the generated
+                          UUID, and the runtime policy, evict the given
+                          persistent TPM handles and, with --deregister,
+                          remove the agent from the registrar first, with
+                          registrar_tls_cert. Nothing is cleared if that
+                          fails, unless --ignore-deregister-errors is given.
+                          The agent must not be running
+    agent-data [--repair]
+                          Print the agent data: the TPM it belongs to, the
+                          generated UUID and the PCRs the bootstrap key is
+                          sealed to. With --repair, clean it up as the agent
+                          would on its next start, and seal the key again
+                          when reprovision_pcr_mask changed. The agent must
+                          not be running
+    quote --nonce <NONCE> [--mask <MASK>] [--ima-ml-entry <N>]
+                          Print the identity quote or, with --mask, the
+                          integrity quote, as the agent answers them, with
+                          an AK created for the command. --ima-ml-entry
+                          returns the measurement list from entry N on
+    register-only         Register the agent with the registrar and exit,
+                          to enroll nodes ahead, e.g. in image builds. The
+                          agent registers again with the same AK when
+                          started
+    seal --in <PATH> --out <PATH> [--pcr-mask <MASK>]
+                          Seal a file of at most 128 bytes to the current
+                          values of the PCRs in MASK, by default
+                          reprovision_pcr_mask, and write the sealed object
+                          to the output file
+    unseal --in <PATH> [--out <PATH>]
+                          Unseal a file sealed with seal, to standard output
+                          by default. Fails if the PCRs changed
+    show-ek               Print the EK certificate and public key, in PEM
+                          and as sent to the registrar
+    show-ak               Print the public key of the AK the agent created
+                          on its first start, in PEM and as sent to the
+                          registrar
+    simulate-revocation --event <PATH> [--payload-dir <PATH>]
+                          Run the revocation actions of the payload for a
+                          revocation event, verified with the revocation
+                          certificate if it is signed. With --payload-dir,
+                          run the actions of a payload not delivered yet
+    test-registrar        Check the registrar step by step: address,
+                          connection, API versions, the keys to register
+                          with, and whether the registrar serves the agent,
+                          without registering it. Print which step fails
+    ima-emulator [--file <PATH>] [--follow]
+                          Extend PCR 10 of a software TPM with a measurement
+                          list, by default the IMA one, for development on
+                          machines without IMA. With --follow, keep
+                          extending the entries appended to the list
+";
+
+static PID_FILE: &str = "keylime_agent.pid";
+
+/// Default PID file in daemon mode, in the runtime directory
+pub(crate) fn default_pid_file() -> PathBuf {
+    Path::new(&run_dir_get()).join(PID_FILE)
+}
+
+/// Maintenance commands, run instead of the agent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Command {
+    /// Factory reset, see clear.rs
+    Clear {
+        deregister: bool,
+        ignore_deregister_errors: bool,
+        evict_handles: Vec<u32>,
+    },
+    /// Agent data inspection, see inspect.rs
+    AgentData {
+        repair: bool,
+    },
+    /// Quotes without running the agent, see offline_quote.rs
+    Quote {
+        nonce: String,
+        mask: Option<String>,
+        ima_ml_entry: Option<usize>,
+    },
+    /// Enrollment without running the agent
+    RegisterOnly,
+    /// Secrets bound to the PCRs, see seal.rs
+    Seal {
+        input: PathBuf,
+        output: PathBuf,
+        pcr_mask: Option<String>,
+    },
+    Unseal {
+        input: PathBuf,
+        output: Option<PathBuf>,
+    },
+    /// Key inspection, see show.rs
+    ShowEk,
+    ShowAk,
+    /// Revocation action tests, see simulate_revocation.rs
+    SimulateRevocation {
+        event: PathBuf,
+        payload_dir: Option<PathBuf>,
+    },
+    /// Registration diagnostics, see registrar_check.rs
+    TestRegistrar,
+    /// Development without IMA, see ima_emulator.rs
+    ImaEmulator {
+        file: PathBuf,
+        follow: bool,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Options {
+    pub daemon: bool,
+    pub pid_file: Option<PathBuf>,
+    pub log_file: Option<PathBuf>,
+    pub self_test: bool,
+    pub container: bool,
+    pub version: bool,
+    pub help: bool,
+    pub command: Option<Command>,
+}
+
+fn value(
+    option: &str,
+    args: &mut impl Iterator<Item = String>,
+) -> Result<PathBuf> {
+    match args.next() {
+        Some(value) if !value.is_empty() && !value.starts_with("--") => {
+            Ok(PathBuf::from(value))
+        }
+        _ => Err(Error::Usage(format!(
+            "{} requires a value\n\n{}",
+            option, USAGE
+        ))),
+    }
+}
+
+fn usage_error(message: String) -> Error {
+    Error::Usage(format!("{}\n\n{}", message, USAGE))
+}
+
+// Parses a command and its options, the rest of the arguments
+fn parse_command(
+    name: &str,
+    args: &mut impl Iterator<Item = String>,
+) -> Result<Command> {
+    match name {
+        "clear" => {
+            let mut deregister = false;
+            let mut ignore_deregister_errors = false;
+            let mut evict_handles = Vec::new();
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--deregister" => deregister = true,
+                    "--ignore-deregister-errors" => {
+                        ignore_deregister_errors = true
+                    }
+                    "--evict-handle" => {
+                        let handle = value(&arg, args)?;
+                        let handle = handle.to_string_lossy();
+                        evict_handles.push(
+                            u32::from_str_radix(
+                                handle.trim_start_matches("0x"),
+                                16,
+                            )
+                            .map_err(|_| {
+                                usage_error(format!(
+                                    "invalid handle {}",
+                                    handle
+                                ))
+                            })?,
+                        );
+                    }
+                    _ => {
+                        return Err(usage_error(format!(
+                            "unknown option {} for clear",
+                            arg
+                        )))
+                    }
+                }
+            }
+            if ignore_deregister_errors && !deregister {
+                return Err(usage_error(String::from(
+                    "--ignore-deregister-errors requires --deregister",
+                )));
+            }
+            Ok(Command::Clear {
+                deregister,
+                ignore_deregister_errors,
+                evict_handles,
+            })
+        }
+        "ima-emulator" => {
+            let mut file = PathBuf::from(IMA_ML);
+            let mut follow = false;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--file" => file = value(&arg, args)?,
+                    "--follow" => follow = true,
+                    _ => {
+                        return Err(usage_error(format!(
+                            "unknown option {} for ima-emulator",
+                            arg
+                        )))
+                    }
+                }
+            }
+            Ok(Command::ImaEmulator { file, follow })
+        }
+        "agent-data" => {
+            let mut repair = false;
+            for arg in args {
+                match arg.as_str() {
+                    "--repair" => repair = true,
+                    _ => {
+                        return Err(usage_error(format!(
+                            "unknown option {} for agent-data",
+                            arg
+                        )))
+                    }
+                }
+            }
+            Ok(Command::AgentData { repair })
+        }
+        "quote" => {
+            let mut nonce = None;
+            let mut mask = None;
+            let mut ima_ml_entry = None;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--nonce" => {
+                        nonce = Some(value(&arg, args)?.display().to_string())
+                    }
+                    "--mask" => {
+                        mask = Some(value(&arg, args)?.display().to_string())
+                    }
+                    "--ima-ml-entry" => {
+                        let entry = value(&arg, args)?;
+                        let entry = entry.to_string_lossy();
+                        ima_ml_entry = Some(entry.parse().map_err(|_| {
+                            usage_error(format!("invalid entry {}", entry))
+                        })?);
+                    }
+                    _ => {
+                        return Err(usage_error(format!(
+                            "unknown option {} for quote",
+                            arg
+                        )))
+                    }
+                }
+            }
+            let nonce = nonce.ok_or_else(|| {
+                usage_error(String::from("quote requires --nonce"))
+            })?;
+            if ima_ml_entry.is_some() && mask.is_none() {
+                return Err(usage_error(String::from(
+                    "--ima-ml-entry requires --mask",
+                )));
+            }
+            Ok(Command::Quote {
+                nonce,
+                mask,
+                ima_ml_entry,
+            })
+        }
+        "seal" | "unseal" => {
+            let mut input = None;
+            let mut output = None;
+            let mut pcr_mask = None;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--in" => input = Some(value(&arg, args)?),
+                    "--out" => output = Some(value(&arg, args)?),
+                    "--pcr-mask" if name == "seal" => {
+                        pcr_mask =
+                            Some(value(&arg, args)?.display().to_string())
+                    }
+                    _ => {
+                        return Err(usage_error(format!(
+                            "unknown option {} for {}",
+                            arg, name
+                        )))
+                    }
+                }
+            }
+            let input = input.ok_or_else(|| {
+                usage_error(format!("{} requires --in", name))
+            })?;
+            if name == "unseal" {
+                return Ok(Command::Unseal { input, output });
+            }
+            Ok(Command::Seal {
+                input,
+                output: output.ok_or_else(|| {
+                    usage_error(String::from("seal requires --out"))
+                })?,
+                pcr_mask,
+            })
+        }
+        "simulate-revocation" => {
+            let mut event = None;
+            let mut payload_dir = None;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--event" => event = Some(value(&arg, args)?),
+                    "--payload-dir" => payload_dir = Some(value(&arg, args)?),
+                    _ => {
+                        return Err(usage_error(format!(
+                            "unknown option {} for simulate-revocation",
+                            arg
+                        )))
+                    }
+                }
+            }
+            Ok(Command::SimulateRevocation {
+                event: event.ok_or_else(|| {
+                    usage_error(String::from(
+                        "simulate-revocation requires --event",
+                    ))
+                })?,
+                payload_dir,
+            })
+        }
+        "register-only" | "show-ek" | "show-ak" | "test-registrar" => {
+            if let Some(arg) = args.next() {
+                return Err(usage_error(format!(
+                    "unknown option {} for {}",
+                    arg, name
+                )));
+            }
+            Ok(match name {
+                "register-only" => Command::RegisterOnly,
+                "show-ek" => Command::ShowEk,
+                "test-registrar" => Command::TestRegistrar,
+                _ => Command::ShowAk,
+            })
+        }
+        _ => Err(usage_error(format!("unknown command {}", name))),
+    }
+}
+
+/*
+ * Input: command line arguments, without the program name
+ * Return: Result wrap the parsed options
+ */
+pub(crate) fn parse(
+    args: impl IntoIterator<Item = String>,
+) -> Result<Options> {
+    let mut options = Options::default();
+    let mut foreground = false;
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        // Accept --option=value as well as --option value
+        let (name, inline) = match arg.find('=') {
+            Some(i) if arg.starts_with("--") => {
+                (arg[..i].to_string(), Some(PathBuf::from(&arg[i + 1..])))
+            }
+            _ => (arg.clone(), None),
+        };
+
+        match name.as_str() {
+            "--daemon" => options.daemon = true,
+            "--foreground" => foreground = true,
+            "--pid-file" => {
+                options.pid_file = Some(match inline {
+                    Some(path) => path,
+                    None => value(&name, &mut args)?,
+                })
+            }
+            "--log-file" => {
+                options.log_file = Some(match inline {
+                    Some(path) => path,
+                    None => value(&name, &mut args)?,
+                })
+            }
+            "--self-test" => options.self_test = true,
+            "--container" => options.container = true,
+            "-V" | "--version" => options.version = true,
+            "-h" | "--help" => options.help = true,
+            _ if !arg.starts_with('-') => {
+                options.command = Some(parse_command(&arg, &mut args)?)
+            }
+            _ => {
+                return Err(Error::Usage(format!(
+                    "unknown option {}\n\n{}",
+                    arg, USAGE
+                )))
+            }
+        }
+    }
+
+    if options.daemon && foreground {
+        return Err(Error::Usage(
+            "--daemon and --foreground are mutually exclusive".to_string(),
+        ));
+    }
+    if options.self_test && options.daemon {
+        return Err(Error::Usage(
+            "--self-test runs in the foreground".to_string(),
+        ));
+    }
+    if options.container && options.daemon {
+        return Err(Error::Usage(
+            "--container runs in the foreground, as the container's main process"
+                .to_string(),
+        ));
+    }
+    if options.command.is_some() && (options.daemon || options.self_test) {
+        return Err(Error::Usage(
+            "commands run in the foreground, instead of the agent"
+                .to_string(),
+        ));
+    }
+    if options.daemon && options.pid_file.is_none() {
+        options.pid_file = Some(default_pid_file());
+    }
+
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_options() {
+        assert_eq!(parse(args(&[])).unwrap(), Options::default()); //#[allow_ci]
+        assert_eq!(
+            parse(args(&["--foreground"])).unwrap(), //#[allow_ci]
+            Options::default()
+        );
+
+        let options =
+            parse(args(&["--daemon", "--log-file", "/tmp/log"])).unwrap(); //#[allow_ci]
+        assert!(options.daemon);
+        assert_eq!(options.pid_file, Some(default_pid_file()));
+        assert_eq!(options.log_file, Some(PathBuf::from("/tmp/log")));
+
+        let options = parse(args(&["--pid-file=/tmp/pid"])).unwrap(); //#[allow_ci]
+        assert!(!options.daemon);
+        assert_eq!(options.pid_file, Some(PathBuf::from("/tmp/pid")));
+
+        assert!(parse(args(&["--self-test"])).unwrap().self_test); //#[allow_ci]
+        assert!(parse(args(&["--container"])).unwrap().container); //#[allow_ci]
+        assert!(parse(args(&["-V"])).unwrap().version); //#[allow_ci]
+        assert!(parse(args(&["--container", "--daemon"])).is_err());
+
+        assert!(parse(args(&["--daemon", "--foreground"])).is_err());
+        assert!(parse(args(&["--pid-file"])).is_err());
+        assert!(parse(args(&["--pid-file", "--daemon"])).is_err());
+        assert!(parse(args(&["--verbose"])).is_err());
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(
+            parse(args(&["clear"])).unwrap().command, //#[allow_ci]
+            Some(Command::Clear {
+                deregister: false,
+                ignore_deregister_errors: false,
+                evict_handles: Vec::new(),
+            })
+        );
+        assert_eq!(
+            parse(args(&[
+                "clear",
+                "--deregister",
+                "--evict-handle",
+                "0x81000001",
+                "--evict-handle",
+                "81000002"
+            ]))
+            .unwrap() //#[allow_ci]
+            .command,
+            Some(Command::Clear {
+                deregister: true,
+                ignore_deregister_errors: false,
+                evict_handles: vec![0x8100_0001, 0x8100_0002],
+            })
+        );
+        assert_eq!(
+            parse(args(&[
+                "clear",
+                "--deregister",
+                "--ignore-deregister-errors"
+            ]))
+            .unwrap() //#[allow_ci]
+            .command,
+            Some(Command::Clear {
+                deregister: true,
+                ignore_deregister_errors: true,
+                evict_handles: Vec::new(),
+            })
+        );
+        assert!(
+            parse(args(&["clear", "--ignore-deregister-errors"])).is_err()
+        );
+
+        assert!(parse(args(&["clear", "--evict-handle", "xyz"])).is_err());
+        assert!(parse(args(&["clear", "--verbose"])).is_err());
+        assert!(parse(args(&["--daemon", "clear"])).is_err());
+        assert_eq!(
+            parse(args(&["show-ek"])).unwrap().command, //#[allow_ci]
+            Some(Command::ShowEk)
+        );
+        assert_eq!(
+            parse(args(&["show-ak"])).unwrap().command, //#[allow_ci]
+            Some(Command::ShowAk)
+        );
+        assert!(parse(args(&["show-ak", "--pem"])).is_err());
+        assert_eq!(
+            parse(args(&["register-only"])).unwrap().command, //#[allow_ci]
+            Some(Command::RegisterOnly)
+        );
+        assert_eq!(
+            parse(args(&["ima-emulator", "--follow", "--file", "/tmp/ml"]))
+                .unwrap() //#[allow_ci]
+                .command,
+            Some(Command::ImaEmulator {
+                file: PathBuf::from("/tmp/ml"),
+                follow: true,
+            })
+        );
+        assert_eq!(
+            parse(args(&["quote", "--nonce", "abc", "--mask", "0x400"]))
+                .unwrap() //#[allow_ci]
+                .command,
+            Some(Command::Quote {
+                nonce: String::from("abc"),
+                mask: Some(String::from("0x400")),
+                ima_ml_entry: None,
+            })
+        );
+        assert!(parse(args(&["quote", "--mask", "0x400"])).is_err());
+        assert!(parse(args(&[
+            "quote",
+            "--nonce",
+            "abc",
+            "--ima-ml-entry",
+            "3"
+        ]))
+        .is_err());
+        assert_eq!(
+            parse(args(&["seal", "--in", "key", "--out", "key.sealed"]))
+                .unwrap() //#[allow_ci]
+                .command,
+            Some(Command::Seal {
+                input: PathBuf::from("key"),
+                output: PathBuf::from("key.sealed"),
+                pcr_mask: None,
+            })
+        );
+        assert_eq!(
+            parse(args(&["unseal", "--in", "key.sealed"]))
+                .unwrap() //#[allow_ci]
+                .command,
+            Some(Command::Unseal {
+                input: PathBuf::from("key.sealed"),
+                output: None,
+            })
+        );
+        assert!(parse(args(&["seal", "--in", "key"])).is_err());
+        assert!(parse(args(&["unseal", "--in", "a", "--pcr-mask", "0x1"]))
+            .is_err());
+        assert_eq!(
+            parse(args(&["test-registrar"])).unwrap().command, //#[allow_ci]
+            Some(Command::TestRegistrar)
+        );
+        assert_eq!(
+            parse(args(&["agent-data", "--repair"])).unwrap().command, //#[allow_ci]
+            Some(Command::AgentData { repair: true })
+        );
+        assert!(parse(args(&["agent-data", "--force"])).is_err());
+        assert_eq!(
+            parse(args(&["simulate-revocation", "--event", "event.json"]))
+                .unwrap() //#[allow_ci]
+                .command,
+            Some(Command::SimulateRevocation {
+                event: PathBuf::from("event.json"),
+                payload_dir: None,
+            })
+        );
+        assert!(parse(args(&["simulate-revocation"])).is_err());
+        assert!(parse(args(&["reset"])).is_err());
+    }
+}
@@ -57,7 +57,7 @@ pub(crate) fn run(
     match env_vars.get_mut("PATH") {
         Some(v) => v.push_str(TPM_TOOLS_PATH),
         None => {
-            return Err(Error::Configuration(
+            return Err(Error::Other(
                 "PATH environment variable doesn't exist".to_string(),
             ));
         }
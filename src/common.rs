@@ -1,10 +1,14 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright 2021 Keylime Authors
 
+use crate::container;
 use crate::error::{Error, Result};
 use ini::Ini;
-use log::*;
+use lazy_static::lazy_static;
 use std::env;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::{error, info};
 
 /*
  * Constants and static variables
@@ -13,14 +17,39 @@ pub const STUB_VTPM: bool = false;
 pub const STUB_IMA: bool = true;
 pub const TPM_DATA_PCR: usize = 16;
 pub const IMA_PCR: usize = 10;
+#[cfg(not(test))]
 pub static DEFAULT_CONFIG: &str = "/etc/keylime.conf";
+// Unit tests read the keylime.conf of the repository, not the host's
+#[cfg(test)]
+pub static DEFAULT_CONFIG: &str =
+    concat!(env!("CARGO_MANIFEST_DIR"), "/keylime.conf");
 pub static RSA_PUBLICKEY_EXPORTABLE: &str = "rsa placeholder";
 pub static TPM_TOOLS_PATH: &str = "/usr/local/bin/";
 pub static IMA_ML_STUB: &str = "../scripts/ima/ascii_runtime_measurements";
-pub static IMA_ML: &str =
-    "/sys/kernel/security/ima/ascii_runtime_measurements";
+pub use keylime::ima::IMA_ML;
+pub static MEASUREDBOOT_ML: &str =
+    "/sys/kernel/security/tpm0/binary_bios_measurements";
 pub static KEY: &str = "secret";
-pub static WORK_DIR: &str = "/tmp";
+pub static WORK_DIR: &str = "/var/lib/keylime";
+pub static RUN_DIR: &str = "/run/keylime";
+pub static TMPFS_DEV_DIR: &str = "/tmp/tmpfs-dev";
+
+// Layout of the secure directory, matching the Python agent so that
+// existing payloads and revocation scripts keep working unmodified.
+pub static UNZIPPED_DIR: &str = "unzipped";
+pub static ACTION_LIST: &str = "action_list";
+pub static REV_CERT: &str = "RevocationNotifier-cert.crt";
+pub static RUNTIME_POLICY: &str = "runtime_policy.json";
+
+// TLS credentials for the agent that may be delivered in the payload, and
+// the directory in the secure mount where they are installed.
+pub static PAYLOAD_TLS_CERT: &str = "agent-cert.crt";
+pub static PAYLOAD_TLS_KEY: &str = "agent-private.pem";
+pub static PAYLOAD_TLS_CA: &str = "cacert.crt";
+pub static TLS_DIR: &str = "tls";
+
+// State persisted in the work directory across reboots
+pub static AGENT_DATA: &str = "agent_data.json";
 
 // Secure mount of tpmfs (False is generally used for development environments)
 #[cfg(not(feature = "testing"))]
@@ -29,6 +58,36 @@ pub static MOUNT_SECURE: bool = true;
 #[cfg(feature = "testing")]
 pub static MOUNT_SECURE: bool = false;
 
+// Built for testing, the agent does not register when
+// KEYLIME_STUB_REGISTRATION is set, and reads the IMA measurement list from
+// KEYLIME_IMA_ML, so that it runs end to end on a software TPM without a
+// registrar nor IMA, see tests/attestation.rs
+#[cfg(not(feature = "testing"))]
+pub(crate) fn registration_stubbed() -> bool {
+    false
+}
+
+#[cfg(feature = "testing")]
+pub(crate) fn registration_stubbed() -> bool {
+    env::var("KEYLIME_STUB_REGISTRATION").is_ok()
+}
+
+#[cfg(not(feature = "testing"))]
+pub(crate) fn ima_ml_get() -> String {
+    IMA_ML.to_string()
+}
+
+#[cfg(feature = "testing")]
+pub(crate) fn ima_ml_get() -> String {
+    env::var("KEYLIME_IMA_ML").unwrap_or_else(|_| IMA_ML.to_string())
+}
+
+lazy_static! {
+    // keylime.conf as parsed on first use, and the file it was read from,
+    // see config_reload()
+    static ref CONFIG: Mutex<Option<(String, Option<Ini>)>> = Mutex::new(None);
+}
+
 /*
  * Return: Returns the configuration file provided in the environment variable
  * KEYLIME_CONFIG or defaults to /etc/keylime.conf
@@ -37,19 +96,65 @@ pub static MOUNT_SECURE: bool = false;
  * let config = config_file_get();
  */
 pub(crate) fn config_file_get() -> String {
-    match env::var("KEYLIME_CONFIG") {
-        Ok(cfg) => {
-            // The variable length must be larger than 0 to accept
-            if !cfg.is_empty() {
-                cfg
-            } else {
-                String::from(DEFAULT_CONFIG)
-            }
-        }
+    config_file(env::var("KEYLIME_CONFIG").ok())
+}
+
+// The configuration file for the value of KEYLIME_CONFIG
+fn config_file(var: Option<String>) -> String {
+    match var {
+        // The variable length must be larger than 0 to accept
+        Some(cfg) if !cfg.is_empty() => cfg,
         _ => String::from(DEFAULT_CONFIG),
     }
 }
 
+// Returns the first of the colon separated directories systemd passes in
+// STATE_DIRECTORY and RUNTIME_DIRECTORY, see systemd.exec(5)
+fn first_dir(dirs: &str) -> Option<&str> {
+    dirs.split(':').next().filter(|dir| !dir.is_empty())
+}
+
+// Returns the directory set in the environment variable of the agent, or
+// else in the one set by systemd
+fn env_dir_get(var: &str, systemd_var: &str) -> Option<String> {
+    match env::var(var) {
+        // The variable length must be larger than 0 to accept
+        Ok(dir) if !dir.is_empty() => Some(dir),
+        _ => env::var(systemd_var)
+            .ok()
+            .and_then(|dirs| first_dir(&dirs).map(String::from)),
+    }
+}
+
+/*
+ * Return: Returns the working directory provided in the environment variable
+ * KEYLIME_DIR, or by systemd in STATE_DIRECTORY, or defaults to
+ * /var/lib/keylime
+ *
+ * All the state the agent keeps across restarts is in this directory, so
+ * that the rest of the file system can be read-only.
+ *
+ * Example call:
+ * let work_dir = work_dir_get();
+ */
+pub(crate) fn work_dir_get() -> String {
+    env_dir_get("KEYLIME_DIR", "STATE_DIRECTORY")
+        .unwrap_or_else(|| String::from(WORK_DIR))
+}
+
+/*
+ * Return: Returns the runtime directory provided in the environment variable
+ * KEYLIME_RUN_DIR, or by systemd in RUNTIME_DIRECTORY, or defaults to
+ * /run/keylime
+ *
+ * Files only needed while the agent runs, like its PID file, are in this
+ * directory.
+ */
+pub(crate) fn run_dir_get() -> String {
+    env_dir_get("KEYLIME_RUN_DIR", "RUNTIME_DIRECTORY")
+        .unwrap_or_else(|| String::from(RUN_DIR))
+}
+
 /// Returns revocation ip from keylime.conf if env var not present
 pub(crate) fn revocation_ip_get() -> Result<String> {
     match env::var("REVOCATION_IP") {
@@ -140,6 +245,36 @@ pub(crate) fn registrar_port_get() -> Result<String> {
     }
 }
 
+/// Returns the password of the TPM owner hierarchy, empty for none
+pub(crate) fn tpm_ownerpassword_get() -> Result<String> {
+    let password = config_get_or("cloud_agent", "tpm_ownerpassword", "")?;
+    // The Python agent takes ownership of the TPM with a random password,
+    // which this agent never does
+    if password == "generate" {
+        return Err(Error::config_value(
+            "cloud_agent",
+            "tpm_ownerpassword",
+            "generate is not supported, set the password of the owner hierarchy",
+        ));
+    }
+    Ok(password)
+}
+
+// Loads keylime.conf, which is optional in container mode
+fn config_load(conf_name: &str) -> Result<Option<Ini>> {
+    if container::enabled() && !Path::new(conf_name).exists() {
+        return Ok(None);
+    }
+    Ok(Some(Ini::load_from_file(conf_name)?))
+}
+
+/// Makes the next configuration access read keylime.conf again
+pub(crate) fn config_reload() {
+    if let Ok(mut cached) = CONFIG.lock() {
+        *cached = None;
+    }
+}
+
 /*
  * Input: [section] and key
  * Return: Returns the matched key
@@ -148,32 +283,98 @@ pub(crate) fn registrar_port_get() -> Result<String> {
  * let port = common::config_get("general","cloudagent_port");
  */
 pub(crate) fn config_get(section: &str, key: &str) -> Result<String> {
+    // In container mode, the environment overrides keylime.conf, which is
+    // optional
+    if let Some(value) = container::env_config(section, key) {
+        return Ok(value);
+    }
     let conf_name = config_file_get();
-    let conf = Ini::load_from_file(&conf_name)?;
-    let section = match conf.section(Some(section.to_owned())) {
-        Some(section) => section,
-        None =>
-        // TODO: Make Error::Configuration an alternative with data instead of string
-        {
-            return Err(Error::Configuration(format!(
-                "Cannot find section called {} in file {}",
-                section, conf_name
-            )))
+    // must unwrap here due to lock mechanism
+    // https://github.com/rust-lang-nursery/failure/issues/192
+    let mut cached = CONFIG.lock().unwrap(); //#[allow_ci]
+    if !matches!(&*cached, Some((name, _)) if *name == conf_name) {
+        *cached = Some((conf_name.clone(), config_load(&conf_name)?));
+    }
+    let conf = match cached.as_ref().and_then(|(_, conf)| conf.as_ref()) {
+        Some(conf) => conf,
+        None => {
+            return match container::default_config(section, key) {
+                Some(value) => Ok(value.to_string()),
+                None => Err(Error::ConfigMissing {
+                    section: section.to_string(),
+                    key: key.to_string(),
+                    file: conf_name,
+                }),
+            }
         }
     };
-    let value = match section.get(key) {
-        Some(value) => value,
-        None =>
-        // TODO: Make Error::Configuration an alternative with data instead of string
-        {
-            return Err(Error::Configuration(format!(
-                "Cannot find key {} in fine {}",
-                key, conf_name
-            )))
-        }
+    let missing = || Error::ConfigMissing {
+        section: section.to_string(),
+        key: key.to_string(),
+        file: conf_name.clone(),
     };
+    conf.section(Some(section.to_owned()))
+        .ok_or_else(missing)?
+        .get(key)
+        .cloned()
+        .ok_or_else(missing)
+}
 
-    Ok(value.clone())
+/*
+ * Input: [section], key and default value
+ * Return: Returns the matched key, or the default value if the key is
+ *         not present in the configuration file
+ *
+ * Example call:
+ * let script = common::config_get_or("cloud_agent", "payload_script", "");
+ */
+pub(crate) fn config_get_or(
+    section: &str,
+    key: &str,
+    default: &str,
+) -> Result<String> {
+    match config_get(section, key) {
+        Err(Error::ConfigMissing { .. }) => Ok(String::from(default)),
+        other => other,
+    }
+}
+
+/*
+ * Input: [section], key and default value
+ * Return: Returns the matched key interpreted as a boolean, or the default
+ *         value if the key is not present in the configuration file
+ *
+ * Accepts the same spellings as Python's ConfigParser.getboolean().
+ *
+ * Example call:
+ * let extract = common::config_get_bool_or("cloud_agent", "extract_payload_zip", true);
+ */
+pub(crate) fn config_get_bool_or(
+    section: &str,
+    key: &str,
+    default: bool,
+) -> Result<bool> {
+    let value = match config_get(section, key) {
+        Ok(value) => value,
+        Err(Error::ConfigMissing { .. }) => return Ok(default),
+        Err(e) => return Err(e),
+    };
+
+    parse_bool(&value).ok_or_else(|| {
+        Error::config_value(
+            section,
+            key,
+            format!("{} is not a boolean", value),
+        )
+    })
+}
+
+pub(crate) fn parse_bool(value: &str) -> Option<bool> {
+    match value.trim().to_lowercase().as_str() {
+        "1" | "yes" | "true" | "on" => Some(true),
+        "0" | "no" | "false" | "off" => Some(false),
+        _ => None,
+    }
 }
 
 /*
@@ -219,13 +420,36 @@ mod tests {
     #[test]
     fn test_config_file_get() {
         // Test with no environment variable
-        env::set_var("KEYLIME_CONFIG", "");
-        assert_eq!(config_file_get(), String::from("/etc/keylime.conf"));
+        assert_eq!(config_file(None), String::from(DEFAULT_CONFIG));
+        assert_eq!(
+            config_file(Some(String::new())),
+            String::from(DEFAULT_CONFIG)
+        );
 
         // Test with an environment variable
-        env::set_var("KEYLIME_CONFIG", "/tmp/testing.conf");
-        assert_eq!(config_file_get(), String::from("/tmp/testing.conf"));
-        // Reset environment
-        env::set_var("KEYLIME_CONFIG", "");
+        assert_eq!(
+            config_file(Some(String::from("/tmp/testing.conf"))),
+            String::from("/tmp/testing.conf")
+        );
+    }
+
+    #[test]
+    fn test_parse_bool() {
+        assert_eq!(parse_bool("True"), Some(true));
+        assert_eq!(parse_bool(" yes "), Some(true));
+        assert_eq!(parse_bool("1"), Some(true));
+        assert_eq!(parse_bool("False"), Some(false));
+        assert_eq!(parse_bool("off"), Some(false));
+        assert_eq!(parse_bool("maybe"), None);
+    }
+
+    #[test]
+    fn systemd_dirs() {
+        assert_eq!(first_dir("/var/lib/keylime"), Some("/var/lib/keylime"));
+        assert_eq!(
+            first_dir("/var/lib/keylime:/var/lib/other"),
+            Some("/var/lib/keylime")
+        );
+        assert_eq!(first_dir(""), None);
     }
 }
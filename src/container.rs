@@ -0,0 +1,146 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Container mode
+//
+// Set KEYLIME_CONTAINER=1, or pass --container, to run the agent in a
+// privileged container, e.g. as a Kubernetes DaemonSet:
+//
+//   - each setting of keylime.conf can be given in an environment variable
+//     KEYLIME_<SECTION>_<KEY>, e.g. KEYLIME_REGISTRAR_REGISTRAR_IP, and
+//     keylime.conf itself is optional, with defaults suited to containers
+//   - a tmpfs already mounted on the secure directory, such as an emptyDir
+//     volume with medium Memory, is used as is instead of mounting one
+//   - the secure directory is left for the container runtime to clean up
+//   - failures are logged with hints on how to set up the container
+
+use crate::common::parse_bool;
+use crate::error::Error;
+
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::error;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static CONTAINER_ENV: &str = "KEYLIME_CONTAINER";
+
+// Settings that have no default in the code, for when there is no
+// keylime.conf. The agent listens on all the addresses of the pod, and
+// hash_ek gives each node its own UUID from the same environment. There
+// is no default registrar, it must be set.
+static DEFAULTS: &[(&str, &str, &str)] = &[
+    ("cloud_agent", "cloudagent_ip", "0.0.0.0"),
+    ("cloud_agent", "cloudagent_port", "9002"),
+    ("cloud_agent", "agent_uuid", "hash_ek"),
+    ("cloud_agent", "enc_keyname", "derived_tci_key"),
+    ("cloud_agent", "dec_payload_file", "decrypted_payload"),
+    ("cloud_agent", "secure_size", "1m"),
+    ("cloud_agent", "revocation_cert", "default"),
+    ("cloud_agent", "tpm_hash_alg", "sha256"),
+    ("cloud_agent", "tpm_encryption_alg", "rsa"),
+    ("cloud_agent", "tpm_signing_alg", "rsassa"),
+    ("general", "receive_revocation_ip", "127.0.0.1"),
+    ("general", "receive_revocation_port", "8992"),
+];
+
+/// Enables container mode, for --container
+pub(crate) fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Whether the agent runs in container mode
+pub(crate) fn enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+        || env::var(CONTAINER_ENV)
+            .ok()
+            .and_then(|value| parse_bool(&value))
+            .unwrap_or(false)
+}
+
+/// Environment variable overriding a setting of keylime.conf
+pub(crate) fn env_name(section: &str, key: &str) -> String {
+    format!("KEYLIME_{}_{}", section, key).to_uppercase()
+}
+
+/// Returns the setting from the environment, in container mode
+pub(crate) fn env_config(section: &str, key: &str) -> Option<String> {
+    if !enabled() {
+        return None;
+    }
+    env::var(env_name(section, key)).ok()
+}
+
+/// Default of a setting in container mode, without keylime.conf
+pub(crate) fn default_config(
+    section: &str,
+    key: &str,
+) -> Option<&'static str> {
+    DEFAULTS
+        .iter()
+        .find(|(s, k, _)| *s == section && *k == key)
+        .map(|(_, _, value)| *value)
+}
+
+// Hint on the setup of the container likely to cause an error
+fn hint(error: &Error) -> Option<&'static str> {
+    match error {
+        Error::Tpm { .. } | Error::TpmInUse => Some(
+            "pass the TPM resource manager of the host to the container, e.g. with --device /dev/tpmrm0 or a hostPath volume in a privileged pod, and run a single agent per node",
+        ),
+        Error::SecureMount { .. } | Error::SecureMountPermissions { .. } => Some(
+            "mount a memory-backed volume on the secure directory, e.g. an emptyDir with medium: Memory or --tmpfs, or run the container privileged",
+        ),
+        Error::Registrar { .. } | Error::Reqwest(_) => Some(
+            "check that KEYLIME_REGISTRAR_REGISTRAR_IP and KEYLIME_REGISTRAR_REGISTRAR_PORT point to a registrar reachable from the container",
+        ),
+        Error::ConfigMissing { .. }
+        | Error::ConfigValue { .. }
+        | Error::Ini(_) => Some(
+            "in container mode, keylime.conf settings are read from KEYLIME_<SECTION>_<KEY> environment variables, e.g. KEYLIME_CLOUD_AGENT_TPM_HASH_ALG",
+        ),
+        Error::Permission => Some(
+            "the agent needs a privileged container, or CAP_SYS_ADMIN and access to the TPM device",
+        ),
+        Error::Io(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            Some("the agent needs a privileged container, or CAP_SYS_ADMIN and access to the TPM device")
+        }
+        _ => None,
+    }
+}
+
+/// Logs a hint on the setup of the container for the error that stopped
+/// the agent, in container mode
+pub(crate) fn log_hint(error: &Error) {
+    if !enabled() {
+        return;
+    }
+    if let Some(hint) = hint(error) {
+        error!("Container setup hint: {}", hint);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn container_config() {
+        assert_eq!(
+            env_name("cloud_agent", "tpm_hash_alg"),
+            "KEYLIME_CLOUD_AGENT_TPM_HASH_ALG"
+        );
+        assert_eq!(
+            default_config("cloud_agent", "cloudagent_ip"),
+            Some("0.0.0.0")
+        );
+        assert_eq!(default_config("registrar", "registrar_ip"), None);
+        assert!(hint(&Error::SecureMount {
+            path: PathBuf::from("/var/lib/keylime/secure"),
+            reason: "unable to mount tmpfs",
+        })
+        .is_some());
+        assert!(hint(&Error::InvalidRequest).is_none());
+    }
+}
@@ -2,46 +2,81 @@
 // Copyright 2021 Keylime Authors
 
 // use super::*;
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
 use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
 use openssl::pkcs5;
 use openssl::pkey::{Id, PKey, PKeyRef, Private, Public};
 use openssl::rsa::{Padding, Rsa};
 use openssl::sign::{Signer, Verifier};
+use openssl::stack::Stack;
+use openssl::symm::{self, Cipher};
+use openssl::x509::extension::{ExtendedKeyUsage, KeyUsage};
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::verify::X509VerifyFlags;
+use openssl::x509::{X509NameBuilder, X509PurposeId, X509StoreContext, X509};
 use std::fs::File;
 use std::io::Read;
+use std::path::Path;
 use std::string::String;
 
 use crate::{Error, Result};
 
-/*
+const AES_BLOCK_SIZE: usize = 16;
+
+/**
  * Inputs: secret key
  *        message to sign
  * Output: signed HMAC result
  *
  * Sign message and return HMAC result string
  */
-pub(crate) fn do_hmac(
-    input_key: String,
-    input_message: String,
-) -> Result<String> {
-    let key = PKey::hmac(input_key.as_bytes())?;
-    let message = input_message.as_bytes();
+pub fn do_hmac(input_key: String, input_message: String) -> Result<String> {
+    let hmac = compute_hmac(input_key.as_bytes(), input_message.as_bytes())?;
+    Ok(to_hex_string(&hmac))
+}
+
+/**
+ * Inputs: secret key
+ *        message to sign
+ * Output: raw HMAC-SHA384 of the message
+ */
+pub fn compute_hmac(key: &[u8], message: &[u8]) -> Result<Vec<u8>> {
+    let key = PKey::hmac(key)?;
     let mut signer = Signer::new(MessageDigest::sha384(), &key)?;
     signer.update(message)?;
-    let hmac = signer.sign_to_vec()?;
-    Ok(to_hex_string(hmac))
+    signer.sign_to_vec().map_err(Error::Crypto)
 }
 
-/*
+/**
+ * Inputs: secret key
+ *        message
+ *        hex encoded HMAC received from a remote party
+ * Output: true if the HMAC matches, compared in constant time
+ */
+pub fn verify_hmac(
+    key: &[u8],
+    message: &[u8],
+    hex_hmac: &str,
+) -> Result<bool> {
+    let expected = compute_hmac(key, message)?;
+    let received = match hex::decode(hex_hmac) {
+        Ok(v) => v,
+        Err(_) => return Ok(false),
+    };
+    Ok(expected.len() == received.len()
+        && openssl::memcmp::eq(&expected, &received))
+}
+
+/**
  * Input: path to PEM-encoded RSA public key
  * Output: OpenSSL RSA key object
  *
  * Import a PEM-encoded RSA public key and return a callable OpenSSL RSA key
  * object.
  */
-pub(crate) fn rsa_import_pubkey(
-    input_key_path: String,
-) -> Result<PKey<Public>> {
+pub fn rsa_import_pubkey(input_key_path: String) -> Result<PKey<Public>> {
     let mut key_buffer = vec![0; 1];
     let mut input_key = File::open(input_key_path)?;
     let _ = input_key.read_to_end(&mut key_buffer)?;
@@ -49,11 +84,13 @@ pub(crate) fn rsa_import_pubkey(
         .map_err(Error::Crypto)
 }
 
-pub(crate) fn rsa_generate(key_size: u32) -> Result<PKey<Private>> {
+/// Generates an RSA private key of the given size in bits
+pub fn rsa_generate(key_size: u32) -> Result<PKey<Private>> {
     PKey::from_rsa(Rsa::generate(key_size)?).map_err(Error::Crypto)
 }
 
-pub(crate) fn rsa_generate_pair(
+/// Generates an RSA key pair of the given size in bits
+pub fn rsa_generate_pair(
     key_size: u32,
 ) -> Result<(PKey<Public>, PKey<Private>)> {
     let private = rsa_generate(key_size)?;
@@ -61,9 +98,8 @@ pub(crate) fn rsa_generate_pair(
     Ok((public, private))
 }
 
-pub(crate) fn pkey_pub_from_priv(
-    privkey: PKey<Private>,
-) -> Result<PKey<Public>> {
+/// Returns the public half of an RSA private key
+pub fn pkey_pub_from_priv(privkey: PKey<Private>) -> Result<PKey<Public>> {
     match privkey.id() {
         Id::RSA => {
             let rsa = Rsa::from_public_components(
@@ -82,7 +118,155 @@ pub(crate) fn pkey_pub_from_priv(
     }
 }
 
-/*
+/**
+ * Input: path to PEM-encoded X509 certificate
+ * Output: OpenSSL X509 certificate object
+ */
+pub fn load_x509(input_cert_path: &Path) -> Result<X509> {
+    let mut cert_buffer = Vec::new();
+    let mut input_cert = File::open(input_cert_path)?;
+    let _ = input_cert.read_to_end(&mut cert_buffer)?;
+    X509::from_pem(&cert_buffer).map_err(Error::Crypto)
+}
+
+/**
+ * Input: path to PEM-encoded private key
+ * Output: OpenSSL private key object
+ */
+pub fn load_private_key(input_key_path: &Path) -> Result<PKey<Private>> {
+    let mut key_buffer = Vec::new();
+    let mut input_key = File::open(input_key_path)?;
+    let _ = input_key.read_to_end(&mut key_buffer)?;
+    PKey::private_key_from_pem(&key_buffer).map_err(Error::Crypto)
+}
+
+/**
+ * Inputs: X509 certificate
+ *         private key
+ * Output: Ok if the key belongs to the certificate and the certificate is
+ *         currently valid, otherwise an error describing the problem
+ */
+pub fn check_x509_key_pair(
+    cert: &X509,
+    key: &PKeyRef<Private>,
+) -> Result<()> {
+    let now = Asn1Time::days_from_now(0)?;
+    if cert.not_before() > now {
+        return Err(Error::Other(format!(
+            "certificate is not valid before {}",
+            cert.not_before()
+        )));
+    }
+    if cert.not_after() < now {
+        return Err(Error::Other(format!(
+            "certificate expired on {}",
+            cert.not_after()
+        )));
+    }
+    if !cert.public_key()?.public_eq(key) {
+        return Err(Error::Other(String::from(
+            "private key does not match the certificate",
+        )));
+    }
+    Ok(())
+}
+
+/**
+ * Inputs: X509 certificate
+ *         private key
+ *         CA certificate the certificate must be issued by, if any
+ * Output: Ok if the certificate can serve TLS with the key, otherwise an
+ *         error describing the problem
+ *
+ * On top of check_x509_key_pair(), the certificate is verified the way a
+ * TLS client would: the chain up to the CA must be valid at the current
+ * time, the CA must be allowed to sign certificates and the key usage and
+ * extended key usage of the certificate must allow a TLS server. Without a
+ * CA, the certificate is trusted on its own, but its validity and usage are
+ * still checked.
+ */
+pub fn check_tls_credentials(
+    cert: &X509,
+    key: &PKeyRef<Private>,
+    ca: Option<&X509>,
+) -> Result<()> {
+    check_x509_key_pair(cert, key)?;
+
+    let mut store = X509StoreBuilder::new()?;
+    match ca {
+        Some(ca) => store.add_cert(ca.clone())?,
+        None => {
+            store.add_cert(cert.clone())?;
+            store.set_flags(X509VerifyFlags::PARTIAL_CHAIN)?;
+        }
+    }
+    store.set_purpose(X509PurposeId::SSL_SERVER)?;
+    let store = store.build();
+
+    let mut context = X509StoreContext::new()?;
+    let chain = Stack::new()?;
+    let failure = context.init(&store, cert, &chain, |context| {
+        Ok(if context.verify_cert()? {
+            None
+        } else {
+            Some(context.error())
+        })
+    })?;
+    match failure {
+        None => Ok(()),
+        Some(e) => Err(Error::Other(format!(
+            "certificate verification failed: {}",
+            e
+        ))),
+    }
+}
+
+/**
+ * Inputs: private key
+ *         common name of the certificate
+ *         number of days the certificate is valid
+ * Output: self-signed X509 certificate for the key
+ *
+ * The certificate is usable by a TLS server, for instance to serve TLS
+ * before a CA issued certificate is available.
+ */
+pub fn generate_x509(
+    key: &PKeyRef<Private>,
+    common_name: &str,
+    days: u32,
+) -> Result<X509> {
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, common_name)?;
+    let name = name.build();
+
+    let mut serial = BigNum::new()?;
+    serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+    let serial = serial.to_asn1_integer()?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(days)?;
+
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    builder.set_serial_number(&serial)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(key)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+    builder.append_extension(
+        KeyUsage::new()
+            .critical()
+            .digital_signature()
+            .key_encipherment()
+            .build()?,
+    )?;
+    builder
+        .append_extension(ExtendedKeyUsage::new().server_auth().build()?)?;
+    builder.sign(key, MessageDigest::sha256())?;
+    Ok(builder.build())
+}
+
+/**
  * Inputs: OpenSSL RSA key
  *         ciphertext to be decrypted
  * Output: decrypted plaintext
@@ -90,7 +274,7 @@ pub(crate) fn pkey_pub_from_priv(
  * Take in an RSA-encrypted ciphertext and an RSA private key and decrypt the
  * ciphertext based on PKCS1 OAEP. Parameters match that of Python-Keylime.
  */
-pub(crate) fn rsa_decrypt(
+pub fn rsa_decrypt(
     private_key: Rsa<Private>,
     ciphertext: String,
 ) -> Result<String> {
@@ -100,10 +284,65 @@ pub(crate) fn rsa_decrypt(
         &mut dec_result,
         Padding::PKCS1,
     )?;
-    Ok(to_hex_string(dec_result[..dec_len].to_vec()))
+    Ok(to_hex_string(&dec_result[..dec_len]))
 }
 
-/*
+/**
+ * Inputs: OpenSSL private key
+ *         ciphertext to be decrypted
+ * Output: decrypted plaintext
+ *
+ * Decrypt a ciphertext with RSA-OAEP (SHA-1, MGF1 with SHA-1), which is how
+ * the tenant and verifier protect the U and V key shares sent to the agent.
+ */
+pub fn rsa_oaep_decrypt(
+    private_key: &PKeyRef<Private>,
+    ciphertext: &[u8],
+) -> Result<Vec<u8>> {
+    let rsa = private_key.rsa()?;
+    let mut dec_result = vec![0; rsa.size() as usize];
+    let dec_len = rsa.private_decrypt(
+        ciphertext,
+        &mut dec_result,
+        Padding::PKCS1_OAEP,
+    )?;
+    dec_result.truncate(dec_len);
+    Ok(dec_result)
+}
+
+/**
+ * Inputs: AES key
+ *         ciphertext in the form iv (16 bytes) + encrypted data + tag (16
+ *         bytes), as produced by Python Keylime's crypto.encrypt()
+ * Output: decrypted plaintext
+ *
+ * Decrypt data with AES-GCM, picking AES-128 or AES-256 from the key size.
+ */
+pub fn decrypt_aead(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let cipher = match key.len() {
+        16 => Cipher::aes_128_gcm(),
+        32 => Cipher::aes_256_gcm(),
+        other => {
+            return Err(Error::Other(format!(
+                "key length {} not supported for AES-GCM",
+                other
+            )))
+        }
+    };
+
+    if data.len() < AES_BLOCK_SIZE * 2 {
+        return Err(Error::Other(String::from(
+            "ciphertext too short for AES-GCM",
+        )));
+    }
+    let (iv, rest) = data.split_at(AES_BLOCK_SIZE);
+    let (ciphertext, tag) = rest.split_at(rest.len() - AES_BLOCK_SIZE);
+
+    symm::decrypt_aead(cipher, key, Some(iv), &[], ciphertext, tag)
+        .map_err(Error::Crypto)
+}
+
+/**
  * Inputs: password to derive key
  *         shared salt
  * Output: derived key
@@ -117,10 +356,7 @@ pub(crate) fn rsa_decrypt(
  * PBKDF2 function defaults to SHA-1 unless otherwise specified, and
  * Python-Keylime uses this default.
  */
-pub(crate) fn kdf(
-    input_password: String,
-    input_salt: String,
-) -> Result<String> {
+pub fn kdf(input_password: String, input_salt: String) -> Result<String> {
     let password = input_password.as_bytes();
     let salt = input_salt.as_bytes();
     let count = 2000;
@@ -135,7 +371,7 @@ pub(crate) fn kdf(
         MessageDigest::sha1(),
         &mut key,
     )?;
-    Ok(to_hex_string(key.to_vec()))
+    Ok(to_hex_string(&key))
 }
 
 /*
@@ -144,19 +380,30 @@ pub(crate) fn kdf(
  *
  * Convert a byte data to a hex representation
  */
-fn to_hex_string(bytes: Vec<u8>) -> String {
-    let strs: Vec<String> =
-        bytes.iter().map(|b| format!("{:02x}", b)).collect();
-    strs.join("")
+fn to_hex_string(bytes: &[u8]) -> String {
+    hex::encode(bytes)
 }
 
-/*
+/**
+ * Input: Private key, and message
+ * Output: RSASSA PKCS#1 v1.5 signature of the message, with SHA-256
+ */
+pub fn asym_sign(
+    keypair: &PKeyRef<Private>,
+    message: &[u8],
+) -> Result<Vec<u8>> {
+    let mut signer = Signer::new(MessageDigest::sha256(), keypair)?;
+    signer.update(message)?;
+    Ok(signer.sign_to_vec()?)
+}
+
+/**
  * Input: Trusted public key, and remote message and signature
  * Output: true if they are verified, otherwise false
  *
  * Verify a remote message and signature against a local rsa cert
  */
-pub(crate) fn asym_verify(
+pub fn asym_verify(
     keypair: &PKeyRef<Public>,
     message: &str,
     signature: &str,
@@ -202,6 +449,173 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_verify_hmac() {
+        let key = b"mysecret";
+        let mac =
+            do_hmac(String::from("mysecret"), String::from("hellothere"))
+                .unwrap(); //#[allow_ci]
+        assert!(verify_hmac(key, b"hellothere", &mac).unwrap()); //#[allow_ci]
+        assert!(!verify_hmac(key, b"hellothere!", &mac).unwrap()); //#[allow_ci]
+        assert!(!verify_hmac(key, b"hellothere", "zz").unwrap()); //#[allow_ci]
+    }
+
+    #[test]
+    fn test_rsa_oaep_decrypt() {
+        let (public, private) = rsa_generate_pair(2048).unwrap(); //#[allow_ci]
+        let rsa = public.rsa().unwrap(); //#[allow_ci]
+        let mut ciphertext = vec![0; rsa.size() as usize];
+        let len = rsa
+            .public_encrypt(b"secret", &mut ciphertext, Padding::PKCS1_OAEP)
+            .unwrap(); //#[allow_ci]
+        ciphertext.truncate(len);
+
+        let plaintext = rsa_oaep_decrypt(&private, &ciphertext).unwrap(); //#[allow_ci]
+        assert_eq!(plaintext, b"secret");
+    }
+
+    #[test]
+    fn test_decrypt_aead() {
+        let key = [0x42u8; 32];
+        let iv = [0x24u8; AES_BLOCK_SIZE];
+        let mut tag = [0u8; AES_BLOCK_SIZE];
+        let ciphertext = symm::encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &key,
+            Some(&iv),
+            &[],
+            b"payload",
+            &mut tag,
+        )
+        .unwrap(); //#[allow_ci]
+
+        let mut data = iv.to_vec();
+        data.extend(&ciphertext);
+        data.extend(&tag);
+        assert_eq!(decrypt_aead(&key, &data).unwrap(), b"payload"); //#[allow_ci]
+
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        assert!(decrypt_aead(&key, &data).is_err());
+    }
+
+    #[test]
+    fn test_check_x509_key_pair() {
+        use openssl::x509::X509Builder;
+
+        let key = rsa_generate(2048).unwrap(); //#[allow_ci]
+        let other_key = rsa_generate(2048).unwrap(); //#[allow_ci]
+
+        let mut builder = X509Builder::new().unwrap(); //#[allow_ci]
+        builder.set_pubkey(&key).unwrap(); //#[allow_ci]
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap()) //#[allow_ci]
+            .unwrap(); //#[allow_ci]
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap()) //#[allow_ci]
+            .unwrap(); //#[allow_ci]
+        builder.sign(&key, MessageDigest::sha256()).unwrap(); //#[allow_ci]
+        let cert = builder.build();
+
+        assert!(check_x509_key_pair(&cert, &key).is_ok());
+        assert!(check_x509_key_pair(&cert, &other_key).is_err());
+    }
+
+    // A certificate issued by the CA key for the key, valid for days
+    fn issue_x509(
+        key: &PKeyRef<Private>,
+        ca: &X509,
+        ca_key: &PKeyRef<Private>,
+        days: u32,
+        server_auth: bool,
+    ) -> X509 {
+        let mut name = X509NameBuilder::new().unwrap(); //#[allow_ci]
+        name.append_entry_by_nid(Nid::COMMONNAME, "agent").unwrap(); //#[allow_ci]
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap(); //#[allow_ci]
+        builder.set_version(2).unwrap(); //#[allow_ci]
+        let serial = BigNum::from_u32(2).unwrap(); //#[allow_ci]
+        builder
+            .set_serial_number(&serial.to_asn1_integer().unwrap()) //#[allow_ci]
+            .unwrap(); //#[allow_ci]
+        builder.set_subject_name(&name).unwrap(); //#[allow_ci]
+        builder.set_issuer_name(ca.subject_name()).unwrap(); //#[allow_ci]
+        builder.set_pubkey(key).unwrap(); //#[allow_ci]
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap()) //#[allow_ci]
+            .unwrap(); //#[allow_ci]
+        builder
+            .set_not_after(&Asn1Time::days_from_now(days).unwrap()) //#[allow_ci]
+            .unwrap(); //#[allow_ci]
+        let mut usage = ExtendedKeyUsage::new();
+        if server_auth {
+            let _ = usage.server_auth();
+        } else {
+            let _ = usage.client_auth();
+        }
+        builder.append_extension(usage.build().unwrap()).unwrap(); //#[allow_ci]
+        builder.sign(ca_key, MessageDigest::sha256()).unwrap(); //#[allow_ci]
+        builder.build()
+    }
+
+    #[test]
+    fn test_check_tls_credentials() {
+        use openssl::x509::extension::BasicConstraints;
+
+        let key = rsa_generate(2048).unwrap(); //#[allow_ci]
+        let ca_key = rsa_generate(2048).unwrap(); //#[allow_ci]
+
+        // Self-signed, valid for a TLS server
+        let cert = generate_x509(&key, "agent", 1).unwrap(); //#[allow_ci]
+        assert!(check_tls_credentials(&cert, &key, None).is_ok());
+        // Expired
+        let expired = generate_x509(&key, "agent", 0).unwrap(); //#[allow_ci]
+        assert!(check_tls_credentials(&expired, &key, None).is_err());
+
+        let mut name = X509NameBuilder::new().unwrap(); //#[allow_ci]
+        name.append_entry_by_nid(Nid::COMMONNAME, "ca").unwrap(); //#[allow_ci]
+        let name = name.build();
+        let mut builder = X509::builder().unwrap(); //#[allow_ci]
+        builder.set_version(2).unwrap(); //#[allow_ci]
+        let serial = BigNum::from_u32(1).unwrap(); //#[allow_ci]
+        builder
+            .set_serial_number(&serial.to_asn1_integer().unwrap()) //#[allow_ci]
+            .unwrap(); //#[allow_ci]
+        builder.set_subject_name(&name).unwrap(); //#[allow_ci]
+        builder.set_issuer_name(&name).unwrap(); //#[allow_ci]
+        builder.set_pubkey(&ca_key).unwrap(); //#[allow_ci]
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap()) //#[allow_ci]
+            .unwrap(); //#[allow_ci]
+        builder
+            .set_not_after(&Asn1Time::days_from_now(2).unwrap()) //#[allow_ci]
+            .unwrap(); //#[allow_ci]
+        builder
+            .append_extension(
+                BasicConstraints::new().critical().ca().build().unwrap(), //#[allow_ci]
+            )
+            .unwrap(); //#[allow_ci]
+        builder
+            .append_extension(
+                KeyUsage::new().critical().key_cert_sign().build().unwrap(), //#[allow_ci]
+            )
+            .unwrap(); //#[allow_ci]
+        builder.sign(&ca_key, MessageDigest::sha256()).unwrap(); //#[allow_ci]
+        let ca = builder.build();
+
+        let issued = issue_x509(&key, &ca, &ca_key, 1, true);
+        assert!(check_tls_credentials(&issued, &key, Some(&ca)).is_ok());
+        // Not issued by that CA
+        assert!(check_tls_credentials(&cert, &key, Some(&ca)).is_err());
+        // Only usable by a TLS client
+        let client = issue_x509(&key, &ca, &ca_key, 1, false);
+        assert!(check_tls_credentials(&client, &key, Some(&ca)).is_err());
+        // Expired
+        let expired = issue_x509(&key, &ca, &ca_key, 0, true);
+        assert!(check_tls_credentials(&expired, &key, Some(&ca)).is_err());
+    }
+
     #[test]
     fn test_hmac_verification() {
         // Generate a keypair
@@ -0,0 +1,215 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Running the agent as a traditional daemon
+//
+// Init systems other than systemd expect the agent to detach itself from
+// the terminal and to record its PID in a PID file. The PID file is locked
+// for as long as the agent runs, so a file left behind by an agent that
+// was killed is detected as stale and reused, while a second agent refuses
+// to start.
+
+use crate::error::{Error, Result};
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::process;
+
+// Environment variables holding paths, which must stay valid once the
+// daemon changed its working directory
+static PATH_VARIABLES: &[&str] =
+    &["KEYLIME_CONFIG", "KEYLIME_DIR", "KEYLIME_RUN_DIR"];
+
+/// A locked PID file, removed when dropped
+#[derive(Debug)]
+pub(crate) struct PidFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl PidFile {
+    /*
+     * Input: path of the PID file
+     * Return: Result wrap the locked PID file
+     *
+     * Fails if another agent holds the lock on the file. A file left over
+     * by an agent that did not exit cleanly is not locked anymore, and is
+     * reused.
+     */
+    pub(crate) fn lock(path: &Path) -> Result<Self> {
+        // The runtime directory does not survive reboots
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            // Only truncate once the lock is held
+            .truncate(false)
+            .mode(0o644)
+            .open(path)
+            .map_err(|e| {
+                Error::Other(format!(
+                    "unable to open PID file {}: {}",
+                    path.display(),
+                    e
+                ))
+            })?;
+
+        let ret = unsafe {
+            libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB)
+        };
+        if ret != 0 {
+            let pid = fs::read_to_string(path).unwrap_or_default();
+            return Err(Error::Other(format!(
+                "another agent (PID {}) is running with PID file {}",
+                pid.trim(),
+                path.display()
+            )));
+        }
+
+        Ok(PidFile {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    /// Writes the PID of the current process, replacing any stale PID
+    pub(crate) fn write_pid(&mut self) -> Result<()> {
+        self.file.set_len(0)?;
+        let _ = self.file.seek(SeekFrom::Start(0))?;
+        writeln!(self.file, "{}", process::id())?;
+        self.file.sync_all()?;
+        Ok(())
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // Best effort: the agent may have lost the permission to do it
+        // after dropping privileges
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn open_log(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o640)
+        .open(path)
+        .map_err(|e| {
+            Error::Other(format!(
+                "unable to open log file {}: {}",
+                path.display(),
+                e
+            ))
+        })
+}
+
+fn dup2(from: RawFd, to: RawFd) -> Result<()> {
+    if unsafe { libc::dup2(from, to) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Sends standard output and standard error, and so the log, to a file
+pub(crate) fn redirect_output(log_file: &Path) -> Result<()> {
+    let log = open_log(log_file)?;
+    dup2(log.as_raw_fd(), libc::STDOUT_FILENO)?;
+    dup2(log.as_raw_fd(), libc::STDERR_FILENO)
+}
+
+// Forks, letting only the child return
+fn fork_and_exit_parent() -> Result<()> {
+    match unsafe { libc::fork() } {
+        -1 => Err(std::io::Error::last_os_error().into()),
+        0 => Ok(()),
+        // Skip destructors, which would e.g. remove the PID file the child
+        // still holds
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+/*
+ * Input: optional log file
+ * Return: Result wrap with error message
+ *
+ * Detaches the agent from the terminal with the usual double fork. This
+ * must happen before any thread is started. Standard input is connected to
+ * /dev/null, and standard output and error to the log file if there is
+ * one, or to /dev/null.
+ */
+pub(crate) fn daemonize(log_file: Option<&Path>) -> Result<()> {
+    // Open the files first, so that errors still reach the terminal
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    let log = match log_file {
+        Some(path) => Some(open_log(path)?),
+        None => None,
+    };
+
+    let cwd = env::current_dir()?;
+    for var in PATH_VARIABLES {
+        if let Some(value) = env::var_os(var) {
+            let path = PathBuf::from(value);
+            if path.is_relative() && !path.as_os_str().is_empty() {
+                env::set_var(var, cwd.join(path));
+            }
+        }
+    }
+
+    fork_and_exit_parent()?;
+    if unsafe { libc::setsid() } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    // The session leader exits, so the daemon can never acquire a
+    // controlling terminal again
+    fork_and_exit_parent()?;
+
+    env::set_current_dir("/")?;
+    let _ = unsafe { libc::umask(0o022) };
+
+    let output = log.as_ref().unwrap_or(&null).as_raw_fd();
+    dup2(null.as_raw_fd(), libc::STDIN_FILENO)?;
+    dup2(output, libc::STDOUT_FILENO)?;
+    dup2(output, libc::STDERR_FILENO)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pid_file_lock() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join("keylime_agent.pid");
+
+        // A stale PID file is reused
+        fs::write(&path, "999999\n").unwrap(); //#[allow_ci]
+        let mut pid_file = PidFile::lock(&path).unwrap(); //#[allow_ci]
+        pid_file.write_pid().unwrap(); //#[allow_ci]
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(), //#[allow_ci]
+            format!("{}\n", process::id())
+        );
+
+        // The file is locked while the agent runs
+        assert!(PidFile::lock(&path).is_err());
+
+        drop(pid_file);
+        assert!(!path.exists());
+        assert!(PidFile::lock(&path).is_ok());
+    }
+}
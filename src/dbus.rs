@@ -0,0 +1,206 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// D-Bus status interface
+//
+// With dbus_service set in keylime.conf, the agent owns the name
+// org.keylime.Agent on the system bus and exports the object
+// /org/keylime/Agent, so that host management daemons can follow it
+// without polling its HTTP port:
+//
+//   properties AgentUUID (s), RegistrationState (s), LastQuoteTime (t, in
+//   seconds since the epoch, 0 before the first quote) and
+//   ProvisioningStatus (s, the bootstrap key delivery state)
+//   method Reregister(), which restarts the agent so that it registers
+//   again, as it does on every start
+//   signals PayloadProvisioned(), AttestationIdle(), TpmErrors() and
+//   RegistrarErrors(), emitted along with the hooks, see hooks.rs
+//
+// The object is served with zbus, by a thread of its own.
+
+use crate::common::config_get_bool_or;
+use crate::error::{Error, Result};
+use crate::{hooks, signals, QuoteData};
+
+use actix_web::web;
+use lazy_static::lazy_static;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tracing::{error, info, warn};
+use zbus::{dbus_interface, fdo, Connection, ObjectServer};
+use zvariant::ObjectPath;
+
+pub(crate) static BUS_NAME: &str = "org.keylime.Agent";
+pub(crate) static OBJECT_PATH: &str = "/org/keylime/Agent";
+
+// Set once Reregister was called
+static REREGISTERING: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    // The bus connection once the service started, for the signals
+    static ref CONNECTION: Mutex<Option<Connection>> = Mutex::new(None);
+}
+
+fn dbus_error(e: impl std::fmt::Display) -> Error {
+    Error::DBus(e.to_string())
+}
+
+fn registration_state(data: &QuoteData) -> &'static str {
+    if REREGISTERING.load(Ordering::SeqCst) {
+        "reregistering"
+    } else if data.registered.load(Ordering::SeqCst) {
+        "registered"
+    } else {
+        "registering"
+    }
+}
+
+fn provisioning_status(data: &QuoteData) -> String {
+    match data.keys.try_lock() {
+        Ok(keys) => keys.state().to_string(),
+        Err(_) => "busy".to_string(),
+    }
+}
+
+struct Agent {
+    data: web::Data<QuoteData>,
+}
+
+#[dbus_interface(name = "org.keylime.Agent")]
+impl Agent {
+    fn reregister(&self) {
+        REREGISTERING.store(true, Ordering::SeqCst);
+        info!("Re-registration requested over D-Bus, restarting");
+        signals::request_restart();
+    }
+
+    #[dbus_interface(property, name = "AgentUUID")]
+    fn agent_uuid(&self) -> String {
+        self.data.agent_uuid.clone()
+    }
+
+    #[dbus_interface(property)]
+    fn registration_state(&self) -> String {
+        registration_state(&self.data).to_string()
+    }
+
+    #[dbus_interface(property)]
+    fn last_quote_time(&self) -> u64 {
+        self.data.last_quote.load(Ordering::SeqCst)
+    }
+
+    #[dbus_interface(property)]
+    fn provisioning_status(&self) -> String {
+        provisioning_status(&self.data)
+    }
+
+    // Declared for introspection, emitted by emit()
+    #[dbus_interface(signal)]
+    fn payload_provisioned(&self) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    fn attestation_idle(&self) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    fn tpm_errors(&self) -> zbus::Result<()>;
+
+    #[dbus_interface(signal)]
+    fn registrar_errors(&self) -> zbus::Result<()>;
+}
+
+// Connects to the system bus and owns the agent's name
+fn connect() -> Result<Connection> {
+    let conn = Connection::new_system().map_err(dbus_error)?;
+    let reply = fdo::DBusProxy::new(&conn)
+        .map_err(dbus_error)?
+        .request_name(BUS_NAME, fdo::RequestNameFlags::DoNotQueue.into())
+        .map_err(dbus_error)?;
+    if !matches!(reply, fdo::RequestNameReply::PrimaryOwner) {
+        return Err(Error::DBus(format!(
+            "unable to own D-Bus name {}: {:?}",
+            BUS_NAME, reply
+        )));
+    }
+    Ok(conn)
+}
+
+fn serve(conn: Connection, data: web::Data<QuoteData>) -> Result<()> {
+    let mut server = ObjectServer::new(&conn);
+    let path = ObjectPath::try_from(OBJECT_PATH).map_err(dbus_error)?;
+    let _ = server.at(&path, Agent { data }).map_err(dbus_error)?;
+    loop {
+        match server.try_handle_next() {
+            Ok(_) => {}
+            Err(zbus::Error::Io(e)) => return Err(e.into()),
+            Err(e) => warn!("Unable to handle D-Bus message: {}", e),
+        }
+    }
+}
+
+/*
+ * Input: shared agent state
+ * Return: Result wrap with error message
+ *
+ * Starts the D-Bus service in a thread of its own if dbus_service is
+ * enabled. The agent fails to start if it cannot own its name on the bus,
+ * while losing the bus connection later is only logged.
+ */
+pub(crate) fn start(data: web::Data<QuoteData>) -> Result<()> {
+    if !config_get_bool_or("cloud_agent", "dbus_service", false)? {
+        return Ok(());
+    }
+
+    let conn = connect()?;
+    *CONNECTION.lock().unwrap() = Some(conn.clone()); //#[allow_ci]
+    info!("Serving D-Bus name {} on the system bus", BUS_NAME);
+    let _ = std::thread::Builder::new().name("dbus".to_string()).spawn(
+        move || {
+            if let Err(e) = serve(conn, data) {
+                error!("D-Bus service stopped: {}", e);
+            }
+        },
+    )?;
+    Ok(())
+}
+
+/// Emits the signal of a hook event, if the D-Bus service is running
+pub(crate) fn emit(event: hooks::Event) {
+    let conn = match CONNECTION.lock() {
+        Ok(conn) => conn.clone(),
+        Err(_) => None,
+    };
+    if let Some(conn) = conn {
+        if let Err(e) = conn.emit_signal(
+            None,
+            OBJECT_PATH,
+            BUS_NAME,
+            event.signal_name(),
+            &(),
+        ) {
+            warn!(
+                "Unable to emit D-Bus signal {}: {}",
+                event.signal_name(),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tpm_backend::{quote_data, MockTpm};
+
+    #[test]
+    fn agent_status() {
+        let data = quote_data(MockTpm::new()).unwrap(); //#[allow_ci]
+        assert_eq!(registration_state(&data), "registering");
+        data.registered.store(true, Ordering::SeqCst);
+        assert_eq!(registration_state(&data), "registered");
+        assert_eq!(provisioning_status(&data), "waiting for keys");
+
+        let _keys = data.keys.lock().unwrap(); //#[allow_ci]
+        assert_eq!(provisioning_status(&data), "busy");
+    }
+}
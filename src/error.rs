@@ -1,68 +1,350 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright 2021 Keylime Authors
 
+// Errors of the agent
+//
+// Variants carry what went wrong as data, the configuration key, the TPM
+// response code kind, the path, the HTTP status, and keep the error they
+// wrap as their source, so that the whole chain can be logged. Every
+// variant has a numeric code, returned in API error bodies along with the
+// message, for tools that act on errors. The codes are stable: a variant
+// keeps its code, and the codes of removed variants are not reused.
+//
+// Error::Other still carries the messages that have no variant of their
+// own yet, mostly in tpm.rs, ffi.rs and the permission and sandboxing
+// setup. Giving them variants is left to a follow-up, new errors should
+// not use it.
+
+use std::path::PathBuf;
 use thiserror::Error;
 use tss_esapi::{
-    constants::response_code::Tss2ResponseCodeKind,
-    Error::{Tss2Error, WrapperError},
+    constants::response_code::Tss2ResponseCodeKind, Error::Tss2Error,
 };
 
+/// Errors of the agent and its library
 #[derive(Error, Debug)]
-pub(crate) enum Error {
-    #[error("HttpServer error: {0}")]
-    ActixWeb(actix_web::Error),
+#[non_exhaustive]
+pub enum Error {
+    /// A TPM command failed
     #[error("TPM Error: {err:?}, kind: {kind:?}, {message}")]
     Tpm {
+        /// The error of the TSS
+        #[source]
         err: tss_esapi::Error,
+        /// Kind of the TPM response code, if any
         kind: Option<Tss2ResponseCodeKind>,
+        /// Description of the error
         message: String,
     },
+    /// A request the agent cannot serve
     #[error("Invalid request")]
-    #[allow(unused)]
     InvalidRequest,
+    /// The configuration file could not be loaded
     #[error("Configuration loading error: {0}")]
     Ini(#[from] ini::ini::Error),
-    #[error("Configuration error: {0}")]
-    Configuration(String),
+    /// A configuration key is missing
+    #[error(
+        "Configuration error: {key} is not set in [{section}] of {file}"
+    )]
+    ConfigMissing {
+        /// Section of the key
+        section: String,
+        /// The missing key
+        key: String,
+        /// Path of the configuration file
+        file: String,
+    },
+    /// A configuration key has an invalid value
+    #[error("Configuration error: invalid {key} in [{section}]: {reason}")]
+    ConfigValue {
+        /// Section of the key
+        section: String,
+        /// The invalid key
+        key: String,
+        /// Why the value is invalid
+        reason: String,
+    },
+    /// The command line is invalid
+    #[error("{0}")]
+    Usage(String),
+    /// An HTTP request failed
     #[error("Reqwest error: {0}")]
     Reqwest(#[from] reqwest::Error),
+    /// The registrar answered with an error status
     #[error("Registrar error: received {code} from {addr}")]
-    Registrar { addr: String, code: u16 },
+    Registrar {
+        /// Address of the registrar
+        addr: String,
+        /// HTTP status received
+        code: u16,
+    },
+    /// The verifier answered with an error status
+    #[error("Verifier error: received {code} from {addr}")]
+    Verifier {
+        /// Address of the verifier
+        addr: String,
+        /// HTTP status received
+        code: u16,
+    },
+    /// JSON could not be serialized or deserialized
     #[error("Serialization/deserialization error: {0}")]
     Serde(#[from] serde_json::Error),
+    /// The agent lacks a permission it needs
     #[error("Permission error")]
     Permission,
+    /// An IO error not tied to a file
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
+    /// An IO error on a file
+    #[error("IO error: {}: {source}", path.display())]
+    File {
+        /// Path of the file
+        path: PathBuf,
+        /// The IO error
+        #[source]
+        source: std::io::Error,
+    },
+    /// Text is not valid UTF-8
     #[error("Text decoding error: {0}")]
     Utf8(#[from] std::string::FromUtf8Error),
-    #[error("Secure Mount error: {0})")]
-    #[allow(unused)]
-    SecureMount(String),
+    /// The secure storage could not be set up
+    #[error("Secure Mount error: {}: {reason}", path.display())]
+    SecureMount {
+        /// Path of the secure storage
+        path: PathBuf,
+        /// What failed
+        reason: &'static str,
+    },
+    /// The TPM is used by another process
     #[error("TPM in use")]
     TpmInUse,
+    /// An invalid UUID
     #[error("UUID error")]
     Uuid(#[from] uuid::Error),
+    /// A command failed, with its exit code and stderr
     #[error("Execution error: {0:?}, {1}")]
     Execution(Option<i32>, String),
+    /// A script failed, with its name, exit code and stderr
     #[error("Error executing script {0}: {1:?}, {2}")]
     Script(String, Option<i32>, String),
+    /// A number could not be parsed
     #[error("Number parsing error: {0}")]
     NumParse(#[from] std::num::ParseIntError),
+    /// An OpenSSL operation failed
     #[error("Crypto error: {0}")]
     Crypto(#[from] openssl::error::ErrorStack),
+    /// A ZeroMQ operation failed
     #[error("ZMQ error: {0}")]
     Zmq(#[from] zmq::Error),
+    /// A payload archive could not be read
+    #[error("Zip error: {0}")]
+    Zip(#[from] zip::result::ZipError),
+    /// A payload is beyond a size limit
+    #[error("Payload error: beyond {limit} of {max}")]
+    Payload {
+        /// Configuration key of the limit
+        limit: &'static str,
+        /// Value of the limit
+        max: u64,
+    },
+    /// A bootstrap key half was rejected
+    #[error("Key delivery error: {half} key rejected, bootstrap key already {state}")]
+    KeyDelivery {
+        /// The rejected half, U or V
+        half: &'static str,
+        /// Delivery state of the bootstrap key
+        state: String,
+    },
+    /// A runtime policy does not replace the current one
+    #[error("Runtime policy error: version {version} does not replace the current version {current}")]
+    Policy {
+        /// Version of the rejected policy
+        version: u64,
+        /// Version of the current policy
+        current: u64,
+    },
+    /// The SEV-SNP report request failed
+    #[error("SEV-SNP error: SNP_GET_REPORT failed with firmware error {firmware_error:#x}: {source}")]
+    Snp {
+        /// Error reported by the firmware
+        firmware_error: u64,
+        /// The IO error of the request
+        #[source]
+        source: std::io::Error,
+    },
+    /// The TDX report could not be read
+    #[error("TDX error: {reason}")]
+    Tdx {
+        /// What failed
+        reason: &'static str,
+    },
+    /// A hook endpoint answered with an error status
+    #[error("Hook error: received {code} from {url}")]
+    Hook {
+        /// URL of the endpoint
+        url: String,
+        /// HTTP status received
+        code: u16,
+    },
+    /// Base64 data could not be decoded
+    #[error("Base64 decoding error: {0}")]
+    Base64(#[from] base64::DecodeError),
+    /// A concurrency limit was reached
+    #[error("Overloaded: {limit} of {max} reached")]
+    Overloaded {
+        /// Configuration key of the limit
+        limit: &'static str,
+        /// Value of the limit
+        max: u64,
+    },
+    /// A background task failed
+    #[error("Background task failed: {0}")]
+    Join(#[from] tokio::task::JoinError),
+    /// The D-Bus service failed
+    #[error("D-Bus error: {0}")]
+    DBus(String),
+    /// Any other error
     #[error("{0}")]
     Other(String),
+    /// An invalid regular expression
+    #[error("Invalid regular expression: {0}")]
+    Regex(#[from] regex::Error),
+    /// A payload archive entry is not allowed
+    #[error("Payload error: entry {entry:?} {reason}")]
+    PayloadEntry {
+        /// Name of the entry
+        entry: String,
+        /// Why it is not allowed
+        reason: &'static str,
+    },
+    /// The secure storage has unsafe ownership or mode
+    #[error("Secure Mount error: {} is owned by UID {uid} with mode {mode:o}, it must belong to the agent and not be accessible to other users", path.display())]
+    SecureMountPermissions {
+        /// Path of the secure storage
+        path: PathBuf,
+        /// Owner of the secure storage
+        uid: u32,
+        /// Mode of the secure storage
+        mode: u32,
+    },
+    /// The SEV-SNP report response is invalid
+    #[error("SEV-SNP error: invalid report response, status {status:#x}, size {size}")]
+    SnpReport {
+        /// Status of the response
+        status: u32,
+        /// Size of the report
+        size: usize,
+    },
+    /// The TDX report comes from an unexpected provider
+    #[error("TDX error: unexpected report provider {provider}")]
+    TdxProvider {
+        /// Name of the provider
+        provider: String,
+    },
+    /// A nonce is longer than allowed
+    #[error("Nonce longer than {max} bytes")]
+    NonceTooLong {
+        /// Maximum length in bytes
+        max: usize,
+    },
 }
 
-impl actix_web::ResponseError for Error {}
+impl actix_web::ResponseError for Error {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        match self {
+            // Beyond a resource limit, see limits.rs
+            Error::Overloaded { .. } => {
+                actix_web::http::StatusCode::SERVICE_UNAVAILABLE
+            }
+            _ => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // In the shape of the other responses of the API
+    fn error_response(&self) -> actix_web::HttpResponse {
+        let status = self.status_code();
+        tracing::warn!(error_code = self.code(), "{}", self);
+        actix_web::HttpResponse::build(status).json(serde_json::json!({
+            "code": status.as_u16(),
+            "status": self.to_string(),
+            "results": {"error_code": self.code()},
+        }))
+    }
+}
 
 impl Error {
-    pub(crate) fn http_code(&self) -> Result<u16> {
+    /// Creates an error for an invalid value of a configuration key
+    pub fn config_value(
+        section: &str,
+        key: &str,
+        reason: impl std::fmt::Display,
+    ) -> Self {
+        Error::ConfigValue {
+            section: section.to_string(),
+            key: key.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Creates an error for an IO error on a file
+    pub fn file(path: impl Into<PathBuf>, source: std::io::Error) -> Self {
+        Error::File {
+            path: path.into(),
+            source,
+        }
+    }
+
+    /// Stable numeric code of the error
+    pub fn code(&self) -> u32 {
         match self {
-            Error::Registrar { addr, code } => Ok(*code),
+            Error::Tpm { .. } => 1,
+            Error::InvalidRequest => 2,
+            Error::Ini(_) => 3,
+            Error::ConfigMissing { .. } => 4,
+            Error::ConfigValue { .. } => 5,
+            Error::Usage(_) => 6,
+            Error::Reqwest(_) => 7,
+            Error::Registrar { .. } => 8,
+            Error::Verifier { .. } => 9,
+            Error::Serde(_) => 10,
+            Error::Permission => 11,
+            Error::Io(_) => 12,
+            Error::File { .. } => 13,
+            Error::Utf8(_) => 14,
+            Error::SecureMount { .. } => 15,
+            Error::TpmInUse => 16,
+            Error::Uuid(_) => 17,
+            Error::Execution(..) => 18,
+            Error::Script(..) => 19,
+            Error::NumParse(_) => 20,
+            Error::Crypto(_) => 21,
+            Error::Zmq(_) => 22,
+            Error::Zip(_) => 23,
+            Error::Payload { .. } => 24,
+            Error::KeyDelivery { .. } => 25,
+            Error::Policy { .. } => 26,
+            Error::Snp { .. } => 27,
+            Error::Tdx { .. } => 28,
+            Error::Hook { .. } => 29,
+            Error::Base64(_) => 30,
+            Error::Overloaded { .. } => 31,
+            Error::Join(_) => 32,
+            Error::Other(_) => 33,
+            Error::DBus(_) => 34,
+            Error::Regex(_) => 35,
+            Error::PayloadEntry { .. } => 36,
+            Error::SecureMountPermissions { .. } => 37,
+            Error::SnpReport { .. } => 38,
+            Error::TdxProvider { .. } => 39,
+            Error::NonceTooLong { .. } => 40,
+        }
+    }
+
+    /// HTTP status of a registrar or verifier error
+    pub fn http_code(&self) -> Result<u16> {
+        match self {
+            Error::Registrar { code, .. } => Ok(*code),
+            Error::Verifier { code, .. } => Ok(*code),
             other => Err(Error::Other(format!(
                 "cannot get http code for Error type {}",
                 other
@@ -70,7 +352,8 @@ impl Error {
         }
     }
 
-    pub(crate) fn exe_code(&self) -> Result<Option<i32>> {
+    /// Exit code of a failed command
+    pub fn exe_code(&self) -> Result<Option<i32>> {
         match self {
             Error::Execution(code, _) => Ok(code.to_owned()),
             other => Err(Error::Other(format!(
@@ -80,7 +363,8 @@ impl Error {
         }
     }
 
-    pub(crate) fn stderr(&self) -> Result<String> {
+    /// Standard error of a failed command
+    pub fn stderr(&self) -> Result<String> {
         match self {
             Error::Execution(_, stderr) => Ok(stderr.to_owned()),
             other => Err(Error::Other(format!(
@@ -113,4 +397,39 @@ impl From<tss_esapi::Error> for Error {
     }
 }
 
-pub(crate) type Result<T> = std::result::Result<T, Error>;
+/// Result of the agent and its library
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn structured_errors() {
+        let e = Error::config_value("cloud_agent", "run_as", "unknown user");
+        assert_eq!(
+            e.to_string(),
+            "Configuration error: invalid run_as in [cloud_agent]: unknown user"
+        );
+        assert_eq!(e.code(), 5);
+
+        let e = Error::file(
+            "/dev/tpmrm0",
+            std::io::Error::from(std::io::ErrorKind::PermissionDenied),
+        );
+        assert!(e.to_string().starts_with("IO error: /dev/tpmrm0: "));
+        assert!(e.source().is_some());
+        assert_eq!(e.code(), 13);
+
+        let e = Error::Overloaded {
+            limit: "max_concurrent_quotes",
+            max: 4,
+        };
+        assert_eq!(
+            e.to_string(),
+            "Overloaded: max_concurrent_quotes of 4 reached"
+        );
+        assert_eq!(e.code(), 31);
+    }
+}
@@ -0,0 +1,354 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Evidence bundles
+//
+// With evidence_dir set, every quote the agent generates is also saved to
+// disk, so that attestations can be audited, or verified after the fact if
+// the verifier was down at the time. A bundle holds the quote with its
+// nonce and PCR mask, the IMA entries added since the previous bundle with
+// the index of the first of them, and the SHA-256 digest of the measured
+// boot event log. The event log does not change while the agent runs, so it
+// is saved once next to the bundles, as event-log-<digest>.bin. When the
+// tenant delivered a runtime policy, the bundle also records its digest and
+// the new IMA entries it does not allow, and a copy of the policy is kept
+// next to the bundles, as runtime-policy-<digest>.json.
+//
+// Bundles are signed with the NK, in a .sig file next to them (RSASSA
+// PKCS#1 v1.5 with SHA-256). The NK is in the bundle, and the quote covers
+// its digest in PCR 16, so verifying the quote also vouches for the
+// signature:
+//
+//   $ openssl dgst -sha256 -verify nk.pem -signature <bundle>.json.sig \
+//         <bundle>.json
+//
+// Only the newest evidence_max_bundles bundles are kept.
+
+use crate::common::{config_get_or, work_dir_get, MEASUREDBOOT_ML};
+use crate::error::{Error, Result};
+use crate::ima::Entries;
+use crate::policy::RuntimePolicy;
+use crate::quotes_handler::KeylimeIdQuote;
+use crate::{crypto, persist};
+
+use openssl::pkey::{PKey, Private};
+use openssl::sha::sha256;
+use serde::Serialize;
+use std::fs;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+const DEFAULT_MAX_BUNDLES: &str = "1000";
+const BUNDLE_PREFIX: &str = "evidence-";
+
+#[derive(Serialize)]
+struct Bundle<'a> {
+    agent_uuid: &'a str,
+    // Seconds since the epoch
+    time: u64,
+    nonce: &'a str,
+    mask: Option<&'a str>,
+    quote: &'a KeylimeIdQuote<'a>,
+    ima_measurement_list_entry: Option<usize>,
+    ima_measurement_list: Option<String>,
+    // SHA-256 digest of the event log
+    event_log: Option<&'a str>,
+    // SHA-256 digest of the runtime policy, and the new IMA entries it
+    // does not allow
+    runtime_policy: Option<&'a str>,
+    runtime_policy_failures: Option<Vec<String>>,
+}
+
+#[derive(Debug)]
+struct EventLog {
+    data: Vec<u8>,
+    sha256: String,
+}
+
+impl EventLog {
+    fn new(data: Vec<u8>) -> Self {
+        let sha256 = hex::encode(sha256(&data));
+        EventLog { data, sha256 }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Evidence {
+    dir: PathBuf,
+    max_bundles: usize,
+    // Read when the agent starts, as it is only readable by root
+    event_log: Option<EventLog>,
+    // Entries of the IMA measurement list saved so far
+    ima_entries: Mutex<usize>,
+    // Bundles saved by this run, to tell apart those saved the same
+    // millisecond
+    saved: AtomicU64,
+}
+
+impl Evidence {
+    /*
+     * Return: Result wrap the evidence store, None if disabled
+     *
+     * Creates evidence_dir if needed.
+     */
+    pub(crate) fn from_config() -> Result<Option<Self>> {
+        let dir = config_get_or("cloud_agent", "evidence_dir", "")?;
+        if dir.is_empty() {
+            return Ok(None);
+        }
+        let max_bundles = config_get_or(
+            "cloud_agent",
+            "evidence_max_bundles",
+            DEFAULT_MAX_BUNDLES,
+        )?
+        .parse::<usize>()
+        .map_err(|e| {
+            Error::config_value("cloud_agent", "evidence_max_bundles", e)
+        })?;
+
+        let event_log = match fs::read(MEASUREDBOOT_ML) {
+            Ok(log) => Some(log),
+            Err(e) => {
+                warn!(
+                    "Unable to read the measured boot event log {}: {}",
+                    MEASUREDBOOT_ML, e
+                );
+                None
+            }
+        };
+
+        Ok(Some(
+            Evidence::new(Path::new(&work_dir_get()).join(dir), max_bundles)?
+                .with_event_log(event_log),
+        ))
+    }
+
+    pub(crate) fn new(dir: PathBuf, max_bundles: usize) -> Result<Self> {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)?;
+        Ok(Evidence {
+            dir,
+            max_bundles,
+            event_log: None,
+            ima_entries: Mutex::new(0),
+            saved: AtomicU64::new(0),
+        })
+    }
+
+    fn with_event_log(self, event_log: Option<Vec<u8>>) -> Self {
+        Evidence {
+            event_log: event_log.map(EventLog::new),
+            ..self
+        }
+    }
+
+    pub(crate) fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /*
+     * Input: agent UUID and NK private key
+     *        nonce and PCR mask of the request, and the quote
+     *        measurement list returned with an integrity quote
+     *        runtime policy delivered by the tenant
+     * Return: Result wrap the path of the bundle
+     *
+     * Saves and signs a bundle, and removes the oldest bundles beyond
+     * evidence_max_bundles.
+     */
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn save(
+        &self,
+        agent_uuid: &str,
+        priv_key: &PKey<Private>,
+        nonce: &str,
+        mask: Option<&str>,
+        quote: &KeylimeIdQuote,
+        ml: Option<Entries>,
+        policy: Option<&RuntimePolicy>,
+    ) -> Result<PathBuf> {
+        let (ima_measurement_list_entry, ima_measurement_list) = match ml {
+            Some(ml) => {
+                // must unwrap here due to lock mechanism
+                // https://github.com/rust-lang-nursery/failure/issues/192
+                let mut saved = self.ima_entries.lock().unwrap(); //#[allow_ci]
+                let (first, new) = ml.starting_at(*saved);
+                *saved = first + new.count();
+                (
+                    Some(first),
+                    Some(new.into_chunks().collect::<Vec<_>>().concat()),
+                )
+            }
+            None => (None, None),
+        };
+
+        let runtime_policy_failures = match (policy, &ima_measurement_list) {
+            (Some(policy), Some(ml)) => Some(policy.appraise(ml)),
+            _ => None,
+        };
+        if let Some(policy) = policy {
+            self.save_policy(policy)?;
+        }
+        if let Some(event_log) = &self.event_log {
+            self.save_event_log(event_log)?;
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let bundle = serde_json::to_vec(&Bundle {
+            agent_uuid,
+            time: now.as_secs(),
+            nonce,
+            mask,
+            quote,
+            ima_measurement_list_entry,
+            ima_measurement_list,
+            event_log: self.event_log.as_ref().map(|log| log.sha256.as_str()),
+            runtime_policy: policy.map(RuntimePolicy::sha256),
+            runtime_policy_failures,
+        })?;
+        let signature = crypto::asym_sign(priv_key, &bundle)?;
+
+        let seq = self.saved.fetch_add(1, Ordering::SeqCst);
+        let path = self.dir.join(format!(
+            "{}{:013}-{:06}.json",
+            BUNDLE_PREFIX,
+            now.as_millis(),
+            seq % 1_000_000
+        ));
+        persist::write(&path, 0o600, &bundle)?;
+        persist::write(&signature_path(&path), 0o600, &signature)?;
+
+        self.rotate()?;
+        Ok(path)
+    }
+
+    // Keeps a copy of the policy bundles refer to
+    fn save_policy(&self, policy: &RuntimePolicy) -> Result<()> {
+        let path = self
+            .dir
+            .join(format!("runtime-policy-{}.json", policy.sha256()));
+        if !path.exists() {
+            persist::write(&path, 0o600, policy.document())?;
+        }
+        Ok(())
+    }
+
+    // Keeps a copy of the event log bundles refer to
+    fn save_event_log(&self, event_log: &EventLog) -> Result<()> {
+        let path =
+            self.dir.join(format!("event-log-{}.bin", event_log.sha256));
+        if !path.exists() {
+            persist::write(&path, 0o600, &event_log.data)?;
+        }
+        Ok(())
+    }
+
+    // Removes the oldest bundles, which sort first by name
+    fn rotate(&self) -> Result<()> {
+        let mut bundles = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if name.starts_with(BUNDLE_PREFIX) && name.ends_with(".json") {
+                bundles.push(name);
+            }
+        }
+        if bundles.len() <= self.max_bundles {
+            return Ok(());
+        }
+
+        bundles.sort();
+        for name in &bundles[..bundles.len() - self.max_bundles] {
+            let path = self.dir.join(name);
+            debug!("Removing evidence bundle {}", path.display());
+            fs::remove_file(&path)?;
+            let _ = fs::remove_file(signature_path(&path));
+        }
+        Ok(())
+    }
+}
+
+fn signature_path(bundle: &Path) -> PathBuf {
+    let mut path = bundle.as_os_str().to_owned();
+    path.push(".sig");
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ima::MeasurementList;
+    use openssl::hash::MessageDigest;
+    use openssl::sign::Verifier;
+    use std::io::Write;
+
+    #[test]
+    fn evidence_bundles() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let evidence = Evidence::new(dir.path().join("evidence"), 2)
+            .unwrap() //#[allow_ci]
+            .with_event_log(Some(b"event log".to_vec()));
+        let (pub_key, priv_key) = crypto::rsa_generate_pair(2048).unwrap(); //#[allow_ci]
+
+        let mut file = tempfile::NamedTempFile::new().unwrap(); //#[allow_ci]
+        let mut ml = MeasurementList::new(Some(file.reopen().unwrap())); //#[allow_ci]
+        let quote = KeylimeIdQuote::default();
+        let policy = RuntimePolicy::parse(
+            br#"{"digests": {"/bin/ls": ["02"]}}"#.to_vec(),
+            1,
+        )
+        .unwrap(); //#[allow_ci]
+        let mut save = |entries: &[u8]| {
+            file.write_all(entries).unwrap(); //#[allow_ci]
+            let ml = ml.update(0).unwrap(); //#[allow_ci]
+            let path = evidence
+                .save(
+                    "uuid",
+                    &priv_key,
+                    "nonce",
+                    Some("0x1"),
+                    &quote,
+                    Some(ml),
+                    Some(&policy),
+                )
+                .unwrap(); //#[allow_ci]
+            let bundle = fs::read(&path).unwrap(); //#[allow_ci]
+            let signature = fs::read(signature_path(&path)).unwrap(); //#[allow_ci]
+            let mut verifier =
+                Verifier::new(MessageDigest::sha256(), &pub_key).unwrap(); //#[allow_ci]
+            assert!(verifier.verify_oneshot(&signature, &bundle).unwrap()); //#[allow_ci]
+            serde_json::from_slice::<serde_json::Value>(&bundle).unwrap() //#[allow_ci]
+        };
+
+        let first = save(b"10 a ima-ng sha1:00 boot_aggregate\n");
+        assert_eq!(first["ima_measurement_list_entry"], 0);
+        let second = save(b"10 b ima-ng sha1:01 /bin/sh\n");
+        assert_eq!(second["ima_measurement_list_entry"], 1);
+        assert_eq!(
+            second["ima_measurement_list"],
+            "10 b ima-ng sha1:01 /bin/sh\n"
+        );
+        assert_eq!(
+            second["runtime_policy_failures"],
+            serde_json::json!(["/bin/sh: not in policy"])
+        );
+        assert_eq!(second["runtime_policy"], policy.sha256());
+        let _ = save(b"");
+
+        // The event log is saved once, and the bundles refer to it
+        let digest = hex::encode(sha256(b"event log"));
+        assert_eq!(second["event_log"], digest);
+        let log = evidence.dir().join(format!("event-log-{}.bin", digest));
+        assert_eq!(fs::read(log).unwrap(), b"event log"); //#[allow_ci]
+
+        // Two bundles, each with its signature, the policy and the event log
+        assert_eq!(fs::read_dir(evidence.dir()).unwrap().count(), 6); //#[allow_ci]
+    }
+}
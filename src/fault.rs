@@ -0,0 +1,198 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+//! Fault injection, for resilience tests
+//!
+//! Built with the testing feature, TPM operations, file reads and registrar
+//! requests can be made to fail or to stall on demand, so that the retry,
+//! timeout and degradation paths are tested without breaking hardware. The
+//! faults are set in KEYLIME_FAULT_INJECTION, or with configure() in tests,
+//! as a comma separated list of `point=action`, where the point is `tpm`,
+//! `file` or `registrar`, and the action `fail`, or `stall:<milliseconds>`,
+//! optionally followed by `*<count>` to only apply to the next count
+//! operations:
+//!
+//! ```text
+//! KEYLIME_FAULT_INJECTION=tpm=stall:5000,registrar=fail*3
+//! ```
+//!
+//! The faults fail with the error the real failure would: a TPM failure
+//! response code, EIO, and a 503 from the registrar. Without the
+//! testing feature, neither this module nor the injection points are built.
+
+use crate::error::{Error, Result};
+
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+static FAULT_INJECTION: &str = "KEYLIME_FAULT_INJECTION";
+static POINTS: &[&str] = &["tpm", "file", "registrar"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Fail,
+    Stall(Duration),
+}
+
+impl Action {
+    fn stall(self) {
+        if let Action::Stall(duration) = self {
+            std::thread::sleep(duration);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Fault {
+    action: Action,
+    // Operations left to apply to, None for all
+    count: Option<u64>,
+}
+
+// The faults set, by injection point
+#[derive(Debug, Default)]
+struct Faults(HashMap<String, Fault>);
+
+impl Faults {
+    fn parse(spec: &str) -> Result<Self> {
+        let invalid =
+            |fault: &str| Error::Other(format!("invalid fault {:?}", fault));
+        let mut faults = HashMap::new();
+        for fault in spec.split(',').map(str::trim).filter(|f| !f.is_empty())
+        {
+            let (point, action) = match fault.find('=') {
+                Some(i) => (&fault[..i], &fault[i + 1..]),
+                None => return Err(invalid(fault)),
+            };
+            if !POINTS.contains(&point) {
+                return Err(invalid(fault));
+            }
+            let (action, count) = match action.find('*') {
+                Some(i) => (
+                    &action[..i],
+                    Some(
+                        action[i + 1..]
+                            .parse()
+                            .map_err(|_| invalid(fault))?,
+                    ),
+                ),
+                None => (action, None),
+            };
+            let action = match action {
+                "fail" => Action::Fail,
+                _ => match action.strip_prefix("stall:") {
+                    Some(ms) => Action::Stall(Duration::from_millis(
+                        ms.parse().map_err(|_| invalid(fault))?,
+                    )),
+                    None => return Err(invalid(fault)),
+                },
+            };
+            let _ = faults.insert(point.to_string(), Fault { action, count });
+        }
+        Ok(Faults(faults))
+    }
+
+    // Returns the action for an operation at a point, if any
+    fn inject(&mut self, point: &str) -> Option<Action> {
+        let fault = self.0.get_mut(point)?;
+        match &mut fault.count {
+            Some(0) => return None,
+            Some(count) => *count -= 1,
+            None => {}
+        }
+        debug!("Injecting {:?} at {}", fault.action, point);
+        Some(fault.action)
+    }
+}
+
+lazy_static! {
+    static ref FAULTS: Mutex<Faults> = {
+        let spec = std::env::var(FAULT_INJECTION).unwrap_or_default();
+        Mutex::new(Faults::parse(&spec).unwrap_or_else(|e| {
+            warn!("Ignoring {}: {}", FAULT_INJECTION, e);
+            Faults::default()
+        }))
+    };
+}
+
+fn inject(point: &str) -> Option<Action> {
+    FAULTS.lock().unwrap().inject(point) //#[allow_ci]
+}
+
+/// Replaces the faults, as set in KEYLIME_FAULT_INJECTION
+pub fn configure(spec: &str) -> Result<()> {
+    let faults = Faults::parse(spec)?;
+    *FAULTS.lock().unwrap() = faults; //#[allow_ci]
+    Ok(())
+}
+
+/// Injects the fault set for TPM operations
+pub fn tpm() -> Result<()> {
+    if let Some(action) = inject("tpm") {
+        action.stall();
+        if let Action::Fail = action {
+            // TPM_RC_FAILURE
+            return Err(tss_esapi::Error::Tss2Error(0x101.into()).into());
+        }
+    }
+    Ok(())
+}
+
+/// Injects the fault set for file reads
+pub fn file(path: &std::path::Path) -> Result<()> {
+    if let Some(action) = inject("file") {
+        action.stall();
+        if let Action::Fail = action {
+            return Err(Error::file(
+                path,
+                std::io::Error::from_raw_os_error(libc::EIO),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Injects the fault set for registrar requests, stalling without blocking
+/// the runtime
+pub async fn registrar(addr: &str) -> Result<()> {
+    if let Some(action) = inject("registrar") {
+        if let Action::Stall(duration) = action {
+            tokio::time::delay_for(duration).await;
+        } else {
+            return Err(Error::Registrar {
+                addr: addr.to_string(),
+                code: 503,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // On a table of its own, as the other tests go through the points
+    #[test]
+    fn fault_injection() {
+        assert!(Faults::parse("").unwrap().0.is_empty()); //#[allow_ci]
+        let faults = Faults::parse("tpm=stall:50, registrar=fail*2").unwrap(); //#[allow_ci]
+        assert_eq!(
+            faults.0["tpm"].action,
+            Action::Stall(Duration::from_millis(50))
+        );
+        assert_eq!(faults.0["registrar"].count, Some(2));
+        assert!(Faults::parse("disk=fail").is_err());
+        assert!(Faults::parse("tpm=explode").is_err());
+        assert!(Faults::parse("tpm=fail*x").is_err());
+
+        let mut faults = Faults::parse("registrar=fail*2").unwrap(); //#[allow_ci]
+        assert_eq!(faults.inject("registrar"), Some(Action::Fail));
+        assert_eq!(faults.inject("registrar"), Some(Action::Fail));
+        assert_eq!(faults.inject("registrar"), None);
+        assert_eq!(faults.inject("tpm"), None);
+    }
+}
@@ -0,0 +1,288 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+//! C API
+//!
+//! Quote generation and IMA measurement list reading, for node agents
+//! written in C or C++ to link instead of calling tpm2-tools themselves.
+//! The header, include/keylime.h, is generated with cbindgen:
+//!
+//! ```text
+//! $ cbindgen --config cbindgen.toml --output include/keylime.h
+//! ```
+//!
+//! Functions returning int return 0 on success and -1 on failure, in which
+//! case keylime_last_error() describes the failure. Strings returned are
+//! owned by the caller and freed with keylime_string_free().
+
+use crate::error::{Error, Result};
+use crate::ima::MeasurementList;
+use crate::tpm;
+
+use openssl::pkey::PKey;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use tss_esapi::handles::KeyHandle;
+use tss_esapi::interface_types::algorithm::HashingAlgorithm;
+use tss_esapi::Context;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// TPM context with the AK quotes are made with, and the IMA measurement
+/// list read so far
+#[derive(Debug)]
+pub struct KeylimeContext {
+    tpm: Context,
+    ak_handle: KeyHandle,
+    ak_public: Vec<u8>,
+    ima_ml: MeasurementList,
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message).unwrap_or_else(|_| {
+        CString::new("error message contains NUL").unwrap_or_default()
+    });
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+// Runs f, turning errors and panics into -1 and the last error
+fn call<F: FnOnce() -> Result<()>>(f: F) -> c_int {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) => {
+            set_last_error(e.to_string());
+            -1
+        }
+        Err(_) => {
+            set_last_error(String::from("panic in keylime"));
+            -1
+        }
+    }
+}
+
+// Safety: s is NULL or a valid NUL-terminated string
+unsafe fn str_arg<'a>(s: *const c_char, name: &str) -> Result<&'a str> {
+    if s.is_null() {
+        return Err(Error::Other(format!("{} is NULL", name)));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| Error::Other(format!("{} is not UTF-8", name)))
+}
+
+// Safety: out is NULL or valid for writes
+unsafe fn set_string(out: *mut *mut c_char, s: String) -> Result<()> {
+    if out.is_null() {
+        return Err(Error::Other(String::from("output is NULL")));
+    }
+    *out = CString::new(s)
+        .map_err(|e| Error::Other(e.to_string()))?
+        .into_raw();
+    Ok(())
+}
+
+fn context_new() -> Result<KeylimeContext> {
+    let mut tpm = tpm::get_tpm2_ctx()?;
+    let keys = tpm::load_keys(&mut tpm, tpm::AkSource::Create)?;
+    tpm.flush_context(keys.ek_handle.into())?;
+    let ak = keys.ak()?;
+    Ok(KeylimeContext {
+        tpm,
+        ak_handle: ak.handle,
+        ak_public: ak.tpm2b_pub.clone(),
+        ima_ml: MeasurementList::default(),
+    })
+}
+
+fn quote(
+    context: &mut KeylimeContext,
+    nonce: &str,
+    mask: Option<&str>,
+    nk_pem: &str,
+) -> Result<String> {
+    let nk = PKey::public_key_from_pem(nk_pem.as_bytes())?;
+    let nk_digest = tpm::pubkey_to_tpm_digest(&nk, HashingAlgorithm::Sha256)?;
+    tpm::quote(
+        &mut context.tpm,
+        nonce.as_bytes(),
+        mask,
+        HashingAlgorithm::Sha256,
+        nk_digest,
+        context.ak_handle,
+    )
+}
+
+/// Message of the last failure on this thread, NULL if none. Valid until
+/// the next call on this thread.
+#[no_mangle]
+pub extern "C" fn keylime_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr())
+    })
+}
+
+/// Frees a string returned by this library
+///
+/// # Safety
+///
+/// s is NULL or a string returned by this library, not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn keylime_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// Opens the TPM and creates the EK and an AK, NULL on failure
+#[no_mangle]
+pub extern "C" fn keylime_context_new() -> *mut KeylimeContext {
+    let mut context = ptr::null_mut();
+    let _ = call(|| {
+        context = Box::into_raw(Box::new(context_new()?));
+        Ok(())
+    });
+    context
+}
+
+/// Flushes the AK and closes the TPM
+///
+/// # Safety
+///
+/// context is NULL or returned by keylime_context_new(), not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn keylime_context_free(context: *mut KeylimeContext) {
+    if !context.is_null() {
+        let mut context = Box::from_raw(context);
+        let _ = context.tpm.flush_context(context.ak_handle.into());
+    }
+}
+
+/// The public area of the AK, as a marshaled TPM2B_PUBLIC to register, in
+/// buf and len. Valid as long as the context is.
+///
+/// # Safety
+///
+/// context is valid, buf and len are valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn keylime_ak_public(
+    context: *const KeylimeContext,
+    buf: *mut *const u8,
+    len: *mut usize,
+) -> c_int {
+    call(|| {
+        if context.is_null() || buf.is_null() || len.is_null() {
+            return Err(Error::Other(String::from("argument is NULL")));
+        }
+        *buf = (*context).ak_public.as_ptr();
+        *len = (*context).ak_public.len();
+        Ok(())
+    })
+}
+
+/// Identity quote for the nonce, with PCR 16 extended with the digest of
+/// the NK, in PEM. The quote is returned in quote, in the format of the
+/// agent API: 'r' + quote + signature + PCR blob.
+///
+/// # Safety
+///
+/// context is valid, nonce and nk_pem are NUL-terminated strings and quote
+/// is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn keylime_identity_quote(
+    context: *mut KeylimeContext,
+    nonce: *const c_char,
+    nk_pem: *const c_char,
+    quote: *mut *mut c_char,
+) -> c_int {
+    call(|| {
+        let context = context
+            .as_mut()
+            .ok_or_else(|| Error::Other(String::from("context is NULL")))?;
+        let nonce = str_arg(nonce, "nonce")?;
+        let nk_pem = str_arg(nk_pem, "nk_pem")?;
+        set_string(quote, self::quote(context, nonce, None, nk_pem)?)
+    })
+}
+
+/// Integrity quote for the nonce and PCR mask, ex. "0x408000", and the IMA
+/// entries from ima_ml_entry on, or the whole list if it has fewer
+/// entries. The quote is returned in quote, the entries in ima_ml and the
+/// index of the first of them in ima_ml_first.
+///
+/// # Safety
+///
+/// context is valid, nonce, mask and nk_pem are NUL-terminated strings,
+/// and quote, ima_ml and ima_ml_first are valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn keylime_integrity_quote(
+    context: *mut KeylimeContext,
+    nonce: *const c_char,
+    mask: *const c_char,
+    nk_pem: *const c_char,
+    ima_ml_entry: usize,
+    quote: *mut *mut c_char,
+    ima_ml: *mut *mut c_char,
+    ima_ml_first: *mut usize,
+) -> c_int {
+    call(|| {
+        let context = context
+            .as_mut()
+            .ok_or_else(|| Error::Other(String::from("context is NULL")))?;
+        let nonce = str_arg(nonce, "nonce")?;
+        let mask = str_arg(mask, "mask")?;
+        let nk_pem = str_arg(nk_pem, "nk_pem")?;
+        if ima_ml_first.is_null() {
+            return Err(Error::Other(String::from("ima_ml_first is NULL")));
+        }
+
+        let q = self::quote(context, nonce, Some(mask), nk_pem)?;
+        let (first, entries) =
+            context.ima_ml.update(0)?.starting_at(ima_ml_entry);
+        set_string(
+            ima_ml,
+            entries.into_chunks().collect::<Vec<_>>().concat(),
+        )?;
+        if let Err(e) = set_string(quote, q) {
+            keylime_string_free(*ima_ml);
+            return Err(e);
+        }
+        *ima_ml_first = first;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ffi_errors() {
+        let mut quote = ptr::null_mut();
+        let nonce = CString::new("1234").unwrap(); //#[allow_ci]
+        let rc = unsafe {
+            keylime_identity_quote(
+                ptr::null_mut(),
+                nonce.as_ptr(),
+                nonce.as_ptr(),
+                &mut quote,
+            )
+        };
+        assert_eq!(rc, -1);
+        assert!(quote.is_null());
+        let error = unsafe { CStr::from_ptr(keylime_last_error()) };
+        assert_eq!(error.to_str().unwrap(), "context is NULL"); //#[allow_ci]
+
+        let mut s = ptr::null_mut();
+        unsafe {
+            set_string(&mut s, String::from("quote")).unwrap(); //#[allow_ci]
+            assert_eq!(CStr::from_ptr(s).to_str().unwrap(), "quote"); //#[allow_ci]
+            keylime_string_free(s);
+            keylime_string_free(ptr::null_mut());
+        }
+    }
+}
@@ -0,0 +1,225 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Wire compatibility with the Python agent
+//
+// Each file in test-data/golden is a request captured against the Python
+// agent along with its response. The request is replayed against the
+// handler code, and the response compared with the captured one: same
+// status, and byte for byte the same body once the whitespace that Python's
+// json.dumps puts after separators is dropped. Values that change between
+// runs, such as quotes and public keys, are "<any>" in the captured body.
+//
+// Point KEYLIME_GOLDEN_DIR to another directory to check a new capture:
+//
+//   $ KEYLIME_GOLDEN_DIR=/tmp/capture cargo test golden
+
+use crate::ima::MeasurementList;
+use crate::keys_handler::verify_response;
+use crate::quotes_handler::{
+    identity_response, integrity_body, KeylimeIdQuote, KeylimeIntegrityQuote,
+};
+
+use actix_web::body::{Body, ResponseBody};
+use actix_web::HttpResponse;
+use futures::StreamExt;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+static PLACEHOLDER: &str = "\"<any>\"";
+
+// What the TPM would have returned, for the placeholders to match
+static QUOTE: &str = "rAQIDBA==:AQIDBA==:AQIDBA==";
+static PUBKEY: &str =
+    "-----BEGIN PUBLIC KEY-----\n-----END PUBLIC KEY-----\n";
+
+#[derive(Deserialize)]
+struct Request {
+    path: String,
+    #[serde(default)]
+    query: HashMap<String, String>,
+    // Agent state when the request was captured
+    key: Option<String>,
+    ima_measurement_list: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    status: u16,
+    body: String,
+}
+
+#[derive(Deserialize)]
+struct Case {
+    request: Request,
+    response: Response,
+}
+
+fn golden_dir() -> PathBuf {
+    std::env::var_os("KEYLIME_GOLDEN_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test-data/golden")
+        })
+}
+
+fn body(response: &HttpResponse) -> Vec<u8> {
+    match response.body() {
+        ResponseBody::Body(Body::Bytes(bytes))
+        | ResponseBody::Other(Body::Bytes(bytes)) => bytes.to_vec(),
+        _ => panic!("response body is not buffered"), //#[allow_ci]
+    }
+}
+
+// Replays the request, returning the status and body of the response
+fn replay(request: &Request) -> (u16, Vec<u8>) {
+    match request.path.as_str() {
+        "/keys/verify" => {
+            let key = request.key.as_ref().map(|k| hex::decode(k).unwrap()); //#[allow_ci]
+            let response =
+                verify_response(key.as_deref(), &request.query["challenge"]);
+            (response.status().as_u16(), body(&response))
+        }
+        "/quotes/identity" => {
+            let quote = KeylimeIdQuote {
+                quote: QUOTE.to_string(),
+                pubkey: PUBKEY,
+                ..Default::default()
+            };
+            let response = identity_response(quote);
+            (response.status().as_u16(), body(&response))
+        }
+        "/quotes/integrity" => {
+            let mut file = tempfile::NamedTempFile::new().unwrap(); //#[allow_ci]
+            let ml = request.ima_measurement_list.as_deref().unwrap_or("");
+            file.write_all(ml.as_bytes()).unwrap(); //#[allow_ci]
+            let mut ml = MeasurementList::new(Some(file.reopen().unwrap())); //#[allow_ci]
+
+            let id_quote = KeylimeIdQuote {
+                quote: QUOTE.to_string(),
+                pubkey: PUBKEY,
+                ..Default::default()
+            };
+            let quote = KeylimeIntegrityQuote::from_id_quote(&id_quote, "");
+            let stream =
+                integrity_body(quote, ml.update(0).unwrap()).unwrap(); //#[allow_ci]
+            let body = futures::executor::block_on(
+                stream.map(|chunk| chunk.unwrap()).collect::<Vec<_>>(), //#[allow_ci]
+            );
+            (200, body.concat())
+        }
+        path => panic!("no replay for {}", path), //#[allow_ci]
+    }
+}
+
+// Drops the whitespace outside of strings
+fn compact(json: &str) -> String {
+    let mut out = String::with_capacity(json.len());
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in json.chars() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+        } else if c == '"' {
+            in_string = true;
+        } else if c.is_whitespace() {
+            continue;
+        }
+        out.push(c);
+    }
+    out
+}
+
+// Length of the JSON string at the start of s, quotes included
+fn string_len(s: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (i, c) in s.char_indices().skip(1) {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            return Some(i + 1);
+        }
+    }
+    None
+}
+
+// Whether actual is expected, with any string where expected has "<any>"
+fn matches(expected: &str, actual: &str) -> bool {
+    let mut parts = expected.split(PLACEHOLDER);
+    let mut rest = match parts.next() {
+        Some(first) if actual.starts_with(first) => &actual[first.len()..],
+        _ => return false,
+    };
+    for part in parts {
+        if !rest.starts_with('"') {
+            return false;
+        }
+        rest = match string_len(rest) {
+            Some(len) if rest[len..].starts_with(part) => {
+                &rest[len + part.len()..]
+            }
+            _ => return false,
+        };
+    }
+    rest.is_empty()
+}
+
+#[test]
+fn golden_compact() {
+    assert_eq!(
+        compact("{\"a\": \"b c\", \"d\": [1, \"\\\" e\"]}"),
+        "{\"a\":\"b c\",\"d\":[1,\"\\\" e\"]}"
+    );
+}
+
+#[test]
+fn golden_matches() {
+    assert!(matches(
+        "{\"a\":\"<any>\",\"b\":1}",
+        "{\"a\":\"x\\\"y\",\"b\":1}"
+    ));
+    assert!(!matches(
+        "{\"a\":\"<any>\",\"b\":1}",
+        "{\"a\":\"x\",\"b\":2}"
+    ));
+    assert!(!matches("{\"a\":\"<any>\"}", "{\"a\":1}"));
+}
+
+#[test]
+fn golden_responses() {
+    let mut cases = 0;
+    let entries = fs::read_dir(golden_dir()).unwrap(); //#[allow_ci]
+    for entry in entries {
+        let path = entry.unwrap().path(); //#[allow_ci]
+        if path.extension() != Some(OsStr::new("json")) {
+            continue;
+        }
+        let case: Case =
+            serde_json::from_slice(&fs::read(&path).unwrap()).unwrap(); //#[allow_ci]
+
+        let (status, body) = replay(&case.request);
+        let body = String::from_utf8(body).unwrap(); //#[allow_ci]
+        assert_eq!(status, case.response.status, "{}", path.display());
+        assert!(
+            matches(&compact(&case.response.body), &body),
+            "{}: expected {}, got {}",
+            path.display(),
+            case.response.body,
+            body
+        );
+        cases += 1;
+    }
+    assert!(cases > 0);
+}
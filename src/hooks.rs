@@ -0,0 +1,204 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Notification hooks
+//
+// Other services on the host may need to know when the agent reaches a
+// given point, e.g. that the payload has been provisioned. For each event
+// keylime.conf can name a command to execute and a URL to POST a JSON
+// notification to, as <prefix>_hook_command and <prefix>_hook_url in the
+// [cloud_agent] section. With dbus_service, a D-Bus signal named after the
+// event is emitted as well, see dbus.rs. Hooks are best effort: failures
+// are logged but do not affect the agent.
+
+use crate::common::config_get_or;
+use crate::dbus;
+use crate::error::{Error, Result};
+use crate::http;
+
+use serde::Serialize;
+use tokio::process::Command;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Event {
+    /// The payload has been decrypted and its script executed successfully
+    PayloadProvisioned,
+    /// No verifier requested an integrity quote for idle_attestation_timeout
+    AttestationIdle,
+    /// TPM errors reached failure_alert_threshold
+    TpmErrors,
+    /// Registrar errors reached failure_alert_threshold
+    RegistrarErrors,
+}
+
+impl Event {
+    /// Name of the event passed to the hooks
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Event::PayloadProvisioned => "payload_provisioned",
+            Event::AttestationIdle => "attestation_idle",
+            Event::TpmErrors => "tpm_errors",
+            Event::RegistrarErrors => "registrar_errors",
+        }
+    }
+
+    /// Name of the D-Bus signal of the event
+    pub(crate) fn signal_name(&self) -> &'static str {
+        match self {
+            Event::PayloadProvisioned => "PayloadProvisioned",
+            Event::AttestationIdle => "AttestationIdle",
+            Event::TpmErrors => "TpmErrors",
+            Event::RegistrarErrors => "RegistrarErrors",
+        }
+    }
+
+    // Prefix of the keylime.conf options configuring the event's hooks
+    fn config_prefix(&self) -> &'static str {
+        match self {
+            Event::PayloadProvisioned => "provisioned",
+            Event::AttestationIdle => "idle",
+            Event::TpmErrors | Event::RegistrarErrors => "failure",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Notification<'a> {
+    event: &'a str,
+    agent_uuid: &'a str,
+}
+
+// Runs the command through the shell, passing the event in the environment
+async fn run_command(
+    command: &str,
+    event: Event,
+    agent_uuid: &str,
+) -> Result<()> {
+    let output = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(command)
+        .env("KEYLIME_EVENT", event.name())
+        .env("KEYLIME_AGENT_UUID", agent_uuid)
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        return Err(Error::Script(
+            command.to_string(),
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ));
+    }
+
+    Ok(())
+}
+
+async fn post_webhook(
+    url: &str,
+    event: Event,
+    agent_uuid: &str,
+) -> Result<()> {
+    let notification = Notification {
+        event: event.name(),
+        agent_uuid,
+    };
+    let resp = http::client().post(url).json(&notification).send().await?;
+
+    if !resp.status().is_success() {
+        return Err(Error::Hook {
+            url: url.to_string(),
+            code: resp.status().as_u16(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Fires the hooks configured for the event
+pub(crate) async fn notify(event: Event, agent_uuid: &str) {
+    let prefix = event.config_prefix();
+    dbus::emit(event);
+
+    match config_get_or(
+        "cloud_agent",
+        &format!("{}_hook_command", prefix),
+        "",
+    ) {
+        Ok(command) if !command.is_empty() => {
+            match run_command(&command, event, agent_uuid).await {
+                Ok(()) => info!("Ran {} hook: {}", event.name(), command),
+                Err(e) => warn!("{} hook failed: {}", event.name(), e),
+            }
+        }
+        Ok(_) => {}
+        Err(e) => warn!("Unable to read {} hook: {}", event.name(), e),
+    }
+
+    match config_get_or("cloud_agent", &format!("{}_hook_url", prefix), "") {
+        Ok(url) if !url.is_empty() => {
+            match post_webhook(&url, event, agent_uuid).await {
+                Ok(()) => info!("Notified {} of {}", url, event.name()),
+                Err(e) => warn!("{} hook failed: {}", event.name(), e),
+            }
+        }
+        Ok(_) => {}
+        Err(e) => warn!("Unable to read {} hook: {}", event.name(), e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{body_json, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn hook_command() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let out = dir.path().join("event");
+        let command = format!(
+            "echo $KEYLIME_EVENT $KEYLIME_AGENT_UUID > {}",
+            out.display()
+        );
+
+        run_command(&command, Event::PayloadProvisioned, "uuid")
+            .await
+            .unwrap(); //#[allow_ci]
+        assert_eq!(
+            std::fs::read_to_string(&out).unwrap(), //#[allow_ci]
+            "payload_provisioned uuid\n"
+        );
+
+        assert!(run_command("exit 1", Event::PayloadProvisioned, "uuid")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn hook_webhook() {
+        let mock_server = MockServer::start().await;
+        let mock = Mock::given(method("POST"))
+            .and(body_json(serde_json::json!({
+                "event": "payload_provisioned",
+                "agent_uuid": "uuid",
+            })))
+            .respond_with(ResponseTemplate::new(200));
+        mock_server.register(mock).await;
+
+        assert!(post_webhook(
+            &mock_server.uri(),
+            Event::PayloadProvisioned,
+            "uuid"
+        )
+        .await
+        .is_ok());
+        assert!(post_webhook(
+            &format!("{}/missing", mock_server.uri()),
+            Event::PayloadProvisioned,
+            "other"
+        )
+        .await
+        .is_err());
+    }
+}
@@ -0,0 +1,58 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Outbound HTTP client
+//
+// All the requests the agent sends, to the registrar, webhooks and the
+// telemetry endpoint, go through a single client, so that connections are
+// kept alive and reused instead of being set up again, TLS handshake
+// included, for every request. Timeouts are set per request.
+
+use lazy_static::lazy_static;
+use std::time::Duration;
+use tracing::warn;
+
+// How long unused connections stay in the pool
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+
+fn builder() -> reqwest::ClientBuilder {
+    reqwest::Client::builder()
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .tcp_keepalive(TCP_KEEPALIVE)
+}
+
+lazy_static! {
+    static ref CLIENT: reqwest::Client =
+        builder().build().unwrap_or_else(|e| {
+            warn!("Unable to configure the HTTP client: {}", e);
+            reqwest::Client::new()
+        });
+}
+
+/// The shared client. Cloning it is cheap, clones share the connections.
+pub fn client() -> &'static reqwest::Client {
+    &CLIENT
+}
+
+/// A client trusting a CA of its own, e.g. that of the verifier, in
+/// addition to the system's
+pub fn client_with_ca(
+    ca_cert: reqwest::Certificate,
+) -> reqwest::Result<reqwest::Client> {
+    builder().add_root_certificate(ca_cert).build()
+}
+
+/// A client for a TLS port requiring client certificates, e.g. that of the
+/// registrar: it trusts the given CA, if any, in addition to the system's,
+/// and presents the given identity
+pub fn client_with_identity(
+    ca_cert: Option<reqwest::Certificate>,
+    identity: reqwest::Identity,
+) -> reqwest::Result<reqwest::Client> {
+    let mut builder = builder().identity(identity);
+    if let Some(ca_cert) = ca_cert {
+        builder = builder.add_root_certificate(ca_cert);
+    }
+    builder.build()
+}
@@ -0,0 +1,112 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Idle attestation monitor
+//
+// A verifier attesting the agent requests an integrity quote every few
+// seconds. An agent that no verifier polls any more, e.g. because it was
+// dropped from the verifier's list, otherwise keeps running silently. With
+// idle_attestation_timeout set in keylime.conf, the agent logs a warning
+// and fires the attestation_idle hooks when no integrity quote was
+// requested for that many minutes, once until the next request.
+
+use crate::common::config_get_or;
+use crate::error::{Error, Result};
+use crate::hooks;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+// How often the monitor checks, at most
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+// Time of the last integrity quote request, in seconds since the epoch, or
+// 0 before the first one
+static LAST_REQUEST: AtomicU64 = AtomicU64::new(0);
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0)
+}
+
+/// Records that a verifier requested an integrity quote
+pub(crate) fn quote_requested() {
+    LAST_REQUEST.store(now(), Ordering::Relaxed);
+}
+
+/*
+ * Return: Result wrap the idle timeout, if configured
+ *
+ * idle_attestation_timeout is in minutes, 0 disabling the monitor.
+ */
+pub(crate) fn idle_timeout_get() -> Result<Option<Duration>> {
+    let minutes =
+        config_get_or("cloud_agent", "idle_attestation_timeout", "0")?
+            .trim()
+            .parse::<u64>()
+            .map_err(|e| {
+                Error::config_value(
+                    "cloud_agent",
+                    "idle_attestation_timeout",
+                    e,
+                )
+            })?;
+    Ok(match minutes {
+        0 => None,
+        minutes => Some(Duration::from_secs(minutes * 60)),
+    })
+}
+
+// Seconds the agent has been idle for, counting from its start until the
+// first request
+fn idle_for(last_request: u64, started: u64, now: u64) -> u64 {
+    now.saturating_sub(last_request.max(started))
+}
+
+/// Fires the idle hooks whenever no integrity quote was requested for the
+/// timeout, until the agent stops
+pub(crate) async fn run_monitor(timeout: Duration, agent_uuid: String) {
+    let started = now();
+    // Last request already reported as idle
+    let mut reported = None;
+
+    loop {
+        tokio::time::delay_for(CHECK_INTERVAL.min(timeout)).await;
+
+        let last_request = LAST_REQUEST.load(Ordering::Relaxed);
+        let idle = idle_for(last_request, started, now());
+        if idle < timeout.as_secs() || reported == Some(last_request) {
+            continue;
+        }
+
+        reported = Some(last_request);
+        if last_request == 0 {
+            warn!(
+                "No verifier requested an integrity quote since the agent started {} minutes ago",
+                idle / 60
+            );
+        } else {
+            warn!(
+                "No verifier requested an integrity quote for {} minutes",
+                idle / 60
+            );
+        }
+        hooks::notify(hooks::Event::AttestationIdle, &agent_uuid).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idle_time() {
+        // No request yet
+        assert_eq!(idle_for(0, 1000, 1600), 600);
+        assert_eq!(idle_for(1500, 1000, 1600), 100);
+        assert_eq!(idle_for(1700, 1000, 1600), 0);
+    }
+}
@@ -0,0 +1,353 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// IMA measurement list cache
+//
+// The measurement list only ever grows, yet every integrity quote returns
+// it whole, so reading and decoding it again for each poll of the verifier
+// dominates the cost of a quote once it holds millions of entries. The
+// entries read so far are kept in memory instead, together with the offset
+// in the file they end at, and each quote only reads the entries appended
+// since the previous one. The kernel still walks its list up to that offset
+// for every read, but no longer formats the entries before it: the file is
+// kept open and read on from where the previous quote stopped, as seeking in
+// securityfs would format them all again.
+//
+// The entries are kept in chunks of about CHUNK_SIZE bytes, shared with the
+// responses streaming them, so that a response neither copies the list nor
+// holds the cache while it is sent. On devices short of memory, the list is
+// read whole for every quote instead, see low_memory().
+
+use crate::error::{Error, Result};
+#[cfg(feature = "testing")]
+use crate::fault;
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::Arc;
+use tracing::debug;
+
+/// Path of the measurement list
+pub static IMA_ML: &str =
+    "/sys/kernel/security/ima/ascii_runtime_measurements";
+
+const CHUNK_SIZE: usize = 64 * 1024;
+const LOW_MEMORY_CHUNK_SIZE: usize = 8 * 1024;
+
+/// The measurement list, with the entries read so far
+#[derive(Debug)]
+pub struct MeasurementList {
+    // Kept open, as the list may no longer be readable once the agent
+    // dropped privileges
+    file: Option<File>,
+    // Entries read so far, and the offset in the file after the last one
+    entries: usize,
+    offset: u64,
+    chunks: Vec<Arc<str>>,
+    chunk_size: usize,
+    // Whether the entries are kept for the next update
+    cached: bool,
+}
+
+impl Default for MeasurementList {
+    fn default() -> Self {
+        MeasurementList {
+            file: None,
+            entries: 0,
+            offset: 0,
+            chunks: Vec::new(),
+            chunk_size: CHUNK_SIZE,
+            cached: true,
+        }
+    }
+}
+
+/// The measurement list as read by an update, in chunks of whole entries
+#[derive(Debug, Clone, Default)]
+pub struct Entries {
+    chunks: Vec<Arc<str>>,
+}
+
+impl Entries {
+    /// Size of the list in bytes
+    pub fn len(&self) -> usize {
+        self.chunks.iter().map(|chunk| chunk.len()).sum()
+    }
+
+    /// Whether the list has no entries
+    pub fn is_empty(&self) -> bool {
+        self.chunks.iter().all(|chunk| chunk.is_empty())
+    }
+
+    /// Number of entries
+    pub fn count(&self) -> usize {
+        self.chunks
+            .iter()
+            .map(|chunk| chunk.matches('\n').count())
+            .sum()
+    }
+
+    /// The chunks the entries are kept in
+    pub fn into_chunks(self) -> impl Iterator<Item = Arc<str>> {
+        self.chunks.into_iter()
+    }
+
+    /**
+     * Input: index of the first entry to return
+     * Return: index of the first entry returned, and the entries
+     *
+     * Returns the whole list if it has fewer entries than asked for, as
+     * happens after a reboot.
+     */
+    pub fn starting_at(self, nth: usize) -> (usize, Entries) {
+        if nth == 0 || nth > self.count() {
+            return (0, self);
+        }
+
+        let mut skip = nth;
+        let mut chunks = Vec::new();
+        for chunk in self.chunks {
+            if skip == 0 {
+                chunks.push(chunk);
+                continue;
+            }
+            let entries = chunk.matches('\n').count();
+            if entries <= skip {
+                skip -= entries;
+                continue;
+            }
+            let start = chunk
+                .match_indices('\n')
+                .nth(skip - 1)
+                .map_or(0, |(end, _)| end + 1);
+            chunks.push(Arc::from(&chunk[start..]));
+            skip = 0;
+        }
+        (nth, Entries { chunks })
+    }
+}
+
+impl MeasurementList {
+    /// Reads the list from the given file, opened by the caller
+    pub fn new(file: Option<File>) -> Self {
+        MeasurementList {
+            file,
+            ..Default::default()
+        }
+    }
+
+    /// Reads the whole list for every update, without keeping it in memory
+    /// in between, in smaller chunks
+    pub fn low_memory(self) -> Self {
+        MeasurementList {
+            chunk_size: LOW_MEMORY_CHUNK_SIZE,
+            cached: false,
+            ..self
+        }
+    }
+
+    /// Whether the list could be opened
+    pub fn is_open(&self) -> bool {
+        self.file.is_some()
+    }
+
+    /**
+     * Input: maximum size of the list in bytes, 0 for no limit
+     * Return: Result wrap the whole measurement list
+     *
+     * Reads the entries appended since the last call. An entry still being
+     * written is left for the next call.
+     */
+    pub fn update(&mut self, max_size: u64) -> Result<Entries> {
+        if !self.cached {
+            self.entries = 0;
+            self.offset = 0;
+        }
+        #[cfg(feature = "testing")]
+        fault::file(std::path::Path::new(IMA_ML))?;
+        let size = self.offset as usize;
+        let budget = match max_size {
+            0 => None,
+            max_size => Some(max_size.saturating_sub(size as u64)),
+        };
+        let mut new = match &mut self.file {
+            Some(file) => read_from(file, self.offset, budget)?,
+            None => read_from(&mut File::open(IMA_ML)?, self.offset, budget)?,
+        };
+        if max_size != 0 && (size + new.len()) as u64 > max_size {
+            return Err(Error::Overloaded {
+                limit: "max_ima_ml_size",
+                max: max_size,
+            });
+        }
+
+        let complete = new
+            .iter()
+            .rposition(|byte| *byte == b'\n')
+            .map_or(0, |end| end + 1);
+        new.truncate(complete);
+        let new = String::from_utf8(new)?;
+
+        let added = new.lines().count();
+        if added > 0 {
+            debug!(
+                "Read {} new IMA entries, {} in total",
+                added,
+                self.entries + added
+            );
+        }
+        self.entries += added;
+        self.offset += complete as u64;
+        self.append(&new);
+        let chunks = if self.cached {
+            self.chunks.clone()
+        } else {
+            std::mem::take(&mut self.chunks)
+        };
+        Ok(Entries { chunks })
+    }
+
+    // Splits the new entries into chunks, the last chunk being completed
+    // first if it is short
+    fn append(&mut self, mut new: &str) {
+        if new.is_empty() {
+            return;
+        }
+        let mut pending = String::new();
+        if let Some(last) = self.chunks.last() {
+            if last.len() < self.chunk_size {
+                pending.push_str(last);
+                let _ = self.chunks.pop();
+            }
+        }
+
+        while !new.is_empty() {
+            let room = self.chunk_size.saturating_sub(pending.len());
+            // Whole entries, at least one
+            let end = if new.len() <= room {
+                new.len()
+            } else {
+                let bytes = new.as_bytes();
+                match bytes[..room].iter().rposition(|byte| *byte == b'\n') {
+                    Some(end) => end + 1,
+                    None if pending.is_empty() => bytes
+                        .iter()
+                        .position(|byte| *byte == b'\n')
+                        .map_or(new.len(), |end| end + 1),
+                    None => 0,
+                }
+            };
+            pending.push_str(&new[..end]);
+            new = &new[end..];
+            if !new.is_empty() {
+                self.chunks.push(Arc::from(pending.as_str()));
+                pending.clear();
+            }
+        }
+        self.chunks.push(Arc::from(pending));
+    }
+}
+
+// Reads from the offset to the end, or budget + 1 bytes at most. The file
+// is only repositioned if the previous read did not stop at the offset,
+// e.g. in the middle of an entry.
+fn read_from(
+    file: &mut File,
+    offset: u64,
+    budget: Option<u64>,
+) -> Result<Vec<u8>> {
+    if file.stream_position()? != offset {
+        let _ = file.seek(SeekFrom::Start(offset))?;
+    }
+    let mut data = Vec::new();
+    let _ = match budget {
+        Some(budget) => file.take(budget + 1).read_to_end(&mut data)?,
+        None => file.read_to_end(&mut data)?,
+    };
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn lines(entries: &Entries) -> Vec<String> {
+        entries
+            .clone()
+            .into_chunks()
+            .flat_map(|chunk| {
+                chunk
+                    .split_inclusive('\n')
+                    .map(String::from)
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn chunks() {
+        let entry = "10 a ima-ng sha1:00 /usr/lib64/libc.so.6\n";
+        let mut ml = MeasurementList::default();
+        let count = 3 * CHUNK_SIZE / entry.len();
+        ml.append(&entry.repeat(count));
+        ml.append(entry);
+
+        let entries = Entries {
+            chunks: ml.chunks.clone(),
+        };
+        assert_eq!(entries.len(), (count + 1) * entry.len());
+        assert_eq!(ml.chunks.len(), 4);
+        for chunk in entries.clone().into_chunks() {
+            assert!(chunk.len() <= CHUNK_SIZE);
+            assert!(chunk.ends_with('\n'));
+        }
+        assert_eq!(lines(&entries).len(), count + 1);
+
+        // Entries from the middle of the second chunk on
+        let per_chunk = lines(&Entries {
+            chunks: vec![ml.chunks[0].clone()],
+        })
+        .len();
+        let (nth, from) = entries.clone().starting_at(per_chunk + 2);
+        assert_eq!(nth, per_chunk + 2);
+        assert_eq!(lines(&from).len(), count + 1 - nth);
+        assert_eq!(entries.clone().starting_at(count + 1).1.len(), 0);
+        assert_eq!(entries.clone().starting_at(count + 2).0, 0);
+    }
+
+    #[test]
+    fn appended_entries() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join("ascii_runtime_measurements");
+        let mut writer = File::create(&path).unwrap(); //#[allow_ci]
+        writer
+            .write_all(b"10 a ima-ng sha1:00 boot_aggregate\n")
+            .unwrap(); //#[allow_ci]
+
+        let mut ml = MeasurementList::new(Some(File::open(&path).unwrap())); //#[allow_ci]
+        assert_eq!(lines(&ml.update(0).unwrap()).len(), 1); //#[allow_ci]
+
+        // The entry being written is only returned once complete
+        writer
+            .write_all(b"10 b ima-ng sha1:01 /bin/sh\n10 c")
+            .unwrap(); //#[allow_ci]
+        assert_eq!(lines(&ml.update(0).unwrap()).len(), 2); //#[allow_ci]
+        writer.write_all(b" ima-ng sha1:02 /bin/ls\n").unwrap(); //#[allow_ci]
+        let text = lines(&ml.update(0).unwrap()).concat(); //#[allow_ci]
+        assert!(
+            text.ends_with("sha1:01 /bin/sh\n10 c ima-ng sha1:02 /bin/ls\n")
+        );
+        assert_eq!(ml.entries, 3);
+
+        assert!(matches!(ml.update(50), Err(Error::Overloaded { .. })));
+        assert!(ml.update(4096).is_ok());
+
+        // Without the cache, every update returns the whole list
+        let file = File::open(&path).unwrap(); //#[allow_ci]
+        let mut ml = MeasurementList::new(Some(file)).low_memory();
+        assert_eq!(lines(&ml.update(0).unwrap()).len(), 3); //#[allow_ci]
+        assert_eq!(lines(&ml.update(0).unwrap()).len(), 3); //#[allow_ci]
+        assert!(ml.chunks.is_empty());
+    }
+}
@@ -0,0 +1,200 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// IMA emulator
+//
+// keylime_agent ima-emulator is the counterpart of keylime_ima_emulator of
+// the Python agent, for development on machines without IMA: it extends
+// PCR 10 of a software TPM with the template hashes of a measurement list
+// in the ascii_runtime_measurements format, so that the PCR matches the
+// list, and the agent can be run with the same list as its IMA_ML, e.g. in
+// a container, to exercise the whole integrity quote path.
+//
+// The template hashes of the list are SHA-1. They are extended as they are
+// into the SHA-1 bank, and padded with zeros into the SHA-256 bank, as the
+// kernel does when it has no SHA-256 template hash. Entries whose template
+// hash is zero, measurement violations, are extended as all ones, as the
+// kernel does.
+//
+// When PCR 10 already holds part of the list, from an earlier run, only
+// the rest of the list is extended. With --follow, entries appended to the
+// list are extended as they come.
+//
+// Only software TPMs are accepted, as the PCRs of a hardware TPM are only
+// reset by a reboot.
+
+use crate::error::{Error, Result};
+use crate::ima::MeasurementList;
+use crate::tpm;
+
+use openssl::sha::sha1;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::path::Path;
+use std::time::Duration;
+use tss_esapi::handles::PcrHandle;
+use tss_esapi::interface_types::algorithm::HashingAlgorithm;
+use tss_esapi::structures::{Digest, DigestValues, PcrSlot};
+
+const SHA1_SIZE: usize = 20;
+const SHA256_SIZE: usize = 32;
+const FOLLOW_INTERVAL: Duration = Duration::from_secs(1);
+
+// Returns the template hash of an entry
+fn template_hash(entry: &str) -> Result<[u8; SHA1_SIZE]> {
+    let field = entry.split_whitespace().nth(1).unwrap_or("");
+    let mut hash = [0u8; SHA1_SIZE];
+    match hex::decode(field) {
+        Ok(bytes) if bytes.len() == SHA1_SIZE => {
+            hash.copy_from_slice(&bytes);
+            Ok(hash)
+        }
+        _ => Err(Error::Other(format!(
+            "invalid IMA entry, without a SHA-1 template hash: {}",
+            entry
+        ))),
+    }
+}
+
+// The digest extended into a bank of the given size for a template hash
+fn extended_digest(hash: &[u8; SHA1_SIZE], size: usize) -> Vec<u8> {
+    if hash.iter().all(|byte| *byte == 0) {
+        return vec![0xff; size];
+    }
+    let mut digest = hash.to_vec();
+    digest.resize(size, 0);
+    digest
+}
+
+/*
+ * Input: template hashes of the list, SHA-1 value of PCR 10
+ * Return: number of entries PCR 10 already holds, None if it does not
+ *         match the list
+ */
+fn replayed(hashes: &[[u8; SHA1_SIZE]], pcr: &[u8]) -> Option<usize> {
+    let mut value = [0u8; SHA1_SIZE];
+    if pcr == value {
+        return Some(0);
+    }
+    for (i, hash) in hashes.iter().enumerate() {
+        let mut data = value.to_vec();
+        data.extend(extended_digest(hash, SHA1_SIZE));
+        value = sha1(&data);
+        if pcr == value {
+            return Some(i + 1);
+        }
+    }
+    None
+}
+
+fn extend(
+    ctx: &mut tss_esapi::Context,
+    hash: &[u8; SHA1_SIZE],
+) -> Result<()> {
+    let mut digests = DigestValues::new();
+    digests.set(
+        HashingAlgorithm::Sha1,
+        Digest::try_from(extended_digest(hash, SHA1_SIZE))?,
+    );
+    digests.set(
+        HashingAlgorithm::Sha256,
+        Digest::try_from(extended_digest(hash, SHA256_SIZE))?,
+    );
+    tpm::extend_pcr(ctx, PcrHandle::Pcr10, digests)
+}
+
+/*
+ * Input: measurement list, whether to keep extending new entries
+ * Return: Result wrap with error message, with follow only on failure
+ */
+pub(crate) fn run(path: &Path, follow: bool) -> Result<()> {
+    let mut ctx = tpm::get_tpm2_ctx()?;
+    if !tss_esapi::utils::get_tpm_vendor(&mut ctx)?.contains("SW") {
+        return Err(Error::Other(String::from(
+            "the IMA emulator only runs with a software TPM",
+        )));
+    }
+
+    let mut ml = MeasurementList::new(Some(File::open(path)?));
+    let entries = ml.update(0)?;
+    let hashes = entries
+        .into_chunks()
+        .collect::<Vec<_>>()
+        .concat()
+        .lines()
+        .map(template_hash)
+        .collect::<Result<Vec<_>>>()?;
+
+    let pcr =
+        tpm::read_pcr(&mut ctx, HashingAlgorithm::Sha1, PcrSlot::Slot10)?;
+    let mut done = replayed(&hashes, &pcr).ok_or_else(|| {
+        Error::Other(String::from(
+            "PCR 10 does not match the measurement list, reset the software TPM",
+        ))
+    })?;
+    if done > 0 {
+        println!("PCR 10 already holds {} entries", done);
+    }
+    for hash in &hashes[done..] {
+        extend(&mut ctx, hash)?;
+    }
+    println!(
+        "Extended PCR 10 with {} entries of {}",
+        hashes.len() - done,
+        path.display()
+    );
+    if !follow {
+        return Ok(());
+    }
+
+    done = hashes.len();
+    loop {
+        std::thread::sleep(FOLLOW_INTERVAL);
+        let (first, new) = ml.update(0)?.starting_at(done);
+        if first != done {
+            // The list is shorter than what was extended
+            return Err(Error::Other(format!(
+                "{} was truncated, reset the software TPM",
+                path.display()
+            )));
+        }
+        for entry in new.into_chunks().collect::<Vec<_>>().concat().lines() {
+            extend(&mut ctx, &template_hash(entry)?)?;
+            done += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static ENTRIES: &str = "10 0adefe762c149c7cec19da62f0da1297fcfbffff ima-ng sha256:f112 /usr/lib64/libcrypto.so\n\
+                            10 0000000000000000000000000000000000000000 ima-ng sha256:0000 /usr/bin/violation\n";
+
+    #[test]
+    fn ima_emulator_replay() {
+        let hashes = ENTRIES
+            .lines()
+            .map(template_hash)
+            .collect::<Result<Vec<_>>>()
+            .unwrap(); //#[allow_ci]
+        assert_eq!(hashes[0][0], 0x0a);
+        assert_eq!(extended_digest(&hashes[1], SHA1_SIZE), vec![0xff; 20]);
+        let padded = extended_digest(&hashes[0], SHA256_SIZE);
+        assert_eq!(&padded[..SHA1_SIZE], &hashes[0][..]);
+        assert_eq!(&padded[SHA1_SIZE..], &[0u8; 12][..]);
+
+        let mut data = vec![0u8; SHA1_SIZE];
+        data.extend(&hashes[0]);
+        let first = sha1(&data);
+        assert_eq!(replayed(&hashes, &[0u8; SHA1_SIZE]), Some(0));
+        assert_eq!(replayed(&hashes, &first), Some(1));
+        let mut data = first.to_vec();
+        data.extend(&[0xffu8; SHA1_SIZE]);
+        assert_eq!(replayed(&hashes, &sha1(&data)), Some(2));
+        assert_eq!(replayed(&hashes, &[1u8; SHA1_SIZE]), None);
+
+        assert!(template_hash("10 abcd ima-ng sha1:00 /bin/sh").is_err());
+    }
+}
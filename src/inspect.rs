@@ -0,0 +1,188 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Agent data inspection
+//
+// keylime_agent agent-data prints what the agent keeps in its agent_data
+// file, see agent_data.rs, to diagnose agents whose identity changed after
+// a reboot: the fingerprint of the TPM owner hierarchy, and whether it is
+// the one of this TPM, the generated UUID, and the PCRs the bootstrap key
+// is sealed to, and whether it unseals with their current values, and
+// whether the AK was stored, which show-ak prints.
+//
+// With --repair, what the agent would otherwise do on its next start, or
+// fail to, is done right away, and nothing that still belongs to this TPM
+// is discarded: an interrupted write is cleaned up, a file that cannot be
+// parsed is set aside, the data of a cleared TPM is discarded, and the
+// bootstrap key is sealed again when the PCRs of the configuration changed
+// and the key still unseals.
+//
+// The agent must not be running.
+
+use crate::agent_data::{self, AgentData};
+use crate::common::tpm_ownerpassword_get;
+use crate::error::{Error, Result};
+use crate::{persist, tpm};
+
+use std::fmt::Write;
+use std::fs;
+use std::io::ErrorKind;
+
+// Formats the agent data, with the fingerprint of this TPM
+fn describe(agent_data: &AgentData, fingerprint: &str) -> String {
+    let mut out = String::new();
+    let _ = match &agent_data.tpm_fingerprint {
+        Some(stored) if agent_data.tpm_changed(fingerprint) => writeln!(
+            out,
+            "TPM owner fingerprint: {}, of another TPM or the TPM was \
+             cleared, this TPM has {}",
+            stored, fingerprint
+        ),
+        Some(stored) => {
+            writeln!(out, "TPM owner fingerprint: {}, this TPM", stored)
+        }
+        None => writeln!(out, "TPM owner fingerprint: none"),
+    };
+    let _ = writeln!(
+        out,
+        "UUID: {}",
+        agent_data.uuid.as_deref().unwrap_or("none generated")
+    );
+    let _ = match &agent_data.payload {
+        Some(sealed) => writeln!(
+            out,
+            "Bootstrap key: sealed to PCRs {} of {}, {}",
+            sealed.key.pcr_mask,
+            sealed.key.hash_alg,
+            match &sealed.payload {
+                Some(payload) =>
+                    format!("with a {} byte payload", payload.len()),
+                None => String::from("without payload"),
+            }
+        ),
+        None => writeln!(out, "Bootstrap key: none"),
+    };
+    let _ = writeln!(
+        out,
+        "AK: {}",
+        if agent_data.ak.is_some() {
+            "stored"
+        } else {
+            "none, created on the first start"
+        }
+    );
+    out
+}
+
+/*
+ * Input: whether to repair the agent data
+ * Return: Result wrap with error message
+ */
+pub(crate) fn run(repair: bool) -> Result<()> {
+    let path = agent_data::agent_data_path();
+    println!("Agent data: {}", path.display());
+    if repair && persist::recover(&path)? {
+        println!("Removed an interrupted write");
+    }
+
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            println!("None, the agent starts afresh");
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let agent_data: AgentData = match serde_json::from_slice(&data) {
+        Ok(agent_data) => agent_data,
+        Err(e) if repair => {
+            persist::set_aside(&path)?;
+            println!("Set aside, unable to parse it: {}", e);
+            return Ok(());
+        }
+        Err(e) => {
+            return Err(Error::Other(format!(
+                "unable to parse {}, set it aside with --repair: {}",
+                path.display(),
+                e
+            )))
+        }
+    };
+
+    let mut ctx = tpm::get_tpm2_ctx()?;
+    tpm::set_owner_auth(&mut ctx, &tpm_ownerpassword_get()?)?;
+    let fingerprint = tpm::owner_fingerprint(&mut ctx)?;
+    print!("{}", describe(&agent_data, &fingerprint));
+    let unsealed = match &agent_data.payload {
+        Some(sealed) if !agent_data.tpm_changed(&fingerprint) => {
+            match sealed.key.unseal(&mut ctx) {
+                Ok(_) => {
+                    println!("Bootstrap key unseals with the current PCRs");
+                    true
+                }
+                Err(e) => {
+                    println!("Bootstrap key does not unseal: {}", e);
+                    false
+                }
+            }
+        }
+        _ => false,
+    };
+
+    if !repair {
+        return Ok(());
+    }
+    if agent_data::reset_if_tpm_cleared(&mut ctx)? {
+        println!("Discarded the agent data of the cleared TPM");
+    } else if agent_data.tpm_fingerprint.is_none() {
+        println!("Recorded the fingerprint of this TPM");
+    }
+    if unsealed && agent_data::reseal_payload(&mut ctx)? {
+        println!("Sealed the bootstrap key again to the configured PCRs");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent_data::{PersistedAk, SealedData, SealedPayload};
+
+    #[test]
+    fn inspect_describe() {
+        assert_eq!(
+            describe(&AgentData::default(), "000b1234"),
+            "TPM owner fingerprint: none\n\
+             UUID: none generated\n\
+             Bootstrap key: none\n\
+             AK: none, created on the first start\n"
+        );
+
+        let agent_data = AgentData {
+            tpm_fingerprint: Some(String::from("000b1234")),
+            uuid: Some(String::from("d432fbb3-d2f1-4a97-9ef7-75bd81c00000")),
+            payload: Some(SealedPayload {
+                key: SealedData {
+                    pcr_mask: String::from("0xff"),
+                    hash_alg: String::from("sha256"),
+                    public: String::new(),
+                    private: String::new(),
+                },
+                payload: None,
+            }),
+            ak: Some(PersistedAk {
+                public: String::new(),
+                private: String::new(),
+            }),
+        };
+        assert_eq!(
+            describe(&agent_data, "000b1234"),
+            "TPM owner fingerprint: 000b1234, this TPM\n\
+             UUID: d432fbb3-d2f1-4a97-9ef7-75bd81c00000\n\
+             Bootstrap key: sealed to PCRs 0xff of sha256, without payload\n\
+             AK: stored\n"
+        );
+        assert!(describe(&agent_data, "000b5678")
+            .starts_with("TPM owner fingerprint: 000b1234, of another TPM"));
+    }
+}
@@ -0,0 +1,486 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Bootstrap key delivery
+//
+// The key K used to decrypt the tenant's payload is split in two halves:
+// U, delivered by the tenant together with an auth tag and the encrypted
+// payload, and V, delivered by the verifier once the integrity quote has
+// been validated. K = U xor V, and the auth tag is HMAC(K, agent_uuid).
+//
+// Tenant and verifier deliver their halves independently, so they may
+// arrive in any order, more than once, or with stale values from an
+// earlier attempt. KeyDelivery keeps the candidate halves until a pair
+// whose combination matches an auth tag is found, and drops halves that
+// have been waiting longer than the configured timeout. Each delivery
+// tries every pair, so at most MAX_KEY_HALVES of each are kept, the
+// oldest being dropped first.
+//
+// Once the payload is provisioned, a half different from the one that was
+// used starts a new delivery cycle, so that the tenant can deliver a new
+// payload without restarting the agent. The previous key stays available
+// until a new one is derived.
+
+use crate::crypto;
+use crate::error::{Error, Result};
+
+use std::time::{Duration, Instant};
+use tracing::{debug, info, info_span, warn};
+
+/// Default time to wait for the missing half after receiving the first one
+pub(crate) const DEFAULT_KEY_DELIVERY_TIMEOUT: Duration =
+    Duration::from_secs(300);
+
+/// Maximum number of U and of V halves waiting for their counterpart
+pub(crate) const MAX_KEY_HALVES: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DeliveryState {
+    /// No key half received yet
+    WaitingForKeys,
+    /// V received, waiting for the tenant's U
+    WaitingForU,
+    /// U received, waiting for the verifier's V
+    WaitingForV,
+    /// Both halves received, but no pair combined into a key matching an
+    /// auth tag yet
+    Combining,
+    /// K derived and verified against the auth tag; the payload is being
+    /// provisioned
+    Verified,
+    /// The payload has been provisioned
+    Provisioned,
+}
+
+impl std::fmt::Display for DeliveryState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = match self {
+            DeliveryState::WaitingForKeys => "waiting for keys",
+            DeliveryState::WaitingForU => "waiting for U",
+            DeliveryState::WaitingForV => "waiting for V",
+            DeliveryState::Combining => "combining",
+            DeliveryState::Verified => "verified",
+            DeliveryState::Provisioned => "provisioned",
+        };
+        write!(f, "{}", state)
+    }
+}
+
+/// U key half as delivered by the tenant
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct UKey {
+    pub key: Vec<u8>,
+    pub auth_tag: String,
+    pub payload: Option<String>,
+}
+
+/// Key derived from a matching U and V pair
+#[derive(Debug, Clone)]
+pub(crate) struct DerivedKey {
+    pub key: Vec<u8>,
+    pub payload: Option<String>,
+    /// Whether the key replaces one whose payload was already provisioned
+    pub replaces: bool,
+}
+
+/// Outcome of delivering a key half
+#[derive(Debug)]
+pub(crate) enum Delivery {
+    /// The half was stored, the counterpart is still missing or no pair
+    /// matched yet
+    Pending,
+    /// The exact same half was already delivered; nothing changed
+    Duplicate,
+    /// The half completed a pair: the key is verified and the payload must
+    /// now be provisioned
+    Derived(DerivedKey),
+}
+
+#[derive(Debug)]
+pub(crate) struct KeyDelivery {
+    state: DeliveryState,
+    u_keys: Vec<UKey>,
+    v_keys: Vec<Vec<u8>>,
+    first_half_at: Option<Instant>,
+    timeout: Duration,
+    final_u: Option<UKey>,
+    final_v: Option<Vec<u8>>,
+    key: Option<Vec<u8>>,
+}
+
+impl Default for KeyDelivery {
+    fn default() -> Self {
+        KeyDelivery::new(DEFAULT_KEY_DELIVERY_TIMEOUT)
+    }
+}
+
+fn xor(u: &[u8], v: &[u8]) -> Option<Vec<u8>> {
+    if u.len() != v.len() {
+        return None;
+    }
+    Some(u.iter().zip(v).map(|(a, b)| a ^ b).collect())
+}
+
+impl KeyDelivery {
+    pub(crate) fn new(timeout: Duration) -> Self {
+        KeyDelivery {
+            state: DeliveryState::WaitingForKeys,
+            u_keys: Vec::new(),
+            v_keys: Vec::new(),
+            first_half_at: None,
+            timeout,
+            final_u: None,
+            final_v: None,
+            key: None,
+        }
+    }
+
+    pub(crate) fn state(&self) -> DeliveryState {
+        self.state
+    }
+
+    /// Returns the derived key K once it has been verified
+    pub(crate) fn key(&self) -> Option<&[u8]> {
+        self.key.as_deref()
+    }
+
+    /// Adds a U half delivered by the tenant
+    pub(crate) fn add_u(
+        &mut self,
+        u: UKey,
+        agent_uuid: &str,
+    ) -> Result<Delivery> {
+        if let Some(final_u) = &self.final_u {
+            if final_u.key == u.key && final_u.auth_tag == u.auth_tag {
+                return Ok(Delivery::Duplicate);
+            } else if self.state != DeliveryState::Provisioned {
+                return Err(Error::KeyDelivery {
+                    half: "U",
+                    state: self.state.to_string(),
+                });
+            }
+            self.new_cycle();
+        }
+
+        self.expire();
+        if self.u_keys.contains(&u) {
+            debug!("Ignoring duplicate U key");
+            return Ok(Delivery::Duplicate);
+        }
+        if self.u_keys.len() >= MAX_KEY_HALVES {
+            warn!("Too many U keys, dropping the oldest one");
+            let _ = self.u_keys.remove(0);
+        }
+        self.u_keys.push(u);
+        let _ = self.first_half_at.get_or_insert_with(Instant::now);
+
+        self.attempt_combination(agent_uuid)
+    }
+
+    /// Adds a V half delivered by the verifier
+    pub(crate) fn add_v(
+        &mut self,
+        v: Vec<u8>,
+        agent_uuid: &str,
+    ) -> Result<Delivery> {
+        if let Some(final_v) = &self.final_v {
+            if *final_v == v {
+                return Ok(Delivery::Duplicate);
+            } else if self.state != DeliveryState::Provisioned {
+                return Err(Error::KeyDelivery {
+                    half: "V",
+                    state: self.state.to_string(),
+                });
+            }
+            self.new_cycle();
+        }
+
+        self.expire();
+        if self.v_keys.contains(&v) {
+            debug!("Ignoring duplicate V key");
+            return Ok(Delivery::Duplicate);
+        }
+        if self.v_keys.len() >= MAX_KEY_HALVES {
+            warn!("Too many V keys, dropping the oldest one");
+            let _ = self.v_keys.remove(0);
+        }
+        self.v_keys.push(v);
+        let _ = self.first_half_at.get_or_insert_with(Instant::now);
+
+        self.attempt_combination(agent_uuid)
+    }
+
+    /// Installs a key recovered from a previous boot whose payload has
+    /// already been provisioned again
+    ///
+    /// The tenant and verifier may still deliver a new pair of halves, which
+    /// then replaces this key.
+    pub(crate) fn restore(&mut self, key: Vec<u8>) {
+        self.key = Some(key);
+        self.state = DeliveryState::Provisioned;
+        info!("Bootstrap key delivery {} from previous boot", self.state);
+    }
+
+    /// Records the result of provisioning the payload for the derived key
+    ///
+    /// On failure the key is forgotten and the halves must be delivered
+    /// again.
+    pub(crate) fn finish(&mut self, provisioned: bool) {
+        if self.state != DeliveryState::Verified {
+            return;
+        }
+
+        if provisioned {
+            self.state = DeliveryState::Provisioned;
+        } else {
+            *self = KeyDelivery::new(self.timeout);
+        }
+        info!("Bootstrap key delivery {}", self.state);
+    }
+
+    // Forgets the halves of the provisioned key so that a new pair can be
+    // delivered. The key itself is kept until it is replaced.
+    fn new_cycle(&mut self) {
+        self.final_u = None;
+        self.final_v = None;
+        self.state = DeliveryState::WaitingForKeys;
+        info!("Starting a new bootstrap key delivery");
+    }
+
+    // Drops all stored halves if the first one has been waiting for longer
+    // than the timeout
+    fn expire(&mut self) {
+        if let Some(first) = self.first_half_at {
+            if first.elapsed() > self.timeout {
+                warn!(
+                    "Discarding {} U and {} V keys received more than {:?} ago",
+                    self.u_keys.len(),
+                    self.v_keys.len(),
+                    self.timeout
+                );
+                self.u_keys.clear();
+                self.v_keys.clear();
+                self.first_half_at = None;
+                self.state = DeliveryState::WaitingForKeys;
+            }
+        }
+    }
+
+    // Tries all U and V pairs in the order they were received, the first
+    // one whose combination matches the U's auth tag wins
+    fn attempt_combination(&mut self, agent_uuid: &str) -> Result<Delivery> {
+        self.state = match (self.u_keys.is_empty(), self.v_keys.is_empty()) {
+            (true, true) => DeliveryState::WaitingForKeys,
+            (true, false) => DeliveryState::WaitingForU,
+            (false, true) => DeliveryState::WaitingForV,
+            (false, false) => DeliveryState::Combining,
+        };
+        if self.state != DeliveryState::Combining {
+            info!("Bootstrap key delivery {}", self.state);
+            return Ok(Delivery::Pending);
+        }
+
+        let _span = info_span!(
+            "combine_keys",
+            u_keys = self.u_keys.len(),
+            v_keys = self.v_keys.len()
+        )
+        .entered();
+        for u in &self.u_keys {
+            for v in &self.v_keys {
+                let key = match xor(&u.key, v) {
+                    Some(key) => key,
+                    None => continue,
+                };
+                if crypto::verify_hmac(
+                    &key,
+                    agent_uuid.as_bytes(),
+                    &u.auth_tag,
+                )? {
+                    let derived = DerivedKey {
+                        key: key.clone(),
+                        payload: u.payload.clone(),
+                        replaces: self.key.is_some(),
+                    };
+                    self.final_u = Some(u.clone());
+                    self.final_v = Some(v.clone());
+                    self.key = Some(key);
+                    self.u_keys.clear();
+                    self.v_keys.clear();
+                    self.first_half_at = None;
+                    self.state = DeliveryState::Verified;
+                    info!("Bootstrap key derived and verified");
+                    return Ok(Delivery::Derived(derived));
+                }
+            }
+        }
+
+        warn!(
+            "No combination of {} U and {} V keys matches an auth tag",
+            self.u_keys.len(),
+            self.v_keys.len()
+        );
+        Ok(Delivery::Pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UUID: &str = "d432fbb3-d2f1-4a97-9ef7-75bd81c00000";
+
+    // Returns a U key and the matching V for the key K = [0x5a; 32]
+    fn key_pair(seed: u8) -> (UKey, Vec<u8>) {
+        let k = vec![0x5au8; 32];
+        let u = vec![seed; 32];
+        let v = xor(&k, &u).unwrap(); //#[allow_ci]
+        let auth_tag =
+            hex::encode(crypto::compute_hmac(&k, UUID.as_bytes()).unwrap()); //#[allow_ci]
+        let u = UKey {
+            key: u,
+            auth_tag,
+            payload: None,
+        };
+        (u, v)
+    }
+
+    #[test]
+    fn u_then_v() {
+        let (u, v) = key_pair(1);
+        let mut delivery = KeyDelivery::default();
+        assert_eq!(delivery.state(), DeliveryState::WaitingForKeys);
+
+        assert!(matches!(delivery.add_u(u, UUID), Ok(Delivery::Pending)));
+        assert_eq!(delivery.state(), DeliveryState::WaitingForV);
+
+        assert!(matches!(delivery.add_v(v, UUID), Ok(Delivery::Derived(_))));
+        assert_eq!(delivery.state(), DeliveryState::Verified);
+        assert_eq!(delivery.key(), Some(&[0x5au8; 32][..]));
+
+        delivery.finish(true);
+        assert_eq!(delivery.state(), DeliveryState::Provisioned);
+    }
+
+    #[test]
+    fn v_then_u_with_stale_halves() {
+        let (stale_u, stale_v) = key_pair(2);
+        let (u, v) = key_pair(3);
+        let mut delivery = KeyDelivery::default();
+
+        assert!(matches!(delivery.add_v(v, UUID), Ok(Delivery::Pending)));
+        assert_eq!(delivery.state(), DeliveryState::WaitingForU);
+
+        // A U whose auth tag is for another agent never combines
+        let mut foreign_u = stale_u;
+        foreign_u.auth_tag = String::from("00");
+        assert!(matches!(
+            delivery.add_u(foreign_u, UUID),
+            Ok(Delivery::Pending)
+        ));
+        assert_eq!(delivery.state(), DeliveryState::Combining);
+
+        assert!(matches!(delivery.add_u(u, UUID), Ok(Delivery::Derived(_))));
+        assert_eq!(delivery.state(), DeliveryState::Verified);
+
+        // Once derived, other halves are rejected
+        assert!(delivery.add_v(stale_v, UUID).is_err());
+    }
+
+    #[test]
+    fn duplicates() {
+        let (u, v) = key_pair(4);
+        let mut delivery = KeyDelivery::default();
+
+        assert!(matches!(
+            delivery.add_u(u.clone(), UUID),
+            Ok(Delivery::Pending)
+        ));
+        assert!(matches!(
+            delivery.add_u(u.clone(), UUID),
+            Ok(Delivery::Duplicate)
+        ));
+        assert!(matches!(
+            delivery.add_v(v.clone(), UUID),
+            Ok(Delivery::Derived(_))
+        ));
+        assert!(matches!(delivery.add_v(v, UUID), Ok(Delivery::Duplicate)));
+        assert!(matches!(delivery.add_u(u, UUID), Ok(Delivery::Duplicate)));
+    }
+
+    #[test]
+    fn timeout() {
+        let (u, v) = key_pair(5);
+        let mut delivery = KeyDelivery::new(Duration::from_secs(0));
+
+        assert!(matches!(delivery.add_u(u, UUID), Ok(Delivery::Pending)));
+        std::thread::sleep(Duration::from_millis(10));
+
+        // The U key expired, so V alone is pending
+        assert!(matches!(delivery.add_v(v, UUID), Ok(Delivery::Pending)));
+        assert_eq!(delivery.state(), DeliveryState::WaitingForU);
+    }
+
+    #[test]
+    fn halves_are_capped() {
+        let (u, v) = key_pair(9);
+        let mut delivery = KeyDelivery::default();
+
+        assert!(matches!(delivery.add_u(u, UUID), Ok(Delivery::Pending)));
+        for seed in 0..MAX_KEY_HALVES as u8 {
+            let (mut stale_u, _) = key_pair(10 + seed);
+            stale_u.auth_tag = String::from("00");
+            let _ = delivery.add_u(stale_u, UUID);
+        }
+        assert_eq!(delivery.u_keys.len(), MAX_KEY_HALVES);
+
+        // The matching U was the oldest one and has been dropped
+        assert!(matches!(delivery.add_v(v, UUID), Ok(Delivery::Pending)));
+        assert_eq!(delivery.state(), DeliveryState::Combining);
+
+        for seed in 0..MAX_KEY_HALVES as u8 + 2 {
+            let _ = delivery.add_v(vec![seed; 32], UUID);
+        }
+        assert_eq!(delivery.v_keys.len(), MAX_KEY_HALVES);
+    }
+
+    #[test]
+    fn new_cycle_after_provisioning() {
+        let (u, v) = key_pair(7);
+        let (new_u, new_v) = key_pair(8);
+        let mut delivery = KeyDelivery::default();
+
+        let _ = delivery.add_u(u.clone(), UUID);
+        match delivery.add_v(v.clone(), UUID) {
+            Ok(Delivery::Derived(derived)) => assert!(!derived.replaces),
+            other => panic!("unexpected delivery {:?}", other), //#[allow_ci]
+        }
+
+        // While the payload is being provisioned other halves are rejected
+        assert!(delivery.add_u(new_u.clone(), UUID).is_err());
+        delivery.finish(true);
+
+        // The same halves are still duplicates, new ones start a new cycle
+        assert!(matches!(delivery.add_u(u, UUID), Ok(Delivery::Duplicate)));
+        assert!(matches!(delivery.add_u(new_u, UUID), Ok(Delivery::Pending)));
+        assert_eq!(delivery.state(), DeliveryState::WaitingForV);
+        assert!(delivery.key().is_some());
+        match delivery.add_v(new_v, UUID) {
+            Ok(Delivery::Derived(derived)) => assert!(derived.replaces),
+            other => panic!("unexpected delivery {:?}", other), //#[allow_ci]
+        }
+        assert_eq!(delivery.state(), DeliveryState::Verified);
+    }
+
+    #[test]
+    fn failed_provisioning_resets() {
+        let (u, v) = key_pair(6);
+        let mut delivery = KeyDelivery::default();
+
+        let _ = delivery.add_u(u, UUID);
+        let _ = delivery.add_v(v, UUID);
+        delivery.finish(false);
+        assert_eq!(delivery.state(), DeliveryState::WaitingForKeys);
+        assert!(delivery.key().is_none());
+    }
+}
@@ -0,0 +1,153 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Kernel keyring storage
+//
+// An agent running without CAP_SYS_ADMIN cannot mount the secure tmpfs.
+// Small secrets such as the derived key K are then kept in the kernel user
+// keyring of the agent's user instead, where they never touch the disk and
+// are only readable by that user. See keyrings(7).
+
+use crate::error::{Error, Result};
+
+use std::ffi::CString;
+use std::fs;
+use tracing::{info, warn};
+
+// From linux/keyctl.h
+const KEY_SPEC_USER_KEYRING: libc::c_long = -4;
+const KEYCTL_SEARCH: libc::c_long = 10;
+const KEYCTL_INVALIDATE: libc::c_long = 21;
+
+// From linux/capability.h
+const CAP_SYS_ADMIN: u32 = 21;
+
+static KEY_TYPE: &str = "user";
+
+/// Prefix of the description of the keys added by the agent
+pub(crate) static KEY_PREFIX: &str = "keylime:";
+
+fn to_cstring(value: &str) -> Result<CString> {
+    CString::new(value)
+        .map_err(|_| Error::Other(format!("{:?} contains a NUL byte", value)))
+}
+
+fn check(
+    ret: libc::c_long,
+    op: &str,
+    description: &str,
+) -> Result<libc::c_long> {
+    if ret < 0 {
+        return Err(Error::Other(format!(
+            "unable to {} key {}: {}",
+            op,
+            description,
+            std::io::Error::last_os_error()
+        )));
+    }
+    Ok(ret)
+}
+
+/*
+ * Input: key description and data
+ * Return: Result wrap the key serial number
+ *
+ * Adds a key to the user keyring, replacing the key with the same
+ * description if there is one.
+ */
+pub(crate) fn add_key(
+    description: &str,
+    data: &[u8],
+) -> Result<libc::c_long> {
+    let key_type = to_cstring(KEY_TYPE)?;
+    let desc = to_cstring(&format!("{}{}", KEY_PREFIX, description))?;
+
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_add_key,
+            key_type.as_ptr(),
+            desc.as_ptr(),
+            data.as_ptr(),
+            data.len(),
+            KEY_SPEC_USER_KEYRING,
+        )
+    };
+    let serial = check(ret, "add", description)?;
+    info!("Stored {} in the user keyring", description);
+    Ok(serial)
+}
+
+fn search_key(description: &str) -> Result<libc::c_long> {
+    let key_type = to_cstring(KEY_TYPE)?;
+    let desc = to_cstring(&format!("{}{}", KEY_PREFIX, description))?;
+
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_keyctl,
+            KEYCTL_SEARCH,
+            KEY_SPEC_USER_KEYRING,
+            key_type.as_ptr(),
+            desc.as_ptr(),
+            0,
+        )
+    };
+    check(ret, "find", description)
+}
+
+/// Removes a key added with add_key, if it exists
+pub(crate) fn remove_key(description: &str) -> Result<()> {
+    let serial = match search_key(description) {
+        Ok(serial) => serial,
+        Err(_) => return Ok(()),
+    };
+
+    let ret =
+        unsafe { libc::syscall(libc::SYS_keyctl, KEYCTL_INVALIDATE, serial) };
+    let _ = check(ret, "invalidate", description)?;
+    info!("Removed {} from the user keyring", description);
+    Ok(())
+}
+
+// Returns the effective capabilities from the contents of
+// /proc/self/status
+fn parse_cap_eff(status: &str) -> Option<u64> {
+    status
+        .lines()
+        .find(|line| line.starts_with("CapEff:"))
+        .and_then(|line| {
+            u64::from_str_radix(line.trim_start_matches("CapEff:").trim(), 16)
+                .ok()
+        })
+}
+
+/// Whether the agent has CAP_SYS_ADMIN, which is needed to mount the
+/// secure directory
+pub(crate) fn has_cap_sys_admin() -> bool {
+    match fs::read_to_string("/proc/self/status")
+        .ok()
+        .as_deref()
+        .and_then(parse_cap_eff)
+    {
+        Some(caps) => caps & (1 << CAP_SYS_ADMIN) != 0,
+        None => {
+            warn!("Unable to read the agent's capabilities");
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cap_eff() {
+        let status = "Name:\tkeylime_agent\nCapInh:\t0000000000000000\nCapEff:\t000001ffffffffff\nCapBnd:\t000001ffffffffff\n";
+        let caps = parse_cap_eff(status).unwrap(); //#[allow_ci]
+        assert_ne!(caps & (1 << CAP_SYS_ADMIN), 0);
+
+        let status = "CapEff:\t0000000000000000\n";
+        assert_eq!(parse_cap_eff(status), Some(0));
+        assert_eq!(parse_cap_eff("Name:\tkeylime_agent\n"), None);
+    }
+}
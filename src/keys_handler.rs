@@ -1,27 +1,474 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright 2021 Keylime Authors
 
+use crate::common::config_get_bool_or;
+use crate::key_delivery::{Delivery, DerivedKey, UKey};
+use crate::{
+    crypto, hooks, payloads, policy, systemd, telemetry,
+    Error as KeylimeError, QuoteData, Result,
+};
+
 use actix_web::{web, HttpResponse, Responder};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tracing::{error, info, info_span, warn, Instrument};
 
 #[derive(Deserialize)]
 pub struct Verify {
     challenge: String,
 }
 
+// The fields of these structs must match what is sent by Python Keylime's
+// tenant (U key) and cloud verifier (V key).
+#[derive(Deserialize)]
+pub struct KeylimeUKey {
+    encrypted_key: String,
+    auth_tag: String,
+    payload: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct KeylimeVKey {
+    encrypted_key: String,
+}
+
+// The runtime policy, base64 encoded, its version, and
+// HMAC(K, "<version>:" + policy)
 #[derive(Deserialize)]
-pub struct UkeyJson {
-    b64_encrypted_key: String, // this will be handled as a blog once wired in
+pub struct KeylimeRuntimePolicy {
+    runtime_policy: String,
+    version: u64,
     auth_tag: String,
 }
 
-pub async fn verify(param: web::Query<Verify>) -> impl Responder {
-    HttpResponse::Ok().body(format!("Challenge: {}", param.challenge))
+#[derive(Serialize)]
+struct KeylimeHmac {
+    hmac: String,
 }
 
-pub async fn ukey(param: web::Json<UkeyJson>) -> impl Responder {
-    HttpResponse::Ok().body(format!(
-        "b64_encrypted_key: {} auth_tag: {}",
-        param.b64_encrypted_key, param.auth_tag
-    ))
+// The fields of this struct and their default values must
+// match what is expected by Python Keylime.
+#[derive(Serialize)]
+struct JsonWrapper<T> {
+    code: u16,
+    status: String,
+    results: T,
+}
+
+impl<T> JsonWrapper<T> {
+    fn success(results: T) -> Self {
+        JsonWrapper {
+            code: 200,
+            status: String::from("Success"),
+            results,
+        }
+    }
+}
+
+impl JsonWrapper<Value> {
+    fn error(code: u16, status: String) -> Self {
+        JsonWrapper {
+            code,
+            status,
+            results: json!({}),
+        }
+    }
+}
+
+// Sheds a key delivery beyond max_concurrent_payloads
+fn overloaded(e: KeylimeError) -> HttpResponse {
+    warn!("{}", e);
+    HttpResponse::ServiceUnavailable()
+        .json(JsonWrapper::error(503, e.to_string()))
+}
+
+// Decodes and decrypts a key half with the agent's NK private key
+fn decrypt_key(encrypted_key: &str, data: &QuoteData) -> Result<Vec<u8>> {
+    let encrypted_key = base64::decode(encrypted_key)?;
+    crypto::rsa_oaep_decrypt(&data.priv_key, &encrypted_key)
+}
+
+// Decrypts the payload with the derived key and installs both in the
+// secure directory, replacing a previously provisioned payload. If
+// re-provisioning is enabled, the key is also sealed to the TPM and stored
+// with the encrypted payload for the next boot.
+async fn provision(derived: &DerivedKey, data: &QuoteData) -> Result<()> {
+    provision_in_span(derived, data)
+        .instrument(
+            info_span!("provision_payload", agent_uuid = %data.agent_uuid),
+        )
+        .await
+}
+
+async fn provision_in_span(
+    derived: &DerivedKey,
+    data: &QuoteData,
+) -> Result<()> {
+    // Writing the payload files, and running the cleanup script of the
+    // previous payload, is left to the blocking thread pool, not to hold up
+    // the other requests
+    let secure_dir = data.secure_dir.clone();
+    let replaces = derived.replaces;
+    let key = derived.key.clone();
+    let payload = derived.payload.clone();
+    let span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || {
+        let _span = span.enter();
+        payloads::provision_encrypted(
+            &secure_dir,
+            &key,
+            payload.as_deref(),
+            replaces,
+        )
+    })
+    .await??;
+
+    if config_get_bool_or("cloud_agent", "reprovision_payload", false)? {
+        let key = derived.key.clone();
+        let payload = derived.payload.clone();
+
+        // The payload is already running at this point, so only warn: the
+        // tenant will have to deliver the keys again after a reboot.
+        if let Err(e) = data
+            .tpm
+            .run(move |backend| {
+                backend.persist_payload(&key, payload.as_deref())
+            })
+            .await
+        {
+            warn!("Unable to persist payload for re-provisioning: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle_delivery(
+    delivery: Result<Delivery>,
+    data: &QuoteData,
+) -> HttpResponse {
+    let response = match delivery {
+        Ok(Delivery::Derived(derived)) => {
+            let result = provision(&derived, data).await;
+
+            // must unwrap here due to lock mechanism
+            // https://github.com/rust-lang-nursery/failure/issues/192
+            data.keys.lock().unwrap().finish(result.is_ok()); //#[allow_ci]
+
+            match result {
+                Ok(()) => {
+                    telemetry::count(telemetry::Counter::PayloadsProvisioned);
+                    // Hooks may take their time, the tenant does not wait
+                    // for them
+                    let agent_uuid = data.agent_uuid.clone();
+                    actix_web::rt::spawn(async move {
+                        hooks::notify(
+                            hooks::Event::PayloadProvisioned,
+                            &agent_uuid,
+                        )
+                        .await
+                    });
+                    HttpResponse::Ok().json(JsonWrapper::success(json!({})))
+                }
+                Err(e) => {
+                    error!("Failed to provision payload: {}", e);
+                    HttpResponse::InternalServerError().json(
+                        JsonWrapper::error(
+                            500,
+                            format!("Failed to provision payload: {}", e),
+                        ),
+                    )
+                }
+            }
+        }
+        Ok(_) => HttpResponse::Ok().json(JsonWrapper::success(json!({}))),
+        Err(e @ KeylimeError::KeyDelivery { .. }) => {
+            warn!("{}", e);
+            HttpResponse::Conflict()
+                .json(JsonWrapper::error(409, e.to_string()))
+        }
+        Err(e) => {
+            warn!("Failed to process key: {}", e);
+            HttpResponse::BadRequest()
+                .json(JsonWrapper::error(400, e.to_string()))
+        }
+    };
+
+    let state = data.keys.lock().unwrap().state(); //#[allow_ci]
+    systemd::status(&format!("Bootstrap key delivery: {}", state));
+
+    response
+}
+
+// This is the U key delivered by the tenant, along with the auth tag
+// HMAC(K, agent_uuid) and the optional payload encrypted with K.
+pub async fn u_key(
+    body: web::Json<KeylimeUKey>,
+    data: web::Data<QuoteData>,
+) -> impl Responder {
+    let _permit = match data.limits.payloads.acquire() {
+        Ok(permit) => permit,
+        Err(e) => return overloaded(e),
+    };
+    let key = match decrypt_key(&body.encrypted_key, &data) {
+        Ok(key) => key,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(JsonWrapper::error(
+                400,
+                format!("Unable to decrypt U key: {}", e),
+            ))
+        }
+    };
+    info!("Received U key");
+
+    let ukey = UKey {
+        key,
+        auth_tag: body.auth_tag.clone(),
+        payload: body.payload.clone(),
+    };
+    let delivery = data.keys.lock().unwrap().add_u(ukey, &data.agent_uuid); //#[allow_ci]
+
+    handle_delivery(delivery, &data).await
+}
+
+// This is the V key delivered by the cloud verifier after a successful
+// integrity quote.
+pub async fn v_key(
+    body: web::Json<KeylimeVKey>,
+    data: web::Data<QuoteData>,
+) -> impl Responder {
+    let _permit = match data.limits.payloads.acquire() {
+        Ok(permit) => permit,
+        Err(e) => return overloaded(e),
+    };
+    let key = match decrypt_key(&body.encrypted_key, &data) {
+        Ok(key) => key,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(JsonWrapper::error(
+                400,
+                format!("Unable to decrypt V key: {}", e),
+            ))
+        }
+    };
+    info!("Received V key");
+
+    let delivery = data.keys.lock().unwrap().add_v(key, &data.agent_uuid); //#[allow_ci]
+
+    handle_delivery(delivery, &data).await
+}
+
+// The tenant checks that the agent derived the right key by asking for the
+// HMAC of a challenge with K.
+pub async fn verify(
+    param: web::Query<Verify>,
+    data: web::Data<QuoteData>,
+) -> impl Responder {
+    let key = data.keys.lock().unwrap().key().map(|k| k.to_vec()); //#[allow_ci]
+
+    verify_response(key.as_deref(), &param.challenge)
+}
+
+pub(crate) fn verify_response(
+    key: Option<&[u8]>,
+    challenge: &str,
+) -> HttpResponse {
+    match key {
+        Some(key) => match crypto::compute_hmac(key, challenge.as_bytes()) {
+            Ok(hmac) => {
+                HttpResponse::Ok().json(JsonWrapper::success(KeylimeHmac {
+                    hmac: hex::encode(hmac),
+                }))
+            }
+            Err(e) => HttpResponse::InternalServerError()
+                .json(JsonWrapper::error(500, e.to_string())),
+        },
+        None => HttpResponse::BadRequest().json(JsonWrapper::error(
+            400,
+            String::from("Bootstrap key not yet available."),
+        )),
+    }
+}
+
+// The tenant delivers the runtime policy once the bootstrap key is derived,
+// authenticated with it along with its version, see policy.rs. Policies
+// older than the current one are rejected, so that they cannot be replayed.
+pub async fn runtime_policy(
+    body: web::Json<KeylimeRuntimePolicy>,
+    data: web::Data<QuoteData>,
+) -> impl Responder {
+    let key = data.keys.lock().unwrap().key().map(|k| k.to_vec()); //#[allow_ci]
+    let key = match key {
+        Some(key) => key,
+        None => {
+            return HttpResponse::BadRequest().json(JsonWrapper::error(
+                400,
+                String::from("Bootstrap key not yet available."),
+            ))
+        }
+    };
+
+    let document = match base64::decode(&body.runtime_policy) {
+        Ok(document) => document,
+        Err(e) => {
+            return HttpResponse::BadRequest()
+                .json(JsonWrapper::error(400, e.to_string()))
+        }
+    };
+    let mut message = format!("{}:", body.version).into_bytes();
+    message.extend(&document);
+    match crypto::verify_hmac(&key, &message, &body.auth_tag) {
+        Ok(true) => {}
+        Ok(false) => {
+            warn!("Runtime policy authentication failed");
+            return HttpResponse::BadRequest().json(JsonWrapper::error(
+                400,
+                String::from("Runtime policy authentication failed"),
+            ));
+        }
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .json(JsonWrapper::error(500, e.to_string()))
+        }
+    }
+    let policy = match policy::RuntimePolicy::parse(document, body.version) {
+        Ok(policy) => Arc::new(policy),
+        Err(e) => {
+            return HttpResponse::BadRequest()
+                .json(JsonWrapper::error(400, e.to_string()))
+        }
+    };
+
+    // Checked and stored under the lock, so that concurrent deliveries
+    // cannot leave an older policy behind
+    let installed = policy.clone();
+    let store_data = data.clone();
+    let stored = tokio::task::spawn_blocking(move || {
+        let mut current = store_data.runtime_policy.lock().unwrap(); //#[allow_ci]
+        if !installed.replaces(current.as_deref()) {
+            return Err(KeylimeError::Policy {
+                version: installed.version(),
+                current: current.as_ref().map_or(0, |p| p.version()),
+            });
+        }
+        installed.save(&store_data.secure_dir)?;
+        *current = Some(installed);
+        Ok(())
+    })
+    .await
+    .map_err(KeylimeError::from)
+    .and_then(|result| result);
+    match stored {
+        Ok(()) => {}
+        Err(e @ KeylimeError::Policy { .. }) => {
+            warn!("Runtime policy rejected: {}", e);
+            return HttpResponse::BadRequest().json(JsonWrapper::error(
+                400,
+                format!("Runtime policy rejected: {}", e),
+            ));
+        }
+        Err(e) => {
+            error!("Failed to store runtime policy: {}", e);
+            return HttpResponse::InternalServerError().json(
+                JsonWrapper::error(
+                    500,
+                    format!("Failed to store runtime policy: {}", e),
+                ),
+            );
+        }
+    }
+
+    info!(
+        "Received runtime policy {} version {}",
+        policy.sha256(),
+        policy.version()
+    );
+    HttpResponse::Ok().json(JsonWrapper::success(json!({})))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api;
+    use crate::tpm_backend::{quote_data, MockTpm};
+    use actix_web::{test, App};
+    use openssl::rsa::Padding;
+
+    // Encrypts a key half for the agent, as the tenant and verifier do
+    fn encrypt_half(data: &QuoteData, half: &[u8]) -> String {
+        let rsa = data.pub_key.rsa().unwrap(); //#[allow_ci]
+        let mut encrypted = vec![0; rsa.size() as usize];
+        let len = rsa
+            .public_encrypt(half, &mut encrypted, Padding::PKCS1_OAEP)
+            .unwrap(); //#[allow_ci]
+        base64::encode(&encrypted[..len])
+    }
+
+    #[test]
+    fn key_delivery() {
+        actix_web::rt::System::new("test").block_on(async {
+            let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+            let mut data = quote_data(MockTpm::new()).unwrap(); //#[allow_ci]
+            data.secure_dir = dir.path().to_path_buf();
+            let data = web::Data::new(data);
+            let mut app = test::init_service(
+                App::new()
+                    .app_data(data.clone())
+                    .configure(|cfg| api::configure(cfg, 0)),
+            )
+            .await;
+
+            let req = test::TestRequest::get()
+                .uri("/v2.0/keys/verify?challenge=abc")
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), 400);
+
+            let u = [0x11u8; 32];
+            let v = [0x22u8; 32];
+            let key = [0x33u8; 32];
+            let auth_tag = hex::encode(
+                crypto::compute_hmac(&key, b"mock").unwrap(), //#[allow_ci]
+            );
+
+            let req = test::TestRequest::post()
+                .uri("/v2.0/keys/ukey")
+                .set_json(&json!({
+                    "encrypted_key": encrypt_half(&data, &u),
+                    "auth_tag": auth_tag,
+                }))
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), 200);
+
+            let req = test::TestRequest::post()
+                .uri("/v2.0/keys/vkey")
+                .set_json(&json!({"encrypted_key": "bm90IGVuY3J5cHRlZA=="}))
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), 400);
+
+            let req = test::TestRequest::post()
+                .uri("/v2.0/keys/vkey")
+                .set_json(&json!({"encrypted_key": encrypt_half(&data, &v)}))
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), 200);
+            assert_eq!(data.keys.lock().unwrap().key(), Some(&key[..])); //#[allow_ci]
+            assert_eq!(
+                std::fs::read_to_string(dir.path().join("derived_tci_key"))
+                    .unwrap(), //#[allow_ci]
+                base64::encode(key)
+            );
+
+            let req = test::TestRequest::get()
+                .uri("/v2.0/keys/verify?challenge=abc")
+                .to_request();
+            let resp: Value = test::read_response_json(&mut app, req).await;
+            assert_eq!(
+                resp["results"]["hmac"],
+                hex::encode(crypto::compute_hmac(&key, b"abc").unwrap()) //#[allow_ci]
+            );
+        });
+    }
 }
@@ -0,0 +1,69 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+#![deny(
+    nonstandard_style,
+    const_err,
+    dead_code,
+    improper_ctypes,
+    non_shorthand_field_patterns,
+    no_mangle_generic_items,
+    overflowing_literals,
+    path_statements,
+    patterns_in_fns_without_body,
+    private_in_public,
+    unconditional_recursion,
+    unused,
+    while_true,
+    missing_copy_implementations,
+    missing_debug_implementations,
+    missing_docs,
+    trivial_casts,
+    trivial_numeric_casts,
+    unused_allocation,
+    unused_comparisons,
+    unused_parens,
+    unused_extern_crates,
+    unused_import_braces,
+    unused_qualifications,
+    unused_results
+)]
+
+//! Keylime attestation, as used by the Rust agent
+//!
+//! The parts of the agent other projects need to attest a machine without
+//! running the agent: TPM quotes and the keys they are made with, the IMA
+//! measurement list, the registrar client and the crypto helpers matching
+//! the Python Keylime implementation. Nothing here reads keylime.conf or
+//! assumes it runs in the agent: the algorithms, handles and addresses are
+//! all given by the caller.
+//!
+//! ```no_run
+//! use keylime::tpm;
+//! use tss_esapi::interface_types::algorithm::AsymmetricAlgorithm;
+//!
+//! # fn main() -> keylime::Result<()> {
+//! let mut ctx = tpm::get_tpm2_ctx()?;
+//! let (ek, _, _) = tpm::create_ek(&mut ctx, AsymmetricAlgorithm::Rsa)?;
+//! let (ak, _, _) = tpm::create_ak(&mut ctx, ek)?;
+//! # Ok(())
+//! # }
+//! ```
+
+/// Crypto helpers, compatible with the Python implementation
+pub mod crypto;
+/// Errors, with their stable codes
+pub mod error;
+#[cfg(feature = "testing")]
+pub mod fault;
+pub mod ffi;
+/// Outbound HTTP client
+pub mod http;
+/// IMA measurement list reading
+pub mod ima;
+/// Registrar client
+pub mod registrar_agent;
+/// TPM keys and quotes
+pub mod tpm;
+
+pub use error::{Error, Result};
@@ -0,0 +1,169 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Resource limits
+//
+// Quotes wait for the TPM, and integrity quotes hold the whole IMA
+// measurement list in memory, so a burst of requests could exhaust a small
+// device. Beyond the limits set in keylime.conf, requests are answered with
+// 503 Service Unavailable instead, for the client to retry later:
+//
+//   max_concurrent_quotes     quotes being generated or waiting for the TPM
+//   max_concurrent_payloads   key deliveries being processed, including the
+//                             provisioning of the payload
+//   max_ima_ml_size           bytes of the IMA measurement list returned
+//   max_runtime_policy_size   bytes of the runtime policy requests the
+//                             tenant sends, larger ones are answered with
+//                             413 Payload Too Large instead
+//
+// 0 disables a limit. With low_memory set, the agent trades speed for
+// memory, see MeasurementList::low_memory(), and allows a single quote at a
+// time unless max_concurrent_quotes is set.
+
+use crate::common::{config_get_bool_or, config_get_or};
+use crate::error::{Error, Result};
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub(crate) static DEFAULT_MAX_CONCURRENT_QUOTES: &str = "4";
+pub(crate) static LOW_MEMORY_MAX_CONCURRENT_QUOTES: &str = "1";
+pub(crate) static DEFAULT_MAX_CONCURRENT_PAYLOADS: &str = "1";
+pub(crate) static DEFAULT_MAX_IMA_ML_SIZE: &str = "0";
+pub(crate) static DEFAULT_MAX_RUNTIME_POLICY_SIZE: &str = "10485760";
+
+/// Bounds the number of operations of a kind running at the same time
+#[derive(Debug)]
+pub(crate) struct Limiter {
+    // The option of keylime.conf setting the limit
+    key: &'static str,
+    max: usize,
+    active: AtomicUsize,
+}
+
+/// An operation allowed to run, until dropped
+#[derive(Debug)]
+pub(crate) struct Permit<'a> {
+    limiter: &'a Limiter,
+}
+
+impl Limiter {
+    pub(crate) fn new(key: &'static str, max: usize) -> Self {
+        Limiter {
+            key,
+            max,
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    /// Starts an operation, failing with Error::Overloaded if the limit is
+    /// reached
+    pub(crate) fn acquire(&self) -> Result<Permit<'_>> {
+        let active = self.active.fetch_add(1, Ordering::SeqCst);
+        let permit = Permit { limiter: self };
+        if self.max != 0 && active >= self.max {
+            return Err(Error::Overloaded {
+                limit: self.key,
+                max: self.max as u64,
+            });
+        }
+        Ok(permit)
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        let _ = self.limiter.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Limits {
+    pub quotes: Limiter,
+    pub payloads: Limiter,
+    /// Maximum size of the IMA measurement list read, 0 for no limit
+    pub max_ima_ml_size: u64,
+    /// Maximum size of runtime policy requests, 0 for no limit
+    pub max_runtime_policy_size: usize,
+    /// Whether to save memory rather than time
+    pub low_memory: bool,
+}
+
+fn limit_get(key: &str, default: &str) -> Result<u64> {
+    config_get_or("cloud_agent", key, default)?
+        .trim()
+        .parse::<u64>()
+        .map_err(|e| Error::config_value("cloud_agent", key, e))
+}
+
+impl Limits {
+    /// Limits from keylime.conf
+    pub(crate) fn from_config() -> Result<Self> {
+        let low_memory =
+            config_get_bool_or("cloud_agent", "low_memory", false)?;
+        Ok(Limits {
+            quotes: Limiter::new(
+                "max_concurrent_quotes",
+                limit_get(
+                    "max_concurrent_quotes",
+                    if low_memory {
+                        LOW_MEMORY_MAX_CONCURRENT_QUOTES
+                    } else {
+                        DEFAULT_MAX_CONCURRENT_QUOTES
+                    },
+                )? as usize,
+            ),
+            payloads: Limiter::new(
+                "max_concurrent_payloads",
+                limit_get(
+                    "max_concurrent_payloads",
+                    DEFAULT_MAX_CONCURRENT_PAYLOADS,
+                )? as usize,
+            ),
+            max_ima_ml_size: limit_get(
+                "max_ima_ml_size",
+                DEFAULT_MAX_IMA_ML_SIZE,
+            )?,
+            max_runtime_policy_size: limit_get(
+                "max_runtime_policy_size",
+                DEFAULT_MAX_RUNTIME_POLICY_SIZE,
+            )? as usize,
+            low_memory,
+        })
+    }
+
+    /// No limits, e.g. for the self-test
+    pub(crate) fn unlimited() -> Self {
+        Limits {
+            quotes: Limiter::new("max_concurrent_quotes", 0),
+            payloads: Limiter::new("max_concurrent_payloads", 0),
+            max_ima_ml_size: 0,
+            max_runtime_policy_size: 0,
+            low_memory: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limiter() {
+        let limiter = Limiter::new("max_concurrent_quotes", 2);
+        let first = limiter.acquire().unwrap(); //#[allow_ci]
+        let _second = limiter.acquire().unwrap(); //#[allow_ci]
+        assert!(matches!(
+            limiter.acquire(),
+            Err(Error::Overloaded { max: 2, .. })
+        ));
+
+        // Rejected attempts do not hold on to the limit
+        drop(first);
+        assert!(limiter.acquire().is_ok());
+
+        let unlimited = Limiter::new("max_concurrent_quotes", 0);
+        let _permits: Vec<Permit> =
+            (0..100).filter_map(|_| unlimited.acquire().ok()).collect();
+        assert_eq!(unlimited.active.load(Ordering::SeqCst), 100);
+    }
+}
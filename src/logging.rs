@@ -0,0 +1,389 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Log targets
+//
+// By default the agent logs to standard error, leaving it to the service
+// manager to capture it. With log_target in keylime.conf, it instead sends
+// each event directly to the local syslog daemon in the RFC 5424 format,
+// or to the systemd journal along with structured fields giving the module
+// and source location. Either way the RUST_LOG environment variable selects
+// what is logged, with the syntax of EnvFilter from tracing-subscriber,
+// which accepts that of env_logger.
+//
+// Events are formatted by the fmt layer of tracing-subscriber, prefixed
+// with the spans they happened in, see spans.rs. The libraries the agent
+// uses log with the log crate instead: tracing-log turns their records into
+// events, which are filtered and formatted the same way.
+
+use crate::common::config_get_or;
+use crate::error::{Error, Result};
+use crate::spans::SpanLayer;
+
+use std::env;
+use std::io::{self, IsTerminal, Write};
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use tracing::level_filters::LevelFilter;
+use tracing::{warn, Level, Metadata};
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, EnvFilter, Registry};
+
+/// Socket of the local syslog daemon
+pub(crate) static SYSLOG_SOCKET: &str = "/dev/log";
+
+/// Socket of the journal for the native protocol, see systemd-journald(8)
+pub(crate) static JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+static IDENTIFIER: &str = "keylime_agent";
+
+// The daemon facility from syslog(3)
+const LOG_DAEMON: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LogTarget {
+    Stderr,
+    Syslog,
+    Journald,
+}
+
+fn parse_target(target: &str) -> Result<LogTarget> {
+    match target.trim().to_lowercase().as_str() {
+        "" | "stderr" => Ok(LogTarget::Stderr),
+        "syslog" => Ok(LogTarget::Syslog),
+        "journald" => Ok(LogTarget::Journald),
+        _ => Err(Error::config_value(
+            "cloud_agent",
+            "log_target",
+            format!("{:?} is not one of stderr, syslog or journald", target),
+        )),
+    }
+}
+
+// What the syslog and journal messages need of the metadata of an event
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct EventInfo {
+    level: Level,
+    target: String,
+    module_path: Option<String>,
+    file: Option<String>,
+    line: Option<u32>,
+}
+
+impl EventInfo {
+    fn new(metadata: &Metadata) -> Self {
+        EventInfo {
+            level: *metadata.level(),
+            target: metadata.target().to_string(),
+            module_path: metadata.module_path().map(String::from),
+            file: metadata.file().map(String::from),
+            line: metadata.line(),
+        }
+    }
+}
+
+// Severity from RFC 5424, also used by the journal's PRIORITY field
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        _ => 7,
+    }
+}
+
+// Formats an event as an RFC 5424 message for the local syslog daemon,
+// leaving the timestamp and host name for it to fill in
+fn syslog_message(info: &EventInfo, message: &str, pid: u32) -> Vec<u8> {
+    format!(
+        "<{}>1 - - {} {} - - {}",
+        LOG_DAEMON * 8 + severity(info.level),
+        IDENTIFIER,
+        pid,
+        message
+    )
+    .into_bytes()
+}
+
+// Appends a field in the journal's native protocol. Values spanning
+// several lines are sent with their length instead of a trailing newline.
+fn journal_field(buf: &mut Vec<u8>, name: &str, value: &str) {
+    buf.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        buf.push(b'\n');
+        buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        buf.push(b'=');
+    }
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(b'\n');
+}
+
+fn journal_message(info: &EventInfo, message: &str) -> Vec<u8> {
+    let mut buf = Vec::new();
+    journal_field(&mut buf, "MESSAGE", message);
+    journal_field(&mut buf, "PRIORITY", &severity(info.level).to_string());
+    journal_field(&mut buf, "SYSLOG_IDENTIFIER", IDENTIFIER);
+    journal_field(&mut buf, "TARGET", &info.target);
+    if let Some(module) = &info.module_path {
+        journal_field(&mut buf, "CODE_MODULE", module);
+    }
+    if let Some(file) = &info.file {
+        journal_field(&mut buf, "CODE_FILE", file);
+    }
+    if let Some(line) = info.line {
+        journal_field(&mut buf, "CODE_LINE", &line.to_string());
+    }
+    buf
+}
+
+// Sends events as datagrams to syslog or the journal
+#[derive(Debug)]
+struct SocketWriter {
+    target: LogTarget,
+    socket: UnixDatagram,
+}
+
+// Collects what the fmt layer writes for an event, and sends it when
+// dropped, once the event is complete
+#[derive(Debug)]
+struct EventWriter<'a> {
+    writer: &'a SocketWriter,
+    info: EventInfo,
+    buf: Vec<u8>,
+}
+
+impl Write for EventWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for EventWriter<'_> {
+    fn drop(&mut self) {
+        let message = String::from_utf8_lossy(&self.buf);
+        let message = message.trim_end();
+        if message.is_empty() {
+            return;
+        }
+
+        let datagram = match self.writer.target {
+            LogTarget::Journald => journal_message(&self.info, message),
+            _ => syslog_message(&self.info, message, process::id()),
+        };
+        // There is nowhere left to report a failure to log
+        if self.writer.socket.send(&datagram).is_err() {
+            let _ = writeln!(io::stderr(), "{} {}", self.info.level, message);
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for SocketWriter {
+    type Writer = EventWriter<'a>;
+
+    // Only used for writes without an event
+    fn make_writer(&'a self) -> Self::Writer {
+        EventWriter {
+            writer: self,
+            info: EventInfo {
+                level: Level::INFO,
+                target: IDENTIFIER.to_string(),
+                module_path: None,
+                file: None,
+                line: None,
+            },
+            buf: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, metadata: &Metadata) -> Self::Writer {
+        EventWriter {
+            writer: self,
+            info: EventInfo::new(metadata),
+            buf: Vec::new(),
+        }
+    }
+}
+
+// Same filter as env_logger, but logging informational messages when
+// RUST_LOG is not set, as appropriate for a service
+fn filter(default: LevelFilter) -> EnvFilter {
+    EnvFilter::builder()
+        .with_default_directive(default.into())
+        .from_env_lossy()
+}
+
+/*
+ * Return: Result wrap with error message
+ *
+ * Initializes logging to the target selected by log_target. If syslog or
+ * the journal cannot be reached, the agent logs to standard error instead.
+ */
+pub(crate) fn init() -> Result<()> {
+    // The configuration may be missing, in which case the agent fails
+    // later with a proper error, logged to standard error
+    let target = match config_get_or("cloud_agent", "log_target", "stderr") {
+        Ok(target) => parse_target(&target)?,
+        Err(_) => LogTarget::Stderr,
+    };
+
+    let path = match target {
+        LogTarget::Stderr => None,
+        LogTarget::Syslog => Some(SYSLOG_SOCKET),
+        LogTarget::Journald => Some(JOURNALD_SOCKET),
+    };
+    let mut unreachable = None;
+    let socket = path.and_then(|path| {
+        match UnixDatagram::unbound()
+            .and_then(|socket| socket.connect(path).map(|_| socket))
+        {
+            Ok(socket) => Some(SocketWriter { target, socket }),
+            Err(e) => {
+                unreachable = Some((path, e));
+                None
+            }
+        }
+    });
+
+    // Spans are timed and exported whatever is logged
+    let spans = SpanLayer::new()
+        .with_filter(filter_fn(|metadata| metadata.is_span()));
+    let (stderr, socket) = match socket {
+        Some(socket) => (
+            None,
+            Some(
+                fmt::layer()
+                    .with_writer(socket)
+                    .with_ansi(false)
+                    .without_time()
+                    .with_level(false)
+                    .with_target(false)
+                    .with_filter(filter(LevelFilter::INFO)),
+            ),
+        ),
+        None => (
+            Some(
+                fmt::layer()
+                    .with_writer(io::stderr)
+                    .with_ansi(io::stderr().is_terminal())
+                    .with_filter(filter(LevelFilter::INFO)),
+            ),
+            None,
+        ),
+    };
+    Registry::default()
+        .with(spans)
+        .with(stderr)
+        .with(socket)
+        .try_init()
+        .map_err(|e| {
+            Error::Other(format!("unable to set up logging: {}", e))
+        })?;
+
+    if let Some((path, e)) = unreachable {
+        warn!(
+            "Unable to connect to {}, logging to standard error: {}",
+            path, e
+        );
+    }
+    Ok(())
+}
+
+/*
+ * Initializes logging to standard error for the commands, which only log
+ * errors unless RUST_LOG says otherwise.
+ */
+pub(crate) fn init_command() {
+    let _ = fmt()
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .with_env_filter(filter(LevelFilter::ERROR))
+        .try_init();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_target() {
+        assert_eq!(parse_target("").unwrap(), LogTarget::Stderr); //#[allow_ci]
+        assert_eq!(parse_target("Syslog").unwrap(), LogTarget::Syslog); //#[allow_ci]
+        assert_eq!(parse_target("journald").unwrap(), LogTarget::Journald); //#[allow_ci]
+        assert!(parse_target("file").is_err());
+    }
+
+    fn event_info(level: Level) -> EventInfo {
+        EventInfo {
+            level,
+            target: String::from("keylime_agent::tpm"),
+            module_path: None,
+            file: None,
+            line: Some(7),
+        }
+    }
+
+    #[test]
+    fn syslog_format() {
+        let message =
+            syslog_message(&event_info(Level::WARN), "Listening on 9002", 42);
+        assert_eq!(
+            String::from_utf8(message).unwrap(), //#[allow_ci]
+            "<28>1 - - keylime_agent 42 - - Listening on 9002"
+        );
+    }
+
+    #[test]
+    fn journal_format() {
+        let message =
+            journal_message(&event_info(Level::ERROR), "two\nlines");
+
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&9u64.to_le_bytes());
+        expected.extend_from_slice(b"two\nlines\n");
+        expected.extend_from_slice(b"PRIORITY=3\n");
+        expected.extend_from_slice(b"SYSLOG_IDENTIFIER=keylime_agent\n");
+        expected.extend_from_slice(b"TARGET=keylime_agent::tpm\n");
+        expected.extend_from_slice(b"CODE_LINE=7\n");
+        assert_eq!(message, expected);
+    }
+
+    #[test]
+    fn socket_events() {
+        let (socket, daemon) = UnixDatagram::pair().unwrap(); //#[allow_ci]
+        let subscriber = Registry::default().with(
+            fmt::layer()
+                .with_writer(SocketWriter {
+                    target: LogTarget::Syslog,
+                    socket,
+                })
+                .with_ansi(false)
+                .without_time()
+                .with_level(false)
+                .with_target(false),
+        );
+
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("quote", nonce = "abc").entered();
+            warn!(pcrs = 3, "Slow quote");
+        });
+
+        let mut buf = [0u8; 256];
+        let len = daemon.recv(&mut buf).unwrap(); //#[allow_ci]
+        assert_eq!(
+            String::from_utf8_lossy(&buf[..len]),
+            format!(
+                "<28>1 - - keylime_agent {} - - \
+                 quote{{nonce=\"abc\"}}: Slow quote pcrs=3",
+                process::id()
+            )
+        );
+    }
+}
@@ -33,23 +33,67 @@
 //  missing_docs: there is many functions missing documentations for now
 #![allow(unused, missing_docs)]
 
+mod agent_data;
+mod agent_uuid;
+mod alerts;
+mod api;
+mod build_info;
+mod clear;
+mod cli;
 mod cmd_exec;
 mod common;
-mod crypto;
-mod error;
+mod container;
+mod daemon;
+mod dbus;
+mod evidence;
+#[cfg(test)]
+mod golden;
 mod hash;
+mod hooks;
+mod idle;
+mod ima_emulator;
+mod inspect;
+mod key_delivery;
+mod keyring;
 mod keys_handler;
+mod limits;
+mod logging;
+mod offline_quote;
+mod payloads;
+mod permissions;
+mod persist;
+mod policy;
+mod push;
+mod quote;
 mod quotes_handler;
-mod registrar_agent;
+mod registrar_check;
 mod revocation;
+mod seal;
+mod seccomp;
+mod secure_loopback;
 mod secure_mount;
-mod tpm;
+mod self_test;
+mod selinux;
+mod show;
+mod signals;
+mod simulate_revocation;
+mod snp;
+mod spans;
+mod spire;
+mod systemd;
+#[cfg(feature = "tdx")]
+mod tdx;
+mod telemetry;
+mod tls;
+mod tpm_backend;
+mod tpm_worker;
 
 use actix_web::{web, App, HttpServer};
 use common::*;
-use error::{Error, Result};
-use futures::{future::TryFutureExt, try_join};
-use log::*;
+use keylime::error::{self, Error, Result};
+#[cfg(feature = "testing")]
+use keylime::fault;
+use keylime::{crypto, http, ima, registrar_agent, tpm};
 use openssl::{
     hash::MessageDigest,
     pkey::{PKey, Private, Public},
@@ -59,9 +103,14 @@ use std::{
     convert::TryFrom,
     fs::File,
     io::{BufReader, Read},
-    path::Path,
-    sync::Mutex,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
+use tracing::{error, info, warn, Instrument};
 use tss_esapi::{
     handles::KeyHandle,
     interface_types::{
@@ -69,7 +118,6 @@ use tss_esapi::{
     },
     utils, Context,
 };
-use uuid::Uuid;
 
 static NOTFOUND: &[u8] = b"Not Found";
 
@@ -77,42 +125,139 @@ static NOTFOUND: &[u8] = b"Not Found";
 // handle quotes.
 #[derive(Debug)]
 pub struct QuoteData {
-    tpmcontext: Mutex<Context>,
+    tpm: tpm_worker::TpmWorker,
     priv_key: PKey<Private>,
     pub_key: PKey<Public>,
+    // The NK as returned in quotes
+    pub_key_pem: String,
     ak_handle: KeyHandle,
+    agent_uuid: String,
+    secure_dir: PathBuf,
+    keys: Mutex<key_delivery::KeyDelivery>,
+    ima_ml: Mutex<ima::MeasurementList>,
+    // Time of the last successful quote, in seconds since the epoch
+    last_quote: AtomicU64,
+    // Whether the registrar activated the agent
+    registered: AtomicBool,
+    limits: limits::Limits,
+    quote_config: quote::QuoteConfig,
+    evidence: Option<evidence::Evidence>,
+    // Delivered by the tenant, see policy.rs
+    runtime_policy: Mutex<Option<Arc<policy::RuntimePolicy>>>,
 }
 
-fn get_uuid(agent_uuid_config: &str) -> String {
-    match agent_uuid_config {
-        "openstack" => {
-            info!("Openstack placeholder...");
-            "openstack".into()
-        }
-        "hash_ek" => {
-            info!("hash_ek placeholder...");
-            "hash_ek".into()
-        }
-        "generate" => {
-            let agent_uuid = Uuid::new_v4();
-            info!("Generated a new UUID: {}", &agent_uuid);
-            agent_uuid.to_string()
-        }
-        uuid_config => match Uuid::parse_str(uuid_config) {
-            Ok(uuid_config) => uuid_config.to_string(),
-            Err(_) => {
-                info!("Misformatted UUID: {}", &uuid_config);
-                let agent_uuid = Uuid::new_v4();
-                agent_uuid.to_string()
+fn main() -> Result<()> {
+    let options = cli::parse(std::env::args().skip(1))?;
+    if options.help {
+        print!("{}", cli::USAGE);
+        return Ok(());
+    }
+    if options.version {
+        print!("{}", build_info::build_info().to_text());
+        return Ok(());
+    }
+    if options.container {
+        container::enable();
+    }
+    if options.self_test {
+        logging::init_command();
+        let passed = actix_web::rt::System::new("keylime_agent")
+            .block_on(self_test::run());
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+    if let Some(command) = options.command {
+        logging::init_command();
+        let mut system = actix_web::rt::System::new("keylime_agent");
+        return match command {
+            cli::Command::Clear {
+                deregister,
+                ignore_deregister_errors,
+                evict_handles,
+            } => system.block_on(clear::run(
+                deregister,
+                ignore_deregister_errors,
+                evict_handles,
+            )),
+            cli::Command::AgentData { repair } => inspect::run(repair),
+            cli::Command::Quote {
+                nonce,
+                mask,
+                ima_ml_entry,
+            } => {
+                system.block_on(offline_quote::run(nonce, mask, ima_ml_entry))
             }
-        },
+            cli::Command::RegisterOnly => system.block_on(register_only()),
+            cli::Command::Seal {
+                input,
+                output,
+                pcr_mask,
+            } => seal::seal(&input, &output, pcr_mask),
+            cli::Command::Unseal { input, output } => {
+                seal::unseal(&input, output.as_deref())
+            }
+            cli::Command::ShowEk => show::ek(),
+            cli::Command::SimulateRevocation { event, payload_dir } => {
+                simulate_revocation::run(&event, payload_dir.as_deref())
+            }
+            cli::Command::TestRegistrar => {
+                system.block_on(registrar_check::run())
+            }
+            cli::Command::ShowAk => show::ak(),
+            cli::Command::ImaEmulator { file, follow } => {
+                ima_emulator::run(&file, follow)
+            }
+        };
+    }
+
+    // Lock the PID file before detaching, so that a running agent is
+    // reported on the terminal
+    let mut pid_file = match &options.pid_file {
+        Some(path) => Some(daemon::PidFile::lock(path)?),
+        None => None,
+    };
+    // Forking is only safe as long as there is a single thread, so detach
+    // before starting the runtime
+    if options.daemon {
+        daemon::daemonize(options.log_file.as_deref())?;
+    } else if let Some(log_file) = &options.log_file {
+        daemon::redirect_output(log_file)?;
+    }
+    if let Some(pid_file) = &mut pid_file {
+        pid_file.write_pid()?;
     }
+
+    let result = actix_web::rt::System::new("keylime_agent").block_on(run());
+    if let Err(e) = &result {
+        container::log_hint(e);
+    }
+    result
 }
 
-#[actix_web::main]
-async fn main() -> Result<()> {
-    pretty_env_logger::init();
+async fn run() -> Result<()> {
+    logging::init()?;
+    let build = build_info::build_info();
+    info!(
+        "keylime_agent {} ({}, built {}), tss-esapi {}, tpm2-tss {}, {}",
+        build.version,
+        build.git_commit,
+        build.build_date,
+        build.tss_esapi,
+        build.tpm2_tss,
+        build.openssl
+    );
+    let otlp = telemetry::otlp_config_get()?;
+    // This must happen before any thread is started
+    let _ = secure_mount::enter_private_namespace()?;
+
+    // Mounting the secure directory and generating the NK pair do not need
+    // the TPM, so they run while the TPM creates the EK and AK
+    let mount_task = tokio::task::spawn_blocking(secure_mount::mount);
+    let nk_task =
+        tokio::task::spawn_blocking(|| crypto::rsa_generate_pair(2048));
+
+    systemd::status("Initializing TPM");
     let mut ctx = tpm::get_tpm2_ctx()?;
+    tpm::set_owner_auth(&mut ctx, &tpm_ownerpassword_get()?)?;
     //  Retreive the TPM Vendor, this allows us to warn if someone is using a
     // Software TPM ("SW")
     if tss_esapi::utils::get_tpm_vendor(&mut ctx)?.contains("SW") {
@@ -121,14 +266,20 @@ async fn main() -> Result<()> {
         warn!("INSECURE: Only use Keylime in this mode for testing or debugging purposes.");
     }
 
+    // A power loss may have interrupted a write of the agent data
+    let _ = persist::recover(&agent_data::agent_data_path())?;
+    if agent_data::reset_if_tpm_cleared(&mut ctx)? {
+        error!(
+            event = "tpm_cleared",
+            "TPM was cleared since the last start, discarded the persisted agent data"
+        );
+    }
+
     info!("Starting server...");
 
     // Gather EK and AK key values and certs
-    let (ek_handle, ek_cert, ek_tpm2b_pub) =
-        tpm::create_ek(&mut ctx, Some(AsymmetricAlgorithm::Rsa))?;
-
-    let (ak_handle, ak_name, ak_tpm2b_pub) =
-        tpm::create_ak(&mut ctx, ek_handle)?;
+    let agent_keys = agent_data::load_keys(&mut ctx, true)?;
+    let ak = agent_keys.ak()?.clone();
 
     // Gather configs
     let cloudagent_ip = cloudagent_ip_get()?;
@@ -136,39 +287,25 @@ async fn main() -> Result<()> {
     let registrar_ip = registrar_ip_get()?;
     let registrar_port = registrar_port_get()?;
     let agent_uuid_config = config_get("cloud_agent", "agent_uuid")?;
-    let agent_uuid = get_uuid(&agent_uuid_config);
+    let agent_uuid =
+        agent_uuid::get(&agent_uuid_config, &agent_keys.ek_tpm2b_pub)?;
 
-    {
-        // Request keyblob material
-        let keyblob = registrar_agent::do_register_agent(
-            &registrar_ip,
-            &registrar_port,
-            &agent_uuid,
-            &ek_tpm2b_pub,
-            &ek_cert,
-            &ak_tpm2b_pub,
-        )
-        .await?;
-        info!("SUCCESS: agent registered");
-
-        let key = tpm::activate_credential(
-            &mut ctx, keyblob, ak_handle, ek_handle,
-        )?;
-        let mackey = base64::encode(key.value());
-        let mackey = PKey::hmac(mackey.as_bytes())?;
-        let mut signer = Signer::new(MessageDigest::sha384(), &mackey)?;
-        signer.update(agent_uuid.as_bytes());
-        let auth_tag = signer.sign_to_vec()?;
-        let auth_tag = hex::encode(&auth_tag);
-
-        registrar_agent::do_activate_agent(
-            &registrar_ip,
-            &registrar_port,
-            &agent_uuid,
-            &auth_tag,
-        )
-        .await?;
-        info!("SUCCESS: agent activated");
+    actix_web::rt::spawn(alerts::run(agent_uuid.clone()));
+    if let Some(timeout) = idle::idle_timeout_get()? {
+        info!(
+            "Warning if no integrity quote is requested for {} minutes",
+            timeout.as_secs() / 60
+        );
+        actix_web::rt::spawn(idle::run_monitor(timeout, agent_uuid.clone()));
+    }
+
+    if let Some((endpoint, interval)) = otlp {
+        info!("Exporting traces and metrics to {}", endpoint);
+        actix_web::rt::spawn(telemetry::run_exporter(
+            endpoint,
+            interval,
+            agent_uuid.clone(),
+        ));
     }
 
     // Generate key pair for secure transmission of u, v keys. The u, v
@@ -178,40 +315,434 @@ async fn main() -> Result<()> {
     //
     // Since we store the u key in memory, discarding this key, which
     // safeguards u and v keys in transit, is not part of the threat model.
-    let (nk_pub, nk_priv) = crypto::rsa_generate_pair(2048)?;
+    let (nk_pub, nk_priv) = nk_task.await??;
+
+    // The decrypted payload and the derived key are stored in the secure
+    // directory, so make sure it is mounted before accepting keys.
+    systemd::status("Mounting the secure directory");
+    let secure_dir = PathBuf::from(mount_task.await??);
+    // Looked up now, as it depends on the privileges of the agent
+    let secure_storage = secure_mount::secure_storage_get()?;
+    let key_delivery_timeout = config_get_or(
+        "cloud_agent",
+        "key_delivery_timeout",
+        &key_delivery::DEFAULT_KEY_DELIVERY_TIMEOUT
+            .as_secs()
+            .to_string(),
+    )?
+    .parse()?;
+    let mut keys = key_delivery::KeyDelivery::new(Duration::from_secs(
+        key_delivery_timeout,
+    ));
+
+    // Provision the payload persisted on a previous boot, if the PCRs it is
+    // sealed to still match. Otherwise wait for the tenant and verifier to
+    // deliver the keys as usual.
+    if config_get_bool_or("cloud_agent", "reprovision_payload", false)? {
+        match agent_data::restore_payload(&mut ctx) {
+            Ok(Some((key, payload))) => match payloads::provision_encrypted(
+                &secure_dir,
+                &key,
+                payload.as_deref(),
+                false,
+            ) {
+                Ok(()) => {
+                    info!("Re-provisioned payload from previous boot");
+                    keys.restore(key);
+                    telemetry::count(telemetry::Counter::PayloadsProvisioned);
+                    hooks::notify(
+                        hooks::Event::PayloadProvisioned,
+                        &agent_uuid,
+                    )
+                    .await;
+                }
+                Err(e) => warn!("Unable to re-provision payload: {}", e),
+            },
+            Ok(None) => info!("No payload persisted for re-provisioning"),
+            Err(e) => warn!("Unable to restore persisted payload: {}", e),
+        }
+    }
+
+    // After re-provisioning, to serve the TLS credentials of the payload
+    let agent_tls = tls::enabled()?;
+    if agent_tls {
+        tls::init(&secure_dir, &nk_priv, &agent_uuid)?;
+    }
 
+    // The measurement list is only readable by root, so keep it open for
+    // after dropping privileges
+    let limits = limits::Limits::from_config()?;
+    if limits.low_memory {
+        info!("Using the low-memory profile");
+    }
+    let ima_ml_path = ima_ml_get();
+    let mut ima_ml = match File::open(&ima_ml_path) {
+        Ok(file) => ima::MeasurementList::new(Some(file)),
+        Err(e) => {
+            warn!(
+                "Unable to open IMA measurement list {}: {}",
+                ima_ml_path, e
+            );
+            ima::MeasurementList::new(None)
+        }
+    };
+    if limits.low_memory {
+        ima_ml = ima_ml.low_memory();
+    }
+    let evidence = evidence::Evidence::from_config()?;
+    let runtime_policy = match policy::load(&secure_dir) {
+        Ok(policy) => policy.map(Arc::new),
+        Err(e) => {
+            warn!("Unable to load the runtime policy: {}", e);
+            None
+        }
+    };
+    let push_config = push::config_get()?;
+    let spire_socket = spire::socket_get()?;
+
+    // Everything the agent writes to after dropping privileges must belong
+    // to the user it runs as
+    let run_as = permissions::run_as_get()?;
+    let seccomp_mode = seccomp::seccomp_mode_get()?;
+    if let Some(ids) = &run_as {
+        permissions::chown(Path::new(&work_dir_get()), ids)?;
+        permissions::chown_recursive(&secure_dir, ids)?;
+        if let Some(evidence) = &evidence {
+            permissions::chown_recursive(evidence.dir(), ids)?;
+        }
+        let agent_data_path = agent_data::agent_data_path();
+        if agent_data_path.exists() {
+            permissions::chown(&agent_data_path, ids)?;
+        }
+    }
+
+    let delivery_state = keys.state();
     let quotedata = web::Data::new(QuoteData {
-        tpmcontext: Mutex::new(ctx),
+        tpm: tpm_worker::TpmWorker::start(ctx)?,
         priv_key: nk_priv,
+        pub_key_pem: String::from_utf8(nk_pub.public_key_to_pem()?)?,
         pub_key: nk_pub,
-        ak_handle,
+        ak_handle: ak.handle,
+        agent_uuid,
+        secure_dir: secure_dir.clone(),
+        keys: Mutex::new(keys),
+        ima_ml: Mutex::new(ima_ml),
+        last_quote: AtomicU64::new(0),
+        registered: AtomicBool::new(false),
+        limits,
+        evidence,
+        quote_config: quote::QuoteConfig::from_config()?,
+        runtime_policy: Mutex::new(runtime_policy),
     });
 
-    let actix_server = HttpServer::new(move || {
+    let watchdog_data = quotedata.clone();
+    let signal_data = quotedata.clone();
+    let dbus_data = quotedata.clone();
+    let registration_data = quotedata.clone();
+    let spire_data = quotedata.clone();
+    let low_memory = quotedata.limits.low_memory;
+    let max_policy_size = quotedata.limits.max_runtime_policy_size;
+    let mut server = HttpServer::new(move || {
         App::new()
             .app_data(quotedata.clone())
+            .configure(|cfg| api::configure(cfg, max_policy_size))
             .service(
-                web::resource("/keys/verify")
-                    .route(web::get().to(keys_handler::verify)),
-            )
-            .service(
-                web::resource("/keys/ukey")
-                    .route(web::post().to(keys_handler::ukey)),
-            )
-            .service(
-                web::resource("/quotes/identity")
-                    .route(web::get().to(quotes_handler::identity)),
-            )
-            .service(
-                web::resource("/quotes/integrity")
-                    .route(web::get().to(quotes_handler::integrity)),
+                web::resource("/info").route(web::get().to(build_info::info)),
             )
     })
-    .bind(format!("{}:{}", cloudagent_ip, cloudagent_port))?
-    .run()
-    .map_err(|x| x.into());
-    info!("Listening on http://{}:{}", cloudagent_ip, cloudagent_port);
-    try_join!(actix_server, revocation::run_revocation_service())?;
+    // Shutting down also involves the secure storage, see signals.rs
+    .disable_signals();
+    if low_memory {
+        server = server.workers(1);
+    }
+
+    // With socket activation systemd binds the port, so the agent does not
+    // need the privileges to do it
+    let listeners = systemd::listen_fds()?;
+    let (listen_ip, listen_port) = match listeners.first() {
+        Some(listener) => {
+            let addr = listener.local_addr()?;
+            (addr.ip().to_string(), addr.port().to_string())
+        }
+        None => (cloudagent_ip.clone(), cloudagent_port.clone()),
+    };
+    let addr = format!("{}:{}", cloudagent_ip, cloudagent_port);
+    if listeners.is_empty() {
+        server = if agent_tls {
+            server.bind_openssl(addr, tls::acceptor()?)?
+        } else {
+            server.bind(addr)?
+        };
+    } else {
+        for listener in listeners {
+            server = if agent_tls {
+                server.listen_openssl(listener, tls::acceptor()?)?
+            } else {
+                server.listen(listener)?
+            };
+        }
+    }
+
+    // The socket is usually in a directory only root can write to
+    let spire_listener = match &spire_socket {
+        Some(socket) => Some(spire::bind(socket, run_as.as_ref())?),
+        None => None,
+    };
+
+    // All privileged steps are done
+    if let Some(ids) = &run_as {
+        permissions::drop_privileges(ids)?;
+    }
+    dbus::start(dbus_data)?;
+    seccomp::install(seccomp_mode)?;
+
+    let server = server.run();
+    let scheme = if agent_tls { "https" } else { "http" };
+    info!("Listening on {}://{}:{}", scheme, listen_ip, listen_port);
+    // The TPM is initialized and the server bound, so the agent is now able
+    // to serve the verifier and tenant, as soon as it is registered
+    systemd::ready(&format!(
+        "Listening on {}:{}, bootstrap key delivery: {}",
+        listen_ip, listen_port, delivery_state
+    ));
+    if let Some(interval) = systemd::watchdog_interval() {
+        info!("Pinging the systemd watchdog every {:?}", interval / 2);
+        actix_web::rt::spawn(systemd::run_watchdog(
+            interval,
+            watchdog_data,
+            systemd::probe_url(scheme, &listen_ip, &listen_port),
+        ));
+    }
+    actix_web::rt::spawn(signals::handle_signals(
+        server.clone(),
+        signal_data,
+    )?);
+    if let (Some(listener), Some(socket)) = (spire_listener, &spire_socket) {
+        info!(
+            "Answering SPIRE node attestation on {}",
+            socket.path.display()
+        );
+        actix_web::rt::spawn(spire::run(
+            listener,
+            spire_data,
+            spire::Identity {
+                ek_cert: base64::encode(&agent_keys.ek_cert),
+                ek_tpm2b_public: base64::encode(&agent_keys.ek_tpm2b_pub),
+                ak_tpm2b_public: base64::encode(&ak.tpm2b_pub),
+            },
+        ));
+    }
+
+    // Registration runs in the background, while the agent serves requests.
+    // It stops the agent, to be restarted, if it fails.
+    actix_web::rt::spawn(async move {
+        let agent_uuid = registration_data.agent_uuid.clone();
+        let registration = register(
+            registration_data.clone(),
+            &registrar_ip,
+            &registrar_port,
+            agent_keys.ek_handle,
+            &agent_keys.ek_cert,
+            &agent_keys.ek_tpm2b_pub,
+            &ak.tpm2b_pub,
+        )
+        .instrument(
+            tracing::info_span!("registration", agent_uuid = %agent_uuid),
+        )
+        .await;
+        match registration {
+            Ok(()) => {
+                registration_data.registered.store(true, Ordering::SeqCst);
+                if let Some(config) = push_config {
+                    actix_web::rt::spawn(push::run(
+                        config,
+                        registration_data,
+                    ));
+                }
+            }
+            Err(e) => {
+                error!("Registration failed: {}", e);
+                alerts::failure_now(&e, &agent_uuid).await;
+                signals::request_restart();
+            }
+        }
+    });
+
+    // The revocation service blocks waiting for messages, so it gets a
+    // thread of its own. The thread is not joined: it is stopped along with
+    // the process.
+    let (revocation_tx, revocation_rx) = tokio::sync::oneshot::channel();
+    let _ = std::thread::Builder::new()
+        .name("revocation".to_string())
+        .spawn(move || {
+            // Not all errors can be sent across threads, their messages can
+            let _ = revocation_tx.send(
+                revocation::run_revocation_service()
+                    .map_err(|e| e.to_string()),
+            );
+        })?;
+
+    // The agent stops if the revocation service fails
+    let result = tokio::select! {
+        result = server => result.map_err(Error::from),
+        result = revocation_rx => match result {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => {
+                Err(Error::Other(format!("revocation service failed: {}", e)))
+            }
+            Err(_) => Err(Error::Other(
+                "revocation service stopped unexpectedly".to_string(),
+            )),
+        },
+    };
+
+    info!("Server stopped");
+    // Unmounting takes the privileges the agent gave up, if it did
+    let can_unmount = secure_storage == secure_mount::SecureStorage::Keyring
+        || (run_as.is_none() && seccomp_mode == seccomp::SeccompMode::Off);
+    if container::enabled()
+        && secure_storage != secure_mount::SecureStorage::Keyring
+    {
+        info!(
+            "Leaving {} to be cleaned up with the container",
+            secure_dir.display()
+        );
+    } else if !can_unmount {
+        warn!(
+            "Leaving {} mounted after dropping privileges",
+            secure_dir.display()
+        );
+    } else if let Err(e) =
+        secure_mount::unmount(&secure_dir.to_string_lossy(), secure_storage)
+    {
+        warn!("Unable to clean up the secure storage: {}", e);
+    }
+    if result.is_ok() && signals::restart_requested() {
+        return Err(Error::Other("stopped to be restarted".to_string()));
+    }
+    result
+}
+
+/*
+ * Input: agent state, registrar and EK and AK
+ * Return: Result wrap with error message
+ *
+ * Registers the agent and activates it with the credential the registrar
+ * encrypted for the AK.
+ */
+async fn register(
+    data: web::Data<QuoteData>,
+    registrar_ip: &str,
+    registrar_port: &str,
+    ek_handle: KeyHandle,
+    ek_cert: &[u8],
+    ek_tpm2b_pub: &[u8],
+    ak_tpm2b_pub: &[u8],
+) -> Result<()> {
+    if registration_stubbed() {
+        warn!(
+            "Registration stubbed for testing, not registering with {}:{}",
+            registrar_ip, registrar_port
+        );
+        return Ok(());
+    }
+
+    // Request keyblob material
+    let keyblob = registrar_agent::do_register_agent(
+        registrar_ip,
+        registrar_port,
+        &data.agent_uuid,
+        ek_tpm2b_pub,
+        ek_cert,
+        ak_tpm2b_pub,
+    )
+    .await?;
+    info!("SUCCESS: agent registered");
+
+    let ak_handle = data.ak_handle;
+    let key = data
+        .tpm
+        .run(move |backend| {
+            backend.activate_credential(keyblob, ak_handle, ek_handle)
+        })
+        .await?;
+    activate(registrar_ip, registrar_port, &data.agent_uuid, key.value())
+        .await?;
+    telemetry::count(telemetry::Counter::Registrations);
+    Ok(())
+}
+
+// Proves to the registrar that the AK is in the same TPM as the EK, with
+// the key it encrypted for the AK
+async fn activate(
+    registrar_ip: &str,
+    registrar_port: &str,
+    agent_uuid: &str,
+    key: &[u8],
+) -> Result<()> {
+    let mackey = base64::encode(key);
+    let mackey = PKey::hmac(mackey.as_bytes())?;
+    let mut signer = Signer::new(MessageDigest::sha384(), &mackey)?;
+    signer.update(agent_uuid.as_bytes());
+    let auth_tag = signer.sign_to_vec()?;
+    let auth_tag = hex::encode(&auth_tag);
+
+    registrar_agent::do_activate_agent(
+        registrar_ip,
+        registrar_port,
+        agent_uuid,
+        &auth_tag,
+    )
+    .await?;
+    info!("SUCCESS: agent activated");
+    Ok(())
+}
+
+/*
+ * Return: Result wrap with error message
+ *
+ * keylime_agent register-only: enrolls the node with the registrar and
+ * exits, for image builds and pre-provisioning pipelines. The AK and the
+ * UUID, when generated, are kept in agent_data, so that the node can be
+ * added with the tenant before it ships. The agent still registers again
+ * on every start.
+ */
+async fn register_only() -> Result<()> {
+    let mut ctx = tpm::get_tpm2_ctx()?;
+    tpm::set_owner_auth(&mut ctx, &tpm_ownerpassword_get()?)?;
+    let _ = persist::recover(&agent_data::agent_data_path())?;
+    let _ = agent_data::reset_if_tpm_cleared(&mut ctx)?;
+
+    let keys = agent_data::load_keys(&mut ctx, true)?;
+    let ak = keys.ak()?;
+    let agent_uuid = agent_uuid::get(
+        &config_get("cloud_agent", "agent_uuid")?,
+        &keys.ek_tpm2b_pub,
+    )?;
+    let registrar_ip = registrar_ip_get()?;
+    let registrar_port = registrar_port_get()?;
+
+    let keyblob = registrar_agent::do_register_agent(
+        &registrar_ip,
+        &registrar_port,
+        &agent_uuid,
+        &keys.ek_tpm2b_pub,
+        &keys.ek_cert,
+        &ak.tpm2b_pub,
+    )
+    .await?;
+    info!("SUCCESS: agent registered");
+    let key = tpm::activate_credential(
+        &mut ctx,
+        keyblob,
+        ak.handle,
+        keys.ek_handle,
+    )?;
+    activate(&registrar_ip, &registrar_port, &agent_uuid, key.value())
+        .await?;
+
+    keys.flush(&mut ctx)?;
+    println!("Registered agent {}", agent_uuid);
     Ok(())
 }
 
@@ -238,10 +769,66 @@ mod tests {
     use super::*;
 
     fn init_logger() {
-        pretty_env_logger::init();
+        logging::init_command();
         info!("Initialized logger for testing suite.");
     }
 
+    #[tokio::test]
+    async fn register_and_activate() {
+        use crate::tpm_backend::{quote_data, MockTpm};
+        use serde_json::json;
+        use tss_esapi::handles::ObjectHandle;
+        use wiremock::matchers::{body_json, method};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let tpm = MockTpm::new();
+        tpm.set_credential(b"activation key");
+        let data = web::Data::new(quote_data(tpm).unwrap()); //#[allow_ci]
+
+        // The registrar encrypts the key for the AK, the agent proves it
+        // decrypted it with HMAC(base64(key), agent_uuid)
+        let mackey =
+            PKey::hmac(base64::encode(b"activation key").as_bytes()).unwrap(); //#[allow_ci]
+        let mut signer =
+            Signer::new(MessageDigest::sha384(), &mackey).unwrap(); //#[allow_ci]
+        signer.update(b"mock").unwrap(); //#[allow_ci]
+        let auth_tag = hex::encode(signer.sign_to_vec().unwrap()); //#[allow_ci]
+
+        let registrar = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "code": 200,
+                "status": "Success",
+                "results": {"blob": base64::encode(b"keyblob")},
+            })))
+            .expect(1)
+            .mount(&registrar)
+            .await;
+        Mock::given(method("PUT"))
+            .and(body_json(json!({ "auth_tag": auth_tag })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "code": 200,
+                "status": "Success",
+                "results": {},
+            })))
+            .expect(1)
+            .mount(&registrar)
+            .await;
+
+        let addr = registrar.address();
+        register(
+            data,
+            &addr.ip().to_string(),
+            &addr.port().to_string(),
+            KeyHandle::from(ObjectHandle::None),
+            b"ek cert",
+            b"ek",
+            b"ak",
+        )
+        .await
+        .unwrap(); //#[allow_ci]
+    }
+
     #[test]
     fn test_read_in_file() {
         assert_eq!(
@@ -250,23 +837,4 @@ mod tests {
             String::from("Hello World!\n")
         );
     }
-
-    #[test]
-    fn test_get_uuid() {
-        assert_eq!(get_uuid("openstack"), "openstack");
-        assert_eq!(get_uuid("hash_ek"), "hash_ek");
-        let _ = Uuid::parse_str(&get_uuid("generate")).unwrap(); //#[allow_ci]
-        assert_eq!(
-            get_uuid("D432FBB3-D2F1-4A97-9EF7-75BD81C00000"),
-            "d432fbb3-d2f1-4a97-9ef7-75bd81c00000"
-        );
-        assert_ne!(
-            get_uuid("D432FBB3-D2F1-4A97-9EF7-75BD81C0000X"),
-            "d432fbb3-d2f1-4a97-9ef7-75bd81c0000X"
-        );
-        let _ = Uuid::parse_str(&get_uuid(
-            "D432FBB3-D2F1-4A97-9EF7-75BD81C0000X",
-        ))
-        .unwrap(); //#[allow_ci]
-    }
 }
@@ -0,0 +1,128 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Offline quotes
+//
+// keylime_agent quote prints the JSON the agent would answer to a quote
+// request, without running the agent, to debug verifier failures without
+// crafting requests to a live agent. Without --mask, it is the identity
+// quote of the tenant, with a mask, the integrity quote of the verifier,
+// with the IMA measurement list, the whole list or, with --ima-ml-entry,
+// the list from that entry on, as for API 2.0 verifiers. The quote is made
+// as by the handlers, see quotes_handler.rs, with an AK and an NK created
+// for the command, as the ones of a running agent are not kept.
+
+use crate::common::IMA_ML;
+use crate::error::{Error, Result};
+use crate::ima::MeasurementList;
+use crate::key_delivery::{KeyDelivery, DEFAULT_KEY_DELIVERY_TIMEOUT};
+use crate::limits::Limits;
+use crate::quotes_handler::{self, KeylimeIntegrityQuote};
+use crate::tpm_worker::TpmWorker;
+use crate::{crypto, quote, tpm, QuoteData};
+
+use actix_web::web;
+use futures::StreamExt;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64},
+    Mutex,
+};
+use tss_esapi::interface_types::algorithm::AsymmetricAlgorithm;
+
+// The handlers only accept alphanumeric parameters
+fn check_param(name: &str, value: &str) -> Result<()> {
+    if value.is_empty() || !value.chars().all(char::is_alphanumeric) {
+        return Err(Error::Usage(format!(
+            "{} should be strictly alphanumeric: {}",
+            name, value
+        )));
+    }
+    Ok(())
+}
+
+/*
+ * Input: nonce, mask of the integrity quote, first IMA entry to return
+ * Return: Result wrap with error message
+ *
+ * Prints the response to standard output.
+ */
+pub(crate) async fn run(
+    nonce: String,
+    mask: Option<String>,
+    ima_ml_entry: Option<usize>,
+) -> Result<()> {
+    check_param("nonce", &nonce)?;
+    if let Some(mask) = &mask {
+        check_param("mask", mask)?;
+    }
+
+    let mut ctx = tpm::get_tpm2_ctx()?;
+    let keys = tpm::load_keys(&mut ctx, tpm::AkSource::Create)?;
+    let ak_handle = keys.ak()?.handle;
+    let (pub_key, priv_key) = crypto::rsa_generate_pair(2048)?;
+    let ima_ml = match &mask {
+        Some(_) => MeasurementList::new(Some(File::open(IMA_ML)?)),
+        None => MeasurementList::default(),
+    };
+
+    let data = web::Data::new(QuoteData {
+        tpm: TpmWorker::start(ctx)?,
+        priv_key,
+        pub_key_pem: String::from_utf8(pub_key.public_key_to_pem()?)?,
+        pub_key,
+        ak_handle,
+        agent_uuid: String::from("offline"),
+        secure_dir: PathBuf::new(),
+        keys: Mutex::new(KeyDelivery::new(DEFAULT_KEY_DELIVERY_TIMEOUT)),
+        ima_ml: Mutex::new(ima_ml),
+        last_quote: AtomicU64::new(0),
+        registered: AtomicBool::new(false),
+        limits: Limits::unlimited(),
+        quote_config: quote::QuoteConfig::from_config()?,
+        evidence: None,
+        runtime_policy: Mutex::new(None),
+    });
+
+    let mut quote =
+        quote::quote(nonce.as_bytes(), mask.as_deref(), data.clone()).await?;
+    quote.pubkey = &data.pub_key_pem;
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+
+    if mask.is_none() {
+        out.write_all(&quotes_handler::identity_body(quote)?)?;
+    } else {
+        let ml = quotes_handler::read_ima_ml(&data)?;
+        let mut quote = KeylimeIntegrityQuote::from_id_quote(&quote, "");
+        let ml = match ima_ml_entry {
+            Some(entry) => {
+                let (nth, ml) = ml.starting_at(entry);
+                quote.ima_measurement_list_entry = Some(nth);
+                ml
+            }
+            None => ml,
+        };
+        let mut body = quotes_handler::integrity_body(quote, ml)?;
+        while let Some(chunk) = body.next().await {
+            out.write_all(&chunk?)?;
+        }
+    }
+    writeln!(out)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offline_quote_params() {
+        assert!(check_param("nonce", "1234567890ABCDEFHIJ").is_ok());
+        assert!(check_param("nonce", "").is_err());
+        assert!(check_param("mask", "0x408000").is_ok());
+        assert!(check_param("mask", "0x408000&").is_err());
+    }
+}
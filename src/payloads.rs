@@ -0,0 +1,748 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+use crate::common::{
+    config_get, config_get_bool_or, config_get_or, PAYLOAD_TLS_CA,
+    PAYLOAD_TLS_CERT, PAYLOAD_TLS_KEY, TLS_DIR, UNZIPPED_DIR,
+};
+use crate::crypto;
+use crate::error::{Error, Result};
+use crate::keyring;
+use crate::persist;
+use crate::secure_mount::{self, SecureStorage};
+use crate::selinux;
+use crate::tls;
+
+use std::convert::TryInto;
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use tracing::{info, warn};
+
+/// Default interpreter for the payload script, matching the Python agent
+pub(crate) static DEFAULT_PAYLOAD_INTERPRETER: &str = "/bin/sh";
+
+/// Directory of the secure directory a new payload is extracted and checked
+/// in, before it replaces the previous one
+static STAGING_DIR: &str = "staging";
+
+/// Runs a script from the unzipped payload directory
+///
+/// If an interpreter is given the script is passed to it as its first
+/// argument, otherwise the script is executed directly and must be
+/// executable.
+pub(crate) fn run_script(
+    dir: &Path,
+    script: &str,
+    interpreter: Option<&str>,
+) -> Result<Output> {
+    let mut command = match interpreter {
+        Some(interpreter) => {
+            let mut command = Command::new(interpreter);
+            let _ = command.arg(script);
+            command
+        }
+        None => Command::new(format!("{}{}", "./", script)),
+    };
+
+    let output = command
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()?;
+
+    if !output.status.success() {
+        let err: Error = output.try_into()?;
+        return Err(Error::Script(
+            String::from(script),
+            err.exe_code()?,
+            err.stderr()?,
+        ));
+    }
+
+    info!("payload script {:?} successful", script);
+    Ok(output)
+}
+
+/// Runs the payload script delivered by the tenant
+///
+/// The script name is read from `payload_script` and the interpreter from
+/// `payload_script_interpreter` in keylime.conf. An empty script name
+/// disables the script, and an empty interpreter runs the script directly.
+/// Returns None if there was nothing to run.
+pub(crate) fn run_payload_script(unzipped: &Path) -> Result<Option<Output>> {
+    run_configured_script(unzipped, "payload_script", "")
+}
+
+/// Runs the cleanup script of a previously provisioned payload
+///
+/// Same as run_payload_script, with the script name read from
+/// `payload_cleanup_script`.
+pub(crate) fn run_cleanup_script(unzipped: &Path) -> Result<Option<Output>> {
+    run_configured_script(unzipped, "payload_cleanup_script", "")
+}
+
+fn run_configured_script(
+    unzipped: &Path,
+    option: &str,
+    default: &str,
+) -> Result<Option<Output>> {
+    let script = config_get_or("cloud_agent", option, default)?;
+    if script.is_empty() {
+        info!("No {} specified, skipping", option);
+        return Ok(None);
+    }
+
+    if !unzipped.join(&script).exists() {
+        warn!(
+            "No payload script {} found in {}",
+            script,
+            unzipped.display()
+        );
+        return Ok(None);
+    }
+
+    let interpreter = config_get_or(
+        "cloud_agent",
+        "payload_script_interpreter",
+        DEFAULT_PAYLOAD_INTERPRETER,
+    )?;
+    let interpreter = if interpreter.is_empty() {
+        None
+    } else {
+        Some(interpreter.as_str())
+    };
+
+    info!(
+        "Executing payload script {} with interpreter {:?}",
+        script, interpreter
+    );
+    run_script(unzipped, &script, interpreter).map(Some)
+}
+
+/// Writes the derived key K into the secure directory
+///
+/// As in the Python agent, the key is stored base64 encoded in the file
+/// named by `enc_keyname` in keylime.conf. With keyring secure storage, it
+/// is added to the user keyring under that name instead.
+pub(crate) fn store_key(secure_dir: &Path, key: &[u8]) -> Result<()> {
+    let keyname = config_get("cloud_agent", "enc_keyname")?;
+    if secure_mount::secure_storage_get()? == SecureStorage::Keyring {
+        let _ = keyring::add_key(&keyname, base64::encode(key).as_bytes())?;
+        return Ok(());
+    }
+
+    let path = secure_dir.join(keyname);
+    persist::write(&path, 0o600, base64::encode(key).as_bytes())?;
+    info!("Stored derived key at {}", path.display());
+    Ok(())
+}
+
+/// Default maximum total decompressed size of the payload zip
+pub(crate) const DEFAULT_EXTRACT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Default maximum number of entries in the payload zip
+pub(crate) const DEFAULT_EXTRACT_MAX_FILES: usize = 1024;
+
+/// Limits enforced when extracting the payload zip
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ExtractLimits {
+    pub max_size: u64,
+    pub max_files: usize,
+}
+
+impl Default for ExtractLimits {
+    fn default() -> Self {
+        ExtractLimits {
+            max_size: DEFAULT_EXTRACT_MAX_SIZE,
+            max_files: DEFAULT_EXTRACT_MAX_FILES,
+        }
+    }
+}
+
+impl ExtractLimits {
+    /// Reads `extract_payload_max_size` and `extract_payload_max_files`
+    /// from keylime.conf, falling back to the defaults when unset
+    pub(crate) fn from_config() -> Result<Self> {
+        let max_size = config_get_or(
+            "cloud_agent",
+            "extract_payload_max_size",
+            &DEFAULT_EXTRACT_MAX_SIZE.to_string(),
+        )?
+        .parse()?;
+        let max_files = config_get_or(
+            "cloud_agent",
+            "extract_payload_max_files",
+            &DEFAULT_EXTRACT_MAX_FILES.to_string(),
+        )?
+        .parse()?;
+
+        Ok(ExtractLimits {
+            max_size,
+            max_files,
+        })
+    }
+}
+
+const S_IFMT: u32 = 0o170000;
+const S_IFLNK: u32 = 0o120000;
+
+/// Returns the relative path of a zip entry, or None if the name is
+/// absolute, contains a `..` component or a NUL byte
+fn entry_path(name: &str) -> Option<PathBuf> {
+    if name.contains('\0') {
+        return None;
+    }
+
+    let mut path = PathBuf::new();
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(c) => path.push(c),
+            Component::CurDir => {}
+            Component::ParentDir
+            | Component::RootDir
+            | Component::Prefix(_) => return None,
+        }
+    }
+
+    if path.as_os_str().is_empty() {
+        None
+    } else {
+        Some(path)
+    }
+}
+
+/// Extracts a zip payload into the `unzipped` directory
+///
+/// Any previously extracted payload is removed first, so the directory
+/// only ever reflects the last delivered payload. Entries with absolute
+/// paths or `..` components and symbolic links are rejected, and the
+/// extraction is aborted once the number of entries or the number of
+/// decompressed bytes exceeds the given limits. Sizes recorded in the
+/// archive are not trusted; the limit applies to the bytes actually
+/// written.
+pub(crate) fn extract_zip(
+    zip_path: &Path,
+    secure_dir: &Path,
+    limits: &ExtractLimits,
+) -> Result<PathBuf> {
+    let unzipped = secure_dir.join(UNZIPPED_DIR);
+    if unzipped.exists() {
+        fs::remove_dir_all(&unzipped)?;
+    }
+    fs::create_dir_all(&unzipped)?;
+
+    let mut archive = zip::ZipArchive::new(fs::File::open(zip_path)?)?;
+    if archive.len() > limits.max_files {
+        return Err(Error::Payload {
+            limit: "extract_payload_max_files",
+            max: limits.max_files as u64,
+        });
+    }
+
+    let mut remaining = limits.max_size;
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let path = match entry_path(entry.name()) {
+            Some(path) => unzipped.join(path),
+            None => {
+                return Err(Error::PayloadEntry {
+                    entry: entry.name().to_string(),
+                    reason: "escapes the extraction directory",
+                })
+            }
+        };
+
+        let mode = entry.unix_mode();
+        if let Some(mode) = mode {
+            if mode & S_IFMT == S_IFLNK {
+                return Err(Error::PayloadEntry {
+                    entry: entry.name().to_string(),
+                    reason: "is a symbolic link",
+                });
+            }
+        }
+
+        if entry.is_dir() {
+            fs::create_dir_all(&path)?;
+            continue;
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // create_new refuses to follow or overwrite anything already
+        // present at the path, e.g. from a duplicated entry
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        // One byte more than allowed tells a file over the limit apart
+        let written = io::copy(
+            &mut (&mut entry).take(remaining.saturating_add(1)),
+            &mut file,
+        )?;
+        if written > remaining {
+            return Err(Error::Payload {
+                limit: "extract_payload_max_size",
+                max: limits.max_size,
+            });
+        }
+        remaining -= written;
+
+        // Keep the executable bits for scripts, but never setuid/setgid
+        // or group/world writable files
+        if let Some(mode) = mode {
+            fs::set_permissions(
+                &path,
+                fs::Permissions::from_mode(mode & 0o755),
+            )?;
+        }
+    }
+
+    info!(
+        "Extracted payload {} into {}",
+        zip_path.display(),
+        unzipped.display()
+    );
+    Ok(unzipped)
+}
+
+/// Installs TLS credentials delivered in the payload
+///
+/// If the unzipped payload contains a certificate and private key they are
+/// validated with crypto::check_tls_credentials(), against the CA
+/// certificate if one is also delivered, and copied into the `tls`
+/// directory of the secure mount. Once the payload is provisioned, the
+/// agent serves its API with them if `enable_agent_tls` is set, see
+/// tls::reload(), and the workloads can use them too. Returns the directory
+/// if credentials were installed.
+pub(crate) fn install_tls_credentials(
+    unzipped: &Path,
+    secure_dir: &Path,
+) -> Result<Option<PathBuf>> {
+    let cert_path = unzipped.join(PAYLOAD_TLS_CERT);
+    let key_path = unzipped.join(PAYLOAD_TLS_KEY);
+    let ca_path = unzipped.join(PAYLOAD_TLS_CA);
+
+    match (cert_path.exists(), key_path.exists()) {
+        (false, false) => return Ok(None),
+        (true, true) => {}
+        _ => {
+            return Err(Error::Other(format!(
+            "payload must contain both {} and {} to install TLS credentials",
+            PAYLOAD_TLS_CERT, PAYLOAD_TLS_KEY
+        )))
+        }
+    }
+
+    let cert = crypto::load_x509(&cert_path)?;
+    let key = crypto::load_private_key(&key_path)?;
+    let ca = if ca_path.exists() {
+        Some(crypto::load_x509(&ca_path)?)
+    } else {
+        None
+    };
+    crypto::check_tls_credentials(&cert, &key, ca.as_ref()).map_err(|e| {
+        Error::Other(format!("invalid {}: {}", PAYLOAD_TLS_CERT, e))
+    })?;
+
+    let tls_dir = secure_dir.join(TLS_DIR);
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&tls_dir)?;
+    let _ = fs::copy(&cert_path, tls_dir.join(PAYLOAD_TLS_CERT))?;
+    // Created with its final mode, so that the key is never readable by
+    // others
+    persist::write(
+        &tls_dir.join(PAYLOAD_TLS_KEY),
+        0o600,
+        &fs::read(&key_path)?,
+    )?;
+    if ca_path.exists() {
+        let _ = fs::copy(&ca_path, tls_dir.join(PAYLOAD_TLS_CA))?;
+    }
+
+    info!(
+        "Installed TLS credentials from payload into {}",
+        tls_dir.display()
+    );
+    Ok(Some(tls_dir))
+}
+
+/// Decrypts a payload as delivered by the tenant, i.e. base64 encoded and
+/// encrypted with the derived key K, and provisions it
+pub(crate) fn provision_encrypted(
+    secure_dir: &Path,
+    key: &[u8],
+    payload: Option<&str>,
+    replaces: bool,
+) -> Result<()> {
+    let payload = match payload {
+        Some(payload) => {
+            Some(crypto::decrypt_aead(key, &base64::decode(payload)?)?)
+        }
+        None => None,
+    };
+
+    provision(secure_dir, key, payload.as_deref(), replaces)
+}
+
+/// Installs the derived key and the optional payload in the secure
+/// directory, using the same layout as the Python agent
+///
+/// If `extract_payload_zip` is set the payload is unzipped and the
+/// configured payload script, if any, is run from the unzipped directory.
+/// With `replaces`, the previous payload is torn down, but only once the
+/// new one was extracted and checked, so that a delivery that fails leaves
+/// the previous payload in place.
+pub(crate) fn provision(
+    secure_dir: &Path,
+    key: &[u8],
+    payload: Option<&[u8]>,
+    replaces: bool,
+) -> Result<()> {
+    // Files are labeled as the secure directory, e.g. for a directory that
+    // is not a mount point with keyring secure storage
+    let _fscreate = match selinux::FsCreateContext::like(secure_dir) {
+        Ok(fscreate) => Some(fscreate),
+        Err(e) => {
+            warn!(
+                "Unable to set the SELinux context of the payload files: {}",
+                e
+            );
+            None
+        }
+    };
+
+    let payload_file = config_get("cloud_agent", "dec_payload_file")?;
+    let staging = secure_dir.join(STAGING_DIR);
+    let staged = match payload {
+        Some(payload) if !payload.is_empty() => {
+            let limits = if config_get_bool_or(
+                "cloud_agent",
+                "extract_payload_zip",
+                true,
+            )? {
+                Some(ExtractLimits::from_config()?)
+            } else {
+                None
+            };
+            stage(&staging, payload, &payload_file, limits.as_ref())?;
+            true
+        }
+        _ => false,
+    };
+
+    if replaces {
+        if let Err(e) = teardown(secure_dir) {
+            let _ = remove_path(&staging);
+            return Err(e);
+        }
+    }
+    store_key(secure_dir, key)?;
+    if !staged {
+        info!("No payload delivered");
+        return Ok(());
+    }
+
+    let unzipped = install_staged(&staging, secure_dir, &payload_file)?;
+    let tls_dir = secure_dir.join(TLS_DIR);
+    if tls_dir.exists() {
+        tls::reload(&tls_dir)?;
+    }
+    if let Some(unzipped) = unzipped {
+        let _ = run_payload_script(&unzipped)?;
+    }
+    Ok(())
+}
+
+// Stores the payload in the staging directory, and with limits, extracts
+// it and checks the TLS credentials it holds there. Nothing is left behind
+// if that fails.
+fn stage(
+    staging: &Path,
+    payload: &[u8],
+    payload_file: &str,
+    limits: Option<&ExtractLimits>,
+) -> Result<()> {
+    remove_path(staging)?;
+    fs::DirBuilder::new().mode(0o700).create(staging)?;
+
+    let result = (|| {
+        let payload_path = staging.join(payload_file);
+        persist::write(&payload_path, 0o600, payload)?;
+        if let Some(limits) = limits {
+            let unzipped = extract_zip(&payload_path, staging, limits)?;
+            let _ = install_tls_credentials(&unzipped, staging)?;
+        }
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = remove_path(staging);
+    }
+    result
+}
+
+// Moves the staged payload into the secure directory, in place of what is
+// left of the previous one. Returns the unzipped directory, if the payload
+// was extracted.
+fn install_staged(
+    staging: &Path,
+    secure_dir: &Path,
+    payload_file: &str,
+) -> Result<Option<PathBuf>> {
+    let extracted = staging.join(UNZIPPED_DIR).exists();
+    for name in [payload_file, UNZIPPED_DIR, TLS_DIR] {
+        let staged = staging.join(name);
+        if staged.exists() {
+            let path = secure_dir.join(name);
+            remove_path(&path)?;
+            fs::rename(&staged, &path)?;
+        }
+    }
+    fs::remove_dir(staging)?;
+
+    info!("Stored decrypted payload in {}", secure_dir.display());
+    Ok(if extracted {
+        Some(secure_dir.join(UNZIPPED_DIR))
+    } else {
+        None
+    })
+}
+
+// Removes a file or directory, ignoring it if it does not exist
+fn remove_path(path: &Path) -> Result<()> {
+    let result = if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    };
+
+    match result {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Removes a previously provisioned payload from the secure directory so
+/// that a new one can be provisioned
+///
+/// The cleanup script of the old payload, if configured, is run first so
+/// that it can stop whatever its payload script started. A failing cleanup
+/// script is logged but does not prevent the removal.
+pub(crate) fn teardown(secure_dir: &Path) -> Result<()> {
+    let unzipped = secure_dir.join(UNZIPPED_DIR);
+    if unzipped.exists() {
+        if let Err(e) = run_cleanup_script(&unzipped) {
+            warn!("Payload cleanup script failed: {}", e);
+        }
+    }
+
+    remove_path(&unzipped)?;
+    remove_path(&secure_dir.join(TLS_DIR))?;
+    remove_path(
+        &secure_dir.join(config_get("cloud_agent", "dec_payload_file")?),
+    )?;
+    let keyname = config_get("cloud_agent", "enc_keyname")?;
+    if secure_mount::secure_storage_get()? == SecureStorage::Keyring {
+        keyring::remove_key(&keyname)?;
+    }
+    remove_path(&secure_dir.join(keyname))?;
+
+    info!("Removed previous payload from {}", secure_dir.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn payload_script_with_interpreter() {
+        let dir = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests"))
+            .join("unzipped");
+
+        let output = run_script(&dir, "autorun.sh", Some("/bin/sh"));
+        assert!(output.is_ok());
+        assert_eq!(
+            String::from_utf8(output.unwrap().stdout).unwrap(), //#[allow_ci]
+            "autorun\n"
+        );
+    }
+
+    #[test]
+    fn payload_script_missing() {
+        let dir = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests"))
+            .join("unzipped");
+
+        let output = run_script(&dir, "missing.sh", Some("/bin/sh"));
+        assert!(output.is_err());
+    }
+
+    fn create_zip(path: &Path, entries: &[(&str, &[u8])]) {
+        let mut zip = zip::ZipWriter::new(fs::File::create(path).unwrap()); //#[allow_ci]
+        for (name, data) in entries {
+            zip.start_file(*name, Default::default()).unwrap(); //#[allow_ci]
+            zip.write_all(data).unwrap(); //#[allow_ci]
+        }
+        let _ = zip.finish().unwrap(); //#[allow_ci]
+    }
+
+    #[test]
+    fn remove_missing_path() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let sub = dir.path().join("sub");
+        fs::create_dir(&sub).unwrap(); //#[allow_ci]
+        fs::write(sub.join("file"), b"data").unwrap(); //#[allow_ci]
+
+        remove_path(&sub).unwrap(); //#[allow_ci]
+        assert!(!sub.exists());
+        assert!(remove_path(&sub).is_ok());
+    }
+
+    #[test]
+    fn extract_zip_layout() {
+        let secure_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let zip_path = secure_dir.path().join("decrypted_payload");
+        create_zip(&zip_path, &[("autorun.sh", b"echo autorun\n")]);
+
+        let unzipped = extract_zip(
+            &zip_path,
+            secure_dir.path(),
+            &ExtractLimits::default(),
+        );
+        assert!(unzipped.is_ok());
+        let unzipped = unzipped.unwrap(); //#[allow_ci]
+        assert_eq!(unzipped, secure_dir.path().join("unzipped"));
+        assert!(unzipped.join("autorun.sh").exists());
+    }
+
+    #[test]
+    fn staged_payload() {
+        let secure_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let staging = secure_dir.path().join(STAGING_DIR);
+        let limits = ExtractLimits::default();
+        let zip_path = secure_dir.path().join("zip");
+        create_zip(&zip_path, &[("autorun.sh", b"echo new\n")]);
+        let payload = fs::read(&zip_path).unwrap(); //#[allow_ci]
+
+        // A payload that cannot be extracted leaves the previous one alone
+        let unzipped = secure_dir.path().join(UNZIPPED_DIR);
+        fs::create_dir(&unzipped).unwrap(); //#[allow_ci]
+        fs::write(unzipped.join("autorun.sh"), b"echo old\n").unwrap(); //#[allow_ci]
+        let staged = stage(&staging, b"not a zip", "payload", Some(&limits));
+        assert!(staged.is_err());
+        assert!(!staging.exists());
+        assert_eq!(
+            fs::read(unzipped.join("autorun.sh")).unwrap(), //#[allow_ci]
+            b"echo old\n"
+        );
+
+        stage(&staging, &payload, "payload", Some(&limits)).unwrap(); //#[allow_ci]
+        let installed =
+            install_staged(&staging, secure_dir.path(), "payload").unwrap(); //#[allow_ci]
+        assert_eq!(installed, Some(unzipped.clone()));
+        assert_eq!(
+            fs::read(unzipped.join("autorun.sh")).unwrap(), //#[allow_ci]
+            b"echo new\n"
+        );
+        assert_eq!(
+            fs::read(secure_dir.path().join("payload")).unwrap(), //#[allow_ci]
+            payload
+        );
+        assert!(!staging.exists());
+    }
+
+    #[test]
+    fn extract_zip_limits() {
+        let secure_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let zip_path = secure_dir.path().join("decrypted_payload");
+        create_zip(&zip_path, &[("a", &[0u8; 64]), ("b", &[0u8; 64])]);
+
+        let limits = ExtractLimits {
+            max_size: 100,
+            max_files: 10,
+        };
+        assert!(extract_zip(&zip_path, secure_dir.path(), &limits).is_err());
+
+        let limits = ExtractLimits {
+            max_size: 1000,
+            max_files: 1,
+        };
+        assert!(extract_zip(&zip_path, secure_dir.path(), &limits).is_err());
+
+        // No limit, as configured with the largest value
+        let limits = ExtractLimits {
+            max_size: u64::MAX,
+            max_files: 10,
+        };
+        let unzipped =
+            extract_zip(&zip_path, secure_dir.path(), &limits).unwrap(); //#[allow_ci]
+        assert_eq!(fs::read(unzipped.join("a")).unwrap(), [0u8; 64]); //#[allow_ci]
+    }
+
+    #[test]
+    fn extract_zip_traversal() {
+        let secure_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let zip_path = secure_dir.path().join("decrypted_payload");
+        create_zip(&zip_path, &[("foo/../../escaped", b"")]);
+
+        assert!(extract_zip(
+            &zip_path,
+            secure_dir.path(),
+            &ExtractLimits::default()
+        )
+        .is_err());
+        assert!(!secure_dir.path().join("escaped").exists());
+    }
+
+    #[test]
+    fn zip_entry_path() {
+        assert_eq!(entry_path("a/b"), Some(PathBuf::from("a/b")));
+        assert_eq!(entry_path("./a"), Some(PathBuf::from("a")));
+        assert_eq!(entry_path("a/../b"), None);
+        assert_eq!(entry_path("/etc/passwd"), None);
+        assert_eq!(entry_path("a\0b"), None);
+        assert_eq!(entry_path(""), None);
+    }
+
+    #[test]
+    fn tls_credentials_absent() {
+        let secure_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let dir = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests"))
+            .join("unzipped");
+
+        let installed = install_tls_credentials(&dir, secure_dir.path());
+        assert!(installed.is_ok());
+        assert!(installed.unwrap().is_none()); //#[allow_ci]
+    }
+
+    #[test]
+    fn tls_credentials_installed() {
+        let unzipped = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let secure_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let key = crypto::rsa_generate(2048).unwrap(); //#[allow_ci]
+        let cert = crypto::generate_x509(&key, "agent", 1).unwrap(); //#[allow_ci]
+        let cert = cert.to_pem().unwrap(); //#[allow_ci]
+        let pem = key.private_key_to_pem_pkcs8().unwrap(); //#[allow_ci]
+        fs::write(unzipped.path().join(PAYLOAD_TLS_CERT), &cert).unwrap(); //#[allow_ci]
+        fs::write(unzipped.path().join(PAYLOAD_TLS_KEY), &pem).unwrap(); //#[allow_ci]
+
+        let tls_dir =
+            install_tls_credentials(unzipped.path(), secure_dir.path())
+                .unwrap() //#[allow_ci]
+                .unwrap(); //#[allow_ci]
+        let key_path = tls_dir.join(PAYLOAD_TLS_KEY);
+        assert_eq!(fs::read(&key_path).unwrap(), pem); //#[allow_ci]
+        let mode = fs::metadata(&key_path).unwrap().permissions().mode(); //#[allow_ci]
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(fs::read(tls_dir.join(PAYLOAD_TLS_CERT)).unwrap(), cert); //#[allow_ci]
+    }
+}
@@ -0,0 +1,250 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Dropping root privileges
+//
+// Mounting the secure directory and opening the TPM and the IMA measurement
+// list require root. Once these are done and the server socket is bound,
+// the agent switches to the user and group given by run_as in keylime.conf,
+// losing all its capabilities, so that the HTTP server and the payload
+// scripts do not run as root.
+
+use crate::common::config_get_or;
+use crate::error::{Error, Result};
+
+use std::ffi::CString;
+use std::fs;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+use tracing::{info, warn};
+
+// Size of the buffer for getpwnam_r and getgrnam_r
+const BUF_SIZE: usize = 16384;
+
+/// User and group the agent runs as after dropping privileges
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct UserIds {
+    pub user: String,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+// Splits run_as into the user and the optional group
+fn parse_run_as(run_as: &str) -> Result<(&str, Option<&str>)> {
+    let mut parts = run_as.trim().splitn(2, ':');
+    let user = parts.next().unwrap_or_default().trim();
+    let group = parts.next().map(str::trim);
+
+    if user.is_empty() || group == Some("") {
+        return Err(Error::config_value(
+            "cloud_agent",
+            "run_as",
+            format!("{:?} is not in the format user[:group]", run_as),
+        ));
+    }
+    Ok((user, group))
+}
+
+fn to_cstring(value: &str) -> Result<CString> {
+    CString::new(value)
+        .map_err(|_| Error::Other(format!("{:?} contains a NUL byte", value)))
+}
+
+// Returns the UID and primary GID of a user
+fn lookup_user(name: &str) -> Result<(libc::uid_t, libc::gid_t)> {
+    let c_name = to_cstring(name)?;
+    let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut buf: Vec<libc::c_char> = vec![0; BUF_SIZE];
+    let mut result: *mut libc::passwd = ptr::null_mut();
+
+    let ret = unsafe {
+        libc::getpwnam_r(
+            c_name.as_ptr(),
+            &mut passwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if ret != 0 || result.is_null() {
+        return Err(Error::config_value(
+            "cloud_agent",
+            "run_as",
+            format!("unknown user {}", name),
+        ));
+    }
+    Ok((passwd.pw_uid, passwd.pw_gid))
+}
+
+/// Returns the GID of a group, for the keylime.conf key it was set with
+pub(crate) fn lookup_group(name: &str, key: &str) -> Result<libc::gid_t> {
+    let c_name = to_cstring(name)?;
+    let mut group: libc::group = unsafe { mem::zeroed() };
+    let mut buf: Vec<libc::c_char> = vec![0; BUF_SIZE];
+    let mut result: *mut libc::group = ptr::null_mut();
+
+    let ret = unsafe {
+        libc::getgrnam_r(
+            c_name.as_ptr(),
+            &mut group,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if ret != 0 || result.is_null() {
+        return Err(Error::config_value(
+            "cloud_agent",
+            key,
+            format!("unknown group {}", name),
+        ));
+    }
+    Ok(group.gr_gid)
+}
+
+/*
+ * Return: Result wrap the user and group to run as, if configured
+ *
+ * Reads run_as from keylime.conf. Without a group, the primary group of the
+ * user is used. Nothing is returned if the agent is not running as root, as
+ * it then has no privileges to drop.
+ */
+pub(crate) fn run_as_get() -> Result<Option<UserIds>> {
+    let run_as = config_get_or("cloud_agent", "run_as", "")?;
+    if run_as.trim().is_empty() {
+        return Ok(None);
+    }
+
+    if unsafe { libc::geteuid() } != 0 {
+        warn!(
+            "Ignoring run_as = {}: the agent is not running as root",
+            run_as
+        );
+        return Ok(None);
+    }
+
+    let (user, group) = parse_run_as(&run_as)?;
+    let (uid, primary_gid) = lookup_user(user)?;
+    let gid = match group {
+        Some(group) => lookup_group(group, "run_as")?,
+        None => primary_gid,
+    };
+
+    Ok(Some(UserIds {
+        user: user.to_string(),
+        uid,
+        gid,
+    }))
+}
+
+/// Changes the owner of a file to the user the agent will run as
+pub(crate) fn chown(path: &Path, ids: &UserIds) -> Result<()> {
+    chown_ids(path, ids.uid, ids.gid)
+}
+
+/// Changes the owner and group of a file, MAX, -1 for lchown(2), leaves
+/// them unchanged
+pub(crate) fn chown_ids(
+    path: &Path,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+) -> Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).map_err(|_| {
+        Error::Other(format!("{} contains a NUL byte", path.display()))
+    })?;
+    // Do not follow symlinks out of the directory
+    if unsafe { libc::lchown(c_path.as_ptr(), uid, gid) } != 0 {
+        return Err(Error::Other(format!(
+            "unable to change owner of {}: {}",
+            path.display(),
+            std::io::Error::last_os_error()
+        )));
+    }
+    Ok(())
+}
+
+/// Same as chown, for a directory and everything below it
+pub(crate) fn chown_recursive(path: &Path, ids: &UserIds) -> Result<()> {
+    chown(path, ids)?;
+    if fs::symlink_metadata(path)?.is_dir() {
+        for entry in fs::read_dir(path)? {
+            chown_recursive(&entry?.path(), ids)?;
+        }
+    }
+    Ok(())
+}
+
+/*
+ * Input: user and group to run as
+ * Return: Result wrap with error message
+ *
+ * Switches the whole process to the user, the group and the user's
+ * supplementary groups. Changing the UID from root clears the permitted
+ * and effective capabilities, and the switch is checked to be
+ * irreversible.
+ */
+pub(crate) fn drop_privileges(ids: &UserIds) -> Result<()> {
+    let c_user = to_cstring(&ids.user)?;
+
+    unsafe {
+        if libc::initgroups(c_user.as_ptr(), ids.gid) != 0 {
+            return Err(Error::Other(format!(
+                "unable to set supplementary groups of {}: {}",
+                ids.user,
+                std::io::Error::last_os_error()
+            )));
+        }
+        if libc::setgid(ids.gid) != 0 {
+            return Err(Error::Other(format!(
+                "unable to set group to {}: {}",
+                ids.gid,
+                std::io::Error::last_os_error()
+            )));
+        }
+        if libc::setuid(ids.uid) != 0 {
+            return Err(Error::Other(format!(
+                "unable to set user to {}: {}",
+                ids.user,
+                std::io::Error::last_os_error()
+            )));
+        }
+
+        if ids.uid != 0 && libc::setuid(0) == 0 {
+            return Err(Error::Other(
+                "still able to regain root after dropping privileges"
+                    .to_string(),
+            ));
+        }
+    }
+
+    info!(
+        "Dropped privileges to user {} ({}), group {}",
+        ids.user, ids.uid, ids.gid
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_as_format() {
+        assert_eq!(
+            parse_run_as("keylime:tss").unwrap(), //#[allow_ci]
+            ("keylime", Some("tss"))
+        );
+        assert_eq!(parse_run_as(" keylime ").unwrap(), ("keylime", None)); //#[allow_ci]
+        assert!(parse_run_as(":tss").is_err());
+        assert!(parse_run_as("keylime:").is_err());
+    }
+
+    #[test]
+    fn lookup_root() {
+        assert_eq!(lookup_user("root").unwrap(), (0, 0)); //#[allow_ci]
+        assert_eq!(lookup_group("root", "run_as").unwrap(), 0); //#[allow_ci]
+        assert!(lookup_user("no-such-keylime-user").is_err());
+    }
+}
@@ -0,0 +1,128 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Crash-safe writes
+//
+// Files the agent relies on after a restart are never modified in place:
+// the new contents go to a temporary file next to it, which is synced and
+// renamed over the old file, and the directory is synced so that the
+// rename itself survives a power loss. Either the old or the new contents
+// are found afterwards, never a mix. What a crash can leave behind is the
+// temporary file, which recover() removes when the agent starts.
+
+use crate::error::Result;
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+// Temporary file the new contents of a file are written to
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+fn sync_dir(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/*
+ * Input: file to write, its permissions and contents
+ * Return: Result wrap with error message
+ *
+ * Replaces the contents of the file atomically and durably.
+ */
+pub(crate) fn write(path: &Path, mode: u32, contents: &[u8]) -> Result<()> {
+    let tmp = tmp_path(path);
+    let result = (|| {
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(mode)
+            .open(&tmp)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        sync_dir(path)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+/*
+ * Input: file written with write()
+ * Return: Result wrap whether an interrupted write was cleaned up
+ *
+ * The file itself holds the last complete contents, so the temporary
+ * file of a write interrupted by a crash is simply removed.
+ */
+pub(crate) fn recover(path: &Path) -> Result<bool> {
+    let tmp = tmp_path(path);
+    match fs::remove_file(&tmp) {
+        Ok(()) => {
+            warn!(
+                "Removed {} left by an interrupted write, keeping the previous {}",
+                tmp.display(),
+                path.display()
+            );
+            Ok(true)
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/*
+ * Input: file that cannot be parsed
+ * Return: Result wrap with error message
+ *
+ * Moves a file corrupted by a write that was not atomic, e.g. by an
+ * earlier version of the agent, out of the way, keeping it for
+ * investigation.
+ */
+pub(crate) fn set_aside(path: &Path) -> Result<()> {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".corrupt");
+    let aside = path.with_file_name(name);
+    fs::rename(path, &aside)?;
+    warn!("Moved corrupt {} to {}", path.display(), aside.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn atomic_write() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join("state.json");
+
+        write(&path, 0o600, b"old").unwrap(); //#[allow_ci]
+        write(&path, 0o600, b"new").unwrap(); //#[allow_ci]
+        assert_eq!(fs::read(&path).unwrap(), b"new"); //#[allow_ci]
+        let mode = fs::metadata(&path).unwrap().permissions().mode(); //#[allow_ci]
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(!tmp_path(&path).exists());
+
+        // A crash before the rename leaves the previous contents
+        fs::write(tmp_path(&path), b"partial").unwrap(); //#[allow_ci]
+        assert!(recover(&path).unwrap()); //#[allow_ci]
+        assert!(!recover(&path).unwrap()); //#[allow_ci]
+        assert_eq!(fs::read(&path).unwrap(), b"new"); //#[allow_ci]
+    }
+}
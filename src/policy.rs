@@ -0,0 +1,256 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Runtime policies
+//
+// The tenant can deliver the runtime policy the agent is attested against,
+// the IMA allowlist and exclude list, to the agent as well. It is stored in
+// the secure directory and loaded again when the agent restarts. The agent
+// appraises the IMA entries it saves in evidence bundles against it, and
+// records which policy they were appraised against, see evidence.rs.
+// Appraisal failures are only recorded: the verifier remains the one to
+// decide whether the agent is trusted.
+//
+// Every policy comes with a version, which the tenant authenticates along
+// with the policy, see keys_handler::runtime_policy(). Versions must
+// increase, so that a policy replaced by a newer one cannot be delivered
+// again.
+//
+// Both the runtime policy format of current Keylime versions, with
+// "digests", and the older allowlists, with "hashes", are accepted.
+
+use crate::common::RUNTIME_POLICY;
+use crate::error::Result;
+use crate::persist;
+
+use openssl::sha::sha256;
+use regex::RegexSet;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use tracing::info;
+
+#[derive(Deserialize)]
+struct PolicyDocument {
+    #[serde(alias = "hashes")]
+    digests: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    excludes: Vec<String>,
+}
+
+// The policy as stored in the secure directory
+#[derive(Deserialize, Serialize)]
+struct StoredPolicy {
+    version: u64,
+    runtime_policy: String,
+}
+
+#[derive(Debug)]
+pub(crate) struct RuntimePolicy {
+    digests: HashMap<String, Vec<String>>,
+    excludes: RegexSet,
+    version: u64,
+    // The policy as delivered, and its SHA-256 digest
+    document: Vec<u8>,
+    sha256: String,
+}
+
+impl RuntimePolicy {
+    pub(crate) fn parse(document: Vec<u8>, version: u64) -> Result<Self> {
+        let parsed: PolicyDocument = serde_json::from_slice(&document)?;
+        let excludes = RegexSet::new(&parsed.excludes)?;
+        let digests = parsed
+            .digests
+            .into_iter()
+            .map(|(path, digests)| {
+                let digests =
+                    digests.iter().map(|d| d.to_lowercase()).collect();
+                (path, digests)
+            })
+            .collect();
+
+        Ok(RuntimePolicy {
+            digests,
+            excludes,
+            version,
+            sha256: hex::encode(sha256(&document)),
+            document,
+        })
+    }
+
+    pub(crate) fn document(&self) -> &[u8] {
+        &self.document
+    }
+
+    pub(crate) fn sha256(&self) -> &str {
+        &self.sha256
+    }
+
+    pub(crate) fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Whether this policy may replace the current one: only newer
+    /// versions, or the current policy delivered again, are accepted
+    pub(crate) fn replaces(&self, current: Option<&RuntimePolicy>) -> bool {
+        match current {
+            None => true,
+            Some(current) => {
+                self.version > current.version
+                    || (self.version == current.version
+                        && self.sha256 == current.sha256)
+            }
+        }
+    }
+
+    /// Stores the policy in the secure directory, for load() after a
+    /// restart
+    pub(crate) fn save(&self, secure_dir: &Path) -> Result<()> {
+        let stored = serde_json::to_vec(&StoredPolicy {
+            version: self.version,
+            runtime_policy: base64::encode(&self.document),
+        })?;
+        persist::write(&secure_dir.join(RUNTIME_POLICY), 0o600, &stored)
+    }
+
+    /*
+     * Input: IMA measurement list entries
+     * Return: the entries not allowed by the policy, with the reason
+     *
+     * Only ima-ng entries are appraised. The boot aggregate is checked by
+     * the verifier against the PCRs.
+     */
+    pub(crate) fn appraise(&self, entries: &str) -> Vec<String> {
+        let mut failures = Vec::new();
+        for entry in entries.lines() {
+            let fields: Vec<&str> = entry.splitn(5, ' ').collect();
+            let (digest, path) = match fields.as_slice() {
+                [_, _, "ima-ng", digest, path] => (digest, *path),
+                _ => continue,
+            };
+            if path == "boot_aggregate" || self.excludes.is_match(path) {
+                continue;
+            }
+            // The digest is prefixed with its algorithm
+            let digest = digest.rsplit(':').next().unwrap_or(digest);
+
+            match self.digests.get(path) {
+                None => failures.push(format!("{}: not in policy", path)),
+                Some(allowed)
+                    if !allowed
+                        .iter()
+                        .any(|d| d == &digest.to_lowercase()) =>
+                {
+                    failures.push(format!(
+                        "{}: digest {} not allowed",
+                        path, digest
+                    ))
+                }
+                Some(_) => {}
+            }
+        }
+        failures
+    }
+}
+
+/*
+ * Input: secure directory
+ * Return: Result wrap the runtime policy stored there, if any
+ */
+pub(crate) fn load(secure_dir: &Path) -> Result<Option<RuntimePolicy>> {
+    match fs::read(secure_dir.join(RUNTIME_POLICY)) {
+        Ok(stored) => {
+            let stored: StoredPolicy = serde_json::from_slice(&stored)?;
+            let document = base64::decode(&stored.runtime_policy)?;
+            let policy = RuntimePolicy::parse(document, stored.version)?;
+            info!(
+                "Loaded runtime policy {} version {}",
+                policy.sha256(),
+                policy.version()
+            );
+            Ok(Some(policy))
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runtime_policy_appraisal() {
+        let policy = RuntimePolicy::parse(
+            br#"{
+                "meta": {"version": 1},
+                "digests": {
+                    "/usr/bin/bash": ["AA11", "bb22"],
+                    "/usr/bin/ls": ["cc33"]
+                },
+                "excludes": ["^/tmp/.*"]
+            }"#
+            .to_vec(),
+            1,
+        )
+        .unwrap(); //#[allow_ci]
+
+        let entries = "10 00 ima-ng sha256:00 boot_aggregate\n\
+                       10 01 ima-ng sha256:aa11 /usr/bin/bash\n\
+                       10 02 ima-ng sha256:dd44 /usr/bin/ls\n\
+                       10 03 ima-ng sha256:ee55 /tmp/build script\n\
+                       10 04 ima-ng sha256:ff66 /usr/bin/nc\n\
+                       10 05 ima-buf sha256:ab device_resume 6e616d65\n";
+        assert_eq!(
+            policy.appraise(entries),
+            vec![
+                "/usr/bin/ls: digest dd44 not allowed",
+                "/usr/bin/nc: not in policy"
+            ]
+        );
+
+        // Older allowlists
+        let allowlist = RuntimePolicy::parse(
+            br#"{"hashes": {"/usr/bin/ls": ["cc33"]}}"#.to_vec(),
+            1,
+        )
+        .unwrap(); //#[allow_ci]
+        assert!(allowlist
+            .appraise("10 01 ima-ng sha1:cc33 /usr/bin/ls\n")
+            .is_empty());
+
+        assert!(RuntimePolicy::parse(
+            br#"{"digests": {}, "excludes": ["("]}"#.to_vec(),
+            1
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn runtime_policy_versions() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        assert!(load(dir.path()).unwrap().is_none()); //#[allow_ci]
+
+        let document = br#"{"digests": {}}"#.to_vec();
+        let policy = RuntimePolicy::parse(document.clone(), 2).unwrap(); //#[allow_ci]
+        assert!(policy.replaces(None));
+        policy.save(dir.path()).unwrap(); //#[allow_ci]
+        let loaded = load(dir.path()).unwrap().unwrap(); //#[allow_ci]
+        assert_eq!(loaded.version(), 2);
+        assert_eq!(loaded.document(), &document[..]);
+
+        // Older versions, or another policy with the same version, are
+        // replays
+        let older = RuntimePolicy::parse(document.clone(), 1).unwrap(); //#[allow_ci]
+        assert!(!older.replaces(Some(&loaded)));
+        let other =
+            RuntimePolicy::parse(br#"{"digests": {"/a": []}}"#.to_vec(), 2)
+                .unwrap(); //#[allow_ci]
+        assert!(!other.replaces(Some(&loaded)));
+        assert!(policy.replaces(Some(&loaded)));
+        let newer = RuntimePolicy::parse(document, 3).unwrap(); //#[allow_ci]
+        assert!(newer.replaces(Some(&loaded)));
+    }
+}
@@ -0,0 +1,336 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Push attestation
+//
+// In the push model proposed for Keylime, the agent does not wait for the
+// verifier to request quotes: with push_verifier_url set, it attests
+// itself every push_interval seconds, once registered. Each round, it asks
+// the verifier for a challenge:
+//
+//   POST <url>/v2.0/agents/<uuid>/attestations
+//
+// which returns the nonce, the PCR mask and the first IMA entry the
+// verifier does not have yet, in the usual envelope, then quotes and sends
+// the evidence, shaped like the results of an integrity quote:
+//
+//   PUT <url>/v2.0/agents/<uuid>/attestations
+//
+// When the verifier cannot be reached or rejects the evidence, the interval
+// is doubled for each failed round, up to MAX_BACKOFF. The agent keeps
+// serving pull requests all the same.
+//
+// The verifier URL must be https://, the certificate of the verifier being
+// checked against the system's CAs and push_verifier_ca_cert, if set. Plain
+// HTTP, e.g. for a verifier on the same host, takes push_insecure_http.
+
+use crate::api::ApiVersion;
+use crate::common::{config_get_bool_or, config_get_or, work_dir_get};
+use crate::error::{Error, Result};
+use crate::quotes_handler::{self, KeylimeIntegrityQuote};
+use crate::{http, idle, quote, QuoteData};
+
+use actix_web::web::Data;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use tracing::{info, warn, Instrument};
+
+const DEFAULT_PUSH_INTERVAL: &str = "2";
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub(crate) struct PushConfig {
+    url: String,
+    interval: Duration,
+    client: reqwest::Client,
+}
+
+#[derive(Debug, Deserialize)]
+struct Response<T> {
+    results: T,
+}
+
+// What the verifier asks the agent to quote
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+struct Challenge {
+    nonce: String,
+    mask: String,
+    #[serde(default)]
+    ima_ml_entry: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct Evidence<'a> {
+    nonce: &'a str,
+    #[serde(flatten)]
+    quote: KeylimeIntegrityQuote<'a>,
+}
+
+/*
+ * Return: Result wrap the push configuration, None if disabled
+ */
+pub(crate) fn config_get() -> Result<Option<PushConfig>> {
+    let url = config_get_or("cloud_agent", "push_verifier_url", "")?;
+    if url.is_empty() {
+        return Ok(None);
+    }
+    let interval =
+        config_get_or("cloud_agent", "push_interval", DEFAULT_PUSH_INTERVAL)?
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|interval| interval.is_finite() && *interval > 0.0)
+            .ok_or_else(|| {
+                Error::config_value(
+                    "cloud_agent",
+                    "push_interval",
+                    "not a positive number of seconds",
+                )
+            })?;
+
+    let insecure_http =
+        config_get_bool_or("cloud_agent", "push_insecure_http", false)?;
+    check_url(&url, insecure_http)?;
+    if insecure_http && url.starts_with("http://") {
+        warn!("Pushing attestations to {} over plain HTTP", url);
+    }
+
+    let ca_cert = config_get_or("cloud_agent", "push_verifier_ca_cert", "")?;
+    let client = if ca_cert.is_empty() {
+        http::client().clone()
+    } else {
+        let path = Path::new(&work_dir_get()).join(ca_cert);
+        let pem = fs::read(&path).map_err(|e| Error::file(&path, e))?;
+        http::client_with_ca(reqwest::Certificate::from_pem(&pem)?)?
+    };
+
+    Ok(Some(PushConfig {
+        url: url.trim_end_matches('/').to_string(),
+        interval: Duration::from_secs_f64(interval),
+        client,
+    }))
+}
+
+// Challenges and evidence go over TLS, unless plain HTTP is allowed
+fn check_url(url: &str, insecure_http: bool) -> Result<()> {
+    if url.starts_with("https://")
+        || (insecure_http && url.starts_with("http://"))
+    {
+        return Ok(());
+    }
+    let reason = if url.starts_with("http://") {
+        "plain HTTP requires push_insecure_http"
+    } else {
+        "not an https:// URL"
+    };
+    Err(Error::config_value(
+        "cloud_agent",
+        "push_verifier_url",
+        reason,
+    ))
+}
+
+fn attestations_url(config: &PushConfig, agent_uuid: &str) -> String {
+    format!(
+        "{}/v{}/agents/{}/attestations",
+        config.url,
+        ApiVersion::CURRENT,
+        agent_uuid
+    )
+}
+
+// Interval before the next round, after failures rounds in a row failed
+fn backoff(interval: Duration, failures: u32) -> Duration {
+    interval
+        .checked_mul(1u32 << failures.min(16))
+        .map_or(MAX_BACKOFF, |backoff| backoff.min(MAX_BACKOFF))
+        .max(interval)
+}
+
+async fn get_challenge(
+    client: &reqwest::Client,
+    addr: &str,
+) -> Result<Challenge> {
+    let resp = client.post(addr).timeout(REQUEST_TIMEOUT).send().await?;
+    if !resp.status().is_success() {
+        return Err(Error::Verifier {
+            addr: addr.to_string(),
+            code: resp.status().as_u16(),
+        });
+    }
+
+    let challenge = resp.json::<Response<Challenge>>().await?.results;
+    if !challenge.nonce.chars().all(char::is_alphanumeric)
+        || !challenge.mask.chars().all(char::is_alphanumeric)
+    {
+        return Err(Error::InvalidRequest);
+    }
+    Ok(challenge)
+}
+
+async fn send_evidence(
+    client: &reqwest::Client,
+    addr: &str,
+    evidence: &Evidence<'_>,
+) -> Result<()> {
+    let resp = client
+        .put(addr)
+        .timeout(REQUEST_TIMEOUT)
+        .json(evidence)
+        .send()
+        .await?;
+    if !resp.status().is_success() {
+        return Err(Error::Verifier {
+            addr: addr.to_string(),
+            code: resp.status().as_u16(),
+        });
+    }
+    Ok(())
+}
+
+// One round: challenge, quote and evidence
+async fn attest(
+    client: &reqwest::Client,
+    addr: &str,
+    data: &Data<QuoteData>,
+) -> Result<()> {
+    let challenge = get_challenge(client, addr).await?;
+    idle::quote_requested();
+
+    let mut quote = {
+        let _permit = data.limits.quotes.acquire()?;
+        quote::quote(
+            challenge.nonce.as_bytes(),
+            Some(&challenge.mask),
+            data.clone(),
+        )
+        .await?
+    };
+    let ima_data = data.clone();
+    let ml = tokio::task::spawn_blocking(move || {
+        quotes_handler::read_ima_ml(&ima_data)
+    })
+    .await??;
+    quote.pubkey = &data.pub_key_pem;
+    quotes_handler::save_evidence(
+        data,
+        &challenge.nonce,
+        Some(&challenge.mask),
+        &quote,
+        Some(ml.clone()),
+    )
+    .await;
+
+    let (first, ml) = ml.starting_at(challenge.ima_ml_entry.unwrap_or(0));
+    let ml = ml.into_chunks().collect::<Vec<_>>().concat();
+    let mut quote = KeylimeIntegrityQuote::from_id_quote(&quote, &ml);
+    quote.ima_measurement_list_entry = Some(first);
+
+    send_evidence(
+        client,
+        addr,
+        &Evidence {
+            nonce: &challenge.nonce,
+            quote,
+        },
+    )
+    .await
+}
+
+/// Attests the agent to the verifier every interval, until the agent stops
+pub(crate) async fn run(config: PushConfig, data: Data<QuoteData>) {
+    let addr = attestations_url(&config, &data.agent_uuid);
+    info!(
+        "Pushing attestations to {} every {:?}",
+        addr, config.interval
+    );
+
+    let mut failures = 0;
+    loop {
+        let result = attest(&config.client, &addr, &data)
+            .instrument(
+                tracing::info_span!("push_attestation", verifier = %addr),
+            )
+            .await;
+        match result {
+            Ok(()) => failures = 0,
+            Err(e) => {
+                failures += 1;
+                warn!(
+                    "Unable to push attestation to {} ({} failures in a row): {}",
+                    addr, failures, e
+                );
+            }
+        }
+        tokio::time::delay_for(backoff(config.interval, failures)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[test]
+    fn push_backoff() {
+        let interval = Duration::from_secs(2);
+        assert_eq!(backoff(interval, 0), interval);
+        assert_eq!(backoff(interval, 3), Duration::from_secs(16));
+        assert_eq!(backoff(interval, 100), MAX_BACKOFF);
+        assert_eq!(
+            backoff(Duration::from_secs(600), 1),
+            Duration::from_secs(600)
+        );
+    }
+
+    #[test]
+    fn push_url() {
+        assert!(check_url("https://verifier:8881", false).is_ok());
+        assert!(check_url("http://127.0.0.1:8881", true).is_ok());
+        assert!(matches!(
+            check_url("http://127.0.0.1:8881", false),
+            Err(Error::ConfigValue { .. })
+        ));
+        assert!(check_url("verifier:8881", true).is_err());
+    }
+
+    #[tokio::test]
+    async fn push_challenge() {
+        let mock_server = MockServer::start().await;
+        let mock = Mock::given(method("POST"))
+            .and(path("/v2.0/agents/uuid/attestations"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "code": 200,
+                "status": "Success",
+                "results": {"nonce": "abc123", "mask": "0x408000", "ima_ml_entry": 7},
+            })));
+        mock_server.register(mock).await;
+        let config = PushConfig {
+            url: mock_server.uri(),
+            interval: Duration::from_secs(2),
+            client: http::client().clone(),
+        };
+
+        let addr = attestations_url(&config, "uuid");
+        let challenge = get_challenge(&config.client, &addr).await.unwrap(); //#[allow_ci]
+        assert_eq!(
+            challenge,
+            Challenge {
+                nonce: String::from("abc123"),
+                mask: String::from("0x408000"),
+                ima_ml_entry: Some(7),
+            }
+        );
+
+        let addr = attestations_url(&config, "other");
+        assert!(matches!(
+            get_challenge(&config.client, &addr).await,
+            Err(Error::Verifier { code: 404, .. })
+        ));
+    }
+}
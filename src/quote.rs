@@ -0,0 +1,213 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Quotes of the agent
+//
+// The TPM quote itself is in the library, see tpm.rs. This runs it on the
+// TPM worker with the agent's AK and NK, and keeps the agent's state up to
+// date: telemetry, alerts, the time of the last quote, and a restart when
+// the TPM was cleared. On SEV-SNP guests, the attestation report of the
+// guest is attached to the quote, see snp.rs, and in trust domains the TDX
+// quote, see tdx.rs. What the quotes are made of is read from keylime.conf
+// once, at startup.
+
+use crate::common::{config_get, config_get_bool_or};
+use crate::error::{Error, Result};
+use crate::quotes_handler::KeylimeIdQuote;
+#[cfg(feature = "tdx")]
+use crate::tdx;
+use crate::{alerts, signals, snp, telemetry, tpm, QuoteData};
+
+use actix_web::web::Data;
+use std::sync::atomic::Ordering;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{error, Instrument};
+use tss_esapi::interface_types::algorithm::HashingAlgorithm;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct QuoteConfig {
+    // PCR bank quoted, tpm_hash_alg
+    pub(crate) hash_alg: HashingAlgorithm,
+    pub(crate) sev_snp_evidence: bool,
+    pub(crate) tdx_evidence: bool,
+}
+
+impl QuoteConfig {
+    pub(crate) fn from_config() -> Result<Self> {
+        Ok(QuoteConfig {
+            hash_alg: tpm::get_hash_alg(config_get(
+                "cloud_agent",
+                "tpm_hash_alg",
+            )?)?,
+            sev_snp_evidence: config_get_bool_or(
+                "cloud_agent",
+                "sev_snp_evidence",
+                false,
+            )?,
+            tdx_evidence: config_get_bool_or(
+                "cloud_agent",
+                "tdx_evidence",
+                false,
+            )?,
+        })
+    }
+}
+
+impl Default for QuoteConfig {
+    fn default() -> Self {
+        QuoteConfig {
+            hash_alg: HashingAlgorithm::Sha256,
+            sev_snp_evidence: false,
+            tdx_evidence: false,
+        }
+    }
+}
+
+// Despite the return type, this function is used for both Identity and
+// Integrity Quotes. The Quote handler will add additional information to
+// turn an Identity Quote into an Integrity Quote.
+pub(crate) async fn quote(
+    nonce: &[u8],
+    mask: Option<&str>,
+    data: Data<QuoteData>,
+) -> Result<KeylimeIdQuote<'static>> {
+    let span = tracing::info_span!(
+        "quote",
+        nonce = %String::from_utf8_lossy(nonce),
+        mask = mask.unwrap_or("none")
+    );
+
+    let result = make_quote(nonce, mask, &data).instrument(span).await;
+    if let Err(e) = &result {
+        alerts::failure(e);
+    }
+    match result {
+        Ok(_) => {
+            telemetry::count(telemetry::Counter::Quotes);
+            if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
+                data.last_quote.store(now.as_secs(), Ordering::SeqCst);
+            }
+        }
+        Err(_) => telemetry::count(telemetry::Counter::QuoteFailures),
+    }
+    result
+}
+
+async fn make_quote(
+    nonce: &[u8],
+    mask: Option<&str>,
+    data: &QuoteData,
+) -> Result<KeylimeIdQuote<'static>> {
+    let config = data.quote_config;
+    let quote = tpm_quote(nonce, mask, config.hash_alg, data).await?;
+
+    // Requested after the quote, so that they are at least as fresh
+    let snp_report = if config.sev_snp_evidence {
+        let nonce = nonce.to_vec();
+        let report = tokio::task::spawn_blocking(move || snp::report(&nonce))
+            .await??;
+        Some(base64::encode(report))
+    } else {
+        None
+    };
+    let tdx_quote = if config.tdx_evidence {
+        Some(base64::encode(tdx_quote(nonce.to_vec()).await?))
+    } else {
+        None
+    };
+
+    Ok(KeylimeIdQuote {
+        quote,
+        snp_report,
+        tdx_quote,
+        ..Default::default()
+    })
+}
+
+// Quotes on the TPM worker with the agent's AK and NK
+async fn tpm_quote(
+    nonce: &[u8],
+    mask: Option<&str>,
+    hash_alg: HashingAlgorithm,
+    data: &QuoteData,
+) -> Result<String> {
+    let nk_digest = tpm::pubkey_to_tpm_digest(&data.pub_key, hash_alg)?;
+
+    let nonce = nonce.to_vec();
+    let mask = mask.map(String::from);
+    let ak_handle = data.ak_handle;
+    let span = tracing::Span::current();
+    data.tpm
+        .run(move |backend| {
+            let _span = span.enter();
+            let quote = backend.quote(
+                &nonce,
+                mask.as_deref(),
+                hash_alg,
+                nk_digest,
+                ak_handle,
+            );
+            // The agent needs a new AK and to register again
+            if let Err(e) = &quote {
+                if tpm::is_flushed_error(e)
+                    && backend.tpm_cleared().unwrap_or(false)
+                {
+                    error!(
+                        event = "tpm_cleared",
+                        "TPM was cleared, restarting the agent"
+                    );
+                    signals::request_restart();
+                }
+            }
+            quote
+        })
+        .await
+}
+
+#[cfg(feature = "tdx")]
+async fn tdx_quote(nonce: Vec<u8>) -> Result<Vec<u8>> {
+    tokio::task::spawn_blocking(move || tdx::quote(&nonce)).await?
+}
+
+#[cfg(not(feature = "tdx"))]
+async fn tdx_quote(_nonce: Vec<u8>) -> Result<Vec<u8>> {
+    Err(Error::config_value(
+        "cloud_agent",
+        "tdx_evidence",
+        "requires an agent built with the tdx feature",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tpm_backend::{quote_data, MockTpm};
+
+    #[tokio::test]
+    async fn quote_mock_tpm() {
+        let tpm = MockTpm::new();
+        tpm.set_pcr(10, &[0xa5; 32]);
+        let data = quote_data(tpm).unwrap(); //#[allow_ci]
+
+        let quote = tpm_quote(
+            b"nonce",
+            Some("0x400"),
+            HashingAlgorithm::Sha256,
+            &data,
+        )
+        .await
+        .unwrap(); //#[allow_ci]
+        let parts = quote[1..].split(':').collect::<Vec<_>>();
+        assert_eq!(base64::decode(parts[0]).unwrap(), b"mock quotenonce"); //#[allow_ci]
+        assert_eq!(base64::decode(parts[2]).unwrap(), vec![0xa5; 32]); //#[allow_ci]
+
+        assert!(tpm_quote(
+            b"nonce",
+            Some("mask"),
+            HashingAlgorithm::Sha256,
+            &data
+        )
+        .await
+        .is_err());
+    }
+}
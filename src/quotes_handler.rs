@@ -1,14 +1,13 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright 2021 Keylime Authors
 
-use crate::{tpm, Error as KeylimeError, QuoteData};
+use crate::api::ApiVersion;
+use crate::{idle, ima, quote, Error as KeylimeError, QuoteData, Result};
 
-use actix_web::{web, HttpResponse, Responder};
-use log::*;
+use actix_web::{web, web::Bytes, HttpResponse, Responder};
+use futures::{future, stream, Stream, StreamExt};
 use serde::{Deserialize, Serialize};
-use std::fs::read_to_string;
-
-const IMA_PATH: &str = "/sys/kernel/security/ima/ascii_runtime_measurements";
+use tracing::{info, warn};
 
 #[derive(Deserialize)]
 pub struct Ident {
@@ -21,27 +20,38 @@ pub struct Integ {
     mask: String,
     vmask: String,
     partial: String,
+    // Since API 2.0, the first IMA entry to return
+    ima_ml_entry: Option<usize>,
 }
 
 // The fields of this struct and their default values must
-// match what is expected by Python Keylime.
+// match what is expected by Python Keylime. The response structs borrow
+// what does not change between requests, such as the NK.
 #[derive(Serialize, Debug)]
-pub(crate) struct KeylimeIdQuote {
+pub(crate) struct KeylimeIdQuote<'a> {
     pub quote: String, // 'r' + quote + sig + pcrblob
-    pub hash_alg: String,
-    pub enc_alg: String,
-    pub sign_alg: String,
-    pub pubkey: String,
+    pub hash_alg: &'static str,
+    pub enc_alg: &'static str,
+    pub sign_alg: &'static str,
+    pub pubkey: &'a str,
+    // SEV-SNP attestation report, base64 encoded, see snp.rs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snp_report: Option<String>,
+    // TDX quote, base64 encoded, see tdx.rs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tdx_quote: Option<String>,
 }
 
-impl Default for KeylimeIdQuote {
+impl Default for KeylimeIdQuote<'_> {
     fn default() -> Self {
         KeylimeIdQuote {
             quote: String::from("r"),
-            hash_alg: String::from("sha256"),
-            enc_alg: String::from("rsa"),
-            sign_alg: String::from("rsassa"),
-            pubkey: String::from(""),
+            hash_alg: "sha256",
+            enc_alg: "rsa",
+            sign_alg: "rsassa",
+            pubkey: "",
+            snp_report: None,
+            tdx_quote: None,
         }
     }
 }
@@ -49,59 +59,74 @@ impl Default for KeylimeIdQuote {
 // The fields of this struct and their default values must
 // match what is expected by Python Keylime.
 #[derive(Serialize, Debug)]
-pub(crate) struct KeylimeIntegrityQuote {
-    pub quote: String, // 'r' + quote + sig + pcrblob
-    pub hash_alg: String,
-    pub enc_alg: String,
-    pub sign_alg: String,
-    pub pubkey: String,
-    pub ima_measurement_list: String,
+pub(crate) struct KeylimeIntegrityQuote<'a> {
+    pub quote: &'a str, // 'r' + quote + sig + pcrblob
+    pub hash_alg: &'a str,
+    pub enc_alg: &'a str,
+    pub sign_alg: &'a str,
+    pub pubkey: &'a str,
+    // Since API 2.0
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ima_measurement_list_entry: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snp_report: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tdx_quote: Option<&'a str>,
+    // Streamed after the rest, so it must stay the last field, see
+    // integrity_body()
+    pub ima_measurement_list: &'a str,
 }
 
-impl KeylimeIntegrityQuote {
-    fn from_id_quote(idquote: KeylimeIdQuote, ima: String) -> Self {
+impl<'a> KeylimeIntegrityQuote<'a> {
+    pub(crate) fn from_id_quote(
+        idquote: &'a KeylimeIdQuote,
+        ima: &'a str,
+    ) -> Self {
         KeylimeIntegrityQuote {
-            quote: idquote.quote,
+            quote: &idquote.quote,
             hash_alg: idquote.hash_alg,
             enc_alg: idquote.enc_alg,
             sign_alg: idquote.sign_alg,
             pubkey: idquote.pubkey,
+            ima_measurement_list_entry: None,
+            snp_report: idquote.snp_report.as_deref(),
+            tdx_quote: idquote.tdx_quote.as_deref(),
             ima_measurement_list: ima,
         }
     }
 }
 
 #[derive(Serialize)]
-struct JsonIdWrapper {
+struct JsonIdWrapper<'a> {
     code: u32,
-    status: String,
-    results: KeylimeIdQuote,
+    status: &'static str,
+    results: KeylimeIdQuote<'a>,
 }
 
 // The fields of this struct and their default values must
 // match what is expected by Python Keylime.
 #[derive(Serialize)]
-struct JsonIntegWrapper {
+struct JsonIntegWrapper<'a> {
     code: u32,
-    status: String,
-    results: KeylimeIntegrityQuote,
+    status: &'static str,
+    results: KeylimeIntegrityQuote<'a>,
 }
 
-impl JsonIdWrapper {
-    fn new(results: KeylimeIdQuote) -> Self {
+impl<'a> JsonIdWrapper<'a> {
+    fn new(results: KeylimeIdQuote<'a>) -> Self {
         JsonIdWrapper {
             code: 200,
-            status: String::from("Success"),
+            status: "Success",
             results,
         }
     }
 }
 
-impl JsonIntegWrapper {
-    fn new(results: KeylimeIntegrityQuote) -> Self {
+impl<'a> JsonIntegWrapper<'a> {
+    fn new(results: KeylimeIntegrityQuote<'a>) -> Self {
         JsonIntegWrapper {
             code: 200,
-            status: String::from("Success"),
+            status: "Success",
             results,
         }
     }
@@ -124,21 +149,122 @@ pub async fn identity(
             .await
     } else {
         info!("Calling Identity Quote with nonce: {}", param.nonce);
+        let _permit = data.limits.quotes.acquire()?;
 
         let mut quote =
-            tpm::quote(param.nonce.as_bytes(), None, data.clone())?;
-        quote.pubkey = String::from_utf8(
-            data.pub_key
-                .public_key_to_pem()
-                .map_err(KeylimeError::from)?,
-        )
-        .map_err(KeylimeError::from)?;
+            quote::quote(param.nonce.as_bytes(), None, data.clone()).await?;
+        quote.pubkey = &data.pub_key_pem;
+        save_evidence(&data, &param.nonce, None, &quote, None).await;
+
+        identity_response(quote).await
+    }
+}
+
+pub(crate) fn identity_response(quote: KeylimeIdQuote) -> HttpResponse {
+    HttpResponse::Ok().json(JsonIdWrapper::new(quote))
+}
+
+// The body of identity_response
+pub(crate) fn identity_body(quote: KeylimeIdQuote) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec(&JsonIdWrapper::new(quote))?)
+}
+
+// Saves the quote in an evidence bundle, if enabled, see evidence.rs. The
+// quote is returned all the same if that fails.
+pub(crate) async fn save_evidence(
+    data: &web::Data<QuoteData>,
+    nonce: &str,
+    mask: Option<&str>,
+    quote: &KeylimeIdQuote<'_>,
+    ml: Option<ima::Entries>,
+) {
+    if data.evidence.is_none() {
+        return;
+    }
 
-        let response = JsonIdWrapper::new(quote);
-        HttpResponse::Ok().json(response).await
+    let data = data.clone();
+    let policy = data.runtime_policy.lock().unwrap().clone(); //#[allow_ci]
+    let nonce = nonce.to_string();
+    let mask = mask.map(String::from);
+    let (quote, hash_alg, enc_alg, sign_alg, snp_report, tdx_quote) = (
+        quote.quote.clone(),
+        quote.hash_alg,
+        quote.enc_alg,
+        quote.sign_alg,
+        quote.snp_report.clone(),
+        quote.tdx_quote.clone(),
+    );
+    let result = tokio::task::spawn_blocking(move || match &data.evidence {
+        Some(evidence) => {
+            let quote = KeylimeIdQuote {
+                quote,
+                hash_alg,
+                enc_alg,
+                sign_alg,
+                pubkey: &data.pub_key_pem,
+                snp_report,
+                tdx_quote,
+            };
+            evidence
+                .save(
+                    &data.agent_uuid,
+                    &data.priv_key,
+                    &nonce,
+                    mask.as_deref(),
+                    &quote,
+                    ml,
+                    policy.as_deref(),
+                )
+                .map(|_| ())
+        }
+        None => Ok(()),
+    })
+    .await;
+
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => warn!("Unable to save evidence bundle: {}", e),
+        Err(e) => warn!("Unable to save evidence bundle: {}", e),
     }
 }
 
+pub(crate) fn read_ima_ml(data: &QuoteData) -> Result<ima::Entries> {
+    let mut ml = data.ima_ml.lock().unwrap(); //#[allow_ci]
+    ml.update(data.limits.max_ima_ml_size)
+}
+
+// JSON string contents of a chunk of the measurement list
+fn escape(chunk: &str) -> Result<Bytes> {
+    let quoted = serde_json::to_vec(chunk)?;
+    Ok(Bytes::copy_from_slice(&quoted[1..quoted.len() - 1]))
+}
+
+/*
+ * Input: quote with an empty measurement list, and the measurement list
+ * Return: Result wrap the response body
+ *
+ * The measurement list is the last field of the response, so the response
+ * is sent as the JSON up to the opening quote of the list, then the list
+ * chunk by chunk as it is escaped, then the end of the JSON. The list is
+ * never copied whole.
+ */
+pub(crate) fn integrity_body(
+    quote: KeylimeIntegrityQuote,
+    ml: ima::Entries,
+) -> Result<impl Stream<Item = Result<Bytes>> + Unpin> {
+    let mut head = serde_json::to_vec(&JsonIntegWrapper::new(quote))?;
+    if !head.ends_with(b"\"\"}}") {
+        return Err(KeylimeError::Other(
+            "measurement list is not last in the quote".to_string(),
+        ));
+    }
+    head.truncate(head.len() - 3);
+
+    Ok(stream::once(future::ready(Ok(Bytes::from(head))))
+        .chain(stream::iter(ml.into_chunks()).map(|chunk| escape(&chunk)))
+        .chain(stream::once(future::ready(Ok(Bytes::from_static(b"\"}}"))))))
+}
+
 // This is a Quote request from the cloud verifier, which will check
 // integrity measurement. The PCRs inclued in the Quote will be specified
 // by the mask, vmask. It should return this data:
@@ -147,6 +273,7 @@ pub async fn identity(
 pub async fn integrity(
     param: web::Query<Integ>,
     data: web::Data<QuoteData>,
+    version: web::Data<ApiVersion>,
 ) -> impl Responder {
     // nonce, mask, vmask can only be in alphanumerical format
     if !param.nonce.chars().all(char::is_alphanumeric) {
@@ -172,27 +299,199 @@ pub async fn integrity(
             ))
             .await
     } else {
+        // Malformed requests do not count as attestation
+        idle::quote_requested();
         info!("Calling Integrity Quote with nonce: {}", param.nonce);
+        let _permit = data.limits.quotes.acquire()?;
 
-        let mut quote = tpm::quote(
+        let mut quote = quote::quote(
             param.nonce.as_bytes(),
             Some(&param.mask),
             data.clone(),
-        )?;
+        )
+        .await?;
+
+        // Reading the measurement list may take long, e.g. on the first
+        // quote, so it is left to the blocking thread pool
+        let ima_data = data.clone();
+        let ml = tokio::task::spawn_blocking(move || read_ima_ml(&ima_data))
+            .await
+            .map_err(KeylimeError::from)??;
+        quote.pubkey = &data.pub_key_pem;
+        save_evidence(
+            &data,
+            &param.nonce,
+            Some(&param.mask),
+            &quote,
+            Some(ml.clone()),
+        )
+        .await;
+        let mut quote = KeylimeIntegrityQuote::from_id_quote(&quote, "");
+
+        // 1.0 verifiers expect the whole list every time
+        let ml = if **version >= ApiVersion::V2_0 {
+            let (nth, ml) = ml.starting_at(param.ima_ml_entry.unwrap_or(0));
+            quote.ima_measurement_list_entry = Some(nth);
+            ml
+        } else {
+            ml
+        };
+
+        HttpResponse::Ok()
+            .content_type("application/json")
+            .streaming(integrity_body(quote, ml)?)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api;
+    use crate::tpm_backend::{quote_data, MockTpm};
+    use actix_web::{test, App};
+    use std::io::Write;
+
+    // Decodes the quoted message and PCRs of a mock quote
+    fn quoted(quote: &serde_json::Value) -> (Vec<u8>, Vec<u8>) {
+        let quote = quote.as_str().unwrap(); //#[allow_ci]
+        let parts = quote[1..].split(':').collect::<Vec<_>>();
+        (
+            base64::decode(parts[0]).unwrap(), //#[allow_ci]
+            base64::decode(parts[2]).unwrap(), //#[allow_ci]
+        )
+    }
+
+    #[test]
+    fn identity_quote() {
+        actix_web::rt::System::new("test").block_on(async {
+            let data = web::Data::new(quote_data(MockTpm::new()).unwrap()); //#[allow_ci]
+            let mut app = test::init_service(
+                App::new()
+                    .app_data(data.clone())
+                    .configure(|cfg| api::configure(cfg, 0)),
+            )
+            .await;
+
+            let req = test::TestRequest::get()
+                .uri("/v2.0/quotes/identity?nonce=1234abcd")
+                .to_request();
+            let resp: serde_json::Value =
+                test::read_response_json(&mut app, req).await;
+            assert_eq!(resp["code"], 200);
+            assert_eq!(resp["results"]["pubkey"], data.pub_key_pem.as_str());
+            let (message, pcrs) = quoted(&resp["results"]["quote"]);
+            assert_eq!(message, b"mock quote1234abcd");
+            assert!(pcrs.is_empty());
+            assert!(
+                data.last_quote.load(std::sync::atomic::Ordering::SeqCst) > 0
+            );
+
+            let req = test::TestRequest::get()
+                .uri("/v2.0/quotes/identity?nonce=12-34")
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(resp.status(), 400);
+        });
+    }
+
+    #[test]
+    fn integrity_quote() {
+        actix_web::rt::System::new("test").block_on(async {
+            let tpm = MockTpm::new();
+            tpm.set_pcr(10, &[0xa5; 32]);
+            let data = web::Data::new(quote_data(tpm).unwrap()); //#[allow_ci]
+            let mut app = test::init_service(
+                App::new()
+                    .app_data(data.clone())
+                    .configure(|cfg| api::configure(cfg, 0)),
+            )
+            .await;
+
+            let req = test::TestRequest::get()
+                .uri("/v2.0/quotes/integrity?nonce=1234abcd&mask=0x401&vmask=0x0&partial=0")
+                .to_request();
+            let resp: serde_json::Value =
+                test::read_response_json(&mut app, req).await;
+            assert_eq!(resp["code"], 200);
+            assert_eq!(resp["results"]["hash_alg"], "sha256");
+            assert_eq!(resp["results"]["ima_measurement_list_entry"], 0);
+            assert_eq!(resp["results"]["ima_measurement_list"], "");
+            let (message, pcrs) = quoted(&resp["results"]["quote"]);
+            assert_eq!(message, b"mock quote1234abcd");
+            let mut expected = vec![0u8; 32];
+            expected.extend(&[0xa5; 32]);
+            assert_eq!(pcrs, expected);
+
+            // 1.0 verifiers get the whole list, without the index
+            let req = test::TestRequest::get()
+                .uri("/quotes/integrity?nonce=1234abcd&mask=0x400&vmask=0x0&partial=0")
+                .to_request();
+            let resp: serde_json::Value =
+                test::read_response_json(&mut app, req).await;
+            assert_eq!(resp["code"], 200);
+            assert!(resp["results"]
+                .get("ima_measurement_list_entry")
+                .is_none());
+
+            for query in &[
+                "nonce=12-34&mask=0x400&vmask=0x0&partial=0",
+                "nonce=1234&mask=0x4.0&vmask=0x0&partial=0",
+                "nonce=1234&mask=0x400&vmask=0x-0&partial=0",
+            ] {
+                let req = test::TestRequest::get()
+                    .uri(&format!("/v2.0/quotes/integrity?{}", query))
+                    .to_request();
+                let resp = test::call_service(&mut app, req).await;
+                assert_eq!(resp.status(), 400);
+            }
+        });
+    }
+
+    #[test]
+    fn streamed_integrity_quote() {
+        let entry = "10 a ima-ng sha1:00 /tmp/\"quoted\\name\"\n";
+        let mut file = tempfile::NamedTempFile::new().unwrap(); //#[allow_ci]
+        file.write_all(entry.as_bytes()).unwrap(); //#[allow_ci]
+        let mut ml = ima::MeasurementList::new(Some(file.reopen().unwrap())); //#[allow_ci]
+
+        let id_quote = KeylimeIdQuote::default();
+        let quote = KeylimeIntegrityQuote::from_id_quote(&id_quote, "");
+        let body = integrity_body(quote, ml.update(0).unwrap()).unwrap(); //#[allow_ci]
+        let body = futures::executor::block_on(
+            body.map(|chunk| chunk.unwrap()).collect::<Vec<_>>(), //#[allow_ci]
+        )
+        .concat();
+
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap(); //#[allow_ci]
+        assert_eq!(json["code"], 200);
+        assert_eq!(json["results"]["hash_alg"], "sha256");
+        assert_eq!(json["results"]["ima_measurement_list"], entry);
+    }
 
-        let mut quote = KeylimeIntegrityQuote::from_id_quote(
-            quote,
-            read_to_string(IMA_PATH)?,
-        );
+    // The evidence attached to the quote goes before the streamed list
+    #[test]
+    fn streamed_integrity_quote_evidence() {
+        let entry = "10 a ima-ng sha1:00 /bin/sh\n";
+        let mut file = tempfile::NamedTempFile::new().unwrap(); //#[allow_ci]
+        file.write_all(entry.as_bytes()).unwrap(); //#[allow_ci]
+        let mut ml = ima::MeasurementList::new(Some(file.reopen().unwrap())); //#[allow_ci]
 
-        quote.pubkey = String::from_utf8(
-            data.pub_key
-                .public_key_to_pem()
-                .map_err(KeylimeError::from)?,
+        let id_quote = KeylimeIdQuote {
+            snp_report: Some(String::from("c25w")),
+            tdx_quote: Some(String::from("dGR4")),
+            ..Default::default()
+        };
+        let quote = KeylimeIntegrityQuote::from_id_quote(&id_quote, "");
+        let body = integrity_body(quote, ml.update(0).unwrap()).unwrap(); //#[allow_ci]
+        let body = futures::executor::block_on(
+            body.map(|chunk| chunk.unwrap()).collect::<Vec<_>>(), //#[allow_ci]
         )
-        .map_err(KeylimeError::from)?;
+        .concat();
 
-        let response = JsonIntegWrapper::new(quote);
-        HttpResponse::Ok().json(response).await
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap(); //#[allow_ci]
+        assert_eq!(json["results"]["snp_report"], "c25w");
+        assert_eq!(json["results"]["tdx_quote"], "dGR4");
+        assert_eq!(json["results"]["ima_measurement_list"], entry);
     }
 }
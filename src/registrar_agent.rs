@@ -1,9 +1,11 @@
 use crate::error::Error;
+#[cfg(feature = "testing")]
+use crate::fault;
+use crate::http;
 
-use log::*;
-use reqwest::header::*;
 use serde::{Deserialize, Serialize};
 use serde_json::Number;
+use tracing::{info, Instrument};
 
 fn serialize_as_base64<S>(
     bytes: &[u8],
@@ -68,6 +70,17 @@ struct Activate<'a> {
 #[derive(Debug, Serialize, Deserialize)]
 struct ActivateResponseResults {}
 
+/// API versions of the registrar
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VersionResponseResults {
+    /// Version the registrar answers with by default
+    pub current_version: String,
+    /// All the versions the registrar supports
+    #[serde(default)]
+    pub supported_versions: Vec<String>,
+}
+
+/// Body of the registrar responses
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Response<T> {
     code: Number,
@@ -80,7 +93,9 @@ struct WrappedBase64Encoded(
     #[serde(deserialize_with = "deserialize_as_base64")] Vec<u8>,
 );
 
-pub(crate) async fn do_activate_agent(
+/// Activates the agent with the registrar, proving that its AK lives in the
+/// same TPM as its EK
+pub async fn do_activate_agent(
     registrar_ip: &str,
     registrar_port: &str,
     agent_uuid: &str,
@@ -88,16 +103,19 @@ pub(crate) async fn do_activate_agent(
 ) -> crate::error::Result<()> {
     let data = Activate { auth_tag };
 
-    #[cfg(test)]
-    let addr = format!("http://{}:{}", registrar_ip, registrar_port);
-
-    #[cfg(not(test))]
     let addr = format!(
         "http://{}:{}/agents/{}",
         registrar_ip, registrar_port, agent_uuid
     );
 
-    let resp = reqwest::Client::new().put(&addr).json(&data).send().await?;
+    #[cfg(feature = "testing")]
+    fault::registrar(&addr).await?;
+    let resp = http::client()
+        .put(&addr)
+        .json(&data)
+        .send()
+        .instrument(tracing::info_span!("activate_agent", registrar = %addr))
+        .await?;
 
     if !resp.status().is_success() {
         return Err(Error::Registrar {
@@ -106,13 +124,15 @@ pub(crate) async fn do_activate_agent(
         });
     }
 
-    let resp: Response<ActivateResponseResults> = resp.json().await?;
+    let _: Response<ActivateResponseResults> = resp.json().await?;
 
     Ok(())
 }
 
+/// Registers the agent's EK and AK, returning the encrypted challenge the
+/// registrar made for them
 #[allow(clippy::too_many_arguments)]
-pub(crate) async fn do_register_agent(
+pub async fn do_register_agent(
     registrar_ip: &str,
     registrar_port: &str,
     agent_uuid: &str,
@@ -126,10 +146,6 @@ pub(crate) async fn do_register_agent(
         aik_tpm,
     };
 
-    #[cfg(test)]
-    let addr = format!("http://{}:{}", registrar_ip, registrar_port);
-
-    #[cfg(not(test))]
     let addr = format!(
         "http://{}:{}/agents/{}",
         registrar_ip, registrar_port, agent_uuid
@@ -137,10 +153,13 @@ pub(crate) async fn do_register_agent(
 
     info!("Sending data to {}", addr);
 
-    let resp = reqwest::Client::new()
+    #[cfg(feature = "testing")]
+    fault::registrar(&addr).await?;
+    let resp = http::client()
         .post(&addr)
         .json(&data)
         .send()
+        .instrument(tracing::info_span!("register_agent", registrar = %addr))
         .await?;
 
     if !resp.status().is_success() {
@@ -159,10 +178,116 @@ pub(crate) async fn do_register_agent(
     }
 }
 
+/**
+ * Input: client for the TLS port of the registrar, its URL and agent UUID
+ * Return: Result wrap with error message
+ *
+ * Removes the agent from the registrar, when a node is repurposed. The
+ * registrar only accepts deletions on its TLS port, from clients with a
+ * certificate issued by its CA, so the caller configures the client and
+ * gives the https:// URL of that port.
+ */
+pub async fn do_deregister_agent(
+    client: &reqwest::Client,
+    registrar_url: &str,
+    agent_uuid: &str,
+) -> crate::error::Result<()> {
+    let addr = format!("{}/agents/{}", registrar_url, agent_uuid);
+
+    #[cfg(feature = "testing")]
+    fault::registrar(&addr).await?;
+    let resp = client
+        .delete(&addr)
+        .send()
+        .instrument(
+            tracing::info_span!("deregister_agent", registrar = %addr),
+        )
+        .await?;
+
+    if !resp.status().is_success() {
+        return Err(Error::Registrar {
+            addr,
+            code: resp.status().as_u16(),
+        });
+    }
+
+    Ok(())
+}
+
+/**
+ * Input: registrar address
+ * Return: Result wrap the API versions of the registrar, None for
+ *         registrars older than the version endpoint
+ */
+pub async fn do_get_version(
+    registrar_ip: &str,
+    registrar_port: &str,
+) -> crate::error::Result<Option<VersionResponseResults>> {
+    let addr = format!("http://{}:{}/version", registrar_ip, registrar_port);
+
+    #[cfg(feature = "testing")]
+    fault::registrar(&addr).await?;
+    let resp = http::client()
+        .get(&addr)
+        .send()
+        .instrument(tracing::info_span!("get_version", registrar = %addr))
+        .await?;
+
+    if resp.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !resp.status().is_success() {
+        return Err(Error::Registrar {
+            addr,
+            code: resp.status().as_u16(),
+        });
+    }
+
+    let resp: Response<VersionResponseResults> = resp.json().await?;
+    Ok(Some(resp.results))
+}
+
+/**
+ * Input: registrar address and agent UUID
+ * Return: Result wrap whether the registrar has the agent, None if it does
+ *         not answer lookups on this port
+ *
+ * The Python registrar only answers lookups on its TLS port, for tenants,
+ * and 405 Method Not Allowed on the port agents register with.
+ */
+pub async fn do_get_agent(
+    registrar_ip: &str,
+    registrar_port: &str,
+    agent_uuid: &str,
+) -> crate::error::Result<Option<bool>> {
+    let addr = format!(
+        "http://{}:{}/agents/{}",
+        registrar_ip, registrar_port, agent_uuid
+    );
+
+    #[cfg(feature = "testing")]
+    fault::registrar(&addr).await?;
+    let resp = http::client()
+        .get(&addr)
+        .send()
+        .instrument(tracing::info_span!("get_agent", registrar = %addr))
+        .await?;
+
+    match resp.status() {
+        status if status.is_success() => Ok(Some(true)),
+        reqwest::StatusCode::NOT_FOUND => Ok(Some(false)),
+        reqwest::StatusCode::METHOD_NOT_ALLOWED => Ok(None),
+        status => Err(Error::Registrar {
+            addr,
+            code: status.as_u16(),
+        }),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use wiremock::matchers::{any, method};
+    use wiremock::matchers::{method, path};
     use wiremock::{Mock, MockServer, ResponseTemplate};
 
     #[tokio::test]
@@ -184,8 +309,6 @@ mod tests {
             .collect::<Vec<&str>>();
         assert_eq!(uri.len(), 2);
 
-        let addr = format!("http://{}:{}", uri[0], uri[1]);
-
         let mock_data = [0u8; 1];
         let response = do_register_agent(
             uri[0], uri[1], "uuid", &mock_data, &mock_data, &mock_data,
@@ -196,12 +319,6 @@ mod tests {
 
     #[tokio::test]
     async fn mock_register_agent_err() {
-        let response: Response<RegisterResponseResults> = Response {
-            code: 200.into(),
-            status: "OK".to_string(),
-            results: RegisterResponseResults { blob: None },
-        };
-
         let mock_server = MockServer::start().await;
         let uri = mock_server.uri();
         let uri = uri.split("//").collect::<Vec<&str>>()[1]
@@ -209,8 +326,6 @@ mod tests {
             .collect::<Vec<&str>>();
         assert_eq!(uri.len(), 2);
 
-        let addr = format!("http://{}:{}", uri[0], uri[1]);
-
         let mock_data = [0u8; 1];
         let response = do_register_agent(
             uri[0], uri[1], "uuid", &mock_data, &mock_data, &mock_data,
@@ -239,18 +354,54 @@ mod tests {
             .collect::<Vec<&str>>();
         assert_eq!(uri.len(), 2);
 
-        let addr = format!("http://{}:{}", uri[0], uri[1]);
-
         let response = do_activate_agent(uri[0], uri[1], "uuid", "tag").await;
         assert!(response.is_ok());
     }
 
     #[tokio::test]
     async fn mock_activate_agent_err() {
-        let response: Response<ActivateResponseResults> = Response {
+        let mock_server = MockServer::start().await;
+        let uri = mock_server.uri();
+        let uri = uri.split("//").collect::<Vec<&str>>()[1]
+            .split(':')
+            .collect::<Vec<&str>>();
+        assert_eq!(uri.len(), 2);
+
+        let response = do_activate_agent(uri[0], uri[1], "uuid", "tag").await;
+        assert!(response.is_err());
+        assert_eq!(response.err().unwrap().http_code().unwrap(), 404); //#[allow_ci]
+    }
+
+    #[tokio::test]
+    async fn mock_deregister_agent() {
+        let mock_server = MockServer::start().await;
+        let client = http::client();
+
+        let response =
+            do_deregister_agent(client, &mock_server.uri(), "uuid").await;
+        assert_eq!(response.err().unwrap().http_code().unwrap(), 404); //#[allow_ci]
+
+        let mock = Mock::given(method("DELETE"))
+            .and(path("/agents/uuid"))
+            .respond_with(ResponseTemplate::new(200));
+        mock_server.register(mock).await;
+        assert!(do_deregister_agent(client, &mock_server.uri(), "uuid")
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn mock_get_version() {
+        let response: Response<VersionResponseResults> = Response {
             code: 200.into(),
-            status: "OK".to_string(),
-            results: ActivateResponseResults {},
+            status: "Success".to_string(),
+            results: VersionResponseResults {
+                current_version: "2.0".to_string(),
+                supported_versions: vec![
+                    "1.0".to_string(),
+                    "2.0".to_string(),
+                ],
+            },
         };
 
         let mock_server = MockServer::start().await;
@@ -260,10 +411,50 @@ mod tests {
             .collect::<Vec<&str>>();
         assert_eq!(uri.len(), 2);
 
-        let addr = format!("http://{}:{}", uri[0], uri[1]);
+        // Older registrars
+        let version = do_get_version(uri[0], uri[1]).await.unwrap(); //#[allow_ci]
+        assert!(version.is_none());
+        assert_eq!(
+            do_get_agent(uri[0], uri[1], "uuid").await.unwrap(), //#[allow_ci]
+            Some(false)
+        );
 
-        let response = do_activate_agent(uri[0], uri[1], "uuid", "tag").await;
-        assert!(response.is_err());
-        assert_eq!(response.err().unwrap().http_code().unwrap(), 404); //#[allow_ci]
+        let mock = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(response));
+        mock_server.register(mock).await;
+        let version = do_get_version(uri[0], uri[1]).await.unwrap(); //#[allow_ci]
+        assert_eq!(version.unwrap().current_version, "2.0"); //#[allow_ci]
+        assert_eq!(
+            do_get_agent(uri[0], uri[1], "uuid").await.unwrap(), //#[allow_ci]
+            Some(true)
+        );
+    }
+
+    #[tokio::test]
+    async fn mock_get_agent_not_allowed() {
+        let mock_server = MockServer::start().await;
+        let uri = mock_server.uri();
+        let uri = uri.split("//").collect::<Vec<&str>>()[1]
+            .split(':')
+            .collect::<Vec<&str>>();
+        assert_eq!(uri.len(), 2);
+
+        let mock = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(405));
+        mock_server.register(mock).await;
+        assert_eq!(
+            do_get_agent(uri[0], uri[1], "uuid").await.unwrap(), //#[allow_ci]
+            None
+        );
+
+        let mock_server = MockServer::start().await;
+        let uri = mock_server.uri();
+        let uri = uri.split("//").collect::<Vec<&str>>()[1]
+            .split(':')
+            .collect::<Vec<&str>>();
+        let mock = Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(500));
+        mock_server.register(mock).await;
+        assert!(do_get_agent(uri[0], uri[1], "uuid").await.is_err());
     }
 }
@@ -0,0 +1,219 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Registrar connectivity check
+//
+// keylime_agent test-registrar goes through what registering takes, one
+// step after the other, and prints which step fails, for agents that
+// cannot register: the registrar address in the configuration, the TCP
+// connection, the API versions the registrar supports, the EK, AK and UUID
+// the agent would register with, and a dry run of the registration, which
+// only looks the agent up at the registrar, as the registrar has no way to
+// check a registration without storing it. The Python registrar only
+// answers lookups on its TLS port, so on the port agents register with the
+// dry run only shows that the registrar serves agents there. A UUID to be
+// generated is not generated by the check. The agent talks to the
+// registrar over plain HTTP, so there is no TLS handshake to check.
+
+use crate::common::{config_get, registrar_ip_get, registrar_port_get};
+use crate::error::{Error, Result};
+use crate::{agent_uuid, registrar_agent, tpm};
+
+use std::fmt::Write;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tss_esapi::interface_types::algorithm::AsymmetricAlgorithm;
+
+// How long to wait for the connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Step {
+    Config,
+    Connect,
+    Version,
+    Tpm,
+    Registration,
+}
+
+static STEPS: &[Step] = &[
+    Step::Config,
+    Step::Connect,
+    Step::Version,
+    Step::Tpm,
+    Step::Registration,
+];
+
+impl Step {
+    fn name(self) -> &'static str {
+        match self {
+            Step::Config => "config",
+            Step::Connect => "connect",
+            Step::Version => "version",
+            Step::Tpm => "tpm",
+            Step::Registration => "registration",
+        }
+    }
+}
+
+// What the steps find out, for the next ones
+#[derive(Default)]
+struct Check {
+    registrar_ip: String,
+    registrar_port: String,
+    // None if the UUID is generated on the first start
+    agent_uuid: Option<String>,
+}
+
+impl Check {
+    async fn step(&mut self, step: Step) -> Result<String> {
+        match step {
+            Step::Config => {
+                self.registrar_ip = registrar_ip_get()?;
+                self.registrar_port = registrar_port_get()?;
+                let _ = self.registrar_port.parse::<u16>().map_err(|e| {
+                    Error::config_value("registrar", "registrar_port", e)
+                })?;
+                Ok(format!("{}:{}", self.registrar_ip, self.registrar_port))
+            }
+            Step::Connect => {
+                let addr =
+                    format!("{}:{}", self.registrar_ip, self.registrar_port);
+                let stream = tokio::time::timeout(
+                    CONNECT_TIMEOUT,
+                    TcpStream::connect(&addr),
+                )
+                .await
+                .map_err(|_| {
+                    Error::Other(format!("timed out connecting to {}", addr))
+                })??;
+                Ok(format!("connected to {}", stream.peer_addr()?))
+            }
+            Step::Version => Ok(
+                match registrar_agent::do_get_version(
+                    &self.registrar_ip,
+                    &self.registrar_port,
+                )
+                .await?
+                {
+                    Some(version) => format!(
+                        "API {}, supports {}",
+                        version.current_version,
+                        version.supported_versions.join(", ")
+                    ),
+                    None => String::from("registrar without API versions"),
+                },
+            ),
+            Step::Tpm => {
+                let mut ctx = tpm::get_tpm2_ctx()?;
+                let keys = tpm::load_keys(&mut ctx, tpm::AkSource::Create)?;
+                keys.flush(&mut ctx)?;
+                self.agent_uuid = agent_uuid::lookup(
+                    &config_get("cloud_agent", "agent_uuid")?,
+                    &keys.ek_tpm2b_pub,
+                )?;
+                Ok(format!(
+                    "agent {}, {}",
+                    self.agent_uuid
+                        .as_deref()
+                        .unwrap_or("UUID generated on the first start"),
+                    if keys.ek_cert.is_empty() {
+                        "without EK certificate"
+                    } else {
+                        "with EK certificate"
+                    }
+                ))
+            }
+            Step::Registration => {
+                let agent_uuid = match &self.agent_uuid {
+                    Some(agent_uuid) => agent_uuid,
+                    None => {
+                        return Ok(String::from("agent not registered yet"))
+                    }
+                };
+                let registered = registrar_agent::do_get_agent(
+                    &self.registrar_ip,
+                    &self.registrar_port,
+                    agent_uuid,
+                )
+                .await?;
+                Ok(String::from(match registered {
+                    Some(true) => {
+                        "agent already registered, registering replaces it"
+                    }
+                    Some(false) => "agent not registered yet",
+                    None => {
+                        "registrar serves agents, lookups need its TLS port"
+                    }
+                }))
+            }
+        }
+    }
+}
+
+// Formats the report, one line per step, the ones after a failure skipped
+fn report(results: &[Result<String>]) -> String {
+    let mut report = String::new();
+    for (i, step) in STEPS.iter().enumerate() {
+        let name = step.name();
+        let _ = match results.get(i) {
+            Some(Ok(detail)) => {
+                writeln!(report, "PASS {:<13} {}", name, detail)
+            }
+            Some(Err(e)) => writeln!(report, "FAIL {:<13} {}", name, e),
+            None => writeln!(report, "SKIP {}", name),
+        };
+    }
+    report
+}
+
+/*
+ * Return: Result wrap with error message, failing at the first step that
+ *         fails
+ *
+ * Prints the report to standard output.
+ */
+pub(crate) async fn run() -> Result<()> {
+    let mut check = Check::default();
+    let mut results = Vec::new();
+    let mut failed = None;
+    for step in STEPS {
+        let result = check.step(*step).await;
+        if result.is_err() {
+            failed = Some(step.name());
+        }
+        results.push(result);
+        if failed.is_some() {
+            break;
+        }
+    }
+
+    print!("{}", report(&results));
+    match failed {
+        Some(name) => {
+            Err(Error::Other(format!("registrar check failed at {}", name)))
+        }
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registrar_check_report() {
+        let results = vec![
+            Ok(String::from("127.0.0.1:8890")),
+            Err(Error::Other(String::from("refused"))),
+        ];
+        assert_eq!(
+            report(&results),
+            "PASS config        127.0.0.1:8890\n\
+             FAIL connect       refused\n\
+             SKIP version\n\
+             SKIP tpm\n\
+             SKIP registration\n"
+        );
+    }
+}
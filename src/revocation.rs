@@ -2,9 +2,11 @@
 // Copyright 2021 Keylime Authors
 
 #[macro_use]
-use log::*;
+use tracing::{debug, error, info, warn};
 
-use crate::common::config_get;
+use crate::common::{
+    config_get, work_dir_get, ACTION_LIST, REV_CERT, UNZIPPED_DIR,
+};
 use crate::crypto;
 use crate::error::*;
 use crate::secure_mount;
@@ -14,7 +16,7 @@ use std::io::Write;
 use std::path::Path;
 use std::process::{Child, Command, Output, Stdio};
 
-use openssl::pkey::PKey;
+use openssl::pkey::{PKey, Public};
 use serde_json::Value;
 
 /// Runs a script with a json value as argument (used for revocation actions)
@@ -62,20 +64,25 @@ pub(crate) fn run_action(
 /// Otherwise, an Error will be returned from the first action that
 /// did not run successfully.
 pub(crate) fn run_revocation_actions(json: Value) -> Result<Vec<Output>> {
-    #[cfg(not(test))]
-    pretty_env_logger::init();
-
     #[cfg(test)]
     let mount = concat!(env!("CARGO_MANIFEST_DIR"), "/tests");
 
     #[cfg(not(test))]
     let mount = secure_mount::mount()?;
-    let unzipped = format!("{}/unzipped", mount);
-    let action_file = format!("{}/unzipped/action_list", mount);
+    let unzipped = format!("{}/{}", mount, UNZIPPED_DIR);
+    run_actions_in(Path::new(&unzipped), json)
+}
+
+/// Runs the revocation actions of the action_list in a payload directory
+pub(crate) fn run_actions_in(
+    unzipped: &Path,
+    json: Value,
+) -> Result<Vec<Output>> {
+    let action_file = unzipped.join(ACTION_LIST);
 
     let mut outputs = Vec::new();
 
-    if Path::new(&action_file).exists() {
+    if action_file.exists() {
         let action_data = std::fs::read_to_string(action_file)
             .expect("unable to read action_list");
 
@@ -87,7 +94,7 @@ pub(crate) fn run_revocation_actions(json: Value) -> Result<Vec<Output>> {
 
         if !action_list.is_empty() {
             for action in action_list {
-                match run_action(Path::new(&unzipped), action, json.clone()) {
+                match run_action(unzipped, action, json.clone()) {
                     Ok(output) => {
                         outputs.push(output);
                     }
@@ -115,14 +122,89 @@ pub(crate) fn run_revocation_actions(json: Value) -> Result<Vec<Output>> {
     Ok(outputs)
 }
 
+/// Returns the path of the certificate used to verify revocation messages
+///
+/// As in the Python agent, `revocation_cert = default` selects the
+/// certificate from the unzipped payload, while any other value is taken
+/// as a path relative to the working directory.
+fn revocation_cert_path(mount: &str) -> Result<String> {
+    match config_get("cloud_agent", "revocation_cert")?.as_str() {
+        "default" => Ok(format!("{}/{}/{}", mount, UNZIPPED_DIR, REV_CERT)),
+        path if Path::new(path).is_absolute() => Ok(path.to_string()),
+        path => Ok(format!("{}/{}", work_dir_get(), path)),
+    }
+}
+
+/// Loads the public key of the certificate used to verify revocation
+/// messages
+///
+/// Unlike the python agent we do not attempt lazy loading. We either have
+/// the certificate, or we don't. If we don't have a key or can't load the
+/// key we return a Configuration error as the service will not work.
+pub(crate) fn revocation_cert(mount: &str) -> Result<PKey<Public>> {
+    let revocation_cert_path = revocation_cert_path(mount)?;
+    if Path::new(&revocation_cert_path).exists() {
+        info!(
+            "Loading the revocation certificate from {}",
+            revocation_cert_path
+        );
+        match crypto::rsa_import_pubkey(revocation_cert_path) {
+            Ok(v) => Ok(v),
+            Err(e) => Err(Error::config_value(
+                "cloud_agent",
+                "revocation_cert",
+                format!("Can not load pubkey: {}", e),
+            )),
+        }
+    } else {
+        error!(
+            "Path {} for the 0mq socket doesn't exist",
+            revocation_cert_path
+        );
+        Err(Error::config_value(
+            "cloud_agent",
+            "revocation_cert",
+            format!(
+                "Path {} for the 0mq socket socket doesn't exist",
+                revocation_cert_path,
+            ),
+        ))
+    }
+}
+
+/// Returns the message of a revocation event, after verifying its
+/// signature with the revocation certificate
+pub(crate) fn verify_event(
+    cert_key: &PKey<Public>,
+    body: &Value,
+) -> Result<Value> {
+    // Ensure we have a signature and a msg
+    let signature = body["signature"].as_str().ok_or_else(|| {
+        Error::Other(String::from(
+            "No signature on revocation message from server",
+        ))
+    })?;
+    let message = body["msg"].as_str().ok_or_else(|| {
+        Error::Other(String::from("No msg on revocation message from server"))
+    })?;
+
+    // Verify the message and signature with our key
+    match crypto::asym_verify(cert_key, message, signature) {
+        Ok(true) => Ok(serde_json::from_str(message)?),
+        _ => Err(Error::Other(format!(
+            "Invalid revocation message siganture {}",
+            body
+        ))),
+    }
+}
+
 /// Handles revocation messages via 0mq
 /// See:
 /// - URL: https://github.com/keylime/keylime/blob/master/keylime/revocation_notifier.py
 ///   Function: await_notifications
-pub(crate) async fn run_revocation_service() -> Result<()> {
+pub(crate) fn run_revocation_service() -> Result<()> {
     let mount = secure_mount::mount()?;
-    let revocation_cert_path =
-        format!("{}/unzipped/RevocationNotifier-cert.crt", mount);
+    let cert_key = revocation_cert(&mount)?;
 
     // Connect to the service via 0mq
     let context = zmq::Context::new();
@@ -138,33 +220,6 @@ pub(crate) async fn run_revocation_service() -> Result<()> {
 
     mysock.connect(endpoint.as_str())?;
 
-    // Unlike the python agent we do not attempt lazy loading. We either
-    // have the certificate, or we don't. If we don't have a key or can't load
-    // the key we return a Configuration error as the service will not work.
-    let cert_key = if Path::new(&revocation_cert_path).exists() {
-        info!(
-            "Loading the revocation certificate from {}",
-            revocation_cert_path
-        );
-        match crypto::rsa_import_pubkey(revocation_cert_path) {
-            Ok(v) => v,
-            Err(e) => {
-                return Err(Error::Configuration(String::from(
-                    "Can not load pubkey",
-                )))
-            }
-        }
-    } else {
-        error!(
-            "Path {} for the 0mq socket doesn't exist",
-            revocation_cert_path
-        );
-        return Err(Error::Configuration(format!(
-            "Path {} for the 0mq socket socket doesn't exist",
-            revocation_cert_path,
-        )));
-    };
-
     info!("Waiting for revocation messages on 0mq {}", endpoint);
 
     // Main revocation service loop. If a message is malformed or
@@ -186,46 +241,16 @@ pub(crate) async fn run_revocation_service() -> Result<()> {
 
         let body: Value = serde_json::from_str(rawbody.as_str())?;
 
-        // Ensure we have a signature, otherwise continue the loop
-        let signature = match body["signature"].as_str() {
-            Some(v) => v,
-            _ => {
-                warn!("No signature on revocation message from server");
-                continue;
-            }
-        };
-
-        // Ensure we have a msg, otherwise continue the loop
-        let message = match body["msg"].as_str() {
-            Some(v) => v,
-            _ => {
-                warn!("No msg on revocation message from server");
-                continue;
-            }
-        };
-
-        // Verify the message and signature with our key
-        let mut verified = crypto::asym_verify(&cert_key, message, signature);
-
-        match verified {
-            Ok(true) => {
-                let msg = body["msg"].as_str();
-                let msg_payload: Value =
-                    serde_json::from_str(match msg.as_deref() {
-                        Some(v) => v,
-                        _ => {
-                            warn!("Unable to decode json in msg");
-                            continue;
-                        }
-                    })?;
+        match verify_event(&cert_key, &body) {
+            Ok(msg_payload) => {
                 debug!(
                     "Revocation signature validated for revocation: {}",
                     msg_payload
                 );
                 let _ = run_revocation_actions(msg_payload)?;
             }
-            _ => {
-                error!("Invalid revocation message siganture {}", body);
+            Err(e) => {
+                error!("{}", e);
             }
         }
     }
@@ -0,0 +1,100 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Sealing files to the TPM
+//
+// keylime_agent seal and unseal protect small secrets of the node with the
+// same trust anchor as the agent: seal binds a file to the current values
+// of a set of PCRs, as the bootstrap key is for re-provisioning, see
+// agent_data.rs, and writes the sealed object as JSON, which unseal turns
+// back into the file as long as the PCRs hold the same values. By default
+// the PCRs are the ones of reprovision_pcr_mask, in the bank of
+// tpm_hash_alg. The TPM seals at most 128 bytes, so larger secrets are
+// better encrypted with a key sealed this way.
+
+use crate::agent_data::{SealedData, DEFAULT_REPROVISION_PCR_MASK};
+use crate::common::{config_get, config_get_or, tpm_ownerpassword_get};
+use crate::error::{Error, Result};
+use crate::{persist, tpm};
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+// Size of the sensitive data of a sealed object, MAX_SYM_DATA
+const MAX_SEALED_SIZE: usize = 128;
+
+fn check_size(path: &Path, data: &[u8]) -> Result<()> {
+    if data.len() > MAX_SEALED_SIZE {
+        return Err(Error::Other(format!(
+            "{} is {} bytes, at most {} bytes can be sealed",
+            path.display(),
+            data.len(),
+            MAX_SEALED_SIZE
+        )));
+    }
+    Ok(())
+}
+
+/*
+ * Input: file to seal, file to write the sealed object to, PCRs to bind to
+ * Return: Result wrap with error message
+ */
+pub(crate) fn seal(
+    input: &Path,
+    output: &Path,
+    pcr_mask: Option<String>,
+) -> Result<()> {
+    let data = fs::read(input)?;
+    check_size(input, &data)?;
+    let pcr_mask = match pcr_mask {
+        Some(pcr_mask) => pcr_mask,
+        None => config_get_or(
+            "cloud_agent",
+            "reprovision_pcr_mask",
+            DEFAULT_REPROVISION_PCR_MASK,
+        )?,
+    };
+    let hash_alg = config_get("cloud_agent", "tpm_hash_alg")?;
+
+    let mut ctx = tpm::get_tpm2_ctx()?;
+    tpm::set_owner_auth(&mut ctx, &tpm_ownerpassword_get()?)?;
+    let sealed = SealedData::seal(&mut ctx, &pcr_mask, &hash_alg, &data)?;
+    persist::write(output, 0o600, &serde_json::to_vec_pretty(&sealed)?)?;
+    println!(
+        "Sealed {} to PCRs {} of {} in {}",
+        input.display(),
+        pcr_mask,
+        hash_alg,
+        output.display()
+    );
+    Ok(())
+}
+
+/*
+ * Input: sealed object written by seal, file to write the data to, or
+ *        standard output
+ * Return: Result wrap with error message
+ */
+pub(crate) fn unseal(input: &Path, output: Option<&Path>) -> Result<()> {
+    let sealed: SealedData = serde_json::from_slice(&fs::read(input)?)?;
+    let mut ctx = tpm::get_tpm2_ctx()?;
+    tpm::set_owner_auth(&mut ctx, &tpm_ownerpassword_get()?)?;
+    let data = sealed.unseal(&mut ctx)?;
+    match output {
+        Some(output) => persist::write(output, 0o600, &data),
+        None => Ok(std::io::stdout().write_all(&data)?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_size() {
+        let path = Path::new("secret");
+        assert!(check_size(path, &[0u8; MAX_SEALED_SIZE]).is_ok());
+        assert!(check_size(path, &[0u8; MAX_SEALED_SIZE + 1]).is_err());
+    }
+}
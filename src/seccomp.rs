@@ -0,0 +1,498 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Seccomp system call filter
+//
+// Once initialization is done, the agent only needs a limited set of system
+// calls to serve requests, talk to the TPM and run the payload and
+// revocation scripts. With seccomp set in keylime.conf, an allowlist of
+// these is installed as a seccomp-bpf filter on all threads of the agent,
+// so that a compromised agent cannot e.g. mount file systems, load kernel
+// modules or trace other processes. The filter is inherited by the scripts
+// the agent runs. See seccomp(2).
+
+use crate::common::config_get_or;
+use crate::error::{Error, Result};
+
+use std::convert::TryFrom;
+use tracing::info;
+
+// From linux/filter.h
+const BPF_LD: u16 = 0x00;
+const BPF_W: u16 = 0x00;
+const BPF_ABS: u16 = 0x20;
+const BPF_JMP: u16 = 0x05;
+const BPF_JEQ: u16 = 0x10;
+const BPF_JGE: u16 = 0x30;
+const BPF_K: u16 = 0x00;
+const BPF_RET: u16 = 0x06;
+
+// From linux/seccomp.h
+const SECCOMP_SET_MODE_FILTER: libc::c_long = 1;
+const SECCOMP_FILTER_FLAG_TSYNC: libc::c_long = 1;
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_LOG: u32 = 0x7ffc_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+// Offsets in struct seccomp_data
+const DATA_NR: u32 = 0;
+const DATA_ARCH: u32 = 4;
+
+// The allowlists depend on the system call numbers of the architecture
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+mod syscalls {
+    // From linux/audit.h
+    #[cfg(target_arch = "x86_64")]
+    pub(super) const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    pub(super) const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    // System calls added after the libc crate version we depend on, with the
+    // same numbers on x86_64 and aarch64
+    pub(super) const SYS_CLONE3: libc::c_long = 435;
+    pub(super) const SYS_CLOSE_RANGE: libc::c_long = 436;
+    pub(super) const SYS_FACCESSAT2: libc::c_long = 439;
+    #[cfg(target_arch = "x86_64")]
+    pub(super) const SYS_RSEQ: libc::c_long = 334;
+    #[cfg(target_arch = "aarch64")]
+    pub(super) const SYS_RSEQ: libc::c_long = 293;
+
+    // clone3 takes its flags in memory, out of reach of the filter. As
+    // other seccomp users do, fail it with ENOSYS, so that glibc falls back
+    // to clone.
+    pub(super) const ENOSYS_SYSCALLS: &[libc::c_long] = &[SYS_CLONE3];
+
+    // System calls available on all supported architectures
+    pub(super) const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+        // Memory
+        libc::SYS_brk,
+        libc::SYS_mmap,
+        libc::SYS_mprotect,
+        libc::SYS_munmap,
+        libc::SYS_mremap,
+        libc::SYS_madvise,
+        libc::SYS_mincore,
+        libc::SYS_msync,
+        libc::SYS_mlock,
+        libc::SYS_munlock,
+        libc::SYS_memfd_create,
+        // Files
+        libc::SYS_openat,
+        libc::SYS_close,
+        SYS_CLOSE_RANGE,
+        libc::SYS_read,
+        libc::SYS_write,
+        libc::SYS_readv,
+        libc::SYS_writev,
+        libc::SYS_pread64,
+        libc::SYS_pwrite64,
+        libc::SYS_lseek,
+        libc::SYS_fstat,
+        libc::SYS_newfstatat,
+        libc::SYS_statx,
+        libc::SYS_statfs,
+        libc::SYS_fstatfs,
+        libc::SYS_faccessat,
+        SYS_FACCESSAT2,
+        libc::SYS_getdents64,
+        libc::SYS_getcwd,
+        libc::SYS_chdir,
+        libc::SYS_fchdir,
+        libc::SYS_mkdirat,
+        libc::SYS_unlinkat,
+        libc::SYS_renameat,
+        libc::SYS_renameat2,
+        libc::SYS_linkat,
+        libc::SYS_symlinkat,
+        libc::SYS_readlinkat,
+        libc::SYS_fchmod,
+        libc::SYS_fchmodat,
+        libc::SYS_fchown,
+        libc::SYS_fchownat,
+        libc::SYS_umask,
+        libc::SYS_utimensat,
+        libc::SYS_truncate,
+        libc::SYS_ftruncate,
+        libc::SYS_fsync,
+        libc::SYS_fdatasync,
+        libc::SYS_fadvise64,
+        libc::SYS_flock,
+        libc::SYS_fcntl,
+        libc::SYS_ioctl,
+        libc::SYS_dup,
+        libc::SYS_dup3,
+        libc::SYS_pipe2,
+        libc::SYS_sendfile,
+        libc::SYS_splice,
+        libc::SYS_copy_file_range,
+        // Polling
+        libc::SYS_ppoll,
+        libc::SYS_pselect6,
+        libc::SYS_epoll_create1,
+        libc::SYS_epoll_ctl,
+        libc::SYS_epoll_pwait,
+        libc::SYS_eventfd2,
+        libc::SYS_timerfd_create,
+        libc::SYS_timerfd_settime,
+        libc::SYS_timerfd_gettime,
+        libc::SYS_inotify_init1,
+        libc::SYS_inotify_add_watch,
+        libc::SYS_inotify_rm_watch,
+        // Network
+        libc::SYS_socket,
+        libc::SYS_socketpair,
+        libc::SYS_bind,
+        libc::SYS_listen,
+        libc::SYS_accept,
+        libc::SYS_accept4,
+        libc::SYS_connect,
+        libc::SYS_shutdown,
+        libc::SYS_getsockname,
+        libc::SYS_getpeername,
+        libc::SYS_getsockopt,
+        libc::SYS_setsockopt,
+        libc::SYS_sendto,
+        libc::SYS_recvfrom,
+        libc::SYS_sendmsg,
+        libc::SYS_recvmsg,
+        libc::SYS_sendmmsg,
+        libc::SYS_recvmmsg,
+        // Threads and processes
+        libc::SYS_clone,
+        libc::SYS_execve,
+        libc::SYS_execveat,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+        libc::SYS_wait4,
+        libc::SYS_waitid,
+        libc::SYS_futex,
+        libc::SYS_set_robust_list,
+        libc::SYS_get_robust_list,
+        libc::SYS_set_tid_address,
+        libc::SYS_sched_yield,
+        libc::SYS_sched_getaffinity,
+        libc::SYS_sched_getparam,
+        libc::SYS_sched_getscheduler,
+        libc::SYS_getpriority,
+        libc::SYS_setpriority,
+        libc::SYS_prctl,
+        libc::SYS_prlimit64,
+        libc::SYS_getrlimit,
+        libc::SYS_setrlimit,
+        libc::SYS_getrusage,
+        libc::SYS_setpgid,
+        libc::SYS_getpgid,
+        libc::SYS_setsid,
+        libc::SYS_getsid,
+        libc::SYS_getpid,
+        libc::SYS_getppid,
+        libc::SYS_gettid,
+        libc::SYS_getuid,
+        libc::SYS_geteuid,
+        libc::SYS_getgid,
+        libc::SYS_getegid,
+        libc::SYS_getresuid,
+        libc::SYS_getresgid,
+        libc::SYS_getgroups,
+        libc::SYS_capget,
+        // Signals
+        libc::SYS_rt_sigaction,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_rt_sigreturn,
+        libc::SYS_rt_sigpending,
+        libc::SYS_rt_sigsuspend,
+        libc::SYS_rt_sigtimedwait,
+        libc::SYS_sigaltstack,
+        libc::SYS_kill,
+        libc::SYS_tgkill,
+        libc::SYS_tkill,
+        libc::SYS_restart_syscall,
+        // Time
+        libc::SYS_clock_gettime,
+        libc::SYS_clock_getres,
+        libc::SYS_clock_nanosleep,
+        libc::SYS_gettimeofday,
+        libc::SYS_nanosleep,
+        libc::SYS_times,
+        // Misc
+        libc::SYS_uname,
+        libc::SYS_sysinfo,
+        libc::SYS_getrandom,
+        // Keyring secure storage
+        libc::SYS_add_key,
+        libc::SYS_keyctl,
+    ];
+
+    // Legacy system calls only found on x86_64, still used by older libc
+    // versions and by the programs the scripts run
+    #[cfg(target_arch = "x86_64")]
+    pub(super) const ARCH_SYSCALLS: &[libc::c_long] = &[
+        libc::SYS_open,
+        libc::SYS_creat,
+        libc::SYS_stat,
+        libc::SYS_lstat,
+        libc::SYS_access,
+        libc::SYS_getdents,
+        libc::SYS_mkdir,
+        libc::SYS_rmdir,
+        libc::SYS_unlink,
+        libc::SYS_rename,
+        libc::SYS_link,
+        libc::SYS_symlink,
+        libc::SYS_readlink,
+        libc::SYS_chmod,
+        libc::SYS_chown,
+        libc::SYS_lchown,
+        libc::SYS_utimes,
+        libc::SYS_dup2,
+        libc::SYS_pipe,
+        libc::SYS_poll,
+        libc::SYS_select,
+        libc::SYS_epoll_create,
+        libc::SYS_epoll_wait,
+        libc::SYS_eventfd,
+        libc::SYS_inotify_init,
+        libc::SYS_fork,
+        libc::SYS_vfork,
+        libc::SYS_getpgrp,
+        libc::SYS_alarm,
+        libc::SYS_time,
+        libc::SYS_arch_prctl,
+        SYS_RSEQ,
+    ];
+
+    #[cfg(target_arch = "aarch64")]
+    pub(super) const ARCH_SYSCALLS: &[libc::c_long] = &[SYS_RSEQ];
+}
+
+/// How strictly the filter is applied, from the seccomp option
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SeccompMode {
+    /// No filter is installed
+    Off,
+    /// System calls outside the allowlist are logged by the kernel, but
+    /// allowed. Useful to check the allowlist against the payload scripts.
+    Log,
+    /// System calls outside the allowlist kill the agent
+    Enforce,
+}
+
+fn parse_mode(mode: &str) -> Result<SeccompMode> {
+    match mode.trim().to_lowercase().as_str() {
+        "" | "off" => Ok(SeccompMode::Off),
+        "log" => Ok(SeccompMode::Log),
+        "enforce" => Ok(SeccompMode::Enforce),
+        _ => Err(Error::config_value(
+            "cloud_agent",
+            "seccomp",
+            format!("{:?} is not one of off, log or enforce", mode),
+        )),
+    }
+}
+
+/// Reads the seccomp option from keylime.conf
+pub(crate) fn seccomp_mode_get() -> Result<SeccompMode> {
+    parse_mode(&config_get_or("cloud_agent", "seccomp", "off")?)
+}
+
+fn stmt(code: u16, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code, jt, jf, k }
+}
+
+fn syscall_nr(nr: libc::c_long) -> Result<u32> {
+    u32::try_from(nr)
+        .map_err(|_| Error::Other(format!("invalid system call {}", nr)))
+}
+
+/*
+ * Input: audit architecture, allowed system calls, system calls failing
+ *        with ENOSYS, action for all other system calls
+ * Return: Result wrap the BPF program
+ *
+ * Any other architecture, including the x32 ABI on x86_64, kills the
+ * process regardless of the action.
+ */
+fn build_filter(
+    arch: u32,
+    allowed: &[libc::c_long],
+    enosys: &[libc::c_long],
+    default_action: u32,
+) -> Result<Vec<libc::sock_filter>> {
+    let mut filter = vec![
+        stmt(BPF_LD | BPF_W | BPF_ABS, DATA_ARCH),
+        jump(BPF_JMP | BPF_JEQ | BPF_K, arch, 1, 0),
+        stmt(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS),
+        stmt(BPF_LD | BPF_W | BPF_ABS, DATA_NR),
+    ];
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        // x32 system calls have this bit set
+        filter.push(jump(BPF_JMP | BPF_JGE | BPF_K, 0x4000_0000, 0, 1));
+        filter.push(stmt(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS));
+    }
+
+    for nr in enosys {
+        filter.push(jump(BPF_JMP | BPF_JEQ | BPF_K, syscall_nr(*nr)?, 0, 1));
+        filter.push(stmt(
+            BPF_RET | BPF_K,
+            SECCOMP_RET_ERRNO | libc::ENOSYS as u32,
+        ));
+    }
+    for nr in allowed {
+        filter.push(jump(BPF_JMP | BPF_JEQ | BPF_K, syscall_nr(*nr)?, 0, 1));
+        filter.push(stmt(BPF_RET | BPF_K, SECCOMP_RET_ALLOW));
+    }
+    filter.push(stmt(BPF_RET | BPF_K, default_action));
+
+    Ok(filter)
+}
+
+// Returns the filter for the architecture the agent runs on, and the
+// number of system calls it allows
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn arch_filter(
+    default_action: u32,
+) -> Result<(Vec<libc::sock_filter>, usize)> {
+    let allowed: Vec<libc::c_long> = syscalls::ALLOWED_SYSCALLS
+        .iter()
+        .chain(syscalls::ARCH_SYSCALLS)
+        .copied()
+        .collect();
+    let filter = build_filter(
+        syscalls::AUDIT_ARCH,
+        &allowed,
+        syscalls::ENOSYS_SYSCALLS,
+        default_action,
+    )?;
+    Ok((filter, allowed.len()))
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn arch_filter(
+    _default_action: u32,
+) -> Result<(Vec<libc::sock_filter>, usize)> {
+    Err(Error::config_value(
+        "cloud_agent",
+        "seccomp",
+        "not supported on this architecture",
+    ))
+}
+
+/*
+ * Input: strictness of the filter
+ * Return: Result wrap with error message
+ *
+ * Installs the filter on all threads of the agent. This sets no_new_privs,
+ * so the scripts the agent runs cannot gain privileges through setuid
+ * programs either.
+ */
+pub(crate) fn install(mode: SeccompMode) -> Result<()> {
+    let default_action = match mode {
+        SeccompMode::Off => return Ok(()),
+        SeccompMode::Log => SECCOMP_RET_LOG,
+        SeccompMode::Enforce => SECCOMP_RET_KILL_PROCESS,
+    };
+
+    let (mut filter, allowed) = arch_filter(default_action)?;
+    let prog = libc::sock_fprog {
+        len: u16::try_from(filter.len()).map_err(|_| {
+            Error::Other("seccomp filter is too long".to_string())
+        })?,
+        filter: filter.as_mut_ptr(),
+    };
+    let prog_ptr: *const libc::sock_fprog = &prog;
+
+    unsafe {
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+            return Err(Error::Other(format!(
+                "unable to set no_new_privs: {}",
+                std::io::Error::last_os_error()
+            )));
+        }
+        if libc::syscall(
+            libc::SYS_seccomp,
+            SECCOMP_SET_MODE_FILTER,
+            SECCOMP_FILTER_FLAG_TSYNC,
+            prog_ptr,
+        ) != 0
+        {
+            return Err(Error::Other(format!(
+                "unable to install seccomp filter: {}",
+                std::io::Error::last_os_error()
+            )));
+        }
+    }
+
+    info!(
+        "Installed seccomp filter allowing {} system calls ({:?} mode)",
+        allowed, mode
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seccomp_mode() {
+        assert_eq!(parse_mode("").unwrap(), SeccompMode::Off); //#[allow_ci]
+        assert_eq!(parse_mode("Log").unwrap(), SeccompMode::Log); //#[allow_ci]
+        assert_eq!(parse_mode("enforce").unwrap(), SeccompMode::Enforce); //#[allow_ci]
+        assert!(parse_mode("strict").is_err());
+    }
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    #[test]
+    fn filter_program() {
+        use syscalls::{AUDIT_ARCH, SYS_CLONE3};
+
+        let filter = build_filter(
+            AUDIT_ARCH,
+            &[libc::SYS_read, libc::SYS_write],
+            &[SYS_CLONE3],
+            SECCOMP_RET_LOG,
+        )
+        .unwrap(); //#[allow_ci]
+
+        // The architecture is checked first
+        assert_eq!(filter[1].k, AUDIT_ARCH);
+        assert_eq!(filter[2].k, SECCOMP_RET_KILL_PROCESS);
+
+        let last = filter.len() - 1;
+        assert_eq!(filter[last].k, SECCOMP_RET_LOG);
+        assert_eq!(filter[last - 1].k, SECCOMP_RET_ALLOW);
+        assert_eq!(filter[last - 2].k, libc::SYS_write as u32);
+        assert_eq!(filter[last - 4].k, libc::SYS_read as u32);
+        assert_eq!(filter[last - 5].k, SECCOMP_RET_ERRNO | 38);
+        assert_eq!(filter[last - 6].k, SYS_CLONE3 as u32);
+
+        assert!(
+            build_filter(AUDIT_ARCH, &[-1], &[], SECCOMP_RET_LOG).is_err()
+        );
+    }
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    #[test]
+    fn allowlist() {
+        use syscalls::{ALLOWED_SYSCALLS, ARCH_SYSCALLS};
+
+        for nr in
+            [libc::SYS_mount, libc::SYS_ptrace, libc::SYS_init_module].iter()
+        {
+            assert!(!ALLOWED_SYSCALLS.contains(nr));
+            assert!(!ARCH_SYSCALLS.contains(nr));
+        }
+    }
+}
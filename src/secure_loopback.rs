@@ -0,0 +1,273 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Encrypted loopback secure storage
+//
+// When the payload does not fit in RAM, the secure directory can instead be
+// an ext4 file system on a dm-crypt device backed by a sparse file in the
+// work directory. The dm-crypt key is random, generated on every start and
+// never written anywhere: the agent passes it to cryptsetup and forgets it,
+// so the contents of the backing file are unreadable once the device is
+// closed, e.g. after a reboot.
+
+use crate::common::{config_get_or, work_dir_get};
+use crate::error::{Error, Result};
+
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use tracing::{info, warn};
+
+/// Name of the device mapper device holding the secure file system
+pub(crate) static MAPPER_NAME: &str = "keylime-secure";
+pub(crate) static MAPPER_DEVICE: &str = "/dev/mapper/keylime-secure";
+
+/// File system created on the device mapper device
+pub(crate) static LOOPBACK_FS_TYPE: &str = "ext4";
+
+/// Default backing file, relative to the work directory
+pub(crate) static DEFAULT_LOOPBACK_FILE: &str = "secure.img";
+
+/// Default size of the backing file
+pub(crate) static DEFAULT_LOOPBACK_SIZE: &str = "64m";
+
+// Size in bytes of the random dm-crypt key: 512 bits for aes-xts-plain64
+const KEY_SIZE: usize = 64;
+
+/*
+ * Input: size as in secure_loopback_size
+ * Return: Result wrap the size in bytes
+ *
+ * Same syntax as secure_size, except that a percentage of the RAM does not
+ * make sense for a file on disk.
+ */
+fn parse_size(size: &str) -> Result<u64> {
+    let size = size.trim();
+    let (digits, multiplier) = match size.char_indices().last() {
+        Some((i, 'k')) | Some((i, 'K')) => (&size[..i], 1 << 10),
+        Some((i, 'm')) | Some((i, 'M')) => (&size[..i], 1 << 20),
+        Some((i, 'g')) | Some((i, 'G')) => (&size[..i], 1 << 30),
+        _ => (size, 1),
+    };
+
+    match digits.parse::<u64>() {
+        Ok(value)
+            if value > 0 && digits.chars().all(|c| c.is_ascii_digit()) =>
+        {
+            value.checked_mul(multiplier).ok_or_else(|| {
+                Error::config_value(
+                    "cloud_agent",
+                    "secure_loopback_size",
+                    format!("{:?} is too large", size),
+                )
+            })
+        }
+        _ => Err(Error::config_value(
+            "cloud_agent",
+            "secure_loopback_size",
+            format!(
+                "{:?} is not a size in bytes with an optional k, m or g suffix",
+                size
+            ),
+        )),
+    }
+}
+
+// Runs a command, optionally writing data to its standard input, and
+// returns its standard output
+fn run(command: &mut Command, stdin: Option<&[u8]>) -> Result<String> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    if let (Some(data), Some(mut pipe)) = (stdin, child.stdin.take()) {
+        pipe.write_all(data)?;
+    }
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(Error::Script(
+            format!("{:?}", command),
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+
+    Ok(String::from_utf8(output.stdout)?.trim().to_string())
+}
+
+// Overwrites the key so that it does not linger in memory once dm-crypt
+// has it
+fn wipe(key: &mut [u8]) {
+    for byte in key.iter_mut() {
+        unsafe { std::ptr::write_volatile(byte, 0) };
+    }
+}
+
+// Creates the file system on the dm-crypt device and mounts it, unmounting
+// it again if the secure directory cannot be restricted
+fn format_and_mount(secure_dir: &str, options: &str) -> Result<()> {
+    let _ = run(
+        Command::new("mkfs").args([
+            "-t",
+            LOOPBACK_FS_TYPE,
+            "-q",
+            MAPPER_DEVICE,
+        ]),
+        None,
+    )?;
+    let _ = run(
+        Command::new("mount")
+            .args(["-t", LOOPBACK_FS_TYPE, "-o", options, MAPPER_DEVICE])
+            .arg(secure_dir),
+        None,
+    )?;
+    if let Err(e) =
+        fs::set_permissions(secure_dir, fs::Permissions::from_mode(0o700))
+    {
+        let _ = run(Command::new("umount").arg(secure_dir), None);
+        return Err(Error::file(secure_dir, e));
+    }
+    Ok(())
+}
+
+/*
+ * Input: secure mount directory, mount options
+ * Return: Result wrap with error message
+ *
+ * Creates the sparse backing file, attaches it to a loop device, opens a
+ * plain dm-crypt device on it with a random key, formats it and mounts it
+ * on the secure directory. Any previous contents of the backing file are
+ * lost. On failure, the devices set up so far are closed again.
+ */
+pub(crate) fn mount(secure_dir: &str, options: &str) -> Result<()> {
+    let size = parse_size(&config_get_or(
+        "cloud_agent",
+        "secure_loopback_size",
+        DEFAULT_LOOPBACK_SIZE,
+    )?)?;
+    let file = Path::new(&work_dir_get()).join(config_get_or(
+        "cloud_agent",
+        "secure_loopback_file",
+        DEFAULT_LOOPBACK_FILE,
+    )?);
+
+    // A device left over from a previous run cannot be unlocked again, as
+    // its key is gone with that run
+    if Path::new(MAPPER_DEVICE).exists() {
+        warn!("Closing stale encrypted device {}", MAPPER_DEVICE);
+        let _ = run(
+            Command::new("cryptsetup").args(["close", MAPPER_NAME]),
+            None,
+        )?;
+    }
+
+    let backing = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&file)?;
+    backing.set_len(size)?;
+    drop(backing);
+
+    // With autoclear the loop device is released when dm-crypt closes it
+    let loop_dev = run(
+        Command::new("losetup")
+            .args(["--find", "--show", "--autoclear"])
+            .arg(&file),
+        None,
+    )?;
+
+    let mut key = vec![0u8; KEY_SIZE];
+    openssl::rand::rand_bytes(&mut key)?;
+    let opened = run(
+        Command::new("cryptsetup").args([
+            "open",
+            "--type",
+            "plain",
+            "--cipher",
+            "aes-xts-plain64",
+            "--key-size",
+            "512",
+            "--key-file",
+            "-",
+            &loop_dev,
+            MAPPER_NAME,
+        ]),
+        Some(&key),
+    );
+    wipe(&mut key);
+    if let Err(e) = opened {
+        let _ =
+            run(Command::new("losetup").args(["--detach", &loop_dev]), None);
+        return Err(e);
+    }
+
+    // Closing the device releases the loop device too
+    if let Err(e) = format_and_mount(secure_dir, options) {
+        let _ = run(
+            Command::new("cryptsetup").args(["close", MAPPER_NAME]),
+            None,
+        );
+        return Err(e);
+    }
+
+    info!(
+        "Mounted encrypted secure storage {} ({} bytes backed by {}) on {}",
+        MAPPER_DEVICE,
+        size,
+        file.display(),
+        secure_dir
+    );
+    Ok(())
+}
+
+/*
+ * Input: secure mount directory
+ * Return: Result wrap with error message
+ *
+ * Unmounts the file system and closes the dm-crypt device, which discards
+ * its key. The loop device is released along with it.
+ */
+pub(crate) fn unmount(secure_dir: &str) -> Result<()> {
+    let _ = run(Command::new("umount").arg(secure_dir), None)?;
+    let _ = run(
+        Command::new("cryptsetup").args(["close", MAPPER_NAME]),
+        None,
+    )?;
+    info!("Closed encrypted secure storage {}", MAPPER_DEVICE);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loopback_size() {
+        assert_eq!(parse_size("4096").unwrap(), 4096); //#[allow_ci]
+        assert_eq!(parse_size("64m").unwrap(), 64 << 20); //#[allow_ci]
+        assert_eq!(parse_size("1G").unwrap(), 1 << 30); //#[allow_ci]
+        assert_eq!(parse_size("512k").unwrap(), 512 << 10); //#[allow_ci]
+
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("10%").is_err());
+        assert!(parse_size("-1m").is_err());
+        assert!(parse_size("99999999999999999999g").is_err());
+        assert!(parse_size("18446744073709551615g").is_err());
+    }
+
+    #[test]
+    fn run_command() {
+        assert_eq!(
+            run(&mut Command::new("cat"), Some(b"key")).unwrap(), //#[allow_ci]
+            "key"
+        );
+        assert!(run(&mut Command::new("false"), None).is_err());
+    }
+}
@@ -4,52 +4,446 @@
 use super::*;
 
 use crate::cmd_exec;
+use crate::container;
 use crate::error::{Error, Result};
+use crate::keyring;
+use crate::secure_loopback;
+use crate::selinux;
 use common::config_get;
+use common::{config_get_bool_or, config_get_or};
 use std::fs;
-use std::os::unix::fs::PermissionsExt;
-use std::process::Command;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::PathBuf;
+use tracing::{debug, error, info, warn};
+
+/*
+ * Input: secure_size value from keylime.conf
+ * Return: Result wrap the size or error message
+ *
+ * Validate the size of the tmpfs before passing it to mount: a number of
+ * bytes with an optional k, m or g suffix, or a percentage of the RAM.
+ */
+fn validate_secure_size(size: &str) -> Result<String> {
+    let size = size.trim();
+    let (digits, percent) = match size.char_indices().last() {
+        Some((i, '%')) => (&size[..i], true),
+        Some((i, 'k')) | Some((i, 'K')) | Some((i, 'm')) | Some((i, 'M'))
+        | Some((i, 'g')) | Some((i, 'G')) => (&size[..i], false),
+        _ => (size, false),
+    };
+
+    let value = match digits.parse::<u64>() {
+        Ok(value) if digits.chars().all(|c| c.is_ascii_digit()) => value,
+        _ => {
+            return Err(Error::config_value(
+                "cloud_agent",
+                "secure_size",
+                format!(
+                    "{:?} is not a size in bytes with an optional k, m or g suffix, or a percentage",
+                    size
+                ),
+            ))
+        }
+    };
+    if value == 0 || (percent && value > 100) {
+        return Err(Error::config_value(
+            "cloud_agent",
+            "secure_size",
+            format!("{:?} is out of range", size),
+        ));
+    }
+
+    Ok(size.to_string())
+}
+
+/*
+ * Return: Result wrap the secure mount directory
+ *
+ * The secure directory is secure_dir from keylime.conf, relative to the work
+ * directory unless absolute, and defaults to the secure directory in the
+ * work directory as in the original python version.
+ */
+pub(crate) fn secure_dir_get() -> Result<String> {
+    let mut secure_dir = config_get_or("cloud_agent", "secure_dir", "")?;
+    if secure_dir.is_empty() {
+        secure_dir = String::from("secure");
+    }
+    // An absolute secure_dir replaces the work directory
+    let secure_dir = Path::new(&work_dir_get()).join(secure_dir);
+
+    match secure_dir.to_str() {
+        Some(s) => Ok(s.to_string()),
+        None => Err(Error::config_value(
+            "cloud_agent",
+            "secure_dir",
+            format!("{:?} is not valid UTF-8", secure_dir),
+        )),
+    }
+}
+
+static MOUNTINFO: &str = "/proc/self/mountinfo";
+
+// Backing store of the secure directory, secure_storage in keylime.conf
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SecureStorage {
+    /// RAM backed tmpfs, as in the original python version
+    Tmpfs,
+    /// dm-crypt device on a loopback file, with an ephemeral key
+    Loopback,
+    /// Kernel user keyring for the derived key, and a plain directory
+    /// for the payload, for agents that cannot mount file systems
+    Keyring,
+}
+
+/*
+ * Return: Result wrap the secure storage in use
+ *
+ * Mounting requires CAP_SYS_ADMIN, so an agent without it falls back to
+ * the kernel keyring whatever secure_storage says, unless the secure
+ * directory was already mounted for it.
+ */
+pub(crate) fn secure_storage_get() -> Result<SecureStorage> {
+    let storage =
+        match config_get_or("cloud_agent", "secure_storage", "tmpfs")?
+            .as_str()
+        {
+            "tmpfs" => SecureStorage::Tmpfs,
+            "loopback" => SecureStorage::Loopback,
+            "keyring" => SecureStorage::Keyring,
+            other => {
+                return Err(Error::config_value(
+                    "cloud_agent",
+                    "secure_storage",
+                    format!(
+                        "{} is not one of tmpfs, loopback or keyring",
+                        other
+                    ),
+                ))
+            }
+        };
+
+    if MOUNT_SECURE
+        && storage != SecureStorage::Keyring
+        && !keyring::has_cap_sys_admin()
+        && !is_mounted(&secure_dir_get()?)
+    {
+        if container::enabled() {
+            warn!("No CAP_SYS_ADMIN to mount {:?} secure storage, using keyring: mount a memory-backed volume on {} instead", storage, secure_dir_get()?);
+        } else {
+            debug!(
+                "No CAP_SYS_ADMIN to mount {:?} secure storage, using keyring",
+                storage
+            );
+        }
+        return Ok(SecureStorage::Keyring);
+    }
+
+    Ok(storage)
+}
+
+// A line of /proc/self/mountinfo, see proc(5)
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct MountInfo {
+    mount_point: String,
+    mount_options: Vec<String>,
+    fs_type: String,
+    source: String,
+    super_options: Vec<String>,
+}
+
+impl MountInfo {
+    fn has_option(&self, option: &str) -> bool {
+        self.mount_options
+            .iter()
+            .chain(self.super_options.iter())
+            .any(|o| o == option)
+    }
+}
+
+// Paths in mountinfo have space, tab, newline and backslash escaped as
+// octal sequences
+fn unescape_mountinfo(field: &str) -> String {
+    let mut out = Vec::with_capacity(field.len());
+    let bytes = field.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 4 <= bytes.len() {
+            let octal = std::str::from_utf8(&bytes[i + 1..i + 4]).ok();
+            if let Some(c) = octal.and_then(|o| u8::from_str_radix(o, 8).ok())
+            {
+                out.push(c);
+                i += 4;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn parse_mountinfo_line(line: &str) -> Option<MountInfo> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    // The optional fields end with a single hyphen
+    let separator = fields.iter().position(|f| *f == "-")?;
+    if separator < 6 || fields.len() < separator + 4 {
+        return None;
+    }
+
+    Some(MountInfo {
+        mount_point: unescape_mountinfo(fields[4]),
+        mount_options: fields[5].split(',').map(String::from).collect(),
+        fs_type: fields[separator + 1].to_string(),
+        source: unescape_mountinfo(fields[separator + 2]),
+        super_options: fields[separator + 3]
+            .split(',')
+            .map(String::from)
+            .collect(),
+    })
+}
+
+// Returns the mount on top of the directory, i.e. the last one listed
+fn find_mount(mountinfo: &str, dir: &str) -> Option<MountInfo> {
+    mountinfo
+        .lines()
+        .filter_map(parse_mountinfo_line)
+        .rfind(|info| info.mount_point == dir)
+}
+
+// Whether a directory is a mount point, e.g. for a secure directory mounted
+// by a systemd mount unit for an agent running unprivileged
+pub(crate) fn is_mounted(dir: &str) -> bool {
+    fs::read_to_string(MOUNTINFO)
+        .map(|mountinfo| find_mount(&mountinfo, dir).is_some())
+        .unwrap_or(false)
+}
+
+// An unprivileged agent uses a secure directory mounted by someone else,
+// which must be its own and not accessible to anybody else
+fn check_owner(secure_dir: &str) -> Result<()> {
+    let metadata = fs::metadata(secure_dir)?;
+    let euid = unsafe { libc::geteuid() };
+    if metadata.uid() != euid || metadata.mode() & 0o077 != 0 {
+        return Err(Error::SecureMountPermissions {
+            path: PathBuf::from(secure_dir),
+            uid: metadata.uid(),
+            mode: metadata.mode() & 0o777,
+        });
+    }
+    Ok(())
+}
+
+/*
+ * Return: Result wrap the mount options for the secure directory
+ *
+ * The secure directory is always mounted writable, without set-user-ID
+ * programs and device files. noexec is optional as payloads that only
+ * deliver keys can use it, while payload scripts may need to execute
+ * files from the unzipped payload.
+ */
+fn hardening_options() -> Result<Vec<&'static str>> {
+    let mut options = vec!["rw", "nosuid", "nodev"];
+    if config_get_bool_or("cloud_agent", "secure_noexec", false)? {
+        options.push("noexec");
+    }
+    Ok(options)
+}
+
+// Hardening options the mount lacks, e.g. as the file system ignored them
+fn missing_hardening(info: &MountInfo) -> Vec<&'static str> {
+    ["nosuid", "nodev", "noexec"]
+        .iter()
+        .copied()
+        .filter(|option| !info.has_option(option))
+        .collect()
+}
+
+// Logs the options the secure directory was mounted with, as the kernel
+// reports them rather than as they were requested
+fn log_mount_options(secure_dir: &str) {
+    let info = match fs::read_to_string(MOUNTINFO)
+        .ok()
+        .and_then(|mountinfo| find_mount(&mountinfo, secure_dir))
+    {
+        Some(info) => info,
+        None => {
+            warn!(
+                "Unable to find the mount of {} in {}",
+                secure_dir, MOUNTINFO
+            );
+            return;
+        }
+    };
+    info!(
+        "Secure storage mounted with options: {}",
+        info.mount_options.join(",")
+    );
+    for option in missing_hardening(&info) {
+        warn!(
+            "Secure storage {} is mounted without {}",
+            secure_dir, option
+        );
+    }
+}
+
+/*
+ * Input: mount found on the secure mount directory, configured storage,
+ *        mount options it must have
+ * Return: Result wrap with error message
+ *
+ * An existing mount is only reused if it is a tmpfs that is only
+ * accessible by its owner with the required options, as the agent would
+ * have mounted it. With loopback storage, it must be the agent's encrypted
+ * device instead.
+ */
+fn validate_mount(
+    info: &MountInfo,
+    storage: SecureStorage,
+    options: &[&str],
+) -> Result<()> {
+    let missing = options.iter().find(|option| !info.has_option(option));
+
+    let (msg, reason) = if storage == SecureStorage::Loopback
+        && info.source != secure_loopback::MAPPER_DEVICE
+    {
+        (format!("secure storage location {} already mounted from {} instead of {}. Unmount to continue", info.mount_point, info.source, secure_loopback::MAPPER_DEVICE), "already mounted from another device")
+    } else if storage != SecureStorage::Loopback && info.fs_type != "tmpfs" {
+        (format!("secure storage location {} already mounted as wrong file system type: {}. Unmount to continue", info.mount_point, info.fs_type), "already mounted as wrong file system type")
+    } else if storage != SecureStorage::Loopback
+        && !info.has_option("mode=700")
+    {
+        (format!("secure storage location {} is mounted without mode=700. Unmount to continue", info.mount_point), "mounted without mode=700")
+    } else if let Some(option) = missing {
+        (format!("secure storage location {} is mounted without {}. Unmount to continue", info.mount_point, option), "mounted without a required option")
+    } else {
+        return Ok(());
+    };
+
+    error!("{}", msg);
+    Err(Error::SecureMount {
+        path: PathBuf::from(&info.mount_point),
+        reason,
+    })
+}
+
 /*
  * Input: secure mount directory
  * Return: Result wrap boolean with error message
  *         - true if directory is mounted
  *         - false if not mounted
  *
- * Check the mount status of the secure mount directory. Same
- * implementation as the original python version.
+ * Check the mount status of the secure mount directory in
+ * /proc/self/mountinfo, and whether an existing mount can be reused.
  */
-fn check_mount(secure_dir: &str) -> Result<bool> {
-    let output = Command::new("mount").output()?;
-
-    let mount_result = String::from_utf8(output.stdout)?;
+fn check_mount(
+    secure_dir: &str,
+    storage: SecureStorage,
+    options: &[&str],
+) -> Result<bool> {
+    // A symbolic link could point the secure directory anywhere, e.g. to a
+    // tmpfs shared with other users
+    if let Ok(metadata) = fs::symlink_metadata(secure_dir) {
+        if metadata.file_type().is_symlink() {
+            let msg = format!("secure storage location {} is a symbolic link. Remove it to continue", secure_dir);
+            error!("{}", msg);
+            return Err(Error::SecureMount {
+                path: PathBuf::from(secure_dir),
+                reason: "is a symbolic link",
+            });
+        }
+    }
 
-    let lines: Vec<&str> = mount_result.split('\n').collect();
+    let mountinfo = fs::read_to_string(MOUNTINFO)?;
+    match find_mount(&mountinfo, secure_dir) {
+        // In a container, the orchestrator mounts the memory-backed volume
+        // with its own options, so only the directory is restricted
+        Some(info)
+            if container::enabled()
+                && storage == SecureStorage::Tmpfs
+                && info.fs_type == "tmpfs" =>
+        {
+            fs::set_permissions(
+                secure_dir,
+                fs::Permissions::from_mode(0o700),
+            )?;
+            info!(
+                "Using memory-backed volume {} provided to the container",
+                secure_dir
+            );
+            Ok(true)
+        }
+        Some(info) => {
+            validate_mount(&info, storage, options)?;
+            info!(
+                "Using existing secure storage {} mount {} ({})",
+                info.fs_type,
+                secure_dir,
+                info.super_options.join(",")
+            );
+            Ok(true)
+        }
+        None => {
+            info!("secure storage location {} not mounted.", secure_dir);
+            Ok(false)
+        }
+    }
+}
 
-    // Check mount list for secure directory
-    for line in lines {
-        let tokens: Vec<&str> = line.split(' ').collect();
+/*
+ * Return: Result wrap boolean with error message
+ *         - true if the agent now runs in a private mount namespace
+ *         - false if secure_mount_namespace is disabled
+ *
+ * Moves the agent to a new mount namespace, so that the secure mount is
+ * not visible in other processes' mount tables. Mounts are made slaves of
+ * the host's: mounts from the host still propagate into the namespace, but
+ * not the other way around. Processes started by the agent, such as the
+ * payload script and revocation actions, inherit the namespace.
+ *
+ * Namespaces are per thread, so this must be called before the agent
+ * starts any other thread.
+ */
+pub(crate) fn enter_private_namespace() -> Result<bool> {
+    if !MOUNT_SECURE
+        || !config_get_bool_or(
+            "cloud_agent",
+            "secure_mount_namespace",
+            false,
+        )?
+    {
+        return Ok(false);
+    }
+    if secure_storage_get()? == SecureStorage::Keyring {
+        warn!(
+            "Not using a private mount namespace for keyring secure storage"
+        );
+        return Ok(false);
+    }
 
-        if tokens.len() < 3 {
-            continue;
+    unsafe {
+        if libc::unshare(libc::CLONE_NEWNS) != 0 {
+            let e = std::io::Error::last_os_error();
+            error!("Unable to create private mount namespace: {}", e);
+            return Err(e.into());
         }
 
-        if tokens[2] == secure_dir {
-            if tokens[0] != "tmpfs" {
-                let msg = format!("secure storage location {} already mounted as wrong file system type: {}. Unmount to continue", secure_dir, tokens[0]);
-                error!("{}", msg);
-                return Err(Error::SecureMount(msg));
-            } else {
-                info!(
-                    "Using existing secure storage tmpsfs mount {}",
-                    secure_dir
-                );
-            }
-            return Ok(true);
+        let root = std::ffi::CString::new("/").unwrap(); //#[allow_ci] : no NUL in literal
+        if libc::mount(
+            std::ptr::null(),
+            root.as_ptr(),
+            std::ptr::null(),
+            libc::MS_REC | libc::MS_SLAVE,
+            std::ptr::null(),
+        ) != 0
+        {
+            let e = std::io::Error::last_os_error();
+            error!("Unable to make mounts private in namespace: {}", e);
+            return Err(e.into());
         }
     }
 
-    info!("secure storage location {} not mounted.", secure_dir);
-    Ok(false)
+    info!("Using a private mount namespace for secure storage");
+    Ok(true)
 }
 
 /*
@@ -63,16 +457,11 @@ pub(crate) fn mount() -> Result<String> {
     // Use /tmpfs-dev directory if MOUNT_SECURE flag is not set. This
     // is for development environment and does not mount to the system.
     if !MOUNT_SECURE {
-        warn!("Using /tmpfs-dev (dev environment)");
-        let secure_dir = format!("{}{}", WORK_DIR, "/tmpfs-dev");
-        let secure_dir_path = Path::new(secure_dir.as_str());
+        warn!("Using {} (dev environment)", TMPFS_DEV_DIR);
+        let secure_dir_path = Path::new(TMPFS_DEV_DIR);
         if !secure_dir_path.exists() {
-            fs::create_dir(secure_dir_path).map_err(|e| {
-                Error::SecureMount(format!(
-                    "unable to create secure dir path: {:?}",
-                    e
-                ))
-            })?;
+            fs::create_dir(secure_dir_path)
+                .map_err(|e| Error::file(secure_dir_path, e))?;
             info!("Directory {:?} created.", secure_dir_path);
         }
 
@@ -80,10 +469,33 @@ pub(crate) fn mount() -> Result<String> {
     }
 
     // Mount the directory to file system
-    let secure_dir = format!("{}/secure", WORK_DIR);
-    let secure_size = config_get("cloud_agent", "secure_size")?;
+    let secure_dir = secure_dir_get()?;
+    let secure_size =
+        validate_secure_size(&config_get("cloud_agent", "secure_size")?)?;
+    let storage = secure_storage_get()?;
+
+    // Without mounting anything, the payload goes into a directory only
+    // accessible by the agent's user. It is not RAM backed, so warn.
+    if storage == SecureStorage::Keyring {
+        warn!("Using keyring secure storage: the derived key is kept in the user keyring, but the payload is stored on disk in {}", secure_dir);
+        fs::create_dir_all(&secure_dir)?;
+        fs::set_permissions(&secure_dir, fs::Permissions::from_mode(0o700))?;
+        if let Some(context) = selinux::secure_context_get(&secure_dir)? {
+            selinux::set_file_context(Path::new(&secure_dir), &context)?;
+        }
+        return Ok(secure_dir);
+    }
+
+    let options = hardening_options()?;
+    // The files in the secure directory are labeled as the directory
+    // itself would be, so that confined payload scripts can read them
+    let mut mount_options = options.join(",");
+    if let Some(context) = selinux::secure_context_get(&secure_dir)? {
+        mount_options.push(',');
+        mount_options.push_str(&selinux::context_mount_option(&context));
+    }
 
-    match check_mount(&secure_dir)? {
+    match check_mount(&secure_dir, storage, &options)? {
         false => {
             // If the directory is not mount to file system, mount the directory to
             // file system.
@@ -94,18 +506,12 @@ pub(crate) fn mount() -> Result<String> {
             // directory permission is set to 448.
 
             if !secure_dir_path.exists() {
-                fs::create_dir(secure_dir_path).map_err(|e| {
-                    Error::SecureMount(format!(
-                        "unable to create secure dir path: {:?}",
-                        e
-                    ))
-                })?;
+                fs::create_dir_all(secure_dir_path)
+                    .map_err(|e| Error::file(secure_dir_path, e))?;
 
                 info!("Directory {:?} created.", secure_dir_path);
-                let metadata =
-                    fs::metadata(secure_dir_path).map_err(|e| {
-                        Error::SecureMount(format!("unable to get metadata for secure dir path: {:?}", e))
-                    })?;
+                let metadata = fs::metadata(secure_dir_path)
+                    .map_err(|e| Error::file(secure_dir_path, e))?;
                 metadata.permissions().set_mode(0o750); // decimal 488
             }
 
@@ -113,43 +519,213 @@ pub(crate) fn mount() -> Result<String> {
                 Some(s) => {
                     info!("Mounting secure storage location {} on tmpfs.", s);
 
-                    // change the secure path directory owner to root
-                    if let Err(e) = chownroot(s.to_string()).map(|path| {
-                        info!("Changed path {} owner to root.", path);
-                    }) {
-                        return Err(Error::SecureMount(
-                                format!(
-                                    "unable to change secure path dir owner to root: received exit code {}",
-                                    e.exe_code()?.unwrap() //#[allow_ci] : because this is an Option
-                                ),
-                            ));
+                    // change the secure path directory owner to root, unless
+                    // the agent runs unprivileged with just CAP_SYS_ADMIN
+                    if unsafe { libc::geteuid() } != 0 {
+                        info!("Not running as root, {} keeps its owner", s);
+                    } else if let Err(e) =
+                        chownroot(s.to_string()).map(|path| {
+                            info!("Changed path {} owner to root.", path);
+                        })
+                    {
+                        error!("Unable to change secure path dir owner to root: {}", e);
+                        return Err(Error::SecureMount {
+                            path: PathBuf::from(s),
+                            reason: "unable to change owner to root",
+                        });
+                    }
+
+                    if storage == SecureStorage::Loopback {
+                        secure_loopback::mount(s, &mount_options)?;
+                        log_mount_options(s);
+                        return Ok(s.to_string());
                     }
 
                     // mount tmpfs with secure directory
                     if let Err(e) = cmd_exec::run(
                         format!(
-                            "mount -t tmpfs -o size={},mode=0700 tmpfs {}",
-                            secure_size, s,
+                            "mount -t tmpfs -o size={},mode=0700,{} tmpfs {}",
+                            secure_size, mount_options, s,
                         ),
                         None,
                     ) {
-                        return Err(Error::SecureMount(
-                            format!(
-                                "unable to mount tmpfs with secure dir: received exit code {}",
-                                e.exe_code()?.unwrap() //#[allow_ci] : because this is an Option
-                            ),
-                        ));
+                        error!(
+                            "Unable to mount tmpfs with secure dir: {}",
+                            e
+                        );
+                        return Err(Error::SecureMount {
+                            path: PathBuf::from(s),
+                            reason: "unable to mount tmpfs",
+                        });
                     }
+                    log_mount_options(s);
 
                     Ok(s.to_string())
                 }
-                None => Err(Error::SecureMount(
-                    "Error mounting secure storage location on tmpfs"
-                        .to_string(),
-                )),
+                None => Err(Error::SecureMount {
+                    path: secure_dir_path.to_path_buf(),
+                    reason: "path is not valid UTF-8",
+                }),
             }
         }
 
-        true => Ok(secure_dir),
+        true => {
+            if unsafe { libc::geteuid() } != 0 {
+                check_owner(&secure_dir)?;
+            }
+            Ok(secure_dir)
+        }
+    }
+}
+
+/*
+ * Input: secure mount directory, secure storage it was mounted with
+ * Return: Result wrap with error message
+ *
+ * Unmounts the secure directory when the agent stops, so that the payload
+ * and the derived key do not outlive it. With keyring secure storage, the
+ * derived key is removed from the keyring instead. The storage must be
+ * the one mount() used, as secure_storage_get() falls back to the keyring
+ * once the agent dropped its privileges.
+ */
+pub(crate) fn unmount(
+    secure_dir: &str,
+    storage: SecureStorage,
+) -> Result<()> {
+    if !MOUNT_SECURE {
+        return Ok(());
+    }
+
+    match storage {
+        SecureStorage::Keyring => {
+            keyring::remove_key(&config_get("cloud_agent", "enc_keyname")?)
+        }
+        SecureStorage::Loopback => secure_loopback::unmount(secure_dir),
+        SecureStorage::Tmpfs => {
+            if let Err(e) =
+                cmd_exec::run(format!("umount {}", secure_dir), None)
+            {
+                error!("Unable to unmount secure dir: {}", e);
+                return Err(Error::SecureMount {
+                    path: PathBuf::from(secure_dir),
+                    reason: "unable to unmount",
+                });
+            }
+            info!("Unmounted secure storage location {}", secure_dir);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static SAMPLE: &str = "\
+22 1 253:0 / / rw,relatime shared:1 - ext4 /dev/mapper/root rw
+45 22 0:40 / /var/lib/keylime/secure rw,nosuid,nodev,relatime shared:24 - tmpfs tmpfs rw,size=1024k,mode=700
+46 22 0:41 / /mnt/with\\040space rw - tmpfs none rw,mode=700
+47 45 0:42 / /var/lib/keylime/secure rw,relatime shared:25 - ext4 /dev/sdb1 rw
+48 22 0:43 / /var/lib/other rw,relatime - tmpfs tmpfs ro,mode=755
+";
+
+    #[test]
+    fn mountinfo_parse() {
+        let info =
+            parse_mountinfo_line(SAMPLE.lines().nth(1).unwrap()).unwrap(); //#[allow_ci]
+        assert_eq!(info.mount_point, "/var/lib/keylime/secure");
+        assert_eq!(info.fs_type, "tmpfs");
+        assert_eq!(info.source, "tmpfs");
+        assert!(info.has_option("relatime"));
+        assert!(info.has_option("mode=700"));
+
+        assert!(find_mount(SAMPLE, "/mnt/with space").is_some());
+        assert!(find_mount(SAMPLE, "/var/lib/keylime").is_none());
+        assert!(parse_mountinfo_line("22 1 253:0 / / rw").is_none());
+    }
+
+    #[test]
+    fn mountinfo_validate() {
+        let options = ["rw", "nosuid", "nodev"];
+
+        // The ext4 mounted on top of the tmpfs is the effective one
+        let stacked = find_mount(SAMPLE, "/var/lib/keylime/secure").unwrap(); //#[allow_ci]
+        assert_eq!(stacked.fs_type, "ext4");
+        assert!(
+            validate_mount(&stacked, SecureStorage::Tmpfs, &options).is_err()
+        );
+
+        let tmpfs =
+            parse_mountinfo_line(SAMPLE.lines().nth(1).unwrap()).unwrap(); //#[allow_ci]
+        assert!(
+            validate_mount(&tmpfs, SecureStorage::Tmpfs, &options).is_ok()
+        );
+        assert!(validate_mount(&tmpfs, SecureStorage::Loopback, &options)
+            .is_err());
+        // Mounted without noexec
+        assert!(validate_mount(
+            &tmpfs,
+            SecureStorage::Tmpfs,
+            &["rw", "nosuid", "nodev", "noexec"]
+        )
+        .is_err());
+
+        let other = find_mount(SAMPLE, "/var/lib/other").unwrap(); //#[allow_ci]
+        assert!(validate_mount(&other, SecureStorage::Tmpfs, &[]).is_err());
+
+        let loopback = parse_mountinfo_line(
+            "50 22 253:3 / /var/lib/keylime/secure rw,nosuid,nodev,relatime - ext4 /dev/mapper/keylime-secure rw",
+        )
+        .unwrap(); //#[allow_ci]
+        assert!(validate_mount(&loopback, SecureStorage::Loopback, &options)
+            .is_ok());
+        assert!(validate_mount(&loopback, SecureStorage::Tmpfs, &options)
+            .is_err());
+
+        let unhardened = parse_mountinfo_line(
+            "51 22 253:3 / /var/lib/keylime/secure rw,relatime - ext4 /dev/mapper/keylime-secure rw",
+        )
+        .unwrap(); //#[allow_ci]
+        assert!(validate_mount(
+            &unhardened,
+            SecureStorage::Loopback,
+            &options
+        )
+        .is_err());
+        assert_eq!(
+            missing_hardening(&unhardened),
+            ["nosuid", "nodev", "noexec"]
+        );
+        assert_eq!(missing_hardening(&loopback), ["noexec"]);
+    }
+
+    #[test]
+    fn secure_size() {
+        assert_eq!(validate_secure_size("1m").unwrap(), "1m"); //#[allow_ci]
+        assert_eq!(validate_secure_size(" 512K ").unwrap(), "512K"); //#[allow_ci]
+        assert_eq!(validate_secure_size("1048576").unwrap(), "1048576"); //#[allow_ci]
+        assert_eq!(validate_secure_size("2g").unwrap(), "2g"); //#[allow_ci]
+        assert_eq!(validate_secure_size("10%").unwrap(), "10%"); //#[allow_ci]
+
+        assert!(validate_secure_size("").is_err());
+        assert!(validate_secure_size("m").is_err());
+        assert!(validate_secure_size("0").is_err());
+        assert!(validate_secure_size("150%").is_err());
+        assert!(validate_secure_size("-1m").is_err());
+        assert!(validate_secure_size("+1m").is_err());
+        assert!(validate_secure_size("1t").is_err());
+        assert!(validate_secure_size("1m,exec").is_err());
+    }
+
+    #[test]
+    fn secure_dir_owner() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().to_str().unwrap(); //#[allow_ci]
+
+        fs::set_permissions(path, fs::Permissions::from_mode(0o700)).unwrap(); //#[allow_ci]
+        assert!(check_owner(path).is_ok());
+
+        fs::set_permissions(path, fs::Permissions::from_mode(0o750)).unwrap(); //#[allow_ci]
+        assert!(check_owner(path).is_err());
     }
 }
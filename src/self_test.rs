@@ -0,0 +1,185 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Startup self-test
+//
+// keylime_agent --self-test checks that the agent would be able to run on
+// this host, without registering: the configuration is valid, the TPM
+// produces a quote, the IMA measurement list is readable, the secure
+// directory can be mounted and the registrar is reachable. It prints a
+// report, and exits with an error if a check failed, so that provisioning
+// pipelines can run it before enrolling a node.
+
+use crate::common::{
+    cloudagent_ip_get, cloudagent_port_get, config_file_get, config_get,
+    registrar_ip_get, registrar_port_get, IMA_ML,
+};
+use crate::error::{Error, Result};
+use crate::ima::MeasurementList;
+use crate::key_delivery::{KeyDelivery, DEFAULT_KEY_DELIVERY_TIMEOUT};
+use crate::limits::Limits;
+use crate::tpm_worker::TpmWorker;
+use crate::{
+    crypto, http, permissions, quote, seccomp, secure_mount, tpm, QuoteData,
+};
+
+use actix_web::web;
+use std::fmt::Write;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64},
+    Mutex,
+};
+use std::time::Duration;
+
+// How long to wait for the registrar
+const REGISTRAR_TIMEOUT: Duration = Duration::from_secs(10);
+
+static NONCE: &[u8] = b"keylimeselftest";
+
+fn check_config() -> Result<String> {
+    let _ = cloudagent_ip_get()?;
+    let _ = cloudagent_port_get()?.parse::<u16>().map_err(|e| {
+        Error::config_value("cloud_agent", "cloudagent_port", e)
+    })?;
+    let _ = registrar_ip_get()?;
+    let _ = registrar_port_get()?
+        .parse::<u16>()
+        .map_err(|e| Error::config_value("registrar", "registrar_port", e))?;
+    let _ = tpm::get_hash_alg(config_get("cloud_agent", "tpm_hash_alg")?)?;
+    let _ = config_get("cloud_agent", "agent_uuid")?;
+    let _ = secure_mount::secure_storage_get()?;
+    let _ = seccomp::seccomp_mode_get()?;
+    let _ = permissions::run_as_get()?;
+    Ok(config_file_get())
+}
+
+// Quotes as for the tenant's identity quote, with a throwaway AK, which is
+// flushed along with the EK whether the quote succeeds or not
+async fn check_quote() -> Result<String> {
+    let mut ctx = tpm::get_tpm2_ctx()?;
+    let keys = tpm::load_keys(&mut ctx, tpm::AkSource::Create)?;
+    let setup = (|| -> Result<_> {
+        let (pub_key, priv_key) = crypto::rsa_generate_pair(2048)?;
+        let pub_key_pem = String::from_utf8(pub_key.public_key_to_pem()?)?;
+        let quote_config = quote::QuoteConfig::from_config()?;
+        Ok((
+            pub_key,
+            priv_key,
+            pub_key_pem,
+            quote_config,
+            keys.ak()?.handle,
+        ))
+    })();
+    let (pub_key, priv_key, pub_key_pem, quote_config, ak_handle) =
+        match setup {
+            Ok(setup) => setup,
+            Err(e) => {
+                let _ = keys.flush(&mut ctx);
+                return Err(e);
+            }
+        };
+
+    let data = web::Data::new(QuoteData {
+        tpm: TpmWorker::start(ctx)?,
+        priv_key,
+        pub_key_pem,
+        pub_key,
+        ak_handle,
+        agent_uuid: String::from("self-test"),
+        secure_dir: PathBuf::new(),
+        keys: Mutex::new(KeyDelivery::new(DEFAULT_KEY_DELIVERY_TIMEOUT)),
+        ima_ml: Mutex::new(MeasurementList::default()),
+        last_quote: AtomicU64::new(0),
+        registered: AtomicBool::new(false),
+        limits: Limits::unlimited(),
+        quote_config,
+        evidence: None,
+        runtime_policy: Mutex::new(None),
+    });
+    let quote = quote::quote(NONCE, None, data.clone()).await;
+    let flushed = data.tpm.run(move |tpm| tpm.flush_keys(&keys)).await;
+    let quote = quote?;
+    flushed?;
+    Ok(format!("{} bytes", quote.quote.len()))
+}
+
+fn check_ima() -> Result<String> {
+    let mut ml = String::new();
+    let _ = File::open(IMA_ML)?.read_to_string(&mut ml)?;
+    Ok(format!("{} entries in {}", ml.lines().count(), IMA_ML))
+}
+
+// Unmounts again what it mounted, but leaves the keyring alone and an
+// existing mount, e.g. that of a running agent, in place
+fn check_secure_mount() -> Result<String> {
+    let storage = secure_mount::secure_storage_get()?;
+    let mounted = secure_mount::is_mounted(&secure_mount::secure_dir_get()?);
+    let secure_dir = secure_mount::mount()?;
+    if !mounted && storage != secure_mount::SecureStorage::Keyring {
+        secure_mount::unmount(&secure_dir, storage)?;
+    }
+    Ok(format!("{} ({:?})", secure_dir, storage))
+}
+
+// Any HTTP response shows that the registrar is reachable
+async fn check_registrar() -> Result<String> {
+    let url =
+        format!("http://{}:{}/", registrar_ip_get()?, registrar_port_get()?);
+    let response = http::client()
+        .get(&url)
+        .timeout(REGISTRAR_TIMEOUT)
+        .send()
+        .await?;
+    Ok(format!("{} answered {}", url, response.status()))
+}
+
+// Formats the report, one line per check
+fn report(results: &[(&str, Result<String>)]) -> String {
+    let mut report = String::new();
+    for (name, result) in results {
+        let _ = match result {
+            Ok(detail) => writeln!(report, "PASS {:<14} {}", name, detail),
+            Err(e) => writeln!(report, "FAIL {:<14} {}", name, e),
+        };
+    }
+    report
+}
+
+/*
+ * Return: whether all checks passed
+ *
+ * Runs the checks and prints the report to standard output.
+ */
+pub(crate) async fn run() -> bool {
+    let results = vec![
+        ("config", check_config()),
+        ("tpm_quote", check_quote().await),
+        ("ima_log", check_ima()),
+        ("secure_mount", check_secure_mount()),
+        ("registrar", check_registrar().await),
+    ];
+
+    print!("{}", report(&results));
+    results.iter().all(|(_, result)| result.is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn self_test_report() {
+        let results = vec![
+            ("config", Ok(String::from("/etc/keylime.conf"))),
+            ("registrar", Err(Error::Usage(String::from("bad")))),
+        ];
+        assert_eq!(
+            report(&results),
+            "PASS config         /etc/keylime.conf\n\
+             FAIL registrar      bad\n"
+        );
+    }
+}
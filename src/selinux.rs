@@ -0,0 +1,210 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// SELinux labeling of the secure directory
+//
+// Files created in a freshly mounted tmpfs get a generic label that payload
+// scripts confined by the policy may not be allowed to read. When SELinux
+// is enforcing, the secure directory is therefore mounted with the context
+// the policy defines for it, as restorecon would set it, and the files the
+// agent writes there are created with the context of the directory. The
+// same is done without libselinux, through the kernel interfaces it uses.
+
+use crate::common::config_get_or;
+use crate::error::{Error, Result};
+
+use std::ffi::CString;
+use std::fs::{self, OpenOptions};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::process::Command;
+use tracing::{info, warn};
+
+static SELINUX_ENFORCE: &str = "/sys/fs/selinux/enforce";
+
+// Context of the files the calling thread creates, see setfscreatecon(3)
+static FSCREATE: &str = "/proc/thread-self/attr/fscreate";
+
+static XATTR_NAME: &str = "security.selinux";
+
+/// Whether SELinux is enabled and enforcing
+pub(crate) fn enforcing() -> bool {
+    fs::read_to_string(SELINUX_ENFORCE)
+        .map(|enforce| enforce.trim() == "1")
+        .unwrap_or(false)
+}
+
+/*
+ * Input: secure mount directory
+ * Return: Result wrap the SELinux context for it, if any
+ *
+ * secure_context from keylime.conf takes precedence. Otherwise, when
+ * SELinux is enforcing, the context is looked up in the policy with
+ * matchpathcon. The directory keeps the context it would get by default if
+ * the lookup fails.
+ */
+pub(crate) fn secure_context_get(secure_dir: &str) -> Result<Option<String>> {
+    let context = config_get_or("cloud_agent", "secure_context", "")?;
+    if !context.trim().is_empty() {
+        return Ok(Some(context.trim().to_string()));
+    }
+    if !enforcing() {
+        return Ok(None);
+    }
+
+    match Command::new("matchpathcon")
+        .args(["-n", secure_dir])
+        .output()
+    {
+        Ok(output) if output.status.success() => {
+            let context = String::from_utf8(output.stdout)?;
+            Ok(Some(context.trim().to_string()))
+        }
+        Ok(output) => {
+            warn!(
+                "No SELinux context for {}: {}",
+                secure_dir,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            Ok(None)
+        }
+        Err(e) => {
+            warn!("Unable to look up the SELinux context for {}: {}, run restorecon on it", secure_dir, e);
+            Ok(None)
+        }
+    }
+}
+
+/// Mount option labeling a whole file system with a context, quoted as
+/// contexts may contain commas
+pub(crate) fn context_mount_option(context: &str) -> String {
+    format!("context=\"{}\"", context)
+}
+
+fn path_cstring(path: &Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| Error::Other(format!("invalid path {}", path.display())))
+}
+
+/// Returns the context of a file, or None without SELinux
+pub(crate) fn file_context(path: &Path) -> Result<Option<String>> {
+    let c_path = path_cstring(path)?;
+    let c_name = CString::new(XATTR_NAME).unwrap(); //#[allow_ci] : no NUL in literal
+    let mut buf = vec![0u8; 256];
+    let len = unsafe {
+        libc::lgetxattr(
+            c_path.as_ptr(),
+            c_name.as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+        )
+    };
+    if len < 0 {
+        let e = std::io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::ENODATA) | Some(libc::ENOTSUP) => Ok(None),
+            _ => Err(e.into()),
+        };
+    }
+
+    buf.truncate(len as usize);
+    while buf.last() == Some(&0) {
+        let _ = buf.pop();
+    }
+    Ok(Some(String::from_utf8(buf)?))
+}
+
+/// Sets the context of a file, as chcon does
+pub(crate) fn set_file_context(path: &Path, context: &str) -> Result<()> {
+    let c_path = path_cstring(path)?;
+    let c_name = CString::new(XATTR_NAME).unwrap(); //#[allow_ci] : no NUL in literal
+    let value = CString::new(context).map_err(|_| {
+        Error::Other(format!("invalid SELinux context {}", context))
+    })?;
+    let value = value.as_bytes_with_nul();
+    if unsafe {
+        libc::lsetxattr(
+            c_path.as_ptr(),
+            c_name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    } != 0
+    {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    info!("Set SELinux context of {} to {}", path.display(), context);
+    Ok(())
+}
+
+// Writes the context of the files created by the calling thread, the
+// default context being restored by writing nothing
+fn set_fscreate(context: &[u8]) -> Result<()> {
+    let file = OpenOptions::new().write(true).open(FSCREATE)?;
+    let written = unsafe {
+        libc::write(
+            file.as_raw_fd(),
+            context.as_ptr() as *const libc::c_void,
+            context.len(),
+        )
+    };
+    if written < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Files created by the current thread get the context of a directory
+/// while this lives
+#[derive(Debug)]
+pub(crate) struct FsCreateContext {
+    active: bool,
+}
+
+impl FsCreateContext {
+    /*
+     * Input: directory the files are created in
+     * Return: Result wrap the guard restoring the default context
+     *
+     * Does nothing if the directory has no context, i.e. without SELinux.
+     */
+    pub(crate) fn like(dir: &Path) -> Result<Self> {
+        let context = match file_context(dir)? {
+            Some(context) => context,
+            None => return Ok(FsCreateContext { active: false }),
+        };
+        let context = CString::new(context).map_err(|_| {
+            Error::Other(format!(
+                "invalid SELinux context of {}",
+                dir.display()
+            ))
+        })?;
+        set_fscreate(context.as_bytes_with_nul())?;
+        Ok(FsCreateContext { active: true })
+    }
+}
+
+impl Drop for FsCreateContext {
+    fn drop(&mut self) {
+        if self.active {
+            if let Err(e) = set_fscreate(&[]) {
+                warn!("Unable to restore the default SELinux file creation context: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selinux_mount_option() {
+        assert_eq!(
+            context_mount_option("system_u:object_r:keylime_tmp_t:s0:c0,c1"),
+            "context=\"system_u:object_r:keylime_tmp_t:s0:c0,c1\""
+        );
+    }
+}
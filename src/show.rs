@@ -0,0 +1,76 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Key inspection
+//
+// keylime_agent show-ek and show-ak print the keys the agent registers,
+// created as on a start of the agent, so that operators can check what the
+// registrar will receive: the public key in PEM, and the TPM2B_PUBLIC the
+// way it is sent to the registrar, base64 encoded, along with the EK
+// certificate. The EK is derived from the TPM seed, so show-ek prints the
+// EK of every start. show-ak prints the AK the agent created on its first
+// start and registers with, as persisted in the agent data.
+
+use crate::error::Result;
+use crate::{agent_data, tpm};
+
+use openssl::x509::X509;
+use std::fmt::Write;
+
+// Formats a key, in PEM and as sent to the registrar
+fn format_key(name: &str, tpm2b_pub: &[u8]) -> Result<String> {
+    let pem = tpm::pub_to_pem(tpm2b_pub)?;
+    let mut out = String::new();
+    let _ = writeln!(out, "{} public key:", name);
+    out.push_str(&String::from_utf8(pem)?);
+    let _ = writeln!(out, "{} TPM2B_PUBLIC (base64):", name);
+    let _ = writeln!(out, "{}", base64::encode(tpm2b_pub));
+    Ok(out)
+}
+
+// Formats the EK certificate, as read from the TPM NV index
+fn format_ek_cert(ek_cert: &[u8]) -> Result<String> {
+    let mut out = String::from("EK certificate:\n");
+    if ek_cert.is_empty() {
+        out.push_str("none\n");
+    } else {
+        out.push_str(&String::from_utf8(X509::from_der(ek_cert)?.to_pem()?)?);
+    }
+    Ok(out)
+}
+
+/// Prints the EK certificate and public key
+pub(crate) fn ek() -> Result<()> {
+    let mut ctx = tpm::get_tpm2_ctx()?;
+    let keys = tpm::load_keys(&mut ctx, tpm::AkSource::None)?;
+    keys.flush(&mut ctx)?;
+
+    print!("{}", format_ek_cert(&keys.ek_cert)?);
+    print!("{}", format_key("EK", &keys.ek_tpm2b_pub)?);
+    Ok(())
+}
+
+/// Prints the public key of the AK the agent registers with
+pub(crate) fn ak() -> Result<()> {
+    let mut ctx = tpm::get_tpm2_ctx()?;
+    let keys = agent_data::load_keys(&mut ctx, false)?;
+    keys.flush(&mut ctx)?;
+
+    print!("{}", format_key("AK", &keys.ak()?.tpm2b_pub)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn show_ek_cert() {
+        assert_eq!(
+            format_ek_cert(&[]).unwrap(), //#[allow_ci]
+            "EK certificate:\nnone\n"
+        );
+        assert!(format_ek_cert(b"not a certificate").is_err());
+        assert!(format_key("AK", b"not a key").is_err());
+    }
+}
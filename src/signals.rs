@@ -0,0 +1,107 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Signal handling
+//
+// SIGTERM and SIGINT shut the agent down gracefully: the server stops
+// accepting connections and lets the requests in flight, and so the TPM
+// operations they started, complete. The secure storage is unmounted once
+// the server stopped. SIGUSR2 logs the state of the agent, to help
+// debugging a running agent without restarting it. keylime.conf is only
+// read once, SIGHUP makes the agent read it again for the settings it
+// looks up while running, such as the hooks and failure alerts.
+
+use crate::common::{config_file_get, config_reload};
+use crate::error::Result;
+use crate::systemd;
+use crate::QuoteData;
+
+use actix_web::dev::Server;
+use actix_web::web;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::info;
+
+// Set when the agent stops to be restarted
+static RESTART: AtomicBool = AtomicBool::new(false);
+
+/// Stops the agent gracefully, as SIGTERM does, but so that it exits with
+/// an error and the service manager starts it again
+pub(crate) fn request_restart() {
+    RESTART.store(true, Ordering::SeqCst);
+    let _ = unsafe { libc::kill(libc::getpid(), libc::SIGTERM) };
+}
+
+/// Whether the agent is stopping because of request_restart
+pub(crate) fn restart_requested() -> bool {
+    RESTART.load(Ordering::SeqCst)
+}
+
+// Logs what the agent is doing, without waiting for the locks held by
+// requests in flight
+fn dump_state(data: &QuoteData) {
+    let delivery = match data.keys.try_lock() {
+        Ok(keys) => keys.state().to_string(),
+        Err(_) => "busy".to_string(),
+    };
+    let tpm = match data.tpm.pending() {
+        0 => "idle".to_string(),
+        pending => format!("busy, {} operations pending", pending),
+    };
+
+    info!(
+        "State: agent {}, bootstrap key delivery {}, TPM {}, secure dir {}, IMA measurement list {}",
+        data.agent_uuid,
+        delivery,
+        tpm,
+        data.secure_dir.display(),
+        if data.ima_ml.try_lock().map_or(true, |ml| ml.is_open()) {
+            "open"
+        } else {
+            "unavailable"
+        }
+    );
+}
+
+/*
+ * Input: running server
+ *        shared agent state
+ * Return: Result wrap the future handling the signals
+ *
+ * The signal handlers are installed right away, so that no signal is
+ * missed before the future is first polled. The future returns once the
+ * server stopped after SIGTERM or SIGINT.
+ */
+pub(crate) fn handle_signals(
+    server: Server,
+    data: web::Data<QuoteData>,
+) -> Result<impl Future<Output = ()>> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut user2 = signal(SignalKind::user_defined2())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+
+    Ok(async move {
+        loop {
+            tokio::select! {
+                _ = terminate.recv() => {
+                    info!("Received SIGTERM, shutting down");
+                    break;
+                }
+                _ = interrupt.recv() => {
+                    info!("Received SIGINT, shutting down");
+                    break;
+                }
+                _ = user2.recv() => dump_state(&data),
+                _ = hangup.recv() => {
+                    info!("Received SIGHUP, reloading {}", config_file_get());
+                    config_reload();
+                }
+            }
+        }
+
+        systemd::stopping();
+        server.stop(true).await;
+    })
+}
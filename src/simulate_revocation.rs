@@ -0,0 +1,99 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Simulated revocations
+//
+// keylime_agent simulate-revocation runs a revocation event through what
+// the revocation service does with the ones of the verifier, see
+// revocation.rs, so that operators can test the revocation actions of a
+// payload before an incident depends on them. An event as sent by the
+// verifier, with msg and signature, is verified with the revocation
+// certificate first. A crafted event without signature is taken as it is,
+// either as the msg of an event or as the message itself, and its actions
+// run without verification.
+//
+// The actions are the ones of the action_list of the delivered payload, in
+// the secure directory, or of the payload directory given with
+// --payload-dir, for payloads not delivered yet.
+
+use crate::common::UNZIPPED_DIR;
+use crate::error::Result;
+use crate::{revocation, secure_mount};
+
+use serde_json::Value;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// The message of an event without signature
+fn unsigned_message(body: Value) -> Result<Value> {
+    match body.get("msg").and_then(Value::as_str) {
+        Some(msg) => Ok(serde_json::from_str(msg)?),
+        None => Ok(body),
+    }
+}
+
+/*
+ * Input: revocation event, payload directory with the actions
+ * Return: Result wrap with error message, failing with the first action
+ *         that fails
+ */
+pub(crate) fn run(event: &Path, payload_dir: Option<&Path>) -> Result<()> {
+    let body: Value = serde_json::from_slice(&fs::read(event)?)?;
+
+    let message = if body.get("signature").is_some() {
+        let cert_key = revocation::revocation_cert(&secure_mount::mount()?)?;
+        let message = revocation::verify_event(&cert_key, &body)?;
+        println!("Event signed with the revocation certificate");
+        message
+    } else {
+        println!(
+            "Event not signed, running its actions without verification"
+        );
+        unsigned_message(body)?
+    };
+
+    let unzipped = match payload_dir {
+        Some(dir) => dir.to_path_buf(),
+        None => PathBuf::from(secure_mount::mount()?).join(UNZIPPED_DIR),
+    };
+    let outputs = revocation::run_actions_in(&unzipped, message)?;
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    for output in &outputs {
+        out.write_all(&output.stdout)?;
+        out.write_all(&output.stderr)?;
+    }
+    writeln!(
+        out,
+        "Ran {} revocation actions from {}",
+        outputs.len(),
+        unzipped.display()
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn simulate_unsigned_message() {
+        let message = json!({"type": "revocation", "ip": "127.0.0.1"});
+        assert_eq!(unsigned_message(message.clone()).unwrap(), message); //#[allow_ci]
+        let event = json!({"msg": message.to_string()});
+        assert_eq!(unsigned_message(event).unwrap(), message); //#[allow_ci]
+        assert!(unsigned_message(json!({"msg": "{"})).is_err());
+    }
+
+    #[test]
+    fn simulate_actions() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/unzipped");
+        let event = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/unzipped/test_ok.json"
+        );
+        assert!(run(Path::new(event), Some(Path::new(dir))).is_ok());
+    }
+}
@@ -0,0 +1,155 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// AMD SEV-SNP attestation reports
+//
+// On SEV-SNP guests, with sev_snp_evidence set, the agent attaches an
+// attestation report of the guest to its quotes, so that confidential VMs
+// are attested with both the TPM, usually a vTPM, and the AMD secure
+// processor. The report is requested from the sev-guest driver, with the
+// nonce of the request in report_data, padded with zeros to 64 bytes, so
+// that the verifier can check that the report is as fresh as the quote.
+// It is returned base64 encoded, as the 1184 byte report defined in the
+// SEV-SNP firmware ABI specification, signed with the VCEK of the host.
+
+use crate::error::{Error, Result};
+
+use std::fs::OpenOptions;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+
+static SEV_GUEST: &str = "/dev/sev-guest";
+
+// Size of report_data
+const REPORT_DATA_SIZE: usize = 64;
+// Size of the response, and of its header, see MSG_REPORT_RSP
+const RESPONSE_SIZE: usize = 4000;
+const RESPONSE_HEADER_SIZE: usize = 32;
+// Version of the guest messages
+const MSG_VERSION: u8 = 1;
+// _IOWR('S', 0x0, struct snp_guest_request_ioctl)
+const SNP_GET_REPORT: libc::c_ulong = 0xc020_5300;
+
+// struct snp_report_req of linux/sev-guest.h
+#[repr(C)]
+struct ReportRequest {
+    user_data: [u8; REPORT_DATA_SIZE],
+    vmpl: u32,
+    reserved: [u8; 28],
+}
+
+// struct snp_guest_request_ioctl of linux/sev-guest.h
+#[repr(C)]
+struct GuestRequest {
+    msg_version: u8,
+    req_data: u64,
+    resp_data: u64,
+    exitinfo2: u64,
+}
+
+/*
+ * Input: nonce of the request
+ * Return: Result wrap the attestation report
+ *
+ * Fails if the agent does not run on a SEV-SNP guest.
+ */
+pub(crate) fn report(nonce: &[u8]) -> Result<Vec<u8>> {
+    if nonce.len() > REPORT_DATA_SIZE {
+        return Err(Error::NonceTooLong {
+            max: REPORT_DATA_SIZE,
+        });
+    }
+    let mut request = ReportRequest {
+        user_data: [0; REPORT_DATA_SIZE],
+        vmpl: 0,
+        reserved: [0; 28],
+    };
+    request.user_data[..nonce.len()].copy_from_slice(nonce);
+    let mut response = vec![0u8; RESPONSE_SIZE];
+
+    let device = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(SEV_GUEST)
+        .map_err(|e| Error::file(SEV_GUEST, e))?;
+    let mut guest_request = GuestRequest {
+        msg_version: MSG_VERSION,
+        req_data: ptr::addr_of!(request) as u64,
+        resp_data: response.as_mut_ptr() as u64,
+        exitinfo2: 0,
+    };
+    // The driver only reads the request and writes the response, both
+    // alive until it returns
+    let rc = unsafe {
+        libc::ioctl(
+            device.as_raw_fd(),
+            SNP_GET_REPORT,
+            ptr::addr_of_mut!(guest_request),
+        )
+    };
+    if rc != 0 {
+        return Err(Error::Snp {
+            firmware_error: guest_request.exitinfo2,
+            source: std::io::Error::last_os_error(),
+        });
+    }
+
+    parse_response(&response)
+}
+
+// Returns the report of a MSG_REPORT_RSP message
+fn parse_response(response: &[u8]) -> Result<Vec<u8>> {
+    let field = |offset: usize| {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&response[offset..offset + 4]);
+        u32::from_le_bytes(bytes)
+    };
+    if response.len() < RESPONSE_HEADER_SIZE {
+        return Err(Error::SnpReport {
+            status: 0,
+            size: response.len(),
+        });
+    }
+    let status = field(0);
+    if status != 0 {
+        return Err(Error::SnpReport { status, size: 0 });
+    }
+    let size = field(4) as usize;
+    response
+        .get(RESPONSE_HEADER_SIZE..RESPONSE_HEADER_SIZE + size)
+        .map(<[u8]>::to_vec)
+        .ok_or(Error::SnpReport { status, size })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snp_report_response() {
+        let mut response = vec![0u8; RESPONSE_SIZE];
+        response[4..8].copy_from_slice(&1184u32.to_le_bytes());
+        response[RESPONSE_HEADER_SIZE] = 2;
+        let report = parse_response(&response).unwrap(); //#[allow_ci]
+        assert_eq!(report.len(), 1184);
+        assert_eq!(report[0], 2);
+
+        response[0] = 0x16;
+        assert!(matches!(
+            parse_response(&response),
+            Err(Error::SnpReport { status: 0x16, .. })
+        ));
+        response[0] = 0;
+        response[4..8].copy_from_slice(&4000u32.to_le_bytes());
+        assert!(parse_response(&response).is_err());
+        assert!(parse_response(&[0u8; 8]).is_err());
+    }
+
+    #[test]
+    fn snp_long_nonce() {
+        assert!(matches!(
+            report(&[0u8; 65]),
+            Err(Error::NonceTooLong { .. })
+        ));
+    }
+}
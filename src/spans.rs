@@ -0,0 +1,188 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Spans for the major operations of the agent
+//
+// Registration, quotes, the combination of the bootstrap key and payload
+// provisioning run in tracing spans carrying the identifiers involved. The
+// fmt layer set up in logging.rs prefixes events with the spans they
+// happened in. This layer logs how long each span took when it closes, at
+// the debug level, so RUST_LOG=debug shows where a slow attestation spends
+// its time, and hands the closed spans to the OpenTelemetry exporter, see
+// telemetry.rs.
+
+use crate::telemetry::{self, FinishedSpan};
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Instant, SystemTime};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{debug, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+// Trace IDs are random, as OpenTelemetry expects
+fn new_trace_id() -> [u8; 16] {
+    let mut trace_id = [0u8; 16];
+    // Only exported spans need a trace ID, and they need a valid one
+    if telemetry::enabled() {
+        let _ = openssl::rand::rand_bytes(&mut trace_id);
+    }
+    trace_id
+}
+
+// The fields of a span, as exported
+#[derive(Debug, Default)]
+struct Fields(Vec<(&'static str, String)>);
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push((field.name(), value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.push((field.name(), format!("{:?}", value)));
+    }
+}
+
+// Kept in the extensions of each span
+#[derive(Debug, Clone)]
+struct SpanData {
+    span_id: u64,
+    parent_span_id: Option<u64>,
+    trace_id: [u8; 16],
+    attributes: Vec<(&'static str, String)>,
+    start: Instant,
+    start_time: SystemTime,
+}
+
+/// Layer timing spans and exporting them once closed
+#[derive(Debug, Default)]
+pub(crate) struct SpanLayer {
+    // Span IDs of the registry are reused once spans close, so exported
+    // spans are numbered apart
+    next_id: AtomicU64,
+}
+
+impl SpanLayer {
+    pub(crate) fn new() -> Self {
+        SpanLayer::default()
+    }
+}
+
+impl<S> Layer<S> for SpanLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes, id: &Id, ctx: Context<S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let mut attributes = Fields::default();
+        attrs.record(&mut attributes);
+
+        // Spans share the trace of their root span
+        let parent = span.parent().and_then(|parent| {
+            parent
+                .extensions()
+                .get::<SpanData>()
+                .map(|data| (data.span_id, data.trace_id))
+        });
+        let (parent_span_id, trace_id) = match parent {
+            Some((span_id, trace_id)) => (Some(span_id), trace_id),
+            None => (None, new_trace_id()),
+        };
+
+        span.extensions_mut().insert(SpanData {
+            // Span IDs must not be 0
+            span_id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+            parent_span_id,
+            trace_id,
+            attributes: attributes.0,
+            start: Instant::now(),
+            start_time: SystemTime::now(),
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record, ctx: Context<S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut attributes = Fields::default();
+            values.record(&mut attributes);
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                data.attributes.extend(attributes.0);
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<S>) {
+        let data = match ctx
+            .span(&id)
+            .and_then(|span| span.extensions_mut().remove::<SpanData>())
+        {
+            Some(data) => data,
+            None => return,
+        };
+        let elapsed = data.start.elapsed();
+        // The span is still there for the context of the event
+        debug!(parent: &id, "took {:?}", elapsed);
+
+        telemetry::record_span(FinishedSpan {
+            name: ctx.metadata(&id).map_or("", |metadata| metadata.name()),
+            trace_id: data.trace_id,
+            span_id: data.span_id,
+            parent_span_id: data.parent_span_id,
+            start: data.start_time,
+            end: data.start_time + elapsed,
+            attributes: data.attributes,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::Registry;
+
+    // The data of the current span
+    fn current() -> SpanData {
+        tracing::dispatcher::get_default(|dispatch| {
+            let registry = dispatch.downcast_ref::<Registry>().unwrap(); //#[allow_ci]
+            let id = tracing::Span::current().id().unwrap(); //#[allow_ci]
+            let span = registry.span(&id).unwrap(); //#[allow_ci]
+            let data = span.extensions().get::<SpanData>().cloned();
+            data.unwrap() //#[allow_ci]
+        })
+    }
+
+    #[test]
+    fn span_data() {
+        let subscriber = Registry::default().with(SpanLayer::new());
+
+        tracing::subscriber::with_default(subscriber, || {
+            let outer = tracing::info_span!("quote", nonce = "abc").entered();
+            let outer_data = current();
+            assert_eq!(outer_data.parent_span_id, None);
+            assert_eq!(
+                outer_data.attributes,
+                vec![("nonce", String::from("abc"))]
+            );
+
+            let inner = tracing::info_span!("make_pcr_blob", pcrs = 3);
+            let inner = inner.entered();
+            let inner_data = current();
+            assert_ne!(inner_data.span_id, outer_data.span_id);
+            assert_eq!(inner_data.parent_span_id, Some(outer_data.span_id));
+            assert_eq!(inner_data.trace_id, outer_data.trace_id);
+            assert_eq!(
+                inner_data.attributes,
+                vec![("pcrs", String::from("3"))]
+            );
+
+            drop(inner);
+            drop(outer);
+        });
+    }
+}
@@ -0,0 +1,297 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// SPIRE node attestation
+//
+// With spire_socket set, the agent listens on that Unix socket for the
+// TPM-based node attestor of the SPIRE agent on the same host, so that one
+// TPM stack serves both the Keylime and the SPIFFE identities of the host.
+// The attestor connects, and sends a single line of JSON with the nonce the
+// SPIRE server challenged it with, base64 encoded, and optionally a PCR
+// mask:
+//
+//   {"nonce": "<base64>", "mask": "0x408000"}
+//
+// The agent answers with a single line of JSON, with a quote of the AK over
+// the nonce, in the format of the agent API, and what the SPIRE server
+// needs to verify it: the EK certificate and public area, to check the TPM
+// against its CAs, and the AK public area, base64 encoded. The UUID of the
+// agent lets the server look the AK up in the Keylime registrar instead.
+//
+//   {"agent_uuid": "...", "quote": "r...", "ek_cert": "...",
+//    "ek_tpm2b_public": "...", "ak_tpm2b_public": "...", "nk": "<PEM>"}
+//
+// or, on failure, {"error": "..."}. The socket belongs to the user the
+// agent runs as, and to spire_socket_group, with spire_socket_mode, 0600 by
+// default, so that a SPIRE agent running as a user of its own can be given
+// access with a group. Requests must arrive within REQUEST_TIMEOUT.
+//
+// This protocol is the agent's own, not the SPIRE plugin interface: the tpm
+// node attestors shipped with SPIRE do not talk to the socket, an attestor
+// plugin sending the requests above and verifying the answers on the
+// server side is needed.
+
+use crate::common::config_get_or;
+use crate::error::{Error, Result};
+use crate::permissions::{self, UserIds};
+use crate::{quote, QuoteData};
+
+use actix_web::web::Data;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::warn;
+
+// Nonces are the qualifying data of quotes, which is at most 64 bytes
+const MAX_NONCE_SIZE: usize = 64;
+const MAX_REQUEST_SIZE: u64 = 4096;
+// Connections that do not send a request in time are closed
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) static DEFAULT_SOCKET_MODE: &str = "0600";
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    nonce: String,
+    #[serde(default)]
+    mask: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Response<'a> {
+    Quote {
+        agent_uuid: &'a str,
+        quote: String,
+        ek_cert: &'a str,
+        ek_tpm2b_public: &'a str,
+        ak_tpm2b_public: &'a str,
+        nk: &'a str,
+    },
+    Error {
+        error: String,
+    },
+}
+
+/// The keys returned with quotes, base64 encoded
+#[derive(Debug)]
+pub(crate) struct Identity {
+    pub(crate) ek_cert: String,
+    pub(crate) ek_tpm2b_public: String,
+    pub(crate) ak_tpm2b_public: String,
+}
+
+/// The socket and who may connect to it
+#[derive(Debug)]
+pub(crate) struct Socket {
+    pub(crate) path: PathBuf,
+    pub(crate) mode: u32,
+    pub(crate) group: Option<libc::gid_t>,
+}
+
+// Parses spire_socket_mode, which must not give access to others
+fn parse_mode(mode: &str) -> Result<u32> {
+    let mode = u32::from_str_radix(mode.trim(), 8).map_err(|e| {
+        Error::config_value("cloud_agent", "spire_socket_mode", e)
+    })?;
+    if mode & !0o660 != 0 {
+        return Err(Error::config_value(
+            "cloud_agent",
+            "spire_socket_mode",
+            format!("{:o} gives more than read and write access to the owner and group", mode),
+        ));
+    }
+    Ok(mode)
+}
+
+/*
+ * Return: Result wrap the socket, None if disabled
+ */
+pub(crate) fn socket_get() -> Result<Option<Socket>> {
+    let path = config_get_or("cloud_agent", "spire_socket", "")?;
+    if path.is_empty() {
+        return Ok(None);
+    }
+    let mode = parse_mode(&config_get_or(
+        "cloud_agent",
+        "spire_socket_mode",
+        DEFAULT_SOCKET_MODE,
+    )?)?;
+    let group = config_get_or("cloud_agent", "spire_socket_group", "")?;
+    let group = match group.trim() {
+        "" => None,
+        group => {
+            Some(permissions::lookup_group(group, "spire_socket_group")?)
+        }
+    };
+    Ok(Some(Socket {
+        path: PathBuf::from(path),
+        mode,
+        group,
+    }))
+}
+
+/*
+ * Input: the socket
+ *        user and group the agent will run as, if it drops privileges
+ * Return: Result wrap the listener
+ *
+ * Replaces the socket a previous run left behind.
+ */
+pub(crate) fn bind(
+    socket: &Socket,
+    run_as: Option<&UserIds>,
+) -> Result<UnixListener> {
+    match fs::remove_file(&socket.path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let listener = UnixListener::bind(&socket.path)?;
+    fs::set_permissions(
+        &socket.path,
+        fs::Permissions::from_mode(socket.mode),
+    )?;
+    let uid = run_as.map_or(libc::uid_t::MAX, |ids| ids.uid);
+    let gid = socket
+        .group
+        .or_else(|| run_as.map(|ids| ids.gid))
+        .unwrap_or(libc::gid_t::MAX);
+    permissions::chown_ids(&socket.path, uid, gid)?;
+    Ok(listener)
+}
+
+// Returns the nonce and mask of a request
+fn parse_request(line: &str) -> Result<(Vec<u8>, Option<String>)> {
+    let request: Request =
+        serde_json::from_str(line).map_err(|_| Error::InvalidRequest)?;
+    let nonce =
+        base64::decode(&request.nonce).map_err(|_| Error::InvalidRequest)?;
+    if nonce.is_empty() || nonce.len() > MAX_NONCE_SIZE {
+        return Err(Error::InvalidRequest);
+    }
+    if let Some(mask) = &request.mask {
+        if !mask
+            .trim_start_matches("0x")
+            .chars()
+            .all(|c| c.is_ascii_hexdigit())
+        {
+            return Err(Error::InvalidRequest);
+        }
+    }
+    Ok((nonce, request.mask))
+}
+
+async fn attest(
+    line: &str,
+    data: &Data<QuoteData>,
+    identity: &Identity,
+) -> Result<String> {
+    let (nonce, mask) = parse_request(line)?;
+    let quote = {
+        let _permit = data.limits.quotes.acquire()?;
+        quote::quote(&nonce, mask.as_deref(), data.clone()).await?
+    };
+    Ok(serde_json::to_string(&Response::Quote {
+        agent_uuid: &data.agent_uuid,
+        quote: quote.quote,
+        ek_cert: &identity.ek_cert,
+        ek_tpm2b_public: &identity.ek_tpm2b_public,
+        ak_tpm2b_public: &identity.ak_tpm2b_public,
+        nk: &data.pub_key_pem,
+    })?)
+}
+
+async fn serve(
+    stream: UnixStream,
+    data: &Data<QuoteData>,
+    identity: &Identity,
+) -> Result<()> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut line = String::new();
+    let mut reader = BufReader::new(reader.take(MAX_REQUEST_SIZE));
+    let _ =
+        tokio::time::timeout(REQUEST_TIMEOUT, reader.read_line(&mut line))
+            .await
+            .map_err(|_| {
+                std::io::Error::new(
+                    ErrorKind::TimedOut,
+                    "timed out waiting for the request",
+                )
+            })??;
+
+    let mut response = match attest(&line, data, identity).await {
+        Ok(response) => response,
+        Err(e) => {
+            warn!("SPIRE node attestation failed: {}", e);
+            serde_json::to_string(&Response::Error {
+                error: e.to_string(),
+            })?
+        }
+    };
+    response.push('\n');
+    writer.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+/// Answers the SPIRE node attestor, until the agent stops
+pub(crate) async fn run(
+    mut listener: UnixListener,
+    data: Data<QuoteData>,
+    identity: Identity,
+) {
+    let identity = std::sync::Arc::new(identity);
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("Unable to accept SPIRE connection: {}", e);
+                continue;
+            }
+        };
+        let data = data.clone();
+        let identity = identity.clone();
+        actix_web::rt::spawn(async move {
+            if let Err(e) = serve(stream, &data, &identity).await {
+                warn!("Unable to answer SPIRE node attestor: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spire_request() {
+        let (nonce, mask) =
+            parse_request(r#"{"nonce": "AAECAw==", "mask": "0x408000"}"#)
+                .unwrap(); //#[allow_ci]
+        assert_eq!(nonce, vec![0, 1, 2, 3]);
+        assert_eq!(mask.as_deref(), Some("0x408000"));
+
+        assert!(parse_request(r#"{"nonce": "AAECAw=="}"#).is_ok());
+        // Nonces must fit in the qualifying data of the quote
+        let long = base64::encode([0u8; 65]);
+        assert!(
+            parse_request(&format!(r#"{{"nonce": "{}"}}"#, long)).is_err()
+        );
+        assert!(parse_request(r#"{"nonce": ""}"#).is_err());
+        assert!(parse_request(r#"{"nonce": "AA==", "mask": "x"}"#).is_err());
+        assert!(parse_request("nonce").is_err());
+    }
+
+    #[test]
+    fn spire_socket_mode() {
+        assert_eq!(parse_mode("0600").unwrap(), 0o600); //#[allow_ci]
+        assert_eq!(parse_mode("660").unwrap(), 0o660); //#[allow_ci]
+        assert!(parse_mode("0666").is_err());
+        assert!(parse_mode("0700").is_err());
+        assert!(parse_mode("rw").is_err());
+    }
+}
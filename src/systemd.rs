@@ -0,0 +1,427 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// systemd service notifications, see sd_notify(3)
+//
+// When the agent runs as a Type=notify unit, systemd passes the path of a
+// datagram socket in NOTIFY_SOCKET. The agent reports READY=1 once it can
+// serve requests and keeps STATUS= up to date with what it is doing.
+// Outside of systemd NOTIFY_SOCKET is not set and nothing is sent.
+//
+// With WatchdogSec= set, systemd also passes WATCHDOG_USEC and restarts the
+// agent if it does not send WATCHDOG=1 within that time. The agent only
+// pings the watchdog after checking that the TPM and the HTTP server still
+// respond, so a wedged agent gets restarted.
+//
+// With socket activation systemd binds the listening socket and passes it
+// to the agent as file descriptor 3, see sd_listen_fds(3).
+
+use crate::error::{Error, Result};
+use crate::{http, QuoteData};
+
+use actix_web::web;
+use lazy_static::lazy_static;
+use std::env;
+use std::ffi::OsStr;
+use std::mem::{self, size_of};
+use std::net::TcpListener;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::process;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+// Builds the address of the notification socket. A leading '@' denotes a
+// socket in the abstract namespace.
+fn socket_addr(path: &[u8]) -> Result<(libc::sockaddr_un, libc::socklen_t)> {
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+
+    if path.is_empty() || path.len() >= addr.sun_path.len() {
+        return Err(Error::Other(format!(
+            "invalid NOTIFY_SOCKET {}",
+            String::from_utf8_lossy(path)
+        )));
+    }
+    for (dst, src) in addr.sun_path.iter_mut().zip(path) {
+        *dst = *src as libc::c_char;
+    }
+    if path[0] == b'@' {
+        addr.sun_path[0] = 0;
+    }
+
+    // Abstract addresses are not NUL terminated, so pass the exact length
+    let len = size_of::<libc::sa_family_t>() + path.len();
+    Ok((addr, len as libc::socklen_t))
+}
+
+/*
+ * Input: newline separated assignments, e.g. "READY=1\nSTATUS=Running"
+ * Return: Result wrap boolean with error message
+ *         - true if the notification was sent
+ *         - false if the agent does not run under systemd
+ */
+pub(crate) fn notify(state: &str) -> Result<bool> {
+    notify_socket(env::var_os("NOTIFY_SOCKET").as_deref(), state)
+}
+
+// Sends the notification to the socket of NOTIFY_SOCKET, if set
+fn notify_socket(path: Option<&OsStr>, state: &str) -> Result<bool> {
+    let path = match path {
+        Some(path) => path,
+        None => return Ok(false),
+    };
+    let (addr, len) = socket_addr(path.as_bytes())?;
+    let addr_ptr: *const libc::sockaddr_un = &addr;
+
+    unsafe {
+        let fd = libc::socket(
+            libc::AF_UNIX,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+            0,
+        );
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let sent = libc::sendto(
+            fd,
+            state.as_ptr() as *const libc::c_void,
+            state.len(),
+            libc::MSG_NOSIGNAL,
+            addr_ptr as *const libc::sockaddr,
+            len,
+        );
+        let err = std::io::Error::last_os_error();
+        let _ = libc::close(fd);
+        if sent < 0 {
+            return Err(err.into());
+        }
+    }
+
+    Ok(true)
+}
+
+/// Tells systemd that the agent finished starting up
+pub(crate) fn ready(status: &str) {
+    match notify(&format!("READY=1\nSTATUS={}", status)) {
+        Ok(true) => info!("Notified systemd of readiness"),
+        Ok(false) => {}
+        Err(e) => warn!("Unable to notify systemd of readiness: {}", e),
+    }
+}
+
+/// Updates the status shown by systemctl status
+pub(crate) fn status(status: &str) {
+    if let Err(e) = notify(&format!("STATUS={}", status)) {
+        warn!("Unable to send status to systemd: {}", e);
+    }
+}
+
+/// Tells systemd that the agent is shutting down
+pub(crate) fn stopping() {
+    if let Err(e) = notify("STOPPING=1\nSTATUS=Shutting down") {
+        warn!("Unable to notify systemd of shutdown: {}", e);
+    }
+}
+
+// First file descriptor passed by systemd
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+// Returns the number of sockets passed from the values of LISTEN_FDS and
+// LISTEN_PID
+fn parse_listen_fds(
+    fds: Option<&str>,
+    pid: Option<&str>,
+    own_pid: u32,
+) -> Result<RawFd> {
+    let fds = match fds {
+        Some(fds) => fds,
+        None => return Ok(0),
+    };
+    if pid.and_then(|pid| pid.parse::<u32>().ok()) != Some(own_pid) {
+        return Ok(0);
+    }
+
+    match fds.parse::<RawFd>() {
+        Ok(count) if count >= 0 => Ok(count),
+        _ => Err(Error::Other(format!("invalid LISTEN_FDS {}", fds))),
+    }
+}
+
+// Takes ownership of a socket passed by systemd, which must be a listening
+// TCP socket
+fn take_listener(fd: RawFd) -> Result<TcpListener> {
+    let mut sock_type: libc::c_int = 0;
+    let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+    let sock_type_ptr: *mut libc::c_int = &mut sock_type;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            sock_type_ptr as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 || sock_type != libc::SOCK_STREAM {
+        return Err(Error::Other(format!(
+            "file descriptor {} passed by systemd is not a stream socket",
+            fd
+        )));
+    }
+
+    // Do not leak the socket to the scripts the agent runs
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    // Fails for sockets that are not TCP
+    let addr = listener.local_addr().map_err(|e| {
+        Error::Other(format!(
+            "socket {} passed by systemd is not a TCP socket: {}",
+            fd, e
+        ))
+    })?;
+    info!("Using socket {} passed by systemd", addr);
+    Ok(listener)
+}
+
+/// Returns the listening sockets passed by systemd socket activation, or
+/// an empty list if the agent was not socket activated
+pub(crate) fn listen_fds() -> Result<Vec<TcpListener>> {
+    let count = parse_listen_fds(
+        env::var("LISTEN_FDS").ok().as_deref(),
+        env::var("LISTEN_PID").ok().as_deref(),
+        process::id(),
+    )?;
+
+    // The sockets are meant for the agent only, not for the processes it
+    // spawns
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDNAMES");
+
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .map(take_listener)
+        .collect()
+}
+
+// Returns the watchdog timeout from the values of WATCHDOG_USEC and
+// WATCHDOG_PID. The watchdog is meant for another process if WATCHDOG_PID
+// is set to a different PID.
+fn parse_watchdog(
+    usec: Option<&str>,
+    pid: Option<&str>,
+    own_pid: u32,
+) -> Option<Duration> {
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok() != Some(own_pid) {
+            return None;
+        }
+    }
+
+    match usec?.parse::<u64>() {
+        Ok(usec) if usec > 0 => Some(Duration::from_micros(usec)),
+        _ => None,
+    }
+}
+
+/// Returns the watchdog timeout if systemd expects the agent to ping the
+/// watchdog
+pub(crate) fn watchdog_interval() -> Option<Duration> {
+    parse_watchdog(
+        env::var("WATCHDOG_USEC").ok().as_deref(),
+        env::var("WATCHDOG_PID").ok().as_deref(),
+        process::id(),
+    )
+}
+
+// Checks that the TPM responds within the timeout. The check waits behind
+// the operations queued on the TPM worker, so this also catches a stuck
+// TPM queue.
+async fn check_tpm(
+    data: web::Data<QuoteData>,
+    timeout: Duration,
+) -> Result<()> {
+    let check = data.tpm.run(|backend| {
+        let _ = backend.get_random(1)?;
+        Ok(())
+    });
+
+    match tokio::time::timeout(timeout, check).await {
+        Ok(result) => result,
+        Err(_) => Err(Error::Other(format!(
+            "TPM did not respond within {:?}",
+            timeout
+        ))),
+    }
+}
+
+lazy_static! {
+    // With enable_agent_tls, the agent's own server presents a self-signed
+    // certificate, or one issued for its public name, so the watchdog does
+    // not verify it
+    static ref PROBE_CLIENT: reqwest::Client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap_or_else(|e| {
+            warn!("Unable to configure the watchdog HTTP client: {}", e);
+            http::client().clone()
+        });
+}
+
+// Checks that the HTTP server answers requests. Any response will do, so
+// the request targets a path with no handler.
+async fn check_http(url: &str, timeout: Duration) -> Result<()> {
+    let _ = PROBE_CLIENT.get(url).timeout(timeout).send().await?;
+    Ok(())
+}
+
+/// Returns the URL the watchdog uses to reach the agent's own HTTP server,
+/// with scheme http or https
+pub(crate) fn probe_url(scheme: &str, ip: &str, port: &str) -> String {
+    let host = match ip {
+        "0.0.0.0" | "" => "127.0.0.1".to_string(),
+        "::" => "[::1]".to_string(),
+        ip if ip.contains(':') && !ip.starts_with('[') => format!("[{}]", ip),
+        ip => ip.to_string(),
+    };
+    format!("{}://{}:{}/", scheme, host, port)
+}
+
+/*
+ * Input: watchdog timeout, agent data, URL of the agent's HTTP server
+ *
+ * Pings the watchdog twice per timeout for as long as both the TPM and the
+ * HTTP server are alive. Both checks must complete within a quarter of the
+ * timeout, so that a healthy agent never misses a ping.
+ */
+pub(crate) async fn run_watchdog(
+    interval: Duration,
+    data: web::Data<QuoteData>,
+    probe_url: String,
+) {
+    let check_timeout = interval / 4;
+
+    loop {
+        tokio::time::delay_for(interval / 2).await;
+
+        if let Err(e) = check_tpm(data.clone(), check_timeout).await {
+            error!("Not pinging the watchdog: {}", e);
+            continue;
+        }
+        if let Err(e) = check_http(&probe_url, check_timeout).await {
+            error!(
+                "Not pinging the watchdog: HTTP server check failed: {}",
+                e
+            );
+            continue;
+        }
+        if let Err(e) = notify("WATCHDOG=1") {
+            warn!("Unable to ping the watchdog: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tpm_backend::{quote_data, MockTpm};
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn notify_to_socket() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join("notify");
+        let socket = UnixDatagram::bind(&path).unwrap(); //#[allow_ci]
+
+        assert!(notify_socket(
+            Some(path.as_os_str()),
+            "READY=1\nSTATUS=Testing"
+        )
+        .unwrap()); //#[allow_ci]
+
+        let mut buf = [0u8; 64];
+        let len = socket.recv(&mut buf).unwrap(); //#[allow_ci]
+        assert_eq!(&buf[..len], b"READY=1\nSTATUS=Testing");
+
+        assert!(!notify_socket(None, "READY=1").unwrap()); //#[allow_ci]
+    }
+
+    #[test]
+    fn abstract_socket_addr() {
+        let (addr, len) = socket_addr(b"@keylime/notify").unwrap(); //#[allow_ci]
+        assert_eq!(addr.sun_path[0], 0);
+        assert_eq!(addr.sun_path[1], b'k' as libc::c_char);
+        assert_eq!(
+            len as usize,
+            size_of::<libc::sa_family_t>() + "@keylime/notify".len()
+        );
+
+        assert!(socket_addr(b"").is_err());
+        assert!(socket_addr(&[b'a'; 200]).is_err());
+    }
+
+    #[test]
+    fn listen_fds_env() {
+        assert_eq!(parse_listen_fds(Some("1"), Some("42"), 42).unwrap(), 1); //#[allow_ci]
+        assert_eq!(parse_listen_fds(Some("1"), Some("1"), 42).unwrap(), 0); //#[allow_ci]
+        assert_eq!(parse_listen_fds(Some("1"), None, 42).unwrap(), 0); //#[allow_ci]
+        assert_eq!(parse_listen_fds(None, None, 42).unwrap(), 0); //#[allow_ci]
+        assert!(parse_listen_fds(Some("-1"), Some("42"), 42).is_err());
+        assert!(parse_listen_fds(Some("all"), Some("42"), 42).is_err());
+    }
+
+    #[test]
+    fn take_tcp_listener() {
+        use std::os::unix::io::IntoRawFd;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap(); //#[allow_ci]
+        let addr = listener.local_addr().unwrap(); //#[allow_ci]
+        let taken = take_listener(listener.into_raw_fd()).unwrap(); //#[allow_ci]
+        assert_eq!(taken.local_addr().unwrap(), addr); //#[allow_ci]
+
+        let socket = UnixDatagram::unbound().unwrap(); //#[allow_ci]
+        assert!(take_listener(socket.into_raw_fd()).is_err());
+    }
+
+    #[test]
+    fn watchdog() {
+        assert_eq!(
+            parse_watchdog(Some("30000000"), None, 42),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_watchdog(Some("30000000"), Some("42"), 42),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(parse_watchdog(Some("30000000"), Some("1"), 42), None);
+        assert_eq!(parse_watchdog(Some("0"), None, 42), None);
+        assert_eq!(parse_watchdog(Some("soon"), None, 42), None);
+        assert_eq!(parse_watchdog(None, None, 42), None);
+    }
+
+    #[test]
+    fn watchdog_probe_url() {
+        assert_eq!(
+            probe_url("http", "0.0.0.0", "9002"),
+            "http://127.0.0.1:9002/"
+        );
+        assert_eq!(probe_url("http", "::", "9002"), "http://[::1]:9002/");
+        assert_eq!(
+            probe_url("http", "fe80::1", "9002"),
+            "http://[fe80::1]:9002/"
+        );
+        assert_eq!(
+            probe_url("https", "10.0.0.1", "9002"),
+            "https://10.0.0.1:9002/"
+        );
+    }
+
+    #[tokio::test]
+    async fn check_mock_tpm() {
+        let data = web::Data::new(quote_data(MockTpm::new()).unwrap()); //#[allow_ci]
+        assert!(check_tpm(data, Duration::from_secs(1)).await.is_ok());
+    }
+}
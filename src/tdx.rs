@@ -0,0 +1,112 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// Intel TDX quotes
+//
+// Built with the tdx feature, and with tdx_evidence set, the agent attaches
+// a TDX quote of the trust domain to its quotes, for confidential workloads
+// on TDX. The quote is requested through configfs-tsm, where the kernel
+// forwards it to the quote generation service of the host, with the nonce
+// of the request in its report data, padded with zeros to 64 bytes, so
+// that the verifier can check that it is as fresh as the TPM quote. It is
+// returned base64 encoded, as the quote generation service returns it.
+
+use crate::error::{Error, Result};
+
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static TSM_REPORT: &str = "/sys/kernel/config/tsm/report";
+static TDX_PROVIDER: &str = "tdx_guest";
+
+const REPORT_DATA_SIZE: usize = 64;
+
+// Reports requested by this run, to name their directories
+static REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+/*
+ * Input: nonce of the request
+ * Return: Result wrap the TDX quote
+ *
+ * Fails if the agent does not run in a trust domain.
+ */
+pub(crate) fn quote(nonce: &[u8]) -> Result<Vec<u8>> {
+    if nonce.len() > REPORT_DATA_SIZE {
+        return Err(Error::NonceTooLong {
+            max: REPORT_DATA_SIZE,
+        });
+    }
+    let dir = Path::new(TSM_REPORT).join(format!(
+        "keylime-{}-{}",
+        process::id(),
+        REQUESTS.fetch_add(1, Ordering::SeqCst)
+    ));
+    fs::create_dir(&dir).map_err(|e| Error::file(&dir, e))?;
+    let result = read_quote(&dir, nonce);
+    let _ = fs::remove_dir(&dir);
+    result
+}
+
+fn generation(dir: &Path) -> Result<u64> {
+    fs::read_to_string(dir.join("generation"))?
+        .trim()
+        .parse()
+        .map_err(Error::from)
+}
+
+// Requests the quote of a report directory of configfs-tsm
+fn read_quote(dir: &Path, nonce: &[u8]) -> Result<Vec<u8>> {
+    let provider = fs::read_to_string(dir.join("provider"))?;
+    if provider.trim() != TDX_PROVIDER {
+        return Err(Error::TdxProvider {
+            provider: provider.trim().to_string(),
+        });
+    }
+
+    let mut report_data = [0u8; REPORT_DATA_SIZE];
+    report_data[..nonce.len()].copy_from_slice(nonce);
+    fs::write(dir.join("inblob"), &report_data[..])?;
+    let before = generation(dir)?;
+    let quote = fs::read(dir.join("outblob"))?;
+    // The generation changes when the report data is written again in
+    // between, by another process using the same directory
+    if generation(dir)? != before {
+        return Err(Error::Tdx {
+            reason: "report data changed while reading the quote",
+        });
+    }
+    if quote.is_empty() {
+        return Err(Error::Tdx {
+            reason: "empty quote",
+        });
+    }
+    Ok(quote)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tdx_read_quote() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        fs::write(dir.path().join("provider"), "tdx_guest\n").unwrap(); //#[allow_ci]
+        fs::write(dir.path().join("generation"), "1\n").unwrap(); //#[allow_ci]
+        fs::write(dir.path().join("outblob"), b"quote").unwrap(); //#[allow_ci]
+
+        let outblob = read_quote(dir.path(), b"nonce").unwrap(); //#[allow_ci]
+        assert_eq!(outblob, b"quote");
+        let inblob = fs::read(dir.path().join("inblob")).unwrap(); //#[allow_ci]
+        assert_eq!(inblob.len(), REPORT_DATA_SIZE);
+        assert_eq!(&inblob[..5], b"nonce");
+
+        fs::write(dir.path().join("provider"), "sev_guest\n").unwrap(); //#[allow_ci]
+        assert!(matches!(
+            read_quote(dir.path(), b"nonce"),
+            Err(Error::TdxProvider { .. })
+        ));
+        assert!(matches!(quote(&[0u8; 65]), Err(Error::NonceTooLong { .. })));
+    }
+}
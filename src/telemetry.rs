@@ -0,0 +1,335 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// OpenTelemetry export
+//
+// With otlp_endpoint set in keylime.conf, the agent periodically sends the
+// tracing spans that finished since the last export, and a few counters,
+// to an OpenTelemetry collector using OTLP over HTTP with JSON encoding.
+// Spans keep the names and fields they are logged with, see spans.rs.
+// Export is best effort: spans are dropped rather than buffered without
+// bound when the collector cannot be reached.
+
+use crate::common::config_get_or;
+use crate::error::{Error, Result};
+use crate::http;
+
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Default interval between exports, in seconds
+pub(crate) static DEFAULT_OTLP_INTERVAL: &str = "10";
+
+// Spans kept between two exports, beyond which new spans are dropped
+const MAX_PENDING_SPANS: usize = 4096;
+
+static SERVICE_NAME: &str = "keylime_agent";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Counters exported as monotonic sums
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Counter {
+    Quotes,
+    QuoteFailures,
+    Registrations,
+    PayloadsProvisioned,
+}
+
+static COUNTERS: [(Counter, &str); 4] = [
+    (Counter::Quotes, "keylime_agent.quotes"),
+    (Counter::QuoteFailures, "keylime_agent.quote_failures"),
+    (Counter::Registrations, "keylime_agent.registrations"),
+    (
+        Counter::PayloadsProvisioned,
+        "keylime_agent.payloads_provisioned",
+    ),
+];
+
+static COUNTS: [AtomicU64; 4] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+/// A span as exported, once it is closed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct FinishedSpan {
+    pub name: &'static str,
+    pub trace_id: [u8; 16],
+    pub span_id: u64,
+    pub parent_span_id: Option<u64>,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub attributes: Vec<(&'static str, String)>,
+}
+
+lazy_static! {
+    // Spans waiting for the next export
+    static ref PENDING: Mutex<Vec<FinishedSpan>> = Mutex::new(Vec::new());
+}
+
+/// Whether spans and counters are collected for export
+pub(crate) fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Increments a counter
+pub(crate) fn count(counter: Counter) {
+    if let Some(i) = COUNTERS.iter().position(|(c, _)| *c == counter) {
+        let _ = COUNTS[i].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Queues a closed span for the next export
+pub(crate) fn record_span(span: FinishedSpan) {
+    if !enabled() {
+        return;
+    }
+    if let Ok(mut pending) = PENDING.lock() {
+        if pending.len() < MAX_PENDING_SPANS {
+            pending.push(span);
+        }
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+fn string_attribute(key: &str, value: &str) -> Value {
+    json!({"key": key, "value": {"stringValue": value}})
+}
+
+fn resource(agent_uuid: &str) -> Value {
+    json!({
+        "attributes": [
+            string_attribute("service.name", SERVICE_NAME),
+            string_attribute("service.instance.id", agent_uuid),
+        ]
+    })
+}
+
+// Body of a request to /v1/traces
+fn traces_body(agent_uuid: &str, spans: &[FinishedSpan]) -> Value {
+    let spans: Vec<Value> = spans
+        .iter()
+        .map(|span| {
+            let mut value = json!({
+                "traceId": hex::encode(span.trace_id),
+                "spanId": hex::encode(span.span_id.to_be_bytes()),
+                "name": span.name,
+                // SPAN_KIND_INTERNAL
+                "kind": 1,
+                "startTimeUnixNano": unix_nanos(span.start),
+                "endTimeUnixNano": unix_nanos(span.end),
+                "attributes": span
+                    .attributes
+                    .iter()
+                    .map(|(key, value)| string_attribute(key, value))
+                    .collect::<Vec<Value>>(),
+            });
+            if let Some(parent) = span.parent_span_id {
+                value["parentSpanId"] =
+                    Value::from(hex::encode(parent.to_be_bytes()));
+            }
+            value
+        })
+        .collect();
+
+    json!({
+        "resourceSpans": [{
+            "resource": resource(agent_uuid),
+            "scopeSpans": [{
+                "scope": {"name": SERVICE_NAME},
+                "spans": spans,
+            }],
+        }]
+    })
+}
+
+// Body of a request to /v1/metrics, with the counters as cumulative sums
+// since the agent started
+fn metrics_body(
+    agent_uuid: &str,
+    counts: &[(&str, u64)],
+    start: SystemTime,
+    now: SystemTime,
+) -> Value {
+    let metrics: Vec<Value> = counts
+        .iter()
+        .map(|(name, count)| {
+            json!({
+                "name": name,
+                "sum": {
+                    "dataPoints": [{
+                        "asInt": count.to_string(),
+                        "startTimeUnixNano": unix_nanos(start),
+                        "timeUnixNano": unix_nanos(now),
+                    }],
+                    // AGGREGATION_TEMPORALITY_CUMULATIVE
+                    "aggregationTemporality": 2,
+                    "isMonotonic": true,
+                },
+            })
+        })
+        .collect();
+
+    json!({
+        "resourceMetrics": [{
+            "resource": resource(agent_uuid),
+            "scopeMetrics": [{
+                "scope": {"name": SERVICE_NAME},
+                "metrics": metrics,
+            }],
+        }]
+    })
+}
+
+async fn post(
+    client: &reqwest::Client,
+    url: &str,
+    body: &Value,
+) -> Result<()> {
+    let response = client.post(url).json(body).send().await?;
+    if !response.status().is_success() {
+        return Err(Error::Other(format!(
+            "OTLP export to {} failed with {}",
+            url,
+            response.status()
+        )));
+    }
+    Ok(())
+}
+
+/*
+ * Return: Result wrap the OTLP endpoint and export interval, if configured
+ *
+ * Enables the collection of spans and counters when an endpoint is set, so
+ * this must be called before the spans to export start.
+ */
+pub(crate) fn otlp_config_get() -> Result<Option<(String, Duration)>> {
+    let endpoint = config_get_or("cloud_agent", "otlp_endpoint", "")?;
+    let endpoint = endpoint.trim().trim_end_matches('/');
+    if endpoint.is_empty() {
+        return Ok(None);
+    }
+
+    let interval =
+        config_get_or("cloud_agent", "otlp_interval", DEFAULT_OTLP_INTERVAL)?
+            .parse::<u64>()
+            .map_err(|e| {
+                Error::config_value("cloud_agent", "otlp_interval", e)
+            })?;
+    if interval == 0 {
+        return Err(Error::config_value(
+            "cloud_agent",
+            "otlp_interval",
+            "must be at least 1 second",
+        ));
+    }
+
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(Some((endpoint.to_string(), Duration::from_secs(interval))))
+}
+
+/// Exports the spans and counters to the endpoint every interval, until the
+/// agent stops
+pub(crate) async fn run_exporter(
+    endpoint: String,
+    interval: Duration,
+    agent_uuid: String,
+) {
+    let client = http::client();
+    let traces_url = format!("{}/v1/traces", endpoint);
+    let metrics_url = format!("{}/v1/metrics", endpoint);
+    let start = SystemTime::now();
+
+    loop {
+        tokio::time::delay_for(interval).await;
+
+        let spans = match PENDING.lock() {
+            Ok(mut pending) => std::mem::take(&mut *pending),
+            Err(_) => Vec::new(),
+        };
+        if !spans.is_empty() {
+            if let Err(e) =
+                post(client, &traces_url, &traces_body(&agent_uuid, &spans))
+                    .await
+            {
+                warn!("Dropped {} spans: {}", spans.len(), e);
+            }
+        }
+
+        let counts: Vec<(&str, u64)> = COUNTERS
+            .iter()
+            .zip(COUNTS.iter())
+            .map(|((_, name), count)| (*name, count.load(Ordering::Relaxed)))
+            .collect();
+        if let Err(e) = post(
+            client,
+            &metrics_url,
+            &metrics_body(&agent_uuid, &counts, start, SystemTime::now()),
+        )
+        .await
+        {
+            warn!("Unable to export metrics: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn otlp_traces() {
+        let start = UNIX_EPOCH + Duration::from_secs(1);
+        let span = FinishedSpan {
+            name: "quote",
+            trace_id: [1; 16],
+            span_id: 2,
+            parent_span_id: Some(1),
+            start,
+            end: start + Duration::from_millis(5),
+            attributes: vec![("nonce", String::from("abc"))],
+        };
+
+        let body = traces_body("d432fbb3", &[span]);
+        let span = &body["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["traceId"], "01010101010101010101010101010101");
+        assert_eq!(span["spanId"], "0000000000000002");
+        assert_eq!(span["parentSpanId"], "0000000000000001");
+        assert_eq!(span["startTimeUnixNano"], "1000000000");
+        assert_eq!(span["endTimeUnixNano"], "1005000000");
+        assert_eq!(span["attributes"][0]["key"], "nonce");
+        assert_eq!(
+            body["resourceSpans"][0]["resource"]["attributes"][1]["value"]
+                ["stringValue"],
+            "d432fbb3"
+        );
+    }
+
+    #[test]
+    fn otlp_metrics() {
+        let body = metrics_body(
+            "d432fbb3",
+            &[("keylime_agent.quotes", 3)],
+            UNIX_EPOCH,
+            UNIX_EPOCH + Duration::from_secs(2),
+        );
+        let metric =
+            &body["resourceMetrics"][0]["scopeMetrics"][0]["metrics"][0];
+        assert_eq!(metric["name"], "keylime_agent.quotes");
+        assert_eq!(metric["sum"]["dataPoints"][0]["asInt"], "3");
+        assert_eq!(metric["sum"]["isMonotonic"], true);
+    }
+}
@@ -0,0 +1,220 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// TLS for the agent API
+//
+// With enable_agent_tls set, the agent serves its API over TLS. It starts
+// with the credentials a previous payload installed in the secure directory
+// if they are still valid, see payloads::install_tls_credentials(), or else
+// with a self-signed certificate for the NK. When a payload delivers new
+// credentials the agent switches to them without a restart: OpenSSL runs
+// the servername callback of the acceptor on every client hello, with or
+// without SNI, and the callback sets the SSL context of the connection to
+// the current one. Connections opened after the delivery get the new
+// certificate, established ones keep theirs.
+//
+// A teardown does not switch back to the NK certificate: the agent serves
+// the last credentials it loaded until another payload delivers new ones.
+
+use crate::common::{
+    config_get_bool_or, PAYLOAD_TLS_CA, PAYLOAD_TLS_CERT, PAYLOAD_TLS_KEY,
+    TLS_DIR,
+};
+use crate::crypto;
+use crate::error::Result;
+
+use lazy_static::lazy_static;
+use openssl::pkey::{PKey, PKeyRef, Private};
+use openssl::ssl::{
+    SniError, SslAcceptor, SslAcceptorBuilder, SslContext, SslMethod,
+};
+use openssl::x509::X509;
+use std::path::Path;
+use std::sync::RwLock;
+use tracing::{info, warn};
+
+// Validity of the self-signed NK certificate, which is generated again on
+// every start
+const NK_CERT_DAYS: u32 = 365;
+
+lazy_static! {
+    // The SSL context of new connections, None unless the agent serves TLS
+    static ref CURRENT: RwLock<Option<SslContext>> = RwLock::new(None);
+}
+
+/*
+ * Return: Result wrap with whether the agent serves its API over TLS
+ */
+pub(crate) fn enabled() -> Result<bool> {
+    config_get_bool_or("cloud_agent", "enable_agent_tls", false)
+}
+
+/*
+ * Input: secure directory, NK and agent UUID
+ * Return: Result wrap with error message
+ *
+ * Sets the credentials the agent starts serving TLS with: those installed
+ * by a previous payload, or else a self-signed certificate for the NK with
+ * the agent UUID as common name.
+ */
+pub(crate) fn init(
+    secure_dir: &Path,
+    nk: &PKeyRef<Private>,
+    agent_uuid: &str,
+) -> Result<()> {
+    let tls_dir = secure_dir.join(TLS_DIR);
+    if tls_dir.exists() {
+        match load(&tls_dir).and_then(|(cert, key, ca)| {
+            crypto::check_tls_credentials(&cert, &key, ca.as_ref())?;
+            context(&cert, &key, ca.as_ref())
+        }) {
+            Ok(context) => {
+                set(context);
+                info!(
+                    "Serving TLS with the credentials in {}",
+                    tls_dir.display()
+                );
+                return Ok(());
+            }
+            Err(e) => warn!(
+                "Unable to serve TLS with the credentials in {}: {}",
+                tls_dir.display(),
+                e
+            ),
+        }
+    }
+
+    let cert = crypto::generate_x509(nk, agent_uuid, NK_CERT_DAYS)?;
+    set(context(&cert, nk, None)?);
+    info!("Serving TLS with a self-signed certificate for the NK");
+    Ok(())
+}
+
+/*
+ * Input: directory holding the TLS credentials delivered in a payload
+ * Return: Result wrap with error message
+ *
+ * Switches new connections to the credentials, which were validated when
+ * the payload was staged. Nothing is done unless the agent serves TLS.
+ */
+pub(crate) fn reload(tls_dir: &Path) -> Result<()> {
+    let serving = CURRENT.read().unwrap().is_some(); //#[allow_ci]
+    if !serving {
+        return Ok(());
+    }
+
+    let (cert, key, ca) = load(tls_dir)?;
+    set(context(&cert, &key, ca.as_ref())?);
+    info!("Serving TLS with the credentials in {}", tls_dir.display());
+    Ok(())
+}
+
+/*
+ * Return: Result wrap with an acceptor for the API listener
+ *
+ * The acceptor itself has no certificate, each connection gets the SSL
+ * context current when its handshake starts.
+ */
+pub(crate) fn acceptor() -> Result<SslAcceptorBuilder> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+    builder.set_servername_callback(|ssl, _| {
+        let current = CURRENT.read().map_err(|_| SniError::ALERT_FATAL)?;
+        match current.as_ref() {
+            Some(context) => ssl
+                .set_ssl_context(context)
+                .map_err(|_| SniError::ALERT_FATAL),
+            None => Err(SniError::ALERT_FATAL),
+        }
+    });
+    Ok(builder)
+}
+
+fn set(context: SslContext) {
+    *CURRENT.write().unwrap() = Some(context); //#[allow_ci]
+}
+
+fn context(
+    cert: &X509,
+    key: &PKeyRef<Private>,
+    ca: Option<&X509>,
+) -> Result<SslContext> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+    builder.set_certificate(cert)?;
+    builder.set_private_key(key)?;
+    builder.check_private_key()?;
+    if let Some(ca) = ca {
+        builder.add_extra_chain_cert(ca.clone())?;
+    }
+    Ok(builder.build().into_context())
+}
+
+// Loads the certificate, key and optional CA certificate in a directory
+fn load(tls_dir: &Path) -> Result<(X509, PKey<Private>, Option<X509>)> {
+    let cert = crypto::load_x509(&tls_dir.join(PAYLOAD_TLS_CERT))?;
+    let key = crypto::load_private_key(&tls_dir.join(PAYLOAD_TLS_KEY))?;
+    let ca_path = tls_dir.join(PAYLOAD_TLS_CA);
+    let ca = if ca_path.exists() {
+        Some(crypto::load_x509(&ca_path)?)
+    } else {
+        None
+    };
+    Ok((cert, key, ca))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::ssl::{SslConnector, SslVerifyMode};
+    use std::fs;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    // The certificate the server presents to a new connection
+    fn peer_certificate(addr: std::net::SocketAddr) -> Vec<u8> {
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap(); //#[allow_ci]
+        connector.set_verify(SslVerifyMode::NONE);
+        let stream = connector
+            .build()
+            .configure()
+            .unwrap() //#[allow_ci]
+            .use_server_name_indication(false)
+            .verify_hostname(false)
+            .connect("localhost", TcpStream::connect(addr).unwrap()) //#[allow_ci]
+            .unwrap(); //#[allow_ci]
+        let cert = stream.ssl().peer_certificate().unwrap(); //#[allow_ci]
+        cert.to_der().unwrap() //#[allow_ci]
+    }
+
+    #[test]
+    fn reload_switches_new_connections() {
+        let secure_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let nk = crypto::rsa_generate(2048).unwrap(); //#[allow_ci]
+        init(secure_dir.path(), &nk, "agent").unwrap(); //#[allow_ci]
+
+        let acceptor = acceptor().unwrap().build(); //#[allow_ci]
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap(); //#[allow_ci]
+        let addr = listener.local_addr().unwrap(); //#[allow_ci]
+        let _ = thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let _ = acceptor.accept(stream);
+            }
+        });
+
+        let nk_cert = peer_certificate(addr);
+        let served = X509::from_der(&nk_cert).unwrap(); //#[allow_ci]
+        assert!(served.public_key().unwrap().public_eq(&nk)); //#[allow_ci]
+
+        // As installed by a payload
+        let tls_dir = secure_dir.path().join(TLS_DIR);
+        fs::create_dir(&tls_dir).unwrap(); //#[allow_ci]
+        let key = crypto::rsa_generate(2048).unwrap(); //#[allow_ci]
+        let cert = crypto::generate_x509(&key, "payload", 1).unwrap(); //#[allow_ci]
+        let pem = cert.to_pem().unwrap(); //#[allow_ci]
+        fs::write(tls_dir.join(PAYLOAD_TLS_CERT), pem).unwrap(); //#[allow_ci]
+        let pem = key.private_key_to_pem_pkcs8().unwrap(); //#[allow_ci]
+        fs::write(tls_dir.join(PAYLOAD_TLS_KEY), pem).unwrap(); //#[allow_ci]
+
+        reload(&tls_dir).unwrap(); //#[allow_ci]
+        assert_eq!(peer_certificate(addr), cert.to_der().unwrap()); //#[allow_ci]
+    }
+}
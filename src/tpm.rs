@@ -2,17 +2,17 @@
 // Copyright 2021 Keylime Authors
 
 use std::convert::{TryFrom, TryInto};
+use std::ffi::CString;
 use std::io::prelude::*;
 use std::str::FromStr;
+use std::sync::Mutex;
 
-use crate::{
-    common::config_get, quotes_handler::KeylimeIdQuote,
-    Error as KeylimeError, QuoteData, Result,
-};
+use crate::error::{Error as KeylimeError, Result};
 
-use actix_web::web::Data;
+use lazy_static::lazy_static;
 
 use openssl::{
+    bn::BigNum,
     pkey::{Id, PKeyRef, Public},
     rsa::Rsa,
 };
@@ -21,75 +21,119 @@ use flate2::{write::ZlibEncoder, Compression};
 
 use tss_esapi::{
     abstraction::{ak, cipher::Cipher, ek, DefaultKey},
-    attributes::session::SessionAttributesBuilder,
-    constants::{session_type::SessionType, tss::TPM2_ALG_NULL},
-    handles::{AuthHandle, KeyHandle, PcrHandle, SessionHandle},
+    attributes::{
+        session::SessionAttributesBuilder, ObjectAttributesBuilder,
+    },
+    constants::{
+        response_code::Tss2ResponseCodeKind,
+        session_type::SessionType,
+        tss::{
+            TPM2_ALG_KEYEDHASH, TPM2_ALG_NULL, TPM2_ALG_RSA, TPM2_ALG_SHA256,
+        },
+    },
+    handles::{
+        AuthHandle, KeyHandle, ObjectHandle, PcrHandle, SessionHandle,
+        TpmHandle,
+    },
     interface_types::{
         algorithm::{AsymmetricAlgorithm, HashingAlgorithm, SignatureScheme},
-        session_handles::AuthSession,
+        dynamic_handles::Persistent,
+        resource_handles::{Hierarchy, Provision},
+        session_handles::{AuthSession, PolicySession},
     },
     structures::{
-        Digest, DigestValues, EncryptedSecret, IDObject, Name,
-        PcrSelectionList, PcrSelectionListBuilder, PcrSlot,
+        Auth, Digest, DigestValues, EncryptedSecret, IDObject,
+        KeyedHashParameters, KeyedHashScheme, Name, PcrSelectionList,
+        PcrSelectionListBuilder, PcrSlot, Private, SensitiveData,
     },
     tss2_esys::{
-        Tss2_MU_TPM2B_PUBLIC_Marshal, Tss2_MU_TPMT_SIGNATURE_Marshal,
-        TPM2B_ATTEST, TPM2B_PUBLIC, TPML_DIGEST, TPML_PCR_SELECTION,
-        TPMS_SCHEME_HASH, TPMT_SIGNATURE, TPMT_SIG_SCHEME, TPMU_SIG_SCHEME,
+        Tss2_MU_TPM2B_PUBLIC_Marshal, Tss2_MU_TPM2B_PUBLIC_Unmarshal,
+        Tss2_MU_TPMT_SIGNATURE_Marshal, TPM2B_ATTEST, TPM2B_PUBLIC,
+        TPML_DIGEST, TPML_PCR_SELECTION, TPMS_SCHEME_HASH, TPMT_SIGNATURE,
+        TPMT_SIG_SCHEME, TPMU_SIG_SCHEME,
     },
-    utils::{PcrData, Signature},
+    utils::{self, PcrData, PublicParmsUnion, Signature, Tpm2BPublicBuilder},
     Context, Tcti,
 };
 
-/*
+/**
  * Input: None
  * Return: Connection context
  *
  * Example call:
  * let mut ctx = tpm::get_tpm2_ctx();
  */
-pub(crate) fn get_tpm2_ctx() -> Result<Context> {
+pub fn get_tpm2_ctx() -> Result<Context> {
     let tcti_path = match std::env::var("TCTI") {
         Ok(val) => val,
-        Err(_) => if std::path::Path::new("/dev/tpmrm0").exists() {
-            "device:/dev/tpmrm0"
-        } else {
-            "device:/dev/tpm0"
-        }
-        .to_string(),
+        Err(_) => format!("device:{}", find_tpm_device()),
     };
 
+    if let Some(device) = tcti_path.strip_prefix("device:") {
+        check_device_access(device)?;
+    }
+
     let tcti = Tcti::from_str(&tcti_path)?;
     unsafe { Context::new(tcti) }.map_err(|e| e.into())
 }
 
-/*
- * Input: Connection context, asymmetric algo (optional)
+// Returns the first TPM resource manager device, or else the first TPM
+// device. A container may be given another TPM than the first of the host.
+fn find_tpm_device() -> String {
+    let names: Vec<String> = match std::fs::read_dir("/dev") {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+
+    for prefix in &["tpmrm", "tpm"] {
+        let first = names
+            .iter()
+            .filter_map(|name| {
+                name.strip_prefix(prefix)
+                    .and_then(|number| number.parse::<u32>().ok())
+            })
+            .min();
+        if let Some(number) = first {
+            return format!("/dev/{}{}", prefix, number);
+        }
+    }
+    String::from("/dev/tpm0")
+}
+
+// The TPM devices belong to the tss group, so that an agent that is a
+// member of it does not need to run as root. Explain what is missing
+// rather than letting the TSS fail with a generic I/O error.
+fn check_device_access(path: &str) -> Result<()> {
+    let c_path = CString::new(path).map_err(|_| {
+        KeylimeError::Other(format!("invalid TPM device {:?}", path))
+    })?;
+    if unsafe { libc::access(c_path.as_ptr(), libc::R_OK | libc::W_OK) } != 0
+    {
+        let e = std::io::Error::last_os_error();
+        return Err(KeylimeError::file(
+            path,
+            std::io::Error::new(
+                e.kind(),
+                format!("{}. Run the agent as root or as a member of the group owning the device, usually tss", e),
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/**
+ * Input: Connection context, asymmetric algo
  * Return: (Key handle, public cert, TPM public object)
  * Example call:
- * let (key, cert, tpm_pub) = tpm::create_ek(context, Some(AsymmetricAlgorithm::Rsa))
+ * let (key, cert, tpm_pub) = tpm::create_ek(context, AsymmetricAlgorithm::Rsa)
  */
-pub(crate) fn create_ek(
+pub fn create_ek(
     context: &mut Context,
-    alg: Option<AsymmetricAlgorithm>,
+    alg: AsymmetricAlgorithm,
 ) -> Result<(KeyHandle, Vec<u8>, Vec<u8>)> {
-    // Set encryption algorithm
-    let alg = match alg {
-        Some(a) => a,
-        None => {
-            match config_get(
-                "cloud_agent",
-                "tpm_encryption_alg",
-            )?
-            .as_str()
-            {
-                "rsa" => AsymmetricAlgorithm::Rsa,
-                "ecc" => AsymmetricAlgorithm::Ecc,
-                _ => return Err(KeylimeError::Configuration(String::from("Encryption algorithm provided in keylime.conf is not supported")))
-            }
-        }
-    };
-
     // Retrieve EK handle, EK pub cert, and TPM pub object
     let handle = ek::create_ek_object(context, alg, DefaultKey)?;
     let cert = ek::retrieve_ek_pubcert(context, alg)?;
@@ -146,16 +190,74 @@ create_marshal_fn!(
     Tss2_MU_TPMT_SIGNATURE_Marshal
 );
 
-// Recreate how tpm2-tools creates the PCR out file. Roughly, this is a
-// TPML_PCR_SELECTION + number of TPML_DIGESTS + TPML_DIGESTs.
-// Reference:
-// https://github.com/tpm2-software/tpm2-tools/blob/master/tools/tpm2_quote.c#L47-L91
-//
-// Note: tpm2-tools does not use its own documented marshaling functions for this output,
-// so the below code recreates the idiosyncratic format tpm2-tools expects. The lengths
-// of the vectors were determined by introspection into running tpm2-tools code. This is
-// not ideal, and we should aim to move away from it if possible.
-pub(crate) fn pcrdata_to_vec(
+/// Inverse of pub_to_vec, used to load objects whose public area was
+/// persisted to disk.
+pub fn vec_to_pub(buf: &[u8]) -> Result<TPM2B_PUBLIC> {
+    let mut offset = 0u64;
+    let mut public = TPM2B_PUBLIC::default();
+
+    let res = unsafe {
+        Tss2_MU_TPM2B_PUBLIC_Unmarshal(
+            buf.as_ptr(),
+            buf.len() as u64,
+            &mut offset,
+            &mut public,
+        )
+    };
+    if res != 0 {
+        return Err(KeylimeError::Other(format!(
+            "Error unmarshaling TPM2B_PUBLIC: return code {}",
+            res
+        )));
+    }
+
+    Ok(public)
+}
+
+/// Returns the PEM encoding of an RSA EK public key, which is hashed for
+/// agent_uuid = hash_ek as in the Python agent
+pub fn ek_pub_to_pem(ek_tpm2b_pub: &[u8]) -> Result<Vec<u8>> {
+    pub_to_pem(ek_tpm2b_pub)
+}
+
+/// Returns the PEM encoding of the public key of a marshaled TPM2B_PUBLIC,
+/// of an RSA key such as the EK or AK
+pub fn pub_to_pem(tpm2b_pub: &[u8]) -> Result<Vec<u8>> {
+    let public = vec_to_pub(tpm2b_pub)?.publicArea;
+    if public.type_ != TPM2_ALG_RSA {
+        return Err(KeylimeError::Other(format!(
+            "key type {:#x} is not supported, only RSA",
+            public.type_
+        )));
+    }
+
+    let (modulus, exponent) = unsafe {
+        let unique = &public.unique.rsa;
+        (
+            unique.buffer[..usize::from(unique.size)].to_vec(),
+            public.parameters.rsaDetail.exponent,
+        )
+    };
+    // An exponent of 0 stands for the default 2^16 + 1
+    let exponent = if exponent == 0 { 65537 } else { exponent };
+
+    let rsa = Rsa::from_public_components(
+        BigNum::from_slice(&modulus)?,
+        BigNum::from_u32(exponent)?,
+    )?;
+    Ok(rsa.public_key_to_pem()?)
+}
+
+/// Recreate how tpm2-tools creates the PCR out file. Roughly, this is a
+/// TPML_PCR_SELECTION + number of TPML_DIGESTS + TPML_DIGESTs.
+/// Reference:
+/// https://github.com/tpm2-software/tpm2-tools/blob/master/tools/tpm2_quote.c#L47-L91
+///
+/// Note: tpm2-tools does not use its own documented marshaling functions for this output,
+/// so the below code recreates the idiosyncratic format tpm2-tools expects. The lengths
+/// of the vectors were determined by introspection into running tpm2-tools code. This is
+/// not ideal, and we should aim to move away from it if possible.
+pub fn pcrdata_to_vec(
     selection_list: PcrSelectionList,
     pcrdata: PcrData,
 ) -> Vec<u8> {
@@ -175,7 +277,7 @@ pub(crate) fn pcrdata_to_vec(
     data_vec
 }
 
-/* Converts a hex value in the form of a string (ex. from keylime.conf's
+/** Converts a hex value in the form of a string (ex. from keylime.conf's
  * ek_handle) to a key handle.
  *
  * Input: &str
@@ -184,36 +286,144 @@ pub(crate) fn pcrdata_to_vec(
  * Example call:
  * let ek_handle = tpm::ek_from_hex_str("0x81000000");
  */
-pub(crate) fn ek_from_hex_str(val: &str) -> Result<KeyHandle> {
+pub fn ek_from_hex_str(val: &str) -> Result<KeyHandle> {
     let val = val.trim_start_matches("0x");
     Ok(KeyHandle::from(u32::from_str_radix(val, 16)?))
 }
 
-/* Creates AK and returns a tuple of its handle, name, and tpm2b_public as a vector.
+/** Creates AK and returns a tuple of its handle, name, and tpm2b_public as a vector.
  *
  * Input: Connection context, parent key's KeyHandle.
  * Return: (Key handle, key name, TPM public object as a vector)
  * Example call:
  * let (key, name, tpm_pub) = tpm::create_ak(context, ek_handle)
 */
-pub(crate) fn create_ak(
+pub fn create_ak(
     ctx: &mut Context,
     handle: KeyHandle,
 ) -> Result<(KeyHandle, Name, Vec<u8>)> {
-    let ak = ak::create_ak(
-        ctx,
+    let ak = load_ak(ctx, handle, AkSource::Create)?
+        .ok_or_else(|| KeylimeError::Other("AK not created".to_string()))?;
+    let (_, name, _) = ctx.read_public(ak.handle)?;
+    Ok((ak.handle, name, ak.tpm2b_pub))
+}
+
+/// The AK to load along with the EK
+#[derive(Debug, Clone, Copy)]
+pub enum AkSource<'a> {
+    /// No AK, only the EK is needed
+    None,
+    /// A new AK, as created by create_ak
+    Create,
+    /// An AK created earlier, see Ak
+    Load {
+        /// Marshaled TPM2B_PUBLIC of the AK
+        public: &'a [u8],
+        /// TPM2B_PRIVATE of the AK
+        private: &'a [u8],
+    },
+}
+
+/// An AK loaded in the TPM
+#[derive(Debug, Clone)]
+pub struct Ak {
+    /// Handle of the AK
+    pub handle: KeyHandle,
+    /// Marshaled TPM2B_PUBLIC of the AK
+    pub tpm2b_pub: Vec<u8>,
+    /// TPM2B_PRIVATE of the AK, protected by the EK, to load it again
+    pub private: Vec<u8>,
+}
+
+/// The EK and AK, loaded in the TPM
+#[derive(Debug, Clone)]
+pub struct AgentKeys {
+    /// Handle of the EK
+    pub ek_handle: KeyHandle,
+    /// DER encoded EK certificate, empty if the TPM has none
+    pub ek_cert: Vec<u8>,
+    /// Marshaled TPM2B_PUBLIC of the EK
+    pub ek_tpm2b_pub: Vec<u8>,
+    /// The AK, unless AkSource::None was given
+    pub ak: Option<Ak>,
+}
+
+impl AgentKeys {
+    /// The AK, failing if none was loaded
+    pub fn ak(&self) -> Result<&Ak> {
+        self.ak
+            .as_ref()
+            .ok_or_else(|| KeylimeError::Other("AK not loaded".to_string()))
+    }
+
+    /// Flushes the EK and AK from the TPM
+    pub fn flush(&self, ctx: &mut Context) -> Result<()> {
+        if let Some(ak) = &self.ak {
+            ctx.flush_context(ak.handle.into())?;
+        }
+        ctx.flush_context(self.ek_handle.into())?;
+        Ok(())
+    }
+}
+
+fn load_ak(
+    ctx: &mut Context,
+    ek_handle: KeyHandle,
+    source: AkSource,
+) -> Result<Option<Ak>> {
+    let (private, public) = match source {
+        AkSource::None => return Ok(None),
+        AkSource::Create => {
+            let ak = ak::create_ak(
+                ctx,
+                ek_handle,
+                HashingAlgorithm::Sha256,
+                SignatureScheme::RsaSsa,
+                None,
+                DefaultKey,
+            )?;
+            (ak.out_private, ak.out_public)
+        }
+        AkSource::Load { public, private } => {
+            (Private::try_from(private)?, vec_to_pub(public)?)
+        }
+    };
+    let tpm2b_pub = pub_to_vec(public);
+    let private_vec = private.value().to_vec();
+    let handle = ak::load_ak(ctx, ek_handle, None, private, public)?;
+    Ok(Some(Ak {
         handle,
-        HashingAlgorithm::Sha256,
-        SignatureScheme::RsaSsa,
-        None,
-        DefaultKey,
-    )?;
-    let ak_tpm2b_pub = ak.out_public;
-    let tpm2_pub_vec = pub_to_vec(ak_tpm2b_pub);
-    let ak_handle =
-        ak::load_ak(ctx, handle, None, ak.out_private, ak.out_public)?;
-    let (_, name, _) = ctx.read_public(ak_handle)?;
-    Ok((ak_handle, name, tpm2_pub_vec))
+        tpm2b_pub,
+        private: private_vec,
+    }))
+}
+
+/**
+ * Input: Connection context, AK to load
+ * Return: The EK and AK
+ *
+ * Creates the EK, as it is derived from the TPM seed, and loads the AK
+ * under it. The EK is flushed again if the AK cannot be loaded, as
+ * happens when it was created under the EK of another TPM owner.
+ *
+ * Example call:
+ * let keys = tpm::load_keys(context, AkSource::Create)
+ */
+pub fn load_keys(ctx: &mut Context, ak: AkSource) -> Result<AgentKeys> {
+    let (ek_handle, ek_cert, ek_tpm2b_pub) =
+        create_ek(ctx, AsymmetricAlgorithm::Rsa)?;
+    match load_ak(ctx, ek_handle, ak) {
+        Ok(ak) => Ok(AgentKeys {
+            ek_handle,
+            ek_cert,
+            ek_tpm2b_pub,
+            ak,
+        }),
+        Err(e) => {
+            let _ = ctx.flush_context(ek_handle.into());
+            Err(e)
+        }
+    }
 }
 
 const TSS_MAGIC: u32 = 3135029470;
@@ -242,7 +452,8 @@ fn parse_cred_and_secret(
     );
 
     let credential = &keyblob[10..(10 + credsize as usize)];
-    let secret = &keyblob[(12 + credsize as usize)..];
+    let secret = &keyblob
+        [(12 + credsize as usize)..(12 + (credsize + secretsize) as usize)];
 
     let credential = IDObject::try_from(credential)?;
     let secret = EncryptedSecret::try_from(secret)?;
@@ -270,7 +481,9 @@ fn create_empty_session(
     Ok(session.unwrap()) //#[allow_ci]
 }
 
-pub(crate) fn activate_credential(
+/// Activates the credential the registrar made for the AK, returning the
+/// secret it holds.
+pub fn activate_credential(
     ctx: &mut Context,
     keyblob: Vec<u8>,
     ak: KeyHandle,
@@ -305,19 +518,249 @@ pub(crate) fn activate_credential(
     resp
 }
 
-// Returns TSS struct corresponding to an algorithm specified as a string, ex.
-// the string from the keylime.conf file.
-pub(crate) fn get_hash_alg(alg: String) -> Result<HashingAlgorithm> {
+/**
+ * Input: Connection context, password of the owner hierarchy, empty for none
+ * Return: Result wrap with error message
+ *
+ * Sets the password the objects created under the owner hierarchy, such
+ * as the parent of sealed objects, are authorized with.
+ */
+pub fn set_owner_auth(ctx: &mut Context, password: &str) -> Result<()> {
+    let auth = Auth::try_from(password.as_bytes())?;
+    ctx.tr_set_auth(ObjectHandle::Owner, &auth)?;
+    Ok(())
+}
+
+// The parent of sealed objects is a primary key created from a fixed
+// template under the owner hierarchy, so it is the same key on every boot
+// and does not need to be persisted. It is authorized with the password
+// set with set_owner_auth.
+fn create_sealing_parent(ctx: &mut Context) -> Result<KeyHandle> {
+    let public = utils::create_restricted_decryption_rsa_public(
+        Cipher::aes_128_cfb(),
+        2048,
+        0,
+    )?;
+    let primary = ctx.execute_with_nullauth_session(|ctx| {
+        ctx.create_primary(Hierarchy::Owner, &public, None, None, None, None)
+    })?;
+
+    Ok(primary.key_handle)
+}
+
+/// Returns a fingerprint of the owner hierarchy: the name of the sealing
+/// parent, which derives from the storage primary seed. Clearing the TPM
+/// changes the seed, and so the fingerprint.
+pub fn owner_fingerprint(ctx: &mut Context) -> Result<String> {
+    let parent = create_sealing_parent(ctx)?;
+    let name = ctx.read_public(parent).map(|(_, name, _)| name);
+    ctx.flush_context(parent.into())?;
+
+    Ok(hex::encode(name?.value()))
+}
+
+/// Whether a TPM error is the one expected when the AK was flushed from
+/// the TPM, as clearing the TPM does
+pub fn is_flushed_error(e: &KeylimeError) -> bool {
+    matches!(
+        e,
+        KeylimeError::Tpm {
+            kind: Some(Tss2ResponseCodeKind::Handle),
+            ..
+        } | KeylimeError::Tpm {
+            kind: Some(Tss2ResponseCodeKind::ReferenceH0),
+            ..
+        }
+    )
+}
+
+// Computes the policy digest that PolicyPCR yields with the current values
+// of the given PCRs.
+fn pcr_policy_digest(
+    ctx: &mut Context,
+    pcrlist: PcrSelectionList,
+) -> Result<Digest> {
+    let session = create_empty_session(ctx, SessionType::Trial)?;
+    let policy = PolicySession::try_from(session)?;
+
+    let digest = ctx
+        .policy_pcr(policy, &Digest::default(), pcrlist)
+        .and_then(|_| ctx.policy_get_digest(policy));
+    ctx.flush_context(SessionHandle::from(session).into())?;
+
+    Ok(digest?)
+}
+
+/** Seals data to the TPM under a policy on the current values of the given
+ * PCRs. The data can only be unsealed by the same TPM, and only while the
+ * PCRs hold the same values.
+ *
+ * Input: Connection context, PCRs to bind to, data of at most 128 bytes
+ * Return: (marshaled TPM2B_PUBLIC, TPM2B_PRIVATE) of the sealed object
+ * Example call:
+ * let (public, private) = tpm::seal_data(context, pcrlist, &key)
+ */
+pub fn seal_data(
+    ctx: &mut Context,
+    pcrlist: PcrSelectionList,
+    data: &[u8],
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    let policy = pcr_policy_digest(ctx, pcrlist)?;
+    let mut auth_policy = [0u8; 64];
+    auth_policy[..policy.value().len()].copy_from_slice(policy.value());
+
+    // No user_with_auth: the object can only be used by satisfying the PCR
+    // policy.
+    let attributes = ObjectAttributesBuilder::new()
+        .with_fixed_tpm(true)
+        .with_fixed_parent(true)
+        .with_no_da(true)
+        .build()?;
+    let public = Tpm2BPublicBuilder::new()
+        .with_type(TPM2_ALG_KEYEDHASH)
+        .with_name_alg(TPM2_ALG_SHA256)
+        .with_object_attributes(attributes)
+        .with_auth_policy(policy.value().len() as u16, auth_policy)
+        .with_parms(PublicParmsUnion::KeyedHashDetail(
+            KeyedHashParameters::new(KeyedHashScheme::Null),
+        ))
+        .build()?;
+    let sensitive = SensitiveData::try_from(data)?;
+
+    let parent = create_sealing_parent(ctx)?;
+    let sealed = ctx.execute_with_nullauth_session(|ctx| {
+        ctx.create(parent, &public, None, Some(&sensitive), None, None)
+    });
+    ctx.flush_context(parent.into())?;
+    let sealed = sealed?;
+
+    Ok((
+        pub_to_vec(sealed.out_public),
+        sealed.out_private.value().to_vec(),
+    ))
+}
+
+/** Unseals data sealed with seal_data. Fails if the PCRs no longer hold the
+ * values they had when the data was sealed.
+ *
+ * Input: Connection context, PCRs the data is bound to, sealed object
+ * Return: The unsealed data
+ * Example call:
+ * let key = tpm::unseal_data(context, pcrlist, &public, &private)
+ */
+pub fn unseal_data(
+    ctx: &mut Context,
+    pcrlist: PcrSelectionList,
+    public: &[u8],
+    private: &[u8],
+) -> Result<Vec<u8>> {
+    let public = vec_to_pub(public)?;
+    let private = Private::try_from(private)?;
+
+    let parent = create_sealing_parent(ctx)?;
+    let sealed = ctx.execute_with_nullauth_session(|ctx| {
+        ctx.load(parent, private, public)
+    });
+    ctx.flush_context(parent.into())?;
+    let sealed = sealed?;
+
+    let session = create_empty_session(ctx, SessionType::Policy)?;
+    let data = ctx
+        .policy_pcr(session.try_into()?, &Digest::default(), pcrlist)
+        .and_then(|_| {
+            ctx.execute_with_session(Some(session), |ctx| {
+                ctx.unseal(sealed.into())
+            })
+        });
+    ctx.flush_context(SessionHandle::from(session).into())?;
+    ctx.flush_context(sealed.into())?;
+
+    Ok(data?.value().to_vec())
+}
+
+/**
+ * Input: Connection context, persistent handle, ex. 0x81010002
+ * Return: Result wrap with error message
+ *
+ * Evicts an object made persistent in the owner hierarchy.
+ */
+pub fn evict_persistent(ctx: &mut Context, handle: u32) -> Result<()> {
+    let persistent = match TpmHandle::try_from(handle)? {
+        TpmHandle::Persistent(persistent) => persistent,
+        _ => {
+            return Err(KeylimeError::Other(format!(
+                "{:#x} is not a persistent handle",
+                handle
+            )))
+        }
+    };
+    let object = ctx.execute_without_session(|ctx| {
+        ctx.tr_from_tpm_public(TpmHandle::Persistent(persistent))
+    })?;
+    let _ = ctx.execute_with_session(Some(AuthSession::Password), |ctx| {
+        ctx.evict_control(
+            Provision::Owner,
+            object,
+            Persistent::Persistent(persistent),
+        )
+    })?;
+    Ok(())
+}
+
+/**
+ * Input: Connection context, PCR bank and PCR
+ * Return: Result wrap the value of the PCR
+ */
+pub fn read_pcr(
+    ctx: &mut Context,
+    hash_alg: HashingAlgorithm,
+    pcr: PcrSlot,
+) -> Result<Vec<u8>> {
+    let pcrlist = PcrSelectionListBuilder::new()
+        .with_selection(hash_alg, &[pcr])
+        .build();
+    let (_, _, pcr_data) =
+        ctx.execute_without_session(|ctx| ctx.pcr_read(&pcrlist))?;
+    pcr_data
+        .pcr_bank(hash_alg)
+        .and_then(|bank| bank.pcr_value(pcr))
+        .map(|digest| digest.value().to_vec())
+        .ok_or_else(|| {
+            KeylimeError::Other(format!(
+                "PCR {:?} of the {:?} bank could not be read",
+                pcr, hash_alg
+            ))
+        })
+}
+
+/**
+ * Input: Connection context, PCR and a digest per bank to extend it with
+ * Return: Result wrap with error message
+ *
+ * Banks that are not allocated are left alone.
+ */
+pub fn extend_pcr(
+    ctx: &mut Context,
+    pcr: PcrHandle,
+    digests: DigestValues,
+) -> Result<()> {
+    ctx.execute_with_nullauth_session(|ctx| ctx.pcr_extend(pcr, digests))?;
+    Ok(())
+}
+
+/// Returns TSS struct corresponding to an algorithm specified as a string, ex.
+/// the string from the keylime.conf file.
+pub fn get_hash_alg(alg: String) -> Result<HashingAlgorithm> {
     match alg.as_str() {
         "sha256" => Ok(HashingAlgorithm::Sha256),
-        other => {
-            Err(KeylimeError::Other(format!("{:?} not implemented", alg)))
-        }
+        _ => Err(KeylimeError::Other(format!("{:?} not implemented", alg))),
     }
 }
 
-#[derive(Debug)]
-pub(crate) enum TpmSigScheme {
+/// Signature schemes of quotes
+#[derive(Debug, Clone, Copy)]
+pub enum TpmSigScheme {
+    /// The scheme of the signing key
     AlgNull,
 }
 
@@ -327,10 +770,8 @@ impl Default for TpmSigScheme {
     }
 }
 
-// Returns TSS struct corresponding to a signature scheme.
-pub(crate) fn get_sig_scheme(
-    scheme: TpmSigScheme,
-) -> Result<TPMT_SIG_SCHEME> {
+/// Returns TSS struct corresponding to a signature scheme.
+pub fn get_sig_scheme(scheme: TpmSigScheme) -> Result<TPMT_SIG_SCHEME> {
     match scheme {
         // The TPM2_ALG_NULL sig scheme can be filled out with placeholder data
         // in the details field.
@@ -342,15 +783,11 @@ pub(crate) fn get_sig_scheme(
                 },
             },
         }),
-        _ => Err(KeylimeError::Other(format!(
-            "The signature scheme {:?} is not implemented",
-            scheme
-        ))),
     }
 }
 
-// Takes a public PKey and returns a DigestValue of it.
-pub(crate) fn pubkey_to_tpm_digest(
+/// Takes a public PKey and returns a DigestValue of it.
+pub fn pubkey_to_tpm_digest(
     pubkey: &PKeyRef<Public>,
     algo: HashingAlgorithm,
 ) -> Result<DigestValues> {
@@ -387,20 +824,20 @@ pub(crate) fn pubkey_to_tpm_digest(
     }
 }
 
-// Reads a mask in the form of some hex value, ex. "0x408000",
-// translating bits that are set to pcrs to include in the list.
-//
-// The masks are sent from the tenant and cloud verifier to indicate
-// the PCRs to include in a Quote. The LSB in the mask corresponds to
-// PCR0. For example, keylime.conf specifies PCRs 15 and 22 under
-// [tenant][tpm_policy]. As a bit mask, this would be represented as
-// 0b010000001000000000000000, which translates to 0x408000.
-//
-// The mask is a string because it is sent as a string from the tenant
-// and verifier. The output from this function can be used to call a
-// Quote from the TSS ESAPI.
-//
-pub(crate) fn read_mask(mask: &str) -> Result<Vec<PcrSlot>> {
+/// Reads a mask in the form of some hex value, ex. "0x408000",
+/// translating bits that are set to pcrs to include in the list.
+///
+/// The masks are sent from the tenant and cloud verifier to indicate
+/// the PCRs to include in a Quote. The LSB in the mask corresponds to
+/// PCR0. For example, keylime.conf specifies PCRs 15 and 22 under
+/// [tenant][tpm_policy]. As a bit mask, this would be represented as
+/// 0b010000001000000000000000, which translates to 0x408000.
+///
+/// The mask is a string because it is sent as a string from the tenant
+/// and verifier. The output from this function can be used to call a
+/// Quote from the TSS ESAPI.
+///
+pub fn read_mask(mask: &str) -> Result<Vec<PcrSlot>> {
     let mut pcrs = Vec::new();
 
     let num = u32::from_str_radix(mask.trim_start_matches("0x"), 16)?;
@@ -443,58 +880,52 @@ pub(crate) fn read_mask(mask: &str) -> Result<Vec<PcrSlot>> {
     Ok(pcrs)
 }
 
-// This encodes a quote string as input to Python Keylime's quote checking functionality.
-// The quote, signature, and pcr blob are concatenated with ':' separators. To match the
-// expected format, the quote, signature, and pcr blob must be individually compressed
-// with zlib at the default compression level and then base64 encoded before concatenation.
-//
-// Reference:
-// https://github.com/keylime/keylime/blob/2dd9e5c968f33bf77110092af9268d13db1806c6 \
-// /keylime/tpm/tpm_main.py#L964-L975
-pub(crate) fn encode_quote_string(
+/// This encodes a quote string as input to Python Keylime's quote checking functionality.
+/// The quote, signature, and pcr blob are concatenated with ':' separators. To match the
+/// expected format, the quote, signature, and pcr blob must be individually compressed
+/// with zlib at the default compression level and then base64 encoded before concatenation.
+/// Each part is compressed and encoded straight into the quote, without intermediate buffers.
+///
+/// Reference:
+/// https://github.com/keylime/keylime/blob/2dd9e5c968f33bf77110092af9268d13db1806c6 \
+/// /keylime/tpm/tpm_main.py#L964-L975
+pub fn encode_quote_string(
     att: TPM2B_ATTEST,
     sig: Signature,
     pcrs_read: PcrSelectionList,
     pcr_data: PcrData,
-) -> Result<String> {
+    quote: &mut String,
+) -> Result<()> {
     // marshal structs to vec in expected formats. these formats are
     // dictated by tpm2_tools.
     let att_vec = &att.attestationData[0..att.size as usize];
     let sig_vec = sig_to_vec(sig.try_into()?);
     let pcr_vec = pcrdata_to_vec(pcrs_read, pcr_data);
 
-    // zlib compression
-    let mut att_comp = ZlibEncoder::new(Vec::new(), Compression::default());
-    att_comp.write_all(att_vec);
-    let att_comp_finished = att_comp.finish()?;
-
-    let mut sig_comp = ZlibEncoder::new(Vec::new(), Compression::default());
-    sig_comp.write_all(&sig_vec);
-    let sig_comp_finished = sig_comp.finish()?;
-
-    let mut pcr_comp = ZlibEncoder::new(Vec::new(), Compression::default());
-    pcr_comp.write_all(&pcr_vec);
-    let pcr_comp_finished = pcr_comp.finish()?;
+    let mut encoded = std::mem::take(quote).into_bytes();
+    encoded.reserve((att_vec.len() + sig_vec.len() + pcr_vec.len()) * 4 / 3);
+    append_compressed(&mut encoded, att_vec)?;
+    encoded.push(b':');
+    append_compressed(&mut encoded, &sig_vec)?;
+    encoded.push(b':');
+    append_compressed(&mut encoded, &pcr_vec)?;
 
-    // base64 encoding
-    let att_str = base64::encode(att_comp_finished);
-    let sig_str = base64::encode(sig_comp_finished);
-    let pcr_str = base64::encode(pcr_comp_finished);
-
-    // create concatenated string
-    let mut quote = String::new();
-    quote.push_str(&att_str);
-    quote.push(':');
-    quote.push_str(&sig_str);
-    quote.push(':');
-    quote.push_str(&pcr_str);
+    *quote = String::from_utf8(encoded)?;
+    Ok(())
+}
 
-    Ok(quote)
+// Appends the data compressed with zlib and base64 encoded
+fn append_compressed(out: &mut Vec<u8>, data: &[u8]) -> Result<()> {
+    let encoder = base64::write::EncoderWriter::new(out, base64::STANDARD);
+    let mut compressor = ZlibEncoder::new(encoder, Compression::default());
+    compressor.write_all(data)?;
+    compressor.finish()?.finish()?;
+    Ok(())
 }
 
-// This function extends Pcr16 with the digest, then creates a PcrList
-// from the given mask and pcr16.
-pub(crate) fn build_pcr_list(
+/// This function extends Pcr16 with the digest, then creates a PcrList
+/// from the given mask and pcr16.
+pub fn build_pcr_list(
     context: &mut Context,
     hash_alg: HashingAlgorithm,
     digest: DigestValues,
@@ -506,6 +937,38 @@ pub(crate) fn build_pcr_list(
         ctx.pcr_extend(PcrHandle::Pcr16, digest)
     })?;
 
+    pcr_selection(hash_alg, mask)
+}
+
+// Selections built for the last masks requested, the most recent last.
+// Verifiers use the same mask for every quote of an agent, so a few entries
+// are enough.
+const PCR_SELECTION_CACHE_SIZE: usize = 8;
+
+lazy_static! {
+    static ref PCR_SELECTIONS: Mutex<Vec<(String, HashingAlgorithm, PcrSelectionList)>> =
+        Mutex::new(Vec::new());
+}
+
+// Returns the selection of the PCRs in the mask, and PCR 16
+fn pcr_selection(
+    hash_alg: HashingAlgorithm,
+    mask: Option<&str>,
+) -> Result<PcrSelectionList> {
+    let key = mask.unwrap_or("");
+    // must unwrap here due to lock mechanism
+    // https://github.com/rust-lang-nursery/failure/issues/192
+    let mut cache = PCR_SELECTIONS.lock().unwrap(); //#[allow_ci]
+    if let Some(index) = cache
+        .iter()
+        .position(|(mask, alg, _)| mask == key && *alg == hash_alg)
+    {
+        let entry = cache.remove(index);
+        let pcrlist = entry.2.clone();
+        cache.push(entry);
+        return Ok(pcrlist);
+    }
+
     // translate mask to vec of pcrs
     let mut pcrs = match mask {
         Some(m) => read_mask(m)?,
@@ -522,23 +985,27 @@ pub(crate) fn build_pcr_list(
         .with_selection(hash_alg, &pcrs)
         .build();
 
+    if cache.len() >= PCR_SELECTION_CACHE_SIZE {
+        let _ = cache.remove(0);
+    }
+    cache.push((key.to_string(), hash_alg, pcrlist.clone()));
     Ok(pcrlist)
 }
 
-// The pcr blob corresponds to the pcr out file that records the list of PCR values,
-// specified by tpm2tools, ex. 'tpm2_quote ... -o <pcrfilename>'. Read more here:
-// https://github.com/tpm2-software/tpm2-tools/blob/master/man/tpm2_quote.1.md
-//
-// It is required by Python Keylime's check_quote functionality. For how the quote is
-// checked, see:
-// https://github.com/keylime/keylime/blob/2dd9e5c968f33bf77110092af9268d13db1806c6/ \
-// keylime/tpm/tpm_main.py#L990
-//
-// For how the quote is created, see:
-// https://github.com/keylime/keylime/blob/2dd9e5c968f33bf77110092af9268d13db1806c6/ \
-// keylime/tpm/tpm_main.py#L965
-//
-pub(crate) fn make_pcr_blob(
+/// The pcr blob corresponds to the pcr out file that records the list of PCR values,
+/// specified by tpm2tools, ex. 'tpm2_quote ... -o <pcrfilename>'. Read more here:
+/// https://github.com/tpm2-software/tpm2-tools/blob/master/man/tpm2_quote.1.md
+///
+/// It is required by Python Keylime's check_quote functionality. For how the quote is
+/// checked, see:
+/// https://github.com/keylime/keylime/blob/2dd9e5c968f33bf77110092af9268d13db1806c6/ \
+/// keylime/tpm/tpm_main.py#L990
+///
+/// For how the quote is created, see:
+/// https://github.com/keylime/keylime/blob/2dd9e5c968f33bf77110092af9268d13db1806c6/ \
+/// keylime/tpm/tpm_main.py#L965
+///
+pub fn make_pcr_blob(
     context: &mut Context,
     pcrlist: PcrSelectionList,
 ) -> Result<(PcrSelectionList, PcrData)> {
@@ -554,29 +1021,29 @@ pub(crate) fn make_pcr_blob(
     Ok((pcrs_read, pcr_data))
 }
 
-// Despite the return type, this function is used for both Identity and
-// Integrity Quotes. The Quote handler will add additional information to
-// turn an Identity Quote into an Integrity Quote.
-pub(crate) fn quote(
+/**
+ * Input: Connection context, nonce, PCR mask (PCR 16 is always included),
+ *        hash algorithm, digest of the NK extended in PCR 16, AK handle
+ * Return: Result wrap the quote string, 'r' + quote + sig + pcrblob
+ *
+ * Despite the name, this is used for both Identity and Integrity Quotes.
+ */
+pub fn quote(
+    context: &mut Context,
     nonce: &[u8],
     mask: Option<&str>,
-    data: Data<QuoteData>,
-) -> Result<KeylimeIdQuote> {
-    let hash_alg = get_hash_alg(config_get("cloud_agent", "tpm_hash_alg")?)?;
-    let nk_digest = pubkey_to_tpm_digest(&data.pub_key, hash_alg)?;
-
-    // must unwrap here due to lock mechanism
-    // https://github.com/rust-lang-nursery/failure/issues/192
-    let mut context = data.tpmcontext.lock().unwrap(); //#[allow_ci]
-
-    let pcrlist = build_pcr_list(&mut context, hash_alg, nk_digest, mask)?;
+    hash_alg: HashingAlgorithm,
+    nk_digest: DigestValues,
+    ak_handle: KeyHandle,
+) -> Result<String> {
+    let pcrlist = build_pcr_list(context, hash_alg, nk_digest, mask)?;
     let sig_scheme = get_sig_scheme(TpmSigScheme::default())?;
 
     // create quote
     let (attestation, sig) =
         context.execute_with_nullauth_session(|ctx| {
             ctx.quote(
-                data.ak_handle,
+                ak_handle,
                 &nonce.try_into()?,
                 sig_scheme,
                 pcrlist.clone(),
@@ -584,25 +1051,20 @@ pub(crate) fn quote(
         })?;
 
     // TSS ESAPI quote does not create pcr blob, so create it separately
-    let (pcrs_read, pcr_data) = make_pcr_blob(&mut context, pcrlist)?;
-
-    let quote = encode_quote_string(attestation, sig, pcrs_read, pcr_data)?;
+    let (pcrs_read, pcr_data) = make_pcr_blob(context, pcrlist)?;
 
-    let mut keylimequote = KeylimeIdQuote::default();
-    keylimequote.quote.push_str(&quote);
+    let mut quote = String::from("r");
+    encode_quote_string(attestation, sig, pcrs_read, pcr_data, &mut quote)?;
 
-    Ok(keylimequote)
+    Ok(quote)
 }
 
-#[ignore] // This will only work as an integration test because it needs keylime.conf
 #[test]
 fn pubkey_to_digest() {
     let (key, _) = crate::crypto::rsa_generate_pair(2048).unwrap(); //#[allow_ci]
-    let hash_alg =
-        get_hash_alg(config_get("cloud_agent", "tpm_hash_alg").unwrap()) //#[allow_ci]
-            .unwrap(); //#[allow_ci]
+    let hash_alg = get_hash_alg(String::from("sha256")).unwrap(); //#[allow_ci]
 
-    let digest = pubkey_to_tpm_digest(&key, hash_alg).unwrap(); //#[allow_ci]
+    assert!(pubkey_to_tpm_digest(&key, hash_alg).is_ok());
 }
 
 #[test]
@@ -679,3 +1141,41 @@ fn mask() {
 
     assert!(read_mask("0x1ffffff").is_err());
 }
+
+#[test]
+fn streamed_encoding() {
+    let data = vec![0x5au8; 4096];
+    let mut compressed = ZlibEncoder::new(Vec::new(), Compression::default());
+    compressed.write_all(&data).unwrap(); //#[allow_ci]
+    let expected = base64::encode(compressed.finish().unwrap()); //#[allow_ci]
+
+    let mut out = b"r".to_vec();
+    append_compressed(&mut out, &data).unwrap(); //#[allow_ci]
+    assert_eq!(out, format!("r{}", expected).into_bytes());
+}
+
+#[test]
+fn pcr_selection_cache() {
+    let selection =
+        pcr_selection(HashingAlgorithm::Sha256, Some("0x408000")).unwrap(); //#[allow_ci]
+    let expected = PcrSelectionListBuilder::new()
+        .with_selection(
+            HashingAlgorithm::Sha256,
+            &[PcrSlot::Slot15, PcrSlot::Slot22, PcrSlot::Slot16],
+        )
+        .build();
+    assert_eq!(selection, expected);
+    // Served from the cache
+    assert_eq!(
+        pcr_selection(HashingAlgorithm::Sha256, Some("0x408000")).unwrap(), //#[allow_ci]
+        expected
+    );
+
+    for mask in 0..2 * PCR_SELECTION_CACHE_SIZE {
+        let _ =
+            pcr_selection(HashingAlgorithm::Sha1, Some(&mask.to_string()))
+                .unwrap(); //#[allow_ci]
+    }
+    assert!(PCR_SELECTIONS.lock().unwrap().len() <= PCR_SELECTION_CACHE_SIZE); //#[allow_ci]
+    assert!(pcr_selection(HashingAlgorithm::Sha1, Some("0x1000000")).is_err());
+}
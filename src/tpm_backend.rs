@@ -0,0 +1,400 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// TPM operations of the handlers
+//
+// The handlers do not use the ESAPI context directly, but the operations of
+// the TpmBackend trait, run on the TPM worker, see tpm_worker.rs. The agent
+// runs them on the TPM context, and unit tests on MockTpm, which answers
+// deterministically without a TPM or swtpm: quotes are made of a canned
+// quote, the nonce and the values set for the PCRs of the mask, so that
+// tests can check what was quoted. PCRs that were not set are zero, in the
+// size of the digests of the bank quoted.
+
+use crate::agent_data;
+use crate::error::Result;
+use crate::tpm;
+
+use tss_esapi::handles::KeyHandle;
+use tss_esapi::interface_types::algorithm::HashingAlgorithm;
+use tss_esapi::structures::{Digest, DigestValues};
+use tss_esapi::Context;
+
+pub(crate) trait TpmBackend: Send {
+    /// Quotes the PCRs of mask with the AK, see tpm::quote
+    fn quote(
+        &mut self,
+        nonce: &[u8],
+        mask: Option<&str>,
+        hash_alg: HashingAlgorithm,
+        nk_digest: DigestValues,
+        ak_handle: KeyHandle,
+    ) -> Result<String>;
+
+    /// Whether the TPM was cleared since the agent data was stored
+    fn tpm_cleared(&mut self) -> Result<bool>;
+
+    /// Decrypts the key the registrar encrypted for the AK
+    fn activate_credential(
+        &mut self,
+        keyblob: Vec<u8>,
+        ak_handle: KeyHandle,
+        ek_handle: KeyHandle,
+    ) -> Result<Digest>;
+
+    /// Seals the bootstrap key and stores it along with the payload
+    fn persist_payload(
+        &mut self,
+        key: &[u8],
+        payload: Option<&str>,
+    ) -> Result<()>;
+
+    /// Random bytes from the TPM
+    fn get_random(&mut self, size: usize) -> Result<Vec<u8>>;
+
+    /// Flushes the transient EK and AK, see AgentKeys::flush
+    fn flush_keys(&mut self, keys: &tpm::AgentKeys) -> Result<()>;
+}
+
+impl TpmBackend for Context {
+    fn quote(
+        &mut self,
+        nonce: &[u8],
+        mask: Option<&str>,
+        hash_alg: HashingAlgorithm,
+        nk_digest: DigestValues,
+        ak_handle: KeyHandle,
+    ) -> Result<String> {
+        tpm::quote(self, nonce, mask, hash_alg, nk_digest, ak_handle)
+    }
+
+    fn tpm_cleared(&mut self) -> Result<bool> {
+        agent_data::tpm_cleared(self)
+    }
+
+    fn activate_credential(
+        &mut self,
+        keyblob: Vec<u8>,
+        ak_handle: KeyHandle,
+        ek_handle: KeyHandle,
+    ) -> Result<Digest> {
+        tpm::activate_credential(self, keyblob, ak_handle, ek_handle)
+    }
+
+    fn persist_payload(
+        &mut self,
+        key: &[u8],
+        payload: Option<&str>,
+    ) -> Result<()> {
+        agent_data::persist_payload(self, key, payload)
+    }
+
+    fn get_random(&mut self, size: usize) -> Result<Vec<u8>> {
+        Ok(Context::get_random(self, size)?.value().to_vec())
+    }
+
+    fn flush_keys(&mut self, keys: &tpm::AgentKeys) -> Result<()> {
+        keys.flush(self)
+    }
+}
+
+#[cfg(test)]
+pub(crate) use mock::{quote_data, MockTpm};
+
+#[cfg(test)]
+mod mock {
+    use super::*;
+    use crate::error::Error;
+    use crate::ima::MeasurementList;
+    use crate::key_delivery::{KeyDelivery, DEFAULT_KEY_DELIVERY_TIMEOUT};
+    use crate::limits::Limits;
+    use crate::quote::QuoteConfig;
+    use crate::tpm_worker::TpmWorker;
+    use crate::{crypto, QuoteData};
+
+    use std::collections::BTreeMap;
+    use std::convert::TryFrom;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicBool, AtomicU64};
+    use std::sync::{Arc, Mutex};
+    use tss_esapi::handles::ObjectHandle;
+
+    // Size of the PCRs of a bank
+    fn pcr_size(hash_alg: HashingAlgorithm) -> Result<usize> {
+        match hash_alg {
+            HashingAlgorithm::Sha1 => Ok(20),
+            HashingAlgorithm::Sha256 => Ok(32),
+            HashingAlgorithm::Sha384 => Ok(48),
+            HashingAlgorithm::Sha512 => Ok(64),
+            other => {
+                Err(Error::Other(format!("no PCR bank for {:?}", other)))
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    struct State {
+        quote: Vec<u8>,
+        pcrs: BTreeMap<u32, Vec<u8>>,
+        cleared: bool,
+        credential: Vec<u8>,
+        persisted: Option<(Vec<u8>, Option<String>)>,
+    }
+
+    /// TPM of unit tests. Clones share the same state, so that tests can
+    /// change and inspect it while a worker owns the mock.
+    #[derive(Debug, Clone)]
+    pub(crate) struct MockTpm {
+        state: Arc<Mutex<State>>,
+    }
+
+    impl MockTpm {
+        /// A TPM whose PCRs are all zero
+        pub(crate) fn new() -> Self {
+            MockTpm {
+                state: Arc::new(Mutex::new(State {
+                    quote: b"mock quote".to_vec(),
+                    pcrs: BTreeMap::new(),
+                    cleared: false,
+                    credential: b"mock credential".to_vec(),
+                    persisted: None,
+                })),
+            }
+        }
+
+        fn state(&self) -> std::sync::MutexGuard<'_, State> {
+            self.state.lock().unwrap() //#[allow_ci]
+        }
+
+        /// Sets the quote the nonce is appended to
+        pub(crate) fn set_quote(&self, quote: &[u8]) {
+            self.state().quote = quote.to_vec();
+        }
+
+        pub(crate) fn set_pcr(&self, index: u32, value: &[u8]) {
+            let _ = self.state().pcrs.insert(index, value.to_vec());
+        }
+
+        pub(crate) fn set_cleared(&self, cleared: bool) {
+            self.state().cleared = cleared;
+        }
+
+        /// Sets the key activate_credential returns
+        pub(crate) fn set_credential(&self, credential: &[u8]) {
+            self.state().credential = credential.to_vec();
+        }
+
+        /// The bootstrap key and payload last persisted
+        pub(crate) fn persisted(&self) -> Option<(Vec<u8>, Option<String>)> {
+            self.state().persisted.clone()
+        }
+    }
+
+    impl TpmBackend for MockTpm {
+        // The quote is "r" followed by the base64 of the canned quote and
+        // the nonce, of a signature and of the values of the PCRs of the
+        // mask, in order, separated by colons as in real quotes
+        fn quote(
+            &mut self,
+            nonce: &[u8],
+            mask: Option<&str>,
+            hash_alg: HashingAlgorithm,
+            _nk_digest: DigestValues,
+            _ak_handle: KeyHandle,
+        ) -> Result<String> {
+            let mask = match mask {
+                Some(mask) => {
+                    u32::from_str_radix(mask.trim_start_matches("0x"), 16)?
+                }
+                None => 0,
+            };
+            let size = pcr_size(hash_alg)?;
+            let state = self.state();
+            let mut quote = state.quote.clone();
+            quote.extend(nonce);
+            let mut pcrs = Vec::new();
+            for index in (0..32).filter(|i| mask & (1 << i) != 0) {
+                match state.pcrs.get(&index) {
+                    Some(value) => pcrs.extend(value),
+                    None => pcrs.extend(vec![0u8; size]),
+                }
+            }
+            Ok(format!(
+                "r{}:{}:{}",
+                base64::encode(quote),
+                base64::encode(b"mock signature"),
+                base64::encode(pcrs)
+            ))
+        }
+
+        fn tpm_cleared(&mut self) -> Result<bool> {
+            Ok(self.state().cleared)
+        }
+
+        fn activate_credential(
+            &mut self,
+            keyblob: Vec<u8>,
+            _ak_handle: KeyHandle,
+            _ek_handle: KeyHandle,
+        ) -> Result<Digest> {
+            if keyblob.is_empty() {
+                return Err(Error::Other("empty keyblob".to_string()));
+            }
+            Ok(Digest::try_from(self.state().credential.clone())?)
+        }
+
+        fn persist_payload(
+            &mut self,
+            key: &[u8],
+            payload: Option<&str>,
+        ) -> Result<()> {
+            self.state().persisted =
+                Some((key.to_vec(), payload.map(String::from)));
+            Ok(())
+        }
+
+        fn get_random(&mut self, size: usize) -> Result<Vec<u8>> {
+            Ok(vec![0x2a; size])
+        }
+
+        fn flush_keys(&mut self, _keys: &tpm::AgentKeys) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// The data of the handlers, with a worker running on a mock TPM
+    pub(crate) fn quote_data(tpm: MockTpm) -> Result<QuoteData> {
+        let (pub_key, priv_key) = crypto::rsa_generate_pair(2048)?;
+        Ok(QuoteData {
+            tpm: TpmWorker::start(tpm)?,
+            priv_key,
+            pub_key_pem: String::from_utf8(pub_key.public_key_to_pem()?)?,
+            pub_key,
+            ak_handle: KeyHandle::from(ObjectHandle::None),
+            agent_uuid: String::from("mock"),
+            secure_dir: PathBuf::new(),
+            keys: Mutex::new(KeyDelivery::new(DEFAULT_KEY_DELIVERY_TIMEOUT)),
+            ima_ml: Mutex::new(MeasurementList::default()),
+            last_quote: AtomicU64::new(0),
+            registered: AtomicBool::new(false),
+            limits: Limits::unlimited(),
+            quote_config: QuoteConfig::default(),
+            evidence: None,
+            runtime_policy: Mutex::new(None),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tss_esapi::handles::ObjectHandle;
+
+    fn quoted_pcrs(quote: &str) -> Vec<u8> {
+        let parts = quote[1..].split(':').collect::<Vec<_>>();
+        assert_eq!(parts.len(), 3);
+        base64::decode(parts[2]).unwrap() //#[allow_ci]
+    }
+
+    #[test]
+    fn mock_tpm_quote() {
+        let tpm = MockTpm::new();
+        tpm.set_pcr(10, &[1u8; 32]);
+        tpm.set_quote(b"mock quote");
+        let mut backend: Box<dyn TpmBackend> = Box::new(tpm.clone());
+
+        let quote = backend
+            .quote(
+                b"nonce",
+                Some("0x400"),
+                HashingAlgorithm::Sha256,
+                DigestValues::new(),
+                KeyHandle::from(ObjectHandle::None),
+            )
+            .unwrap(); //#[allow_ci]
+        assert!(quote.starts_with('r'));
+        assert_eq!(quoted_pcrs(&quote), vec![1u8; 32]);
+        let body = base64::decode(quote[1..].split(':').next().unwrap()) //#[allow_ci]
+            .unwrap(); //#[allow_ci]
+        assert_eq!(body, b"mock quotenonce");
+
+        // Same state, same quote
+        let again = backend
+            .quote(
+                b"nonce",
+                Some("0x400"),
+                HashingAlgorithm::Sha256,
+                DigestValues::new(),
+                KeyHandle::from(ObjectHandle::None),
+            )
+            .unwrap(); //#[allow_ci]
+        assert_eq!(quote, again);
+
+        let quote = backend
+            .quote(
+                b"nonce",
+                Some("0x401"),
+                HashingAlgorithm::Sha256,
+                DigestValues::new(),
+                KeyHandle::from(ObjectHandle::None),
+            )
+            .unwrap(); //#[allow_ci]
+        let mut pcrs = vec![0u8; 32];
+        pcrs.extend(&[1u8; 32]);
+        assert_eq!(quoted_pcrs(&quote), pcrs);
+
+        // Unset PCRs are as large as the digests of the bank
+        for (hash_alg, size) in &[
+            (HashingAlgorithm::Sha1, 20),
+            (HashingAlgorithm::Sha384, 48),
+            (HashingAlgorithm::Sha512, 64),
+        ] {
+            let quote = backend
+                .quote(
+                    b"nonce",
+                    Some("0x3"),
+                    *hash_alg,
+                    DigestValues::new(),
+                    KeyHandle::from(ObjectHandle::None),
+                )
+                .unwrap(); //#[allow_ci]
+            assert_eq!(quoted_pcrs(&quote), vec![0u8; 2 * size]);
+        }
+        assert!(backend
+            .quote(
+                b"nonce",
+                Some("0x1"),
+                HashingAlgorithm::Sm3_256,
+                DigestValues::new(),
+                KeyHandle::from(ObjectHandle::None),
+            )
+            .is_err());
+        assert!(backend
+            .quote(
+                b"nonce",
+                Some("mask"),
+                HashingAlgorithm::Sha256,
+                DigestValues::new(),
+                KeyHandle::from(ObjectHandle::None),
+            )
+            .is_err());
+
+        backend.persist_payload(b"key", Some("payload")).unwrap(); //#[allow_ci]
+        assert_eq!(
+            tpm.persisted(),
+            Some((b"key".to_vec(), Some("payload".to_string())))
+        );
+        tpm.set_credential(b"key");
+        let key = backend
+            .activate_credential(
+                b"keyblob".to_vec(),
+                KeyHandle::from(ObjectHandle::None),
+                KeyHandle::from(ObjectHandle::None),
+            )
+            .unwrap(); //#[allow_ci]
+        assert_eq!(key.value(), b"key");
+        assert!(!backend.tpm_cleared().unwrap()); //#[allow_ci]
+        tpm.set_cleared(true);
+        assert!(backend.tpm_cleared().unwrap()); //#[allow_ci]
+    }
+}
@@ -0,0 +1,121 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// TPM worker thread
+//
+// ESAPI calls block until the TPM answers, which takes hundreds of
+// milliseconds for a quote. Once the agent serves requests, the TPM context
+// therefore belongs to a thread of its own, and the HTTP workers send it
+// the operations to run, in order, over a channel. The operations are those
+// of the TpmBackend trait, see tpm_backend.rs, so that the worker runs them
+// on a mock TPM in unit tests. They wait for the
+// results asynchronously, so that requests that do not need the TPM are
+// served in the meantime. An operation that panics fails on its own, and
+// the worker goes on with the next one.
+
+use crate::error::{Error, Result};
+#[cfg(feature = "testing")]
+use crate::fault;
+use crate::tpm_backend::TpmBackend;
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use tracing::{debug, error};
+
+type Job = Box<dyn FnOnce(&mut dyn TpmBackend) + Send>;
+
+#[derive(Debug)]
+pub(crate) struct TpmWorker {
+    jobs: Mutex<mpsc::Sender<Job>>,
+    // Operations submitted and not finished yet
+    pending: Arc<AtomicUsize>,
+}
+
+impl TpmWorker {
+    /// Starts the thread owning the TPM context, or the mock TPM of tests
+    pub(crate) fn start<B>(mut backend: B) -> Result<Self>
+    where
+        B: TpmBackend + 'static,
+    {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let pending = Arc::new(AtomicUsize::new(0));
+
+        let thread_pending = pending.clone();
+        let _ = std::thread::Builder::new().name("tpm".to_string()).spawn(
+            move || {
+                for job in receiver {
+                    // The result sender is dropped with the job, so the
+                    // caller gets an error
+                    if catch_unwind(AssertUnwindSafe(|| job(&mut backend)))
+                        .is_err()
+                    {
+                        error!("TPM operation panicked");
+                    }
+                    let _ = thread_pending.fetch_sub(1, Ordering::SeqCst);
+                }
+                debug!("TPM worker stopped");
+            },
+        )?;
+
+        Ok(TpmWorker {
+            jobs: Mutex::new(jobs),
+            pending,
+        })
+    }
+
+    fn submit(&self, job: Job) -> Result<()> {
+        let _ = self.pending.fetch_add(1, Ordering::SeqCst);
+        let sent = match self.jobs.lock() {
+            Ok(jobs) => jobs.send(job).is_ok(),
+            Err(_) => false,
+        };
+        if !sent {
+            let _ = self.pending.fetch_sub(1, Ordering::SeqCst);
+            return Err(Error::Other("TPM worker stopped".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Runs an operation on the TPM, waiting for it asynchronously
+    pub(crate) async fn run<F, R>(&self, operation: F) -> Result<R>
+    where
+        F: FnOnce(&mut dyn TpmBackend) -> Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        self.submit(Box::new(move |backend| {
+            #[cfg(feature = "testing")]
+            let result = fault::tpm().and_then(|()| operation(backend));
+            #[cfg(not(feature = "testing"))]
+            let result = operation(backend);
+            let _ = sender.send(result);
+        }))?;
+        receiver.await.map_err(|_| {
+            Error::Other("TPM operation did not complete".to_string())
+        })?
+    }
+
+    /// Number of operations waiting for or using the TPM
+    pub(crate) fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tpm_backend::MockTpm;
+
+    #[tokio::test]
+    async fn worker_survives_panic() {
+        let worker = TpmWorker::start(MockTpm::new()).unwrap(); //#[allow_ci]
+        let result: Result<()> =
+            worker.run(|_| panic!("operation failed")).await; //#[allow_ci]
+        assert!(result.is_err());
+
+        let random = worker.run(|tpm| tpm.get_random(4)).await;
+        assert_eq!(random.unwrap(), vec![0x2a; 4]); //#[allow_ci]
+    }
+}
@@ -0,0 +1,540 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2021 Keylime Authors
+
+// End-to-end tests of the attestation path
+//
+// Built with the testing feature, these start a software TPM with a fresh
+// state, and the agent on it, as the tenant and verifier find it: without
+// a registrar, registration stubbed, and with a measurement list of the
+// test in place of IMA's, see common.rs. They then request identity and
+// integrity quotes, and deliver the bootstrap key and a payload, over the
+// agent's HTTP API. One restarts the agent, to check that it keeps its AK.
+//
+// The agents built for testing share their secure directory, so the tests
+// run one at a time.
+//
+// They need swtpm in the PATH:
+//
+//   $ cargo test --features testing --test attestation
+
+#![cfg(feature = "testing")]
+
+use flate2::read::ZlibDecoder;
+use keylime::crypto;
+use lazy_static::lazy_static;
+use openssl::rand::rand_bytes;
+use openssl::rsa::{Padding, Rsa};
+use openssl::symm::{encrypt_aead, Cipher};
+use serde_json::{json, Value};
+use std::fs;
+use std::io::Read;
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+const AGENT_UUID: &str = "d432fbb3-d2f1-4a97-9ef7-75bd81c00000";
+// The agent creates the EK and AK before it listens
+const START_TIMEOUT: Duration = Duration::from_secs(60);
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+// The ports are free when picked, but another process may bind them before
+// swtpm or the agent do, which then exit and are started again on others
+const START_ATTEMPTS: usize = 3;
+const NONCE: &str = "1234567890ABCDEFHIJ";
+// Where the agent built for testing keeps its secure directory
+const SECURE_DIR: &str = "/tmp/tmpfs-dev";
+const PAYLOAD_FILE: &str = "attestation_test_payload";
+
+static IMA_ENTRIES: &str = "10 0adefe762c149c7cec19da62f0da1297fcfbffff ima-ng sha256:f1125b940480d20ad841d26d5ea253edc0704b5ec1548c891edf212cb1a9365e /usr/lib64/libcrypto.so.1.1.1k\n\
+                            10 0000000000000000000000000000000000000000 ima-ng sha256:0000000000000000000000000000000000000000000000000000000000000000 /usr/bin/violation\n";
+
+lazy_static! {
+    static ref SERIAL: Mutex<()> = Mutex::new(());
+}
+
+fn free_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap(); //#[allow_ci]
+    listener.local_addr().unwrap().port() //#[allow_ci]
+}
+
+// swtpm listens on a port for commands, and the next for control
+fn free_port_pair() -> u16 {
+    loop {
+        let port = free_port();
+        if port < u16::MAX
+            && TcpListener::bind(("127.0.0.1", port + 1)).is_ok()
+        {
+            return port;
+        }
+    }
+}
+
+// Sets a key of a section of keylime.conf, adding it if missing
+fn set(conf: &str, section: &str, key: &str, value: &str) -> String {
+    let setting = format!("{} = {}", key, value);
+    let mut out = Vec::new();
+    let mut in_section = false;
+    let mut done = false;
+    for line in conf.lines() {
+        if line.starts_with('[') {
+            if in_section && !done {
+                out.push(setting.clone());
+                done = true;
+            }
+            in_section = line.trim() == format!("[{}]", section);
+        } else if in_section
+            && !done
+            && !line.starts_with('#')
+            && line.split('=').next().map(str::trim) == Some(key)
+        {
+            out.push(setting.clone());
+            done = true;
+            continue;
+        }
+        out.push(line.to_string());
+    }
+    if !done {
+        if !in_section {
+            out.push(format!("[{}]", section));
+        }
+        out.push(setting);
+    }
+    out.join("\n") + "\n"
+}
+
+// Fails if the process exited before it listened on the port
+fn wait_for_port(
+    port: u16,
+    process: &mut Child,
+    name: &str,
+) -> Result<(), String> {
+    let start = Instant::now();
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        let exited = process.try_wait().unwrap(); //#[allow_ci]
+        if let Some(status) = exited {
+            return Err(format!("{} exited: {}", name, status));
+        }
+        if start.elapsed() > START_TIMEOUT {
+            panic!("{} did not listen on port {}", name, port); //#[allow_ci]
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+    Ok(())
+}
+
+// Starts a process listening on a port picked by port(), again on another
+// port if it exits before it listens
+fn start_on_port(
+    name: &str,
+    port: impl Fn() -> u16,
+    spawn: impl Fn(u16) -> Child,
+) -> (Child, u16) {
+    for attempt in 1..=START_ATTEMPTS {
+        let port = port();
+        let mut process = spawn(port);
+        match wait_for_port(port, &mut process, name) {
+            Ok(()) => return (process, port),
+            Err(e) if attempt < START_ATTEMPTS => {
+                eprintln!("{}, starting it again", e)
+            }
+            Err(e) => panic!("{}", e), //#[allow_ci]
+        }
+    }
+    unreachable!() //#[allow_ci]
+}
+
+// What the agent is started with, to start it again
+struct AgentEnv {
+    conf_path: PathBuf,
+    work_dir: PathBuf,
+    run_dir: PathBuf,
+    ima_ml: PathBuf,
+    tpm_port: u16,
+}
+
+impl AgentEnv {
+    fn spawn(&self) -> Child {
+        Command::new(env!("CARGO_BIN_EXE_keylime_agent"))
+            .env("KEYLIME_CONFIG", &self.conf_path)
+            .env("KEYLIME_DIR", &self.work_dir)
+            .env("KEYLIME_RUN_DIR", &self.run_dir)
+            .env("KEYLIME_IMA_ML", &self.ima_ml)
+            .env("KEYLIME_STUB_REGISTRATION", "1")
+            .env(
+                "TCTI",
+                format!("swtpm:host=127.0.0.1,port={}", self.tpm_port),
+            )
+            .spawn()
+            .unwrap() //#[allow_ci]
+    }
+}
+
+// A software TPM and the agent using it, stopped when dropped
+struct Harness {
+    swtpm: Child,
+    agent: Child,
+    agent_env: AgentEnv,
+    agent_port: u16,
+    url: String,
+    _dir: tempfile::TempDir,
+    // Held until the agent is stopped
+    _serial: MutexGuard<'static, ()>,
+}
+
+impl Harness {
+    fn start() -> Self {
+        Harness::start_with(&[])
+    }
+
+    // Starts the agent with the given settings of keylime.conf, on top of
+    // those of the tests
+    fn start_with(settings: &[(&str, &str, &str)]) -> Self {
+        // A test that failed does not stop the others
+        let serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let tpm_state = dir.path().join("tpm");
+        let work_dir = dir.path().join("work");
+        let run_dir = dir.path().join("run");
+        for path in &[&tpm_state, &work_dir, &run_dir] {
+            fs::create_dir(path).unwrap(); //#[allow_ci]
+        }
+        fs::create_dir_all(SECURE_DIR).unwrap(); //#[allow_ci]
+
+        let (swtpm, tpm_port) =
+            start_on_port("swtpm", free_port_pair, |port| {
+                Command::new("swtpm")
+                    .arg("socket")
+                    .arg("--tpm2")
+                    .arg("--tpmstate")
+                    .arg(format!("dir={}", tpm_state.display()))
+                    .arg("--flags")
+                    .arg("startup-clear")
+                    .arg("--server")
+                    .arg(format!("type=tcp,port={}", port))
+                    .arg("--ctrl")
+                    .arg(format!("type=tcp,port={}", port + 1))
+                    .spawn()
+                    .expect("swtpm must be installed to run these tests") //#[allow_ci]
+            });
+
+        // Revocation messages are verified with this key, the agent does
+        // not start without it
+        let (revocation_key, _) = crypto::rsa_generate_pair(2048).unwrap(); //#[allow_ci]
+        let revocation_cert = dir.path().join("revocation.pem");
+        fs::write(
+            &revocation_cert,
+            revocation_key.public_key_to_pem().unwrap(), //#[allow_ci]
+        )
+        .unwrap(); //#[allow_ci]
+        let ima_ml = dir.path().join("ascii_runtime_measurements");
+        fs::write(&ima_ml, IMA_ENTRIES).unwrap(); //#[allow_ci]
+
+        let template = fs::read_to_string(
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("keylime.conf"),
+        )
+        .unwrap(); //#[allow_ci]
+        let revocation_cert = revocation_cert.display().to_string();
+        let agent_env = AgentEnv {
+            conf_path: dir.path().join("keylime.conf"),
+            work_dir,
+            run_dir,
+            ima_ml,
+            tpm_port,
+        };
+        let (agent, agent_port) =
+            start_on_port("keylime_agent", free_port, |agent_port| {
+                let revocation_port = free_port().to_string();
+                let mut conf = template.clone();
+                for (section, key, value) in [
+                    ("general", "receive_revocation_ip", "127.0.0.1"),
+                    ("general", "receive_revocation_port", &revocation_port),
+                    ("cloud_agent", "cloudagent_ip", "127.0.0.1"),
+                    (
+                        "cloud_agent",
+                        "cloudagent_port",
+                        &agent_port.to_string(),
+                    ),
+                    ("cloud_agent", "agent_uuid", AGENT_UUID),
+                    ("cloud_agent", "revocation_cert", &revocation_cert),
+                    ("cloud_agent", "dec_payload_file", PAYLOAD_FILE),
+                    ("cloud_agent", "extract_payload_zip", "False"),
+                    ("cloud_agent", "tpm_hash_alg", "sha256"),
+                ]
+                .iter()
+                .chain(settings)
+                {
+                    conf = set(&conf, section, key, value);
+                }
+                fs::write(&agent_env.conf_path, conf).unwrap(); //#[allow_ci]
+                agent_env.spawn()
+            });
+
+        Harness {
+            swtpm,
+            agent,
+            agent_env,
+            agent_port,
+            url: format!("http://127.0.0.1:{}/v2.0", agent_port),
+            _dir: dir,
+            _serial: serial,
+        }
+    }
+
+    // Stops the agent and starts it again with the same TPM, work directory
+    // and port, as after a reboot of the node
+    fn restart_agent(&mut self) {
+        let _ = self.agent.kill();
+        let _ = self.agent.wait();
+        self.agent = self.agent_env.spawn();
+        wait_for_port(self.agent_port, &mut self.agent, "keylime_agent")
+            .unwrap(); //#[allow_ci]
+    }
+
+    // The agent data the agent keeps in its work directory
+    fn agent_data(&self) -> Value {
+        let path = self.agent_env.work_dir.join("agent_data.json");
+        serde_json::from_slice(&fs::read(path).unwrap()).unwrap() //#[allow_ci]
+    }
+
+    // Errors may not have a JSON body, they are returned as null
+    async fn get(&self, path: &str) -> (u16, Value) {
+        let response = reqwest::get(&format!("{}{}", self.url, path))
+            .await
+            .unwrap(); //#[allow_ci]
+        let status = response.status().as_u16();
+        (status, response.json().await.unwrap_or(Value::Null))
+    }
+
+    async fn post(&self, path: &str, body: &Value) -> (u16, Value) {
+        let response = reqwest::Client::new()
+            .post(&format!("{}{}", self.url, path))
+            .json(body)
+            .send()
+            .await
+            .unwrap(); //#[allow_ci]
+        let status = response.status().as_u16();
+        (status, response.json().await.unwrap_or(Value::Null))
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        let _ = self.agent.kill();
+        let _ = self.agent.wait();
+        let _ = self.swtpm.kill();
+        let _ = self.swtpm.wait();
+    }
+}
+
+// Returns the TPMS_ATTEST, signature and PCR blob of a quote
+fn decode_quote(quote: &str) -> Vec<Vec<u8>> {
+    assert!(quote.starts_with('r'));
+    quote[1..]
+        .split(':')
+        .map(|part| {
+            let mut data = Vec::new();
+            let _ = ZlibDecoder::new(&base64::decode(part).unwrap()[..]) //#[allow_ci]
+                .read_to_end(&mut data)
+                .unwrap(); //#[allow_ci]
+            data
+        })
+        .collect()
+}
+
+fn contains(data: &[u8], part: &[u8]) -> bool {
+    data.windows(part.len()).any(|window| window == part)
+}
+
+#[tokio::test]
+async fn quotes() {
+    let agent = Harness::start();
+
+    let (status, json) = agent
+        .get(&format!("/quotes/identity?nonce={}", NONCE))
+        .await;
+    assert_eq!(status, 200, "{}", json);
+    let results = &json["results"];
+    assert_eq!(results["hash_alg"], "sha256");
+    let quote = decode_quote(results["quote"].as_str().unwrap()); //#[allow_ci]
+    assert_eq!(quote.len(), 3);
+    // The nonce is the qualifying data of the quote
+    assert!(contains(&quote[0], NONCE.as_bytes()));
+    assert!(!quote[1].is_empty());
+    let pubkey = results["pubkey"].as_str().unwrap(); //#[allow_ci]
+    assert!(Rsa::public_key_from_pem(pubkey.as_bytes()).is_ok());
+
+    let (status, json) = agent
+        .get(&format!(
+            "/quotes/integrity?nonce={}&mask=0x400&vmask=0x0&partial=1",
+            NONCE
+        ))
+        .await;
+    assert_eq!(status, 200, "{}", json);
+    let results = &json["results"];
+    assert_eq!(results["ima_measurement_list"], IMA_ENTRIES);
+    let quote = decode_quote(results["quote"].as_str().unwrap()); //#[allow_ci]
+    assert!(contains(&quote[0], NONCE.as_bytes()));
+
+    // Entries from the second one on
+    let (status, json) = agent
+        .get(&format!(
+            "/quotes/integrity?nonce={}&mask=0x400&vmask=0x0&partial=1&ima_ml_entry=1",
+            NONCE
+        ))
+        .await;
+    assert_eq!(status, 200, "{}", json);
+    assert_eq!(json["results"]["ima_measurement_list_entry"], 1);
+    assert_eq!(
+        json["results"]["ima_measurement_list"],
+        IMA_ENTRIES.lines().nth(1).unwrap().to_string() + "\n" //#[allow_ci]
+    );
+
+    let (status, _) =
+        agent.get("/quotes/identity?nonce=not-alphanumeric").await;
+    assert_eq!(status, 400);
+}
+
+#[tokio::test]
+async fn ak_persisted_across_restarts() {
+    let mut agent = Harness::start();
+    let ak = agent.agent_data()["ak"].clone();
+    assert!(ak["public"].is_string(), "{}", ak);
+    assert!(ak["private"].is_string(), "{}", ak);
+
+    // A persisted AK that cannot be loaded would be replaced by a new one
+    agent.restart_agent();
+    assert_eq!(agent.agent_data()["ak"], ak);
+
+    let (status, json) = agent
+        .get(&format!("/quotes/identity?nonce={}", NONCE))
+        .await;
+    assert_eq!(status, 200, "{}", json);
+    let quote = decode_quote(json["results"]["quote"].as_str().unwrap()); //#[allow_ci]
+    assert!(contains(&quote[0], NONCE.as_bytes()));
+}
+
+#[tokio::test]
+async fn key_delivery() {
+    let agent = Harness::start();
+
+    let (status, _) = agent.get("/keys/verify?challenge=abc").await;
+    assert_eq!(status, 400);
+
+    // The NK the halves of the key are encrypted with, as the tenant and
+    // verifier get it
+    let (_, json) = agent
+        .get(&format!("/quotes/identity?nonce={}", NONCE))
+        .await;
+    let nk = Rsa::public_key_from_pem(
+        json["results"]["pubkey"].as_str().unwrap().as_bytes(), //#[allow_ci]
+    )
+    .unwrap(); //#[allow_ci]
+    let encrypt = |half: &[u8]| {
+        let mut encrypted = vec![0; nk.size() as usize];
+        let len = nk
+            .public_encrypt(half, &mut encrypted, Padding::PKCS1_OAEP)
+            .unwrap(); //#[allow_ci]
+        encrypted.truncate(len);
+        base64::encode(encrypted)
+    };
+
+    let mut k = [0u8; 32];
+    let mut u = [0u8; 32];
+    let mut iv = [0u8; 16];
+    rand_bytes(&mut k).unwrap(); //#[allow_ci]
+    rand_bytes(&mut u).unwrap(); //#[allow_ci]
+    rand_bytes(&mut iv).unwrap(); //#[allow_ci]
+    let v = k.iter().zip(&u).map(|(k, u)| k ^ u).collect::<Vec<_>>();
+
+    // As Python Keylime's crypto.encrypt() does: iv, ciphertext and tag
+    let mut tag = [0u8; 16];
+    let mut payload = iv.to_vec();
+    payload.extend(
+        encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &k,
+            Some(&iv),
+            &[],
+            b"attestation test payload",
+            &mut tag,
+        )
+        .unwrap(), //#[allow_ci]
+    );
+    payload.extend(&tag);
+
+    let auth_tag = hex::encode(
+        crypto::compute_hmac(&k, AGENT_UUID.as_bytes()).unwrap(), //#[allow_ci]
+    );
+    let (status, json) = agent
+        .post(
+            "/keys/ukey",
+            &json!({
+                "encrypted_key": encrypt(&u),
+                "auth_tag": auth_tag,
+                "payload": base64::encode(payload),
+            }),
+        )
+        .await;
+    assert_eq!(status, 200, "{}", json);
+    let (status, json) = agent
+        .post("/keys/vkey", &json!({ "encrypted_key": encrypt(&v) }))
+        .await;
+    assert_eq!(status, 200, "{}", json);
+
+    // The agent proves it derived K
+    let (status, json) = agent.get("/keys/verify?challenge=abc").await;
+    assert_eq!(status, 200, "{}", json);
+    assert_eq!(
+        json["results"]["hmac"],
+        hex::encode(crypto::compute_hmac(&k, b"abc").unwrap()) //#[allow_ci]
+    );
+    let payload = Path::new(SECURE_DIR).join(PAYLOAD_FILE);
+    assert_eq!(
+        fs::read(&payload).unwrap(), //#[allow_ci]
+        b"attestation test payload"
+    );
+    let _ = fs::remove_file(payload);
+}
+
+// Without SEV-SNP or TDX, quotes fail with an error saying why, rather than
+// being returned without the evidence asked for
+#[tokio::test]
+async fn confidential_computing_evidence() {
+    let agent =
+        Harness::start_with(&[("cloud_agent", "sev_snp_evidence", "True")]);
+    let (status, json) = agent
+        .get(&format!("/quotes/identity?nonce={}", NONCE))
+        .await;
+    if Path::new("/dev/sev-guest").exists() {
+        assert_eq!(status, 200, "{}", json);
+        assert!(json["results"]["snp_report"].is_string());
+    } else {
+        assert_eq!(status, 500, "{}", json);
+        // Error::File
+        assert_eq!(json["results"]["error_code"], 13);
+        let message = json["status"].as_str().unwrap(); //#[allow_ci]
+        assert!(message.contains("/dev/sev-guest"), "{}", message);
+    }
+    drop(agent);
+
+    let agent =
+        Harness::start_with(&[("cloud_agent", "tdx_evidence", "True")]);
+    let (status, json) = agent
+        .get(&format!("/quotes/identity?nonce={}", NONCE))
+        .await;
+    if !cfg!(feature = "tdx") {
+        assert_eq!(status, 500, "{}", json);
+        // Error::ConfigValue
+        assert_eq!(json["results"]["error_code"], 5);
+        let message = json["status"].as_str().unwrap(); //#[allow_ci]
+        assert!(message.contains("tdx_evidence"), "{}", message);
+    } else if !Path::new("/sys/kernel/config/tsm/report").exists() {
+        assert_eq!(status, 500, "{}", json);
+        assert_eq!(json["results"]["error_code"], 13);
+    } else if status == 200 {
+        assert!(json["results"]["tdx_quote"].is_string());
+    } else {
+        assert_eq!(status, 500, "{}", json);
+    }
+}
@@ -33,3 +33,6 @@ RUST_BACKTRACE=1 cargo build
 
 echo "-------- Testing"
 TCTI=tabrmd:bus_type=session RUST_BACKTRACE=1 RUST_LOG=info cargo test -- --nocapture
+
+echo "-------- Testing the attestation path"
+RUST_BACKTRACE=1 RUST_LOG=info cargo test --features testing --test attestation -- --nocapture
@@ -0,0 +1,5 @@
+#!/bin/sh
+# SPDX-License-Identifier: Apache-2.0
+# Copyright 2021 Keylime Authors
+
+echo autorun